@@ -11,6 +11,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use safekeeper::membership::{Generation, INVALID_GENERATION};
 use utils::{
     connstring::connection_host_port,
     lsn::Lsn,
@@ -20,8 +21,13 @@ use utils::{
 
 use crate::local_env::LocalEnv;
 use crate::postgresql_conf::PostgresConf;
+use crate::safekeeper::SafekeeperNode;
 use crate::storage::PageServerNode;
 
+/// Configuration file with the safekeepers' membership configuration
+/// generation, included by postgresql.conf.
+const SAFEKEEPERS_GENERATION_CONF: &str = "safekeepers_generation.conf";
+
 //
 // ComputeControlPlane
 //
@@ -342,6 +348,9 @@ impl PostgresNode {
                 .collect::<Vec<String>>()
                 .join(",");
             conf.append("wal_acceptors", &safekeepers);
+            // The membership configuration generation of the timeline can
+            // change between the starts, it's written to a separate file.
+            conf.append("include_if_exists", SAFEKEEPERS_GENERATION_CONF);
         } else {
             // We only use setup without safekeepers for tests,
             // and don't care about data durability on pageserver,
@@ -361,6 +370,39 @@ impl PostgresNode {
         Ok(())
     }
 
+    /// Once the safekeepers' membership of the timeline is configured, they
+    /// only accept the walproposer with its current generation. Write it to
+    /// the file included by postgresql.conf.
+    fn write_safekeepers_generation(&self) -> Result<()> {
+        let mut conf = PostgresConf::new();
+        let generation = self.safekeepers_generation();
+        if generation != INVALID_GENERATION {
+            conf.append("zenith.safekeepers_generation", &generation.to_string());
+        }
+        fs::write(
+            self.pgdata().join(SAFEKEEPERS_GENERATION_CONF),
+            conf.to_string(),
+        )?;
+        Ok(())
+    }
+
+    /// Newest membership configuration generation of the timeline among the
+    /// safekeepers. The ones that are down or don't have the timeline yet are
+    /// skipped.
+    fn safekeepers_generation(&self) -> Generation {
+        self.env
+            .safekeepers
+            .iter()
+            .filter_map(|sk_conf| {
+                SafekeeperNode::from_env(&self.env, sk_conf)
+                    .timeline_membership(self.tenant_id, self.timeline_id)
+                    .ok()
+            })
+            .map(|mconf| mconf.generation)
+            .max()
+            .unwrap_or(INVALID_GENERATION)
+    }
+
     fn load_basebackup(&self, auth_token: &Option<String>) -> Result<()> {
         let backup_lsn = if let Some(lsn) = self.lsn {
             Some(lsn)
@@ -456,6 +498,9 @@ impl PostgresNode {
 
         // 2. Bring back config files
         fs::write(&postgresql_conf_path, postgresql_conf)?;
+        if !self.env.safekeepers.is_empty() {
+            self.write_safekeepers_generation()?;
+        }
 
         // 3. Load basebackup
         self.load_basebackup(auth_token)?;
@@ -14,6 +14,7 @@ use postgres::Config;
 use reqwest::blocking::{Client, RequestBuilder, Response};
 use reqwest::{IntoUrl, Method};
 use safekeeper::http::models::TimelineCreateRequest;
+use safekeeper::membership::Configuration;
 use thiserror::Error;
 use utils::{
     connstring::connection_address,
@@ -310,4 +311,22 @@ impl SafekeeperNode {
             .error_from_body()?
             .json()?)
     }
+
+    pub fn timeline_membership(
+        &self,
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+    ) -> Result<Configuration> {
+        Ok(self
+            .http_request(
+                Method::GET,
+                format!(
+                    "{}/tenant/{}/timeline/{}/membership",
+                    self.http_base_url, tenant_id, timeline_id
+                ),
+            )
+            .send()?
+            .error_from_body()?
+            .json()?)
+    }
 }
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
+use std::fs::File;
 use std::io::Write;
 use std::net::TcpStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 use std::{io, result, thread};
@@ -410,6 +411,18 @@ impl PageServerNode {
                     .map(|x| x.parse::<usize>())
                     .transpose()?,
                 pitr_interval: settings.get("pitr_interval").map(|x| x.to_string()),
+                max_local_layers_size: settings
+                    .get("max_local_layers_size")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
+                wal_redo_process_pool_size: settings
+                    .get("wal_redo_process_pool_size")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()?,
+                max_logical_size: settings
+                    .get("max_logical_size")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
             })
             .send()?
             .error_from_body()?
@@ -449,6 +462,15 @@ impl PageServerNode {
                     .get("image_creation_threshold")
                     .map(|x| x.parse::<usize>().unwrap()),
                 pitr_interval: settings.get("pitr_interval").map(|x| x.to_string()),
+                max_local_layers_size: settings
+                    .get("max_local_layers_size")
+                    .map(|x| x.parse::<u64>().unwrap()),
+                wal_redo_process_pool_size: settings
+                    .get("wal_redo_process_pool_size")
+                    .map(|x| x.parse::<usize>().unwrap()),
+                max_logical_size: settings
+                    .get("max_logical_size")
+                    .map(|x| x.parse::<u64>().unwrap()),
             })
             .send()?
             .error_from_body()?;
@@ -456,6 +478,28 @@ impl PageServerNode {
         Ok(())
     }
 
+    pub fn tenant_detach(&self, tenant_id: ZTenantId) -> Result<()> {
+        self.http_request(
+            Method::POST,
+            format!("{}/tenant/{}/detach", self.http_base_url, tenant_id),
+        )
+        .send()?
+        .error_from_body()?;
+
+        Ok(())
+    }
+
+    pub fn tenant_delete(&self, tenant_id: ZTenantId) -> Result<()> {
+        self.http_request(
+            Method::DELETE,
+            format!("{}/tenant/{}", self.http_base_url, tenant_id),
+        )
+        .send()?
+        .error_from_body()?;
+
+        Ok(())
+    }
+
     pub fn timeline_list(&self, tenant_id: &ZTenantId) -> anyhow::Result<Vec<TimelineInfo>> {
         let timeline_infos: Vec<TimelineInfo> = self
             .http_request(
@@ -474,6 +518,7 @@ impl PageServerNode {
         tenant_id: ZTenantId,
         new_timeline_id: Option<ZTimelineId>,
         ancestor_start_lsn: Option<Lsn>,
+        ancestor_start_timestamp: Option<String>,
         ancestor_timeline_id: Option<ZTimelineId>,
     ) -> anyhow::Result<Option<TimelineInfo>> {
         let timeline_info_response = self
@@ -484,6 +529,7 @@ impl PageServerNode {
             .json(&TimelineCreateRequest {
                 new_timeline_id,
                 ancestor_start_lsn,
+                ancestor_start_timestamp,
                 ancestor_timeline_id,
             })
             .send()?
@@ -492,4 +538,35 @@ impl PageServerNode {
 
         Ok(timeline_info_response)
     }
+    pub fn timeline_import(
+        &self,
+        tenant_id: ZTenantId,
+        new_timeline_id: ZTimelineId,
+        base_lsn: Lsn,
+        end_lsn: Option<Lsn>,
+        tar_path: &Path,
+    ) -> anyhow::Result<Option<TimelineInfo>> {
+        let mut query = vec![("base_lsn", base_lsn.to_string())];
+        if let Some(end_lsn) = end_lsn {
+            query.push(("end_lsn", end_lsn.to_string()));
+        }
+        let tar_file = File::open(tar_path)
+            .with_context(|| format!("Failed to open {}", tar_path.display()))?;
+
+        let timeline_info_response = self
+            .http_request(
+                Method::POST,
+                format!(
+                    "{}/tenant/{}/timeline/{}/import",
+                    self.http_base_url, tenant_id, new_timeline_id
+                ),
+            )
+            .query(&query)
+            .body(tar_file)
+            .send()?
+            .error_from_body()?
+            .json::<Option<TimelineInfo>>()?;
+
+        Ok(timeline_info_response)
+    }
 }
@@ -7,7 +7,7 @@ use std::{
 };
 
 use regex::{Captures, Regex};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 
 pub use etcd_client::*;
@@ -23,10 +23,26 @@ use utils::{
 /// This way allows isolating safekeeper/pageserver groups in the same etcd cluster.
 pub const DEFAULT_NEON_BROKER_ETCD_PREFIX: &str = "neon";
 
-#[derive(Debug, Deserialize, Serialize)]
-struct SafekeeperTimeline {
-    safekeeper_id: NodeId,
-    info: SkTimelineInfo,
+/// Kind of the node that publishes its timeline data to the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    Safekeeper,
+    Pageserver,
+}
+
+impl Display for NodeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeKind::Safekeeper => f.write_str("safekeeper"),
+            NodeKind::Pageserver => f.write_str("pageserver"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NodeTimeline<V> {
+    node_id: NodeId,
+    info: V,
 }
 
 /// Published data about safekeeper's timeline. Fields made optional for easy migrations.
@@ -58,6 +74,20 @@ pub struct SkTimelineInfo {
     pub safekeeper_connection_string: Option<String>,
 }
 
+/// Published data about pageserver's timeline. Fields made optional for easy migrations.
+#[serde_as]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PsTimelineInfo {
+    /// LSN up to which the pageserver has persisted the timeline data on local disk.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub disk_consistent_lsn: Option<Lsn>,
+    /// LSN up to which the timeline data is uploaded to the remote storage.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub remote_consistent_lsn: Option<Lsn>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum BrokerError {
     #[error("Etcd client error: {0}. Context: {1}")]
@@ -69,20 +99,23 @@ pub enum BrokerError {
 }
 
 /// A way to control the data retrieval from a certain subscription.
-pub struct SkTimelineSubscription {
-    safekeeper_timeline_updates:
-        mpsc::UnboundedReceiver<HashMap<ZTenantTimelineId, HashMap<NodeId, SkTimelineInfo>>>,
+pub struct TimelineSubscription<V> {
+    timeline_updates: mpsc::UnboundedReceiver<HashMap<ZTenantTimelineId, HashMap<NodeId, V>>>,
     kind: SkTimelineSubscriptionKind,
     watcher_handle: JoinHandle<Result<(), BrokerError>>,
     watcher: Watcher,
 }
 
-impl SkTimelineSubscription {
+/// Subscription to the timeline data published by safekeepers.
+pub type SkTimelineSubscription = TimelineSubscription<SkTimelineInfo>;
+
+/// Subscription to the timeline data published by pageservers.
+pub type PsTimelineSubscription = TimelineSubscription<PsTimelineInfo>;
+
+impl<V> TimelineSubscription<V> {
     /// Asynchronously polls for more data from the subscription, suspending the current future if there's no data sent yet.
-    pub async fn fetch_data(
-        &mut self,
-    ) -> Option<HashMap<ZTenantTimelineId, HashMap<NodeId, SkTimelineInfo>>> {
-        self.safekeeper_timeline_updates.recv().await
+    pub async fn fetch_data(&mut self) -> Option<HashMap<ZTenantTimelineId, HashMap<NodeId, V>>> {
+        self.timeline_updates.recv().await
     }
 
     /// Cancels the subscription, stopping the data poller and waiting for it to shut down.
@@ -105,7 +138,7 @@ impl SkTimelineSubscription {
     }
 }
 
-/// The subscription kind to the timeline updates from safekeeper.
+/// The subscription kind to the timeline updates from safekeepers or pageservers.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SkTimelineSubscriptionKind {
     broker_etcd_prefix: String,
@@ -134,15 +167,15 @@ impl SkTimelineSubscriptionKind {
         }
     }
 
-    fn watch_regex(&self) -> Regex {
+    fn watch_regex(&self, node_kind: NodeKind) -> Regex {
         match self.kind {
             SubscriptionKind::All => Regex::new(&format!(
-                r"^{}/([[:xdigit:]]+)/([[:xdigit:]]+)/safekeeper/([[:digit:]])$",
+                r"^{}/([[:xdigit:]]+)/([[:xdigit:]]+)/{node_kind}/([[:digit:]]+)$",
                 self.broker_etcd_prefix
             ))
             .expect("wrong regex for 'everything' subscription"),
             SubscriptionKind::Tenant(tenant_id) => Regex::new(&format!(
-                r"^{}/{tenant_id}/([[:xdigit:]]+)/safekeeper/([[:digit:]])$",
+                r"^{}/{tenant_id}/([[:xdigit:]]+)/{node_kind}/([[:digit:]]+)$",
                 self.broker_etcd_prefix
             ))
             .expect("wrong regex for 'tenant' subscription"),
@@ -150,7 +183,7 @@ impl SkTimelineSubscriptionKind {
                 tenant_id,
                 timeline_id,
             }) => Regex::new(&format!(
-                r"^{}/{tenant_id}/{timeline_id}/safekeeper/([[:digit:]])$",
+                r"^{}/{tenant_id}/{timeline_id}/{node_kind}/([[:digit:]]+)$",
                 self.broker_etcd_prefix
             ))
             .expect("wrong regex for 'timeline' subscription"),
@@ -159,16 +192,21 @@ impl SkTimelineSubscriptionKind {
 
     /// Etcd key to use for watching a certain timeline updates from safekeepers.
     pub fn watch_key(&self) -> String {
+        self.node_watch_key(NodeKind::Safekeeper)
+    }
+
+    /// Etcd key to use for watching a certain timeline updates from nodes of the given kind.
+    pub fn node_watch_key(&self, node_kind: NodeKind) -> String {
         match self.kind {
             SubscriptionKind::All => self.broker_etcd_prefix.to_string(),
             SubscriptionKind::Tenant(tenant_id) => {
-                format!("{}/{tenant_id}/safekeeper", self.broker_etcd_prefix)
+                format!("{}/{tenant_id}/{node_kind}", self.broker_etcd_prefix)
             }
             SubscriptionKind::Timeline(ZTenantTimelineId {
                 tenant_id,
                 timeline_id,
             }) => format!(
-                "{}/{tenant_id}/{timeline_id}/safekeeper",
+                "{}/{tenant_id}/{timeline_id}/{node_kind}",
                 self.broker_etcd_prefix
             ),
         }
@@ -193,11 +231,30 @@ pub async fn subscribe_to_safekeeper_timeline_updates(
     client: &mut Client,
     subscription: SkTimelineSubscriptionKind,
 ) -> Result<SkTimelineSubscription, BrokerError> {
-    info!("Subscribing to timeline updates, subscription kind: {subscription:?}");
+    subscribe_to_timeline_updates(client, subscription, NodeKind::Safekeeper).await
+}
+
+/// Same as [`subscribe_to_safekeeper_timeline_updates`], for the timeline updates from pageservers.
+pub async fn subscribe_to_pageserver_timeline_updates(
+    client: &mut Client,
+    subscription: SkTimelineSubscriptionKind,
+) -> Result<PsTimelineSubscription, BrokerError> {
+    subscribe_to_timeline_updates(client, subscription, NodeKind::Pageserver).await
+}
+
+async fn subscribe_to_timeline_updates<V>(
+    client: &mut Client,
+    subscription: SkTimelineSubscriptionKind,
+    node_kind: NodeKind,
+) -> Result<TimelineSubscription<V>, BrokerError>
+where
+    V: DeserializeOwned + Send + 'static,
+{
+    info!("Subscribing to {node_kind} timeline updates, subscription kind: {subscription:?}");
 
     let (watcher, mut stream) = client
         .watch(
-            subscription.watch_key(),
+            subscription.node_watch_key(node_kind),
             Some(WatchOptions::new().with_prefix()),
         )
         .await
@@ -208,10 +265,10 @@ pub async fn subscribe_to_safekeeper_timeline_updates(
             )
         })?;
 
-    let (timeline_updates_sender, safekeeper_timeline_updates) = mpsc::unbounded_channel();
+    let (timeline_updates_sender, timeline_updates) = mpsc::unbounded_channel();
 
     let subscription_kind = subscription.kind;
-    let regex = subscription.watch_regex();
+    let regex = subscription.watch_regex(node_kind);
     let watcher_handle = tokio::spawn(async move {
         while let Some(resp) = stream.message().await.map_err(|e| BrokerError::InternalError(format!(
             "Failed to get messages from the subscription stream, kind: {subscription_kind:?}, error: {e}"
@@ -221,7 +278,7 @@ pub async fn subscribe_to_safekeeper_timeline_updates(
                 break;
             }
 
-            let mut timeline_updates: HashMap<ZTenantTimelineId, HashMap<NodeId, SkTimelineInfo>> = HashMap::new();
+            let mut timeline_updates: HashMap<ZTenantTimelineId, HashMap<NodeId, V>> = HashMap::new();
             // Keep track that the timeline data updates from etcd arrive in the right order.
             // https://etcd.io/docs/v3.5/learning/api_guarantees/#isolation-level-and-consistency-of-replicas
             // > etcd does not ensure linearizability for watch operations. Users are expected to verify the revision of watch responses to ensure correct ordering.
@@ -241,7 +298,7 @@ pub async fn subscribe_to_safekeeper_timeline_updates(
                                 match timeline_updates
                                     .entry(zttid)
                                     .or_default()
-                                    .entry(timeline.safekeeper_id)
+                                    .entry(timeline.node_id)
                                 {
                                     hash_map::Entry::Occupied(mut o) => {
                                         let old_etcd_kv_version = timeline_etcd_versions.get(&zttid).copied().unwrap_or(i64::MIN);
@@ -272,19 +329,19 @@ pub async fn subscribe_to_safekeeper_timeline_updates(
         Ok(())
     });
 
-    Ok(SkTimelineSubscription {
+    Ok(TimelineSubscription {
         kind: subscription,
-        safekeeper_timeline_updates,
+        timeline_updates,
         watcher_handle,
         watcher,
     })
 }
 
-fn parse_etcd_key_value(
+fn parse_etcd_key_value<V: DeserializeOwned>(
     subscription_kind: SubscriptionKind,
     regex: &Regex,
     kv: &KeyValue,
-) -> Result<Option<(ZTenantTimelineId, SafekeeperTimeline)>, BrokerError> {
+) -> Result<Option<(ZTenantTimelineId, NodeTimeline<V>)>, BrokerError> {
     let caps = if let Some(caps) = regex.captures(kv.key_str().map_err(|e| {
         BrokerError::EtcdClient(e, format!("Failed to represent kv {kv:?} as key str"))
     })?) {
@@ -293,7 +350,7 @@ fn parse_etcd_key_value(
         return Ok(None);
     };
 
-    let (zttid, safekeeper_id) = match subscription_kind {
+    let (zttid, node_id) = match subscription_kind {
         SubscriptionKind::All => (
             ZTenantTimelineId::new(
                 parse_capture(&caps, 1).map_err(BrokerError::ParsingError)?,
@@ -319,11 +376,12 @@ fn parse_etcd_key_value(
     })?;
     Ok(Some((
         zttid,
-        SafekeeperTimeline {
-            safekeeper_id,
+        NodeTimeline {
+            node_id,
             info: serde_json::from_str(info_str).map_err(|e| {
                 BrokerError::ParsingError(format!(
-                    "Failed to parse '{info_str}' as safekeeper timeline info: {e}"
+                    "Failed to parse '{info_str}' as {}: {e}",
+                    std::any::type_name::<V>()
                 ))
             })?,
         },
@@ -925,6 +925,9 @@ impl<'a> BeMessage<'a> {
 pub struct ZenithFeedback {
     // Last known size of the timeline. Used to enforce timeline size limit.
     pub current_timeline_size: u64,
+    // Limit of the timeline size set on the pageserver, 0 if there's none.
+    // The pageserver refuses WAL that grows the timeline past it.
+    pub max_timeline_size: u64,
     // Parts of StandbyStatusUpdate we resend to compute via safekeeper
     pub ps_writelsn: u64,
     pub ps_applylsn: u64,
@@ -934,12 +937,13 @@ pub struct ZenithFeedback {
 
 // NOTE: Do not forget to increment this number when adding new fields to ZenithFeedback.
 // Do not remove previously available fields because this might be backwards incompatible.
-pub const ZENITH_FEEDBACK_FIELDS_NUMBER: u8 = 5;
+pub const ZENITH_FEEDBACK_FIELDS_NUMBER: u8 = 6;
 
 impl ZenithFeedback {
     pub fn empty() -> ZenithFeedback {
         ZenithFeedback {
             current_timeline_size: 0,
+            max_timeline_size: 0,
             ps_writelsn: 0,
             ps_applylsn: 0,
             ps_flushlsn: 0,
@@ -962,6 +966,9 @@ impl ZenithFeedback {
         write_cstr(&Bytes::from("current_timeline_size"), buf)?;
         buf.put_i32(8);
         buf.put_u64(self.current_timeline_size);
+        write_cstr(&Bytes::from("max_timeline_size"), buf)?;
+        buf.put_i32(8);
+        buf.put_u64(self.max_timeline_size);
 
         write_cstr(&Bytes::from("ps_writelsn"), buf)?;
         buf.put_i32(8);
@@ -1000,6 +1007,11 @@ impl ZenithFeedback {
                     assert_eq!(len, 8);
                     zf.current_timeline_size = buf.get_u64();
                 }
+                "max_timeline_size" => {
+                    let len = buf.get_i32();
+                    assert_eq!(len, 8);
+                    zf.max_timeline_size = buf.get_u64();
+                }
                 "ps_writelsn" => {
                     let len = buf.get_i32();
                     assert_eq!(len, 8);
@@ -1049,6 +1061,7 @@ mod tests {
         let mut zf = ZenithFeedback::empty();
         // Fill zf wih some values
         zf.current_timeline_size = 12345678;
+        zf.max_timeline_size = 87654321;
         // Set rounded time to be able to compare it with deserialized value,
         // because it is rounded up to microseconds during serialization.
         zf.ps_replytime = *PG_EPOCH + Duration::from_secs(100_000_000);
@@ -154,11 +154,25 @@ fn main() -> Result<()> {
                 .arg(Arg::new("ancestor-branch-name").long("ancestor-branch-name").takes_value(true)
                     .help("Use last Lsn of another timeline (and its data) as base when creating the new timeline. The timeline gets resolved by its branch name.").required(false))
                 .arg(Arg::new("ancestor-start-lsn").long("ancestor-start-lsn").takes_value(true)
-                    .help("When using another timeline as base, use a specific Lsn in it instead of the latest one").required(false)))
+                    .help("When using another timeline as base, use a specific Lsn in it instead of the latest one").required(false))
+                .arg(Arg::new("ancestor-start-timestamp").long("ancestor-start-timestamp").takes_value(true)
+                    .conflicts_with("ancestor-start-lsn")
+                    .help("When using another timeline as base, branch at the last commit before this point of time, in RFC 3339 format").required(false)))
             .subcommand(App::new("create")
                 .about("Create a new blank timeline")
                 .arg(tenant_id_arg.clone())
                 .arg(branch_name_arg.clone()))
+            .subcommand(App::new("import")
+                .about("Create a new timeline from a tar archive of a PostgreSQL data directory, e.g. made with 'pg_basebackup -Ft -Xfetch'")
+                .arg(tenant_id_arg.clone())
+                .arg(timeline_id_arg.clone())
+                .arg(branch_name_arg.clone())
+                .arg(Arg::new("base-lsn").long("base-lsn").takes_value(true)
+                    .help("Lsn the data files in the archive are imported at, the checkpoint REDO pointer of the backup").required(true))
+                .arg(Arg::new("end-lsn").long("end-lsn").takes_value(true)
+                    .help("Replay the WAL in the archive up to this Lsn. No WAL is replayed by default").required(false))
+                .arg(Arg::new("file").long("file").takes_value(true)
+                    .help("Path to the tar archive").required(true)))
         ).subcommand(
             App::new("tenant")
             .setting(AppSettings::ArgRequiredElseHelp)
@@ -173,6 +187,14 @@ fn main() -> Result<()> {
                 .arg(tenant_id_arg.clone())
                 .arg(Arg::new("config").short('c').takes_value(true).multiple_occurrences(true).required(false))
                 )
+            .subcommand(App::new("detach")
+                .about("Detach the tenant from the pageserver, keeping its data in the remote storage")
+                .arg(tenant_id_arg.clone())
+                )
+            .subcommand(App::new("delete")
+                .about("Delete the tenant from the pageserver and the remote storage")
+                .arg(tenant_id_arg.clone())
+                )
         )
         .subcommand(
             App::new("pageserver")
@@ -545,7 +567,7 @@ fn handle_tenant(tenant_match: &ArgMatches, env: &mut local_env::LocalEnv) -> an
             // Create an initial timeline for the new tenant
             let new_timeline_id = parse_timeline_id(create_match)?;
             let timeline = pageserver
-                .timeline_create(new_tenant_id, new_timeline_id, None, None)?
+                .timeline_create(new_tenant_id, new_timeline_id, None, None, None)?
                 .context(format!(
                     "Failed to create initial timeline for tenant {new_tenant_id}"
                 ))?;
@@ -577,6 +599,20 @@ fn handle_tenant(tenant_match: &ArgMatches, env: &mut local_env::LocalEnv) -> an
                 .with_context(|| format!("Tenant config failed for tenant with id {tenant_id}"))?;
             println!("tenant {tenant_id} successfully configured on the pageserver");
         }
+        Some(("detach", detach_match)) => {
+            let tenant_id = get_tenant_id(detach_match, env)?;
+            pageserver
+                .tenant_detach(tenant_id)
+                .with_context(|| format!("Tenant detach failed for tenant with id {tenant_id}"))?;
+            println!("tenant {tenant_id} successfully detached from the pageserver");
+        }
+        Some(("delete", delete_match)) => {
+            let tenant_id = get_tenant_id(delete_match, env)?;
+            pageserver
+                .tenant_delete(tenant_id)
+                .with_context(|| format!("Tenant delete failed for tenant with id {tenant_id}"))?;
+            println!("tenant {tenant_id} successfully deleted from the pageserver");
+        }
         Some((sub_name, _)) => bail!("Unexpected tenant subcommand '{}'", sub_name),
         None => bail!("no tenant subcommand provided"),
     }
@@ -598,7 +634,7 @@ fn handle_timeline(timeline_match: &ArgMatches, env: &mut local_env::LocalEnv) -
                 .value_of("branch-name")
                 .ok_or_else(|| anyhow!("No branch name provided"))?;
             let timeline = pageserver
-                .timeline_create(tenant_id, None, None, None)?
+                .timeline_create(tenant_id, None, None, None, None)?
                 .ok_or_else(|| anyhow!("Failed to create new timeline for tenant {}", tenant_id))?;
             let new_timeline_id = timeline.timeline_id;
 
@@ -635,8 +671,17 @@ fn handle_timeline(timeline_match: &ArgMatches, env: &mut local_env::LocalEnv) -
                 .map(Lsn::from_str)
                 .transpose()
                 .context("Failed to parse ancestor start Lsn from the request")?;
+            let start_timestamp = branch_match
+                .value_of("ancestor-start-timestamp")
+                .map(str::to_string);
             let timeline = pageserver
-                .timeline_create(tenant_id, None, start_lsn, Some(ancestor_timeline_id))?
+                .timeline_create(
+                    tenant_id,
+                    None,
+                    start_lsn,
+                    start_timestamp,
+                    Some(ancestor_timeline_id),
+                )?
                 .ok_or_else(|| anyhow!("Failed to create new timeline for tenant {}", tenant_id))?;
             let new_timeline_id = timeline.timeline_id;
 
@@ -652,6 +697,50 @@ fn handle_timeline(timeline_match: &ArgMatches, env: &mut local_env::LocalEnv) -
                 timeline.timeline_id, last_record_lsn, tenant_id, ancestor_branch_name,
             );
         }
+        Some(("import", import_match)) => {
+            let tenant_id = get_tenant_id(import_match, env)?;
+            let new_timeline_id =
+                parse_timeline_id(import_match)?.unwrap_or_else(ZTimelineId::generate);
+            let new_branch_name = import_match
+                .value_of("branch-name")
+                .ok_or_else(|| anyhow!("No branch name provided"))?;
+            let base_lsn = import_match
+                .value_of("base-lsn")
+                .map(Lsn::from_str)
+                .transpose()
+                .context("Failed to parse base Lsn from the request")?
+                .ok_or_else(|| anyhow!("No base Lsn provided"))?;
+            let end_lsn = import_match
+                .value_of("end-lsn")
+                .map(Lsn::from_str)
+                .transpose()
+                .context("Failed to parse end Lsn from the request")?;
+            let tar_path = import_match
+                .value_of("file")
+                .map(Path::new)
+                .ok_or_else(|| anyhow!("No archive file provided"))?;
+
+            let timeline = pageserver
+                .timeline_import(tenant_id, new_timeline_id, base_lsn, end_lsn, tar_path)?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Timeline {} already exists for tenant {}",
+                        new_timeline_id,
+                        tenant_id
+                    )
+                })?;
+
+            let last_record_lsn = timeline
+                .local
+                .expect("no local timeline info")
+                .last_record_lsn;
+            env.register_branch_mapping(new_branch_name.to_string(), tenant_id, new_timeline_id)?;
+
+            println!(
+                "Imported timeline '{}' at Lsn {} for tenant: {}",
+                timeline.timeline_id, last_record_lsn, tenant_id,
+            );
+        }
         Some((sub_name, _)) => bail!("Unexpected tenant subcommand '{}'", sub_name),
         None => bail!("no tenant subcommand provided"),
     }
@@ -1,6 +1,6 @@
 //! Main entry point for the Page Server executable.
 
-use std::{env, path::Path, str::FromStr};
+use std::{env, fs::File, path::Path, str::FromStr};
 use tracing::*;
 
 use anyhow::{bail, Context, Result};
@@ -9,11 +9,13 @@ use clap::{App, Arg};
 use daemonize::Daemonize;
 
 use fail::FailScenario;
+use fs2::FileExt;
 use pageserver::{
+    broker,
     config::{defaults::*, PageServerConf},
     http, page_cache, page_service, profiling, tenant_mgr, thread_mgr,
     thread_mgr::ThreadKind,
-    timelines, virtual_file, LOG_FILE_NAME,
+    timelines, virtual_file, walreceiver, LOCK_FILE_NAME, LOG_FILE_NAME,
 };
 use utils::{
     auth::JwtAuth,
@@ -216,6 +218,13 @@ fn start_pageserver(conf: &'static PageServerConf, daemonize: bool) -> Result<()
 
     info!("version: {GIT_VERSION}");
 
+    // Prevent running multiple pageservers, or modifying the files with the scrub
+    // utility, on the same directory. The lock is held until the process exits.
+    let lock_file = File::create(LOCK_FILE_NAME).context("failed to open lockfile")?;
+    lock_file
+        .try_lock_exclusive()
+        .with_context(|| format!("lock file {LOCK_FILE_NAME} is locked by some other process"))?;
+
     // TODO: Check that it looks like a valid repository before going further
 
     // bind sockets before daemonizing so we report errors early and do not return until we are listening
@@ -276,6 +285,13 @@ fn start_pageserver(conf: &'static PageServerConf, daemonize: bool) -> Result<()
 
     let remote_index = tenant_mgr::init_tenant_mgr(conf)?;
 
+    // Pick safekeepers to stream WAL from, based on their state in the broker,
+    // and let the safekeepers know how much of the WAL is durable here.
+    if !conf.broker_endpoints.is_empty() {
+        walreceiver::launch_wal_receiver_manager(conf)?;
+        broker::launch_broker_publisher(conf, remote_index.clone())?;
+    }
+
     // Spawn a new thread for the http endpoint
     // bind before launching separate thread so the error reported before startup exits
     let auth_cloned = auth.clone();
@@ -0,0 +1,583 @@
+//! Main entry point for the scrub executable
+//!
+//! Checks the layer files of a tenant for consistency, both in the local
+//! timeline directories and in the remote storage, and reports the problems
+//! found. With `--fix`, the problems that can be fixed without losing data
+//! are fixed:
+//!
+//! - local layer files that fail validation are removed, if the remote storage
+//!   has a copy of them, to be downloaded again on demand
+//! - layer files in the remote index but missing in the remote storage are
+//!   uploaded again, if there's a valid local copy of them
+//! - files in the remote storage that are not in the remote index are deleted
+//!
+//! Overlapping layers and unrecognized local files are only reported.
+//!
+//! The pageserver must not be running while the tool fixes anything: `--fix`
+//! takes the pageserver's lock file, and fails if the pageserver holds it.
+use anyhow::{ensure, Context, Result};
+use clap::{App, Arg};
+use fs2::FileExt;
+use pageserver::layered_repository::filename::{DeltaFileName, ImageFileName};
+use pageserver::layered_repository::metadata::{TimelineMetadata, METADATA_FILE_NAME};
+use pageserver::layered_repository::{validate_layerfile_from_path, TIMELINES_SEGMENT_NAME};
+use pageserver::page_cache;
+use pageserver::storage_sync::index::{IndexPart, RemoteTimeline};
+use pageserver::{virtual_file, LOCK_FILE_NAME};
+use remote_storage::{GenericRemoteStorage, LocalFs, RemoteStorage, RemoteStorageConfig};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::runtime::Runtime;
+use utils::{
+    project_git_version,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+project_git_version!(GIT_VERSION);
+
+fn main() -> Result<()> {
+    let arg_matches = App::new("Zenith scrub utility")
+        .about("Check the layer files of a tenant, locally and in the remote storage")
+        .version(GIT_VERSION)
+        .arg(
+            Arg::new("workdir")
+                .short('D')
+                .long("workdir")
+                .takes_value(true)
+                .help("Working directory for the pageserver"),
+        )
+        .arg(
+            Arg::new("tenant_id")
+                .help("Id of the tenant to check")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::new("fix")
+                .long("fix")
+                .help("Fix the problems that can be fixed without losing data"),
+        )
+        .get_matches();
+
+    let workdir = Path::new(arg_matches.value_of("workdir").unwrap_or(".zenith"));
+    let workdir = workdir
+        .canonicalize()
+        .with_context(|| format!("Error opening workdir '{}'", workdir.display()))?;
+    let tenant_id = ZTenantId::from_str(arg_matches.value_of("tenant_id").unwrap())
+        .context("Failed to parse tenant id from the arguments")?;
+    let fix = arg_matches.is_present("fix");
+
+    // Fixing removes files that a running pageserver might be uploading or reading,
+    // keep the pageserver from running while the tool works.
+    let _lock_file = if fix {
+        let lock_file_path = workdir.join(LOCK_FILE_NAME);
+        let lock_file = fs::File::create(&lock_file_path)
+            .with_context(|| format!("Failed to open lock file {}", lock_file_path.display()))?;
+        lock_file.try_lock_exclusive().with_context(|| {
+            format!(
+                "Lock file {} is locked, stop the pageserver before using --fix",
+                lock_file_path.display()
+            )
+        })?;
+        Some(lock_file)
+    } else {
+        None
+    };
+
+    // Basic initialization of things that don't change after startup
+    virtual_file::init(10);
+    page_cache::init(100);
+
+    let timelines_path = workdir
+        .join("tenants")
+        .join(tenant_id.to_string())
+        .join(TIMELINES_SEGMENT_NAME);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create the runtime")?;
+
+    let (problems, fixed) = match remote_storage_config(&workdir)? {
+        Some(storage_config) => match GenericRemoteStorage::new(workdir, &storage_config)? {
+            GenericRemoteStorage::Local(storage) => {
+                Scrubber::new(Some(&storage), runtime, fix).scrub_tenant(&timelines_path)?
+            }
+            GenericRemoteStorage::S3(storage) => {
+                Scrubber::new(Some(&storage), runtime, fix).scrub_tenant(&timelines_path)?
+            }
+        },
+        None => {
+            println!("No remote storage configured, checking local files only");
+            Scrubber::<LocalFs>::new(None, runtime, fix).scrub_tenant(&timelines_path)?
+        }
+    };
+
+    println!("Found {problems} problems, fixed {fixed}");
+    ensure!(problems == fixed, "{} problems left", problems - fixed);
+    Ok(())
+}
+
+/// Read the remote storage configuration from the pageserver config file
+fn remote_storage_config(workdir: &Path) -> Result<Option<RemoteStorageConfig>> {
+    let cfg_file_path = workdir.join("pageserver.toml");
+    let cfg_file_contents = fs::read_to_string(&cfg_file_path)
+        .with_context(|| format!("No pageserver config at '{}'", cfg_file_path.display()))?;
+    let toml = cfg_file_contents
+        .parse::<toml_edit::Document>()
+        .with_context(|| {
+            format!(
+                "Failed to read '{}' as pageserver config",
+                cfg_file_path.display()
+            )
+        })?;
+    toml.get("remote_storage")
+        .map(RemoteStorageConfig::from_toml)
+        .transpose()
+}
+
+struct Scrubber<'a, S> {
+    storage: Option<&'a S>,
+    runtime: Runtime,
+    fix: bool,
+    problems: usize,
+    fixed: usize,
+}
+
+impl<'a, P, S> Scrubber<'a, S>
+where
+    P: Debug,
+    S: RemoteStorage<RemoteObjectId = P>,
+{
+    fn new(storage: Option<&'a S>, runtime: Runtime, fix: bool) -> Self {
+        Scrubber {
+            storage,
+            runtime,
+            fix,
+            problems: 0,
+            fixed: 0,
+        }
+    }
+
+    /// Check all the timelines of the tenant, returns the number of problems found and fixed.
+    fn scrub_tenant(mut self, timelines_path: &Path) -> Result<(usize, usize)> {
+        let remote_files = match self.storage {
+            Some(storage) => self
+                .runtime
+                .block_on(storage.list())
+                .context("Failed to list the remote storage files")?
+                .iter()
+                .map(|remote_id| storage.local_path(remote_id))
+                .filter(|path| {
+                    path.as_ref()
+                        .map_or(true, |path| path.starts_with(timelines_path))
+                })
+                .collect::<Result<HashSet<_>>>()?,
+            None => HashSet::new(),
+        };
+
+        // Timelines present either locally or in the remote storage
+        let mut timeline_ids = BTreeSet::new();
+        if timelines_path.exists() {
+            for entry in fs::read_dir(timelines_path)? {
+                let file_name = entry?.file_name();
+                if let Ok(timeline_id) = ZTimelineId::from_str(&file_name.to_string_lossy()) {
+                    timeline_ids.insert(timeline_id);
+                }
+            }
+        }
+        for remote_file in &remote_files {
+            let timeline_dir = remote_file
+                .strip_prefix(timelines_path)?
+                .iter()
+                .next()
+                .unwrap_or_default();
+            if let Ok(timeline_id) = ZTimelineId::from_str(&timeline_dir.to_string_lossy()) {
+                timeline_ids.insert(timeline_id);
+            }
+        }
+
+        for timeline_id in timeline_ids {
+            let timeline_path = timelines_path.join(timeline_id.to_string());
+            let timeline_remote_files = remote_files
+                .iter()
+                .filter(|path| path.parent() == Some(timeline_path.as_path()))
+                .cloned()
+                .collect::<HashSet<_>>();
+            self.scrub_timeline(timeline_id, &timeline_path, &timeline_remote_files)
+                .with_context(|| format!("Failed to check timeline {timeline_id}"))?;
+        }
+
+        Ok((self.problems, self.fixed))
+    }
+
+    fn scrub_timeline(
+        &mut self,
+        timeline_id: ZTimelineId,
+        timeline_path: &Path,
+        remote_files: &HashSet<PathBuf>,
+    ) -> Result<()> {
+        println!("Checking timeline {timeline_id}");
+
+        // Validate the local files, remembering which layers are valid
+        let mut local_layers = HashMap::new();
+        if timeline_path.exists() {
+            for entry in fs::read_dir(timeline_path)? {
+                let path = entry?.path();
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                if file_name == METADATA_FILE_NAME {
+                    if let Err(e) = fs::read(&path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|bytes| TimelineMetadata::from_bytes(&bytes))
+                    {
+                        self.problem(timeline_id, format!("invalid metadata file: {e:#}"));
+                    }
+                } else if is_layer_file_name(&file_name) {
+                    let valid = match validate_layerfile_from_path(&path) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            self.problem(
+                                timeline_id,
+                                format!("corrupt layer file {file_name}: {e:#}"),
+                            );
+                            false
+                        }
+                    };
+                    local_layers.insert(path.clone(), valid);
+                } else if !file_name.ends_with(".old") {
+                    self.problem(timeline_id, format!("unrecognized file {file_name}"));
+                }
+            }
+        }
+
+        // Cross-check the remote index with the files in the remote storage
+        let index_part_path = timeline_path
+            .join(IndexPart::FILE_NAME)
+            .with_extension(IndexPart::FILE_EXTENSION);
+        let indexed_layers = if remote_files.contains(&index_part_path) {
+            self.download_index_part(timeline_path, &index_part_path)?
+        } else {
+            if self.storage.is_some() {
+                self.problem(
+                    timeline_id,
+                    "no index part in the remote storage".to_string(),
+                );
+            }
+            HashSet::new()
+        };
+
+        for layer in &indexed_layers {
+            if remote_files.contains(layer) {
+                continue;
+            }
+            self.problem(
+                timeline_id,
+                format!(
+                    "layer {} is in the remote index, but missing in the remote storage",
+                    layer.display()
+                ),
+            );
+            if self.fix && local_layers.get(layer) == Some(&true) {
+                self.upload(layer)?;
+                println!("  uploaded the local copy of the layer");
+                self.fixed += 1;
+            }
+        }
+
+        for remote_file in remote_files {
+            if remote_file == &index_part_path || indexed_layers.contains(remote_file) {
+                continue;
+            }
+            self.problem(
+                timeline_id,
+                format!(
+                    "file {} in the remote storage is not in the remote index",
+                    remote_file.display()
+                ),
+            );
+            if self.fix {
+                self.delete_remote(remote_file)?;
+                println!("  deleted the file from the remote storage");
+                self.fixed += 1;
+            }
+        }
+
+        for (layer, valid) in &local_layers {
+            if !valid && self.fix && remote_files.contains(layer) && indexed_layers.contains(layer)
+            {
+                fs::remove_file(layer)
+                    .with_context(|| format!("Failed to remove {}", layer.display()))?;
+                println!(
+                    "  removed corrupt layer {}, it will be downloaded again",
+                    layer.display()
+                );
+                self.fixed += 1;
+            }
+        }
+
+        let all_layers = local_layers
+            .keys()
+            .chain(indexed_layers.iter())
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect::<BTreeSet<_>>();
+        for overlap in find_overlapping_layers(&all_layers) {
+            self.problem(timeline_id, overlap);
+        }
+
+        Ok(())
+    }
+
+    fn problem(&mut self, timeline_id: ZTimelineId, description: String) {
+        println!("timeline {timeline_id}: {description}");
+        self.problems += 1;
+    }
+
+    /// Download the index part, and return the layers listed in it
+    fn download_index_part(
+        &self,
+        timeline_path: &Path,
+        index_part_path: &Path,
+    ) -> Result<HashSet<PathBuf>> {
+        let storage = self.storage.unwrap();
+        let remote_id = storage.remote_object_id(index_part_path)?;
+        let mut index_part_bytes = Vec::new();
+        self.runtime
+            .block_on(storage.download(&remote_id, &mut index_part_bytes))
+            .with_context(|| format!("Failed to download index part {remote_id:?}"))?;
+        let index_part: IndexPart = serde_json::from_slice(&index_part_bytes)
+            .with_context(|| format!("Failed to deserialize index part {remote_id:?}"))?;
+        let remote_timeline = RemoteTimeline::from_index_part(timeline_path, index_part)?;
+        Ok(remote_timeline.stored_files().clone())
+    }
+
+    fn upload(&self, local_path: &Path) -> Result<()> {
+        let storage = self.storage.unwrap();
+        let remote_id = storage.remote_object_id(local_path)?;
+        self.runtime
+            .block_on(async {
+                let file = tokio::fs::File::open(local_path).await?;
+                let size = file.metadata().await?.len() as usize;
+                storage.upload(file, size, &remote_id, None).await
+            })
+            .with_context(|| format!("Failed to upload {}", local_path.display()))
+    }
+
+    fn delete_remote(&self, local_path: &Path) -> Result<()> {
+        let storage = self.storage.unwrap();
+        let remote_id = storage.remote_object_id(local_path)?;
+        self.runtime
+            .block_on(storage.delete(&remote_id))
+            .with_context(|| format!("Failed to delete {remote_id:?}"))
+    }
+}
+
+fn is_layer_file_name(file_name: &str) -> bool {
+    ImageFileName::parse_str(file_name).is_some() || DeltaFileName::parse_str(file_name).is_some()
+}
+
+fn ranges_overlap<T: Ord>(a: &Range<T>, b: &Range<T>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Find the delta layers covering the same keys at the same LSNs, and the image
+/// layers with the same keys at the same LSN.
+fn find_overlapping_layers(layer_names: &BTreeSet<String>) -> Vec<String> {
+    let images = layer_names
+        .iter()
+        .filter_map(|name| ImageFileName::parse_str(name))
+        .collect::<Vec<_>>();
+    let deltas = layer_names
+        .iter()
+        .filter_map(|name| DeltaFileName::parse_str(name))
+        .collect::<Vec<_>>();
+
+    let mut overlaps = Vec::new();
+    for (i, a) in images.iter().enumerate() {
+        for b in &images[i + 1..] {
+            if a.lsn == b.lsn && ranges_overlap(&a.key_range, &b.key_range) {
+                overlaps.push(format!("image layers {a} and {b} overlap"));
+            }
+        }
+    }
+    for (i, a) in deltas.iter().enumerate() {
+        for b in &deltas[i + 1..] {
+            if ranges_overlap(&a.lsn_range, &b.lsn_range)
+                && ranges_overlap(&a.key_range, &b.key_range)
+            {
+                overlaps.push(format!("delta layers {a} and {b} overlap"));
+            }
+        }
+    }
+    overlaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use utils::lsn::Lsn;
+
+    const KEY_0: &str = "000000000000000000000000000000000000";
+    const KEY_1: &str = "000000000000000000000000000000000100";
+    const KEY_2: &str = "000000000000000000000000000000000200";
+
+    fn layer_names(names: &[String]) -> BTreeSet<String> {
+        names.iter().cloned().collect()
+    }
+
+    fn new_runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn overlapping_layers() {
+        let image_a = format!("{KEY_0}-{KEY_2}__0000000000000020");
+        let image_b = format!("{KEY_1}-{KEY_2}__0000000000000020");
+        let image_c = format!("{KEY_0}-{KEY_2}__0000000000000030");
+        let delta_a = format!("{KEY_0}-{KEY_1}__0000000000000010-0000000000000020");
+        let delta_b = format!("{KEY_0}-{KEY_2}__0000000000000018-0000000000000028");
+        let delta_c = format!("{KEY_1}-{KEY_2}__0000000000000010-0000000000000020");
+        let delta_d = format!("{KEY_0}-{KEY_2}__0000000000000020-0000000000000030");
+
+        // Adjacent ranges don't overlap
+        assert!(find_overlapping_layers(&layer_names(&[
+            image_a.clone(),
+            image_c.clone(),
+            delta_a.clone(),
+            delta_c.clone(),
+            delta_d.clone(),
+        ]))
+        .is_empty());
+
+        let overlaps = find_overlapping_layers(&layer_names(&[image_a, image_b, image_c]));
+        assert_eq!(overlaps.len(), 1, "{overlaps:?}");
+        assert!(overlaps[0].starts_with("image layers"));
+
+        // A delta overlaps with the deltas both in key range and LSN range
+        let overlaps = find_overlapping_layers(&layer_names(&[delta_a, delta_b, delta_c]));
+        assert_eq!(overlaps.len(), 2, "{overlaps:?}");
+        assert!(overlaps.iter().all(|o| o.starts_with("delta layers")));
+    }
+
+    struct TestTimeline {
+        workdir: tempfile::TempDir,
+        timelines_path: PathBuf,
+        timeline_path: PathBuf,
+        storage: LocalFs,
+    }
+
+    impl TestTimeline {
+        fn new() -> Self {
+            let workdir = tempdir().unwrap();
+            let timelines_path = workdir
+                .path()
+                .join("tenants")
+                .join(ZTenantId::generate().to_string())
+                .join(TIMELINES_SEGMENT_NAME);
+            let timeline_path = timelines_path.join(ZTimelineId::generate().to_string());
+            fs::create_dir_all(&timeline_path).unwrap();
+            let storage = LocalFs::new(
+                workdir.path().join("remote_storage"),
+                workdir.path().to_owned(),
+            )
+            .unwrap();
+            TestTimeline {
+                workdir,
+                timelines_path,
+                timeline_path,
+                storage,
+            }
+        }
+
+        fn remote_path(&self, local_path: &Path) -> PathBuf {
+            self.workdir
+                .path()
+                .join("remote_storage")
+                .join(local_path.strip_prefix(self.workdir.path()).unwrap())
+        }
+
+        fn write_local(&self, file_name: &str, contents: &[u8]) -> PathBuf {
+            let path = self.timeline_path.join(file_name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+
+        fn write_remote(&self, file_name: &str, contents: &[u8]) -> PathBuf {
+            let path = self.timeline_path.join(file_name);
+            let remote_path = self.remote_path(&path);
+            fs::create_dir_all(remote_path.parent().unwrap()).unwrap();
+            fs::write(&remote_path, contents).unwrap();
+            path
+        }
+
+        fn write_remote_index(&self, layers: &[PathBuf]) {
+            let metadata =
+                TimelineMetadata::new(Lsn(0x30), None, None, Lsn(0), Lsn(0), Lsn(0), None);
+            let mut remote_timeline = RemoteTimeline::new(metadata);
+            remote_timeline.add_timeline_layers(layers.iter().cloned());
+            let index_part =
+                IndexPart::from_remote_timeline(&self.timeline_path, remote_timeline).unwrap();
+            let index_part_name = Path::new(IndexPart::FILE_NAME)
+                .with_extension(IndexPart::FILE_EXTENSION)
+                .to_string_lossy()
+                .to_string();
+            self.write_remote(&index_part_name, &serde_json::to_vec(&index_part).unwrap());
+        }
+
+        fn scrub(&self, fix: bool) -> (usize, usize) {
+            Scrubber::new(Some(&self.storage), new_runtime(), fix)
+                .scrub_tenant(&self.timelines_path)
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn missing_index_part() {
+        let timeline = TestTimeline::new();
+        let layer_name = format!("{KEY_0}-{KEY_1}__0000000000000020");
+        timeline.write_remote(&layer_name, b"layer");
+
+        // The index part is missing, and the layer is not indexed
+        assert_eq!(timeline.scrub(false), (2, 0));
+    }
+
+    #[test]
+    fn index_validation_and_fix() {
+        let timeline = TestTimeline::new();
+
+        // A local layer that fails validation, with an indexed remote copy
+        let corrupt_name = format!("{KEY_0}-{KEY_1}__0000000000000020");
+        let corrupt_layer = timeline.write_local(&corrupt_name, b"garbage");
+        timeline.write_remote(&corrupt_name, b"layer");
+        // An indexed layer that is missing in the remote storage, without a local copy
+        let lost_layer = timeline
+            .timeline_path
+            .join(format!("{KEY_1}-{KEY_2}__0000000000000020"));
+        // A remote layer that is not in the index
+        let orphan_layer =
+            timeline.write_remote(&format!("{KEY_0}-{KEY_2}__0000000000000030"), b"orphan");
+        // A local file that is neither a layer nor metadata
+        timeline.write_local("unknown_file", b"");
+        timeline.write_remote_index(&[corrupt_layer.clone(), lost_layer]);
+
+        // Without --fix, only report the problems
+        assert_eq!(timeline.scrub(false), (4, 0));
+        assert!(corrupt_layer.exists());
+        assert!(timeline.remote_path(&orphan_layer).exists());
+
+        // The corrupt layer is removed to be downloaded again, the orphan is deleted,
+        // the lost layer and the unknown file can't be fixed
+        assert_eq!(timeline.scrub(true), (4, 2));
+        assert!(!corrupt_layer.exists());
+        assert!(timeline.remote_path(&corrupt_layer).exists());
+        assert!(!timeline.remote_path(&orphan_layer).exists());
+
+        assert_eq!(timeline.scrub(true), (2, 0));
+    }
+}
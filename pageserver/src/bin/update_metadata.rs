@@ -50,6 +50,8 @@ fn main() -> Result<()> {
             meta.ancestor_lsn(),
             meta.latest_gc_cutoff_lsn(),
             meta.initdb_lsn(),
+            // the logical size at the new LSN is not known
+            None,
         );
         update_meta = true;
     }
@@ -62,6 +64,7 @@ fn main() -> Result<()> {
             meta.ancestor_lsn(),
             meta.latest_gc_cutoff_lsn(),
             meta.initdb_lsn(),
+            meta.logical_size(),
         );
         update_meta = true;
     }
@@ -0,0 +1,161 @@
+//!
+//! Publishing the pageserver's timeline state to the etcd broker.
+//!
+//! For each local timeline, the pageserver publishes the LSN up to which the
+//! timeline data is persisted on local disk, and the LSN up to which it is
+//! uploaded to the remote storage. Safekeepers use the latter to decide how
+//! much WAL they can remove.
+//!
+
+use crate::config::PageServerConf;
+use crate::storage_sync::index::RemoteIndex;
+use crate::tenant_mgr;
+use crate::thread_mgr;
+use crate::thread_mgr::ThreadKind;
+use anyhow::{Context, Result};
+use etcd_broker::{Client, NodeKind, PsTimelineInfo, PutOptions, SkTimelineSubscriptionKind};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::*;
+use utils::{lsn::Lsn, zid::ZTenantTimelineId};
+
+const PUSH_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const LEASE_TTL_SEC: i64 = 10;
+
+/// Launch the thread that publishes the timelines' state to the broker.
+pub fn launch_broker_publisher(
+    conf: &'static PageServerConf,
+    remote_index: RemoteIndex,
+) -> Result<()> {
+    thread_mgr::spawn(
+        ThreadKind::BrokerPublisher,
+        None,
+        None,
+        "broker publisher thread",
+        false,
+        move || {
+            thread_main(conf, remote_index);
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+fn thread_main(conf: &'static PageServerConf, remote_index: RemoteIndex) {
+    let _enter = info_span!("broker publisher").entered();
+    info!("started, broker endpoints {:?}", conf.broker_endpoints);
+
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("failed to create the broker publisher runtime: {:?}", e);
+            return;
+        }
+    };
+
+    runtime.block_on(async {
+        while !thread_mgr::is_shutdown_requested() {
+            tokio::select! {
+                biased;
+                _ = thread_mgr::shutdown_watcher() => break,
+                res = push_loop(conf, &remote_index) => {
+                    if let Err(e) = res {
+                        warn!("failed to publish timeline state to the broker: {:?}", e);
+                    }
+                }
+            }
+
+            tokio::select! {
+                biased;
+                _ = thread_mgr::shutdown_watcher() => break,
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+            }
+        }
+    });
+
+    info!("broker publisher thread stopped");
+}
+
+/// Key to per timeline per pageserver data.
+fn timeline_pageserver_path(conf: &PageServerConf, zttid: ZTenantTimelineId) -> String {
+    format!(
+        "{}/{}",
+        SkTimelineSubscriptionKind::timeline(conf.broker_etcd_prefix.clone(), zttid)
+            .node_watch_key(NodeKind::Pageserver),
+        conf.id
+    )
+}
+
+/// Push the state of the loaded local timelines to the broker, whenever it changes.
+async fn push_loop(conf: &'static PageServerConf, remote_index: &RemoteIndex) -> Result<()> {
+    let mut client = Client::connect(&conf.broker_endpoints, None).await?;
+
+    // Get and maintain lease to automatically delete obsolete data
+    let lease = client.lease_grant(LEASE_TTL_SEC, None).await?;
+    let (mut keeper, mut ka_stream) = client.lease_keep_alive(lease.id()).await?;
+
+    // What was published last, with the current lease
+    let mut published: HashMap<ZTenantTimelineId, (Lsn, Option<Lsn>)> = HashMap::new();
+    loop {
+        // note: the tenants lock is not held across the awaits below
+        let timelines = tenant_mgr::list_loaded_local_timelines();
+
+        // Remove the data of the timelines that are gone, e.g. detached
+        let gone: Vec<ZTenantTimelineId> = published
+            .keys()
+            .filter(|zttid| !timelines.iter().any(|(id, _)| id == *zttid))
+            .copied()
+            .collect();
+        for zttid in gone {
+            client
+                .delete(timeline_pageserver_path(conf, zttid), None)
+                .await
+                .context("failed to delete pageserver timeline info")?;
+            published.remove(&zttid);
+        }
+
+        for (zttid, timeline) in timelines {
+            let disk_consistent_lsn = timeline.tline.get_disk_consistent_lsn();
+            // None if the timeline has nothing uploaded to the remote storage yet
+            let remote_consistent_lsn = remote_index
+                .read()
+                .await
+                .timeline_entry(&zttid)
+                .map(|remote_timeline| remote_timeline.metadata.disk_consistent_lsn());
+
+            let state = (disk_consistent_lsn, remote_consistent_lsn);
+            if published.get(&zttid) == Some(&state) {
+                continue;
+            }
+
+            let ps_info = PsTimelineInfo {
+                disk_consistent_lsn: Some(disk_consistent_lsn),
+                remote_consistent_lsn,
+            };
+            client
+                .put(
+                    timeline_pageserver_path(conf, zttid),
+                    serde_json::to_string(&ps_info)?,
+                    Some(PutOptions::new().with_lease(lease.id())),
+                )
+                .await
+                .context("failed to push pageserver timeline info")?;
+            published.insert(zttid, state);
+        }
+
+        // revive the lease
+        keeper
+            .keep_alive()
+            .await
+            .context("failed to send LeaseKeepAliveRequest")?;
+        ka_stream
+            .message()
+            .await
+            .context("failed to receive LeaseKeepAliveResponse")?;
+        tokio::time::sleep(PUSH_INTERVAL).await;
+    }
+}
@@ -67,6 +67,9 @@ pub mod defaults {
 #gc_horizon = {DEFAULT_GC_HORIZON}
 #image_creation_threshold = {DEFAULT_IMAGE_CREATION_THRESHOLD}
 #pitr_interval = '{DEFAULT_PITR_INTERVAL}'
+#max_local_layers_size = {DEFAULT_MAX_LOCAL_LAYERS_SIZE} # in bytes
+#wal_redo_process_pool_size = {DEFAULT_WAL_REDO_PROCESS_POOL_SIZE}
+#max_logical_size = {DEFAULT_MAX_LOGICAL_SIZE} # in bytes
 
 # [remote_storage]
 
@@ -113,6 +116,10 @@ pub struct PageServerConf {
     pub profiling: ProfilingConfig,
     pub default_tenant_conf: TenantConf,
 
+    /// Compression to apply to the blobs written into new layer files.
+    /// Layer files are readable regardless of this setting.
+    pub layer_compression: LayerCompression,
+
     /// A prefix to add in etcd brokers before every key.
     /// Can be used for isolating different pageserver groups withing the same etcd cluster.
     pub broker_etcd_prefix: String,
@@ -140,6 +147,25 @@ impl FromStr for ProfilingConfig {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    None,
+    Zstd,
+}
+
+impl FromStr for LayerCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LayerCompression, Self::Err> {
+        let result = match s {
+            "none" => LayerCompression::None,
+            "zstd" => LayerCompression::Zstd,
+            _ => bail!("invalid value \"{s}\" for layer_compression option, valid values are \"none\" and \"zstd\""),
+        };
+        Ok(result)
+    }
+}
+
 // use dedicated enum for builder to better indicate the intention
 // and avoid possible confusion with nested options
 pub enum BuilderValue<T> {
@@ -183,6 +209,7 @@ struct PageServerConfigBuilder {
     id: BuilderValue<NodeId>,
 
     profiling: BuilderValue<ProfilingConfig>,
+    layer_compression: BuilderValue<LayerCompression>,
     broker_etcd_prefix: BuilderValue<String>,
     broker_endpoints: BuilderValue<Vec<Url>>,
 }
@@ -210,6 +237,7 @@ impl Default for PageServerConfigBuilder {
             remote_storage_config: Set(None),
             id: NotSet,
             profiling: Set(ProfilingConfig::Disabled),
+            layer_compression: Set(LayerCompression::None),
             broker_etcd_prefix: Set(etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string()),
             broker_endpoints: Set(Vec::new()),
         }
@@ -284,6 +312,10 @@ impl PageServerConfigBuilder {
         self.profiling = BuilderValue::Set(profiling)
     }
 
+    pub fn layer_compression(&mut self, layer_compression: LayerCompression) {
+        self.layer_compression = BuilderValue::Set(layer_compression)
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         let broker_endpoints = self
             .broker_endpoints
@@ -322,6 +354,9 @@ impl PageServerConfigBuilder {
                 .ok_or(anyhow!("missing remote_storage_config"))?,
             id: self.id.ok_or(anyhow!("missing id"))?,
             profiling: self.profiling.ok_or(anyhow!("missing profiling"))?,
+            layer_compression: self
+                .layer_compression
+                .ok_or(anyhow!("missing layer_compression"))?,
             // TenantConf is handled separately
             default_tenant_conf: TenantConf::default(),
             broker_endpoints,
@@ -401,6 +436,7 @@ impl PageServerConf {
                 }
                 "id" => builder.id(NodeId(parse_toml_u64(key, item)?)),
                 "profiling" => builder.profiling(parse_toml_from_str(key, item)?),
+                "layer_compression" => builder.layer_compression(parse_toml_from_str(key, item)?),
                 "broker_etcd_prefix" => builder.broker_etcd_prefix(parse_toml_string(key, item)?),
                 "broker_endpoints" => builder.broker_endpoints(
                     parse_toml_array(key, item)?
@@ -481,6 +517,24 @@ impl PageServerConf {
             t_conf.pitr_interval = Some(parse_toml_duration("pitr_interval", pitr_interval)?);
         }
 
+        if let Some(max_local_layers_size) = item.get("max_local_layers_size") {
+            t_conf.max_local_layers_size = Some(parse_toml_u64(
+                "max_local_layers_size",
+                max_local_layers_size,
+            )?);
+        }
+
+        if let Some(wal_redo_process_pool_size) = item.get("wal_redo_process_pool_size") {
+            t_conf.wal_redo_process_pool_size = Some(
+                parse_toml_u64("wal_redo_process_pool_size", wal_redo_process_pool_size)?
+                    .try_into()?,
+            );
+        }
+
+        if let Some(max_logical_size) = item.get("max_logical_size") {
+            t_conf.max_logical_size = Some(parse_toml_u64("max_logical_size", max_logical_size)?);
+        }
+
         Ok(t_conf)
     }
 
@@ -507,6 +561,7 @@ impl PageServerConf {
             remote_storage_config: None,
             profiling: ProfilingConfig::Disabled,
             default_tenant_conf: TenantConf::dummy_conf(),
+            layer_compression: LayerCompression::None,
             broker_endpoints: Vec::new(),
             broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
         }
@@ -637,6 +692,7 @@ id = 10
                 remote_storage_config: None,
                 profiling: ProfilingConfig::Disabled,
                 default_tenant_conf: TenantConf::default(),
+                layer_compression: LayerCompression::None,
                 broker_endpoints: vec![broker_endpoint
                     .parse()
                     .expect("Failed to parse a valid broker endpoint URL")],
@@ -681,6 +737,7 @@ id = 10
                 remote_storage_config: None,
                 profiling: ProfilingConfig::Disabled,
                 default_tenant_conf: TenantConf::default(),
+                layer_compression: LayerCompression::None,
                 broker_endpoints: vec![broker_endpoint
                     .parse()
                     .expect("Failed to parse a valid broker endpoint URL")],
@@ -0,0 +1,125 @@
+//!
+//! Export a timeline at a given LSN as a self-contained tar archive.
+//!
+//! The archive contains image layers that cover the whole keyspace of the
+//! timeline at the LSN, the timeline metadata file, and an `IndexPart` that
+//! lists the layers, in the same format that storage_sync keeps in the remote
+//! storage. All history before the LSN, including the ancestor timelines, is
+//! collapsed into the images, so the exported timeline has no ancestor and
+//! starts at the LSN.
+//!
+//! The layer files embed the tenant and timeline ids, so the archive can only
+//! be loaded back as the same timeline: either by extracting it into the
+//! timeline directory on a pageserver that doesn't have the timeline, or by
+//! uploading the files to the timeline's prefix in the remote storage and
+//! attaching the timeline.
+//!
+use anyhow::{ensure, Context, Result};
+use std::fs::File;
+use std::path::Path;
+use std::time::SystemTime;
+use tar::{Builder, Header};
+use tracing::*;
+
+use crate::layered_repository::metadata::{TimelineMetadata, METADATA_FILE_NAME};
+use crate::repository::Timeline;
+use crate::storage_sync::index::{IndexPart, RemoteTimeline};
+use crate::tenant_mgr;
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+///
+/// Write the archive of the timeline at 'req_lsn', or at the end of the
+/// timeline if it's not given, into 'tar_path'. 'work_dir' is an empty
+/// directory for the intermediate layer files; the caller is responsible for
+/// removing it. Returns the LSN of the export.
+///
+pub fn export_timeline(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    req_lsn: Option<Lsn>,
+    work_dir: &Path,
+    tar_path: &Path,
+) -> Result<Lsn> {
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+
+    // Like in basebackup, we can only provide prev_record_lsn when exporting
+    // at the end of the timeline.
+    let end_of_timeline = timeline.tline.get_last_record_rlsn();
+    let lsn = req_lsn.unwrap_or(end_of_timeline.last);
+    ensure!(
+        lsn <= end_of_timeline.last,
+        "LSN {} is beyond the last record LSN {}",
+        lsn,
+        end_of_timeline.last
+    );
+    let prev_record_lsn = if lsn == end_of_timeline.last && end_of_timeline.prev != Lsn(0) {
+        Some(end_of_timeline.prev)
+    } else {
+        None
+    };
+    info!(
+        "exporting timeline at lsn={}, prev_lsn={:?}",
+        lsn, prev_record_lsn
+    );
+
+    // Keep GC from removing the history at 'lsn' while reading the timeline
+    let gc_lease = timeline.tline.lease_gc_lsn(lsn)?;
+    let keyspace = timeline.collect_keyspace(lsn)?;
+    let layer_paths = timeline
+        .tline
+        .export_image_layers(&keyspace, &gc_lease, work_dir)?;
+
+    // Nothing before the export LSN is left, so that's where the exported
+    // timeline begins.
+    let logical_size = timeline.get_current_logical_size_non_incremental(lsn)? as u64;
+    drop(gc_lease);
+    let metadata = TimelineMetadata::new(
+        lsn,
+        prev_record_lsn,
+        None,
+        Lsn(0),
+        lsn,
+        lsn,
+        Some(logical_size),
+    );
+    let metadata_bytes = metadata.to_bytes()?;
+    let mut remote_timeline = RemoteTimeline::new(metadata);
+    remote_timeline.add_timeline_layers(layer_paths.iter().cloned());
+    let index_part = IndexPart::from_remote_timeline(work_dir, remote_timeline)?;
+    let index_part_bytes = serde_json::to_vec(&index_part)?;
+
+    let file = File::create(tar_path)
+        .with_context(|| format!("failed to create archive {}", tar_path.display()))?;
+    let mut ar = Builder::new(file);
+    for path in &layer_paths {
+        let name = path.strip_prefix(work_dir)?;
+        ar.append_path_with_name(path, name)?;
+    }
+    append_file(&mut ar, METADATA_FILE_NAME, &metadata_bytes)?;
+    append_file(
+        &mut ar,
+        &format!("{}.{}", IndexPart::FILE_NAME, IndexPart::FILE_EXTENSION),
+        &index_part_bytes,
+    )?;
+    ar.into_inner()?;
+
+    info!("exported {} layers at lsn {}", layer_paths.len(), lsn);
+    Ok(lsn)
+}
+
+fn append_file(ar: &mut Builder<File>, path: &str, data: &[u8]) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0b110000000); // -rw-------
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    );
+    ar.append_data(&mut header, path, data)?;
+    Ok(())
+}
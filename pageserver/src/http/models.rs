@@ -17,6 +17,9 @@ pub struct TimelineCreateRequest {
     #[serde(default)]
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub ancestor_start_lsn: Option<Lsn>,
+    /// Branch at the point of time instead of 'ancestor_start_lsn', in RFC 3339 format.
+    #[serde(default)]
+    pub ancestor_start_timestamp: Option<String>,
 }
 
 #[serde_as]
@@ -33,6 +36,9 @@ pub struct TenantCreateRequest {
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
     pub pitr_interval: Option<String>,
+    pub max_local_layers_size: Option<u64>,
+    pub wal_redo_process_pool_size: Option<usize>,
+    pub max_logical_size: Option<u64>,
 }
 
 #[serde_as]
@@ -68,6 +74,9 @@ pub struct TenantConfigRequest {
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
     pub pitr_interval: Option<String>,
+    pub max_local_layers_size: Option<u64>,
+    pub wal_redo_process_pool_size: Option<usize>,
+    pub max_logical_size: Option<u64>,
 }
 
 impl TenantConfigRequest {
@@ -82,6 +91,9 @@ impl TenantConfigRequest {
             gc_period: None,
             image_creation_threshold: None,
             pitr_interval: None,
+            max_local_layers_size: None,
+            wal_redo_process_pool_size: None,
+            max_logical_size: None,
         }
     }
 }
@@ -1,21 +1,30 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
-use hyper::StatusCode;
+use hyper::body::HttpBody;
+use hyper::{header, StatusCode};
 use hyper::{Body, Request, Response, Uri};
+use rand::{distributions::Alphanumeric, Rng};
 use remote_storage::GenericRemoteStorage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::*;
 
 use super::models::{
     StatusResponse, TenantConfigRequest, TenantCreateRequest, TenantCreateResponse,
     TimelineCreateRequest,
 };
-use crate::repository::Repository;
+use crate::pgdatadir_mapping::{rel_block_to_key, LsnForTimestamp};
+use crate::reltag::RelTag;
+use crate::repository::{Key, Repository, Timeline, TimelineHasChildren};
 use crate::storage_sync;
 use crate::storage_sync::index::{RemoteIndex, RemoteTimeline};
 use crate::tenant_config::TenantConfOpt;
 use crate::timelines::{LocalTimelineInfo, RemoteTimelineInfo, TimelineInfo};
-use crate::{config::PageServerConf, tenant_mgr, timelines};
+use crate::{config::PageServerConf, export_timeline, tenant_mgr, timelines};
+use postgres_ffi::xlog_utils::to_pg_timestamp;
 use utils::{
     auth::JwtAuth,
     http::{
@@ -25,6 +34,7 @@ use utils::{
         request::parse_request_param,
         RequestExt, RouterBuilder,
     },
+    lsn::Lsn,
     zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
@@ -90,15 +100,51 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
 
     check_permission(&request, Some(tenant_id))?;
 
+    let ancestor_start_timestamp = request_data
+        .ancestor_start_timestamp
+        .as_deref()
+        .map(|timestamp| {
+            humantime::parse_rfc3339(timestamp).map_err(|e| {
+                ApiError::BadRequest(format!(
+                    "invalid ancestor_start_timestamp '{}': {}",
+                    timestamp, e
+                ))
+            })
+        })
+        .transpose()?;
+    if ancestor_start_timestamp.is_some() {
+        if request_data.ancestor_start_lsn.is_some() {
+            return Err(ApiError::BadRequest(
+                "'ancestor_start_lsn' and 'ancestor_start_timestamp' cannot be both specified"
+                    .to_string(),
+            ));
+        }
+        if request_data.ancestor_timeline_id.is_none() {
+            return Err(ApiError::BadRequest(
+                "'ancestor_start_timestamp' requires 'ancestor_timeline_id'".to_string(),
+            ));
+        }
+    }
+
     let new_timeline_info = tokio::task::spawn_blocking(move || {
-        let _enter = info_span!("/timeline_create", tenant = %tenant_id, new_timeline = ?request_data.new_timeline_id, lsn=?request_data.ancestor_start_lsn).entered();
+        let _enter = info_span!("/timeline_create", tenant = %tenant_id, new_timeline = ?request_data.new_timeline_id, lsn=?request_data.ancestor_start_lsn, timestamp=?request_data.ancestor_start_timestamp).entered();
+        let ancestor_start_lsn =
+            match (request_data.ancestor_timeline_id, ancestor_start_timestamp) {
+                (Some(ancestor_timeline_id), Some(timestamp)) => Some(find_ancestor_start_lsn(
+                    tenant_id,
+                    ancestor_timeline_id,
+                    timestamp,
+                )?),
+                _ => request_data.ancestor_start_lsn,
+            };
         timelines::create_timeline(
             get_config(&request),
             tenant_id,
             request_data.new_timeline_id.map(ZTimelineId::from),
             request_data.ancestor_timeline_id.map(ZTimelineId::from),
-            request_data.ancestor_start_lsn,
+            ancestor_start_lsn,
         )
+        .map_err(ApiError::from)
     })
     .await
     .map_err(ApiError::from_err)??;
@@ -109,6 +155,57 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
     })
 }
 
+///
+/// Find the LSN on the ancestor timeline to create a point-in-time branch at
+/// 'timestamp'.
+///
+fn find_ancestor_start_lsn(
+    tenant_id: ZTenantId,
+    ancestor_timeline_id: ZTimelineId,
+    timestamp: SystemTime,
+) -> Result<Lsn, ApiError> {
+    let ancestor_timeline =
+        tenant_mgr::get_local_timeline_with_load(tenant_id, ancestor_timeline_id)
+            .context("Cannot branch off the timeline that's not present locally")?;
+
+    match ancestor_timeline.find_lsn_for_timestamp(to_pg_timestamp(timestamp))? {
+        LsnForTimestamp::Present(lsn) => {
+            info!(
+                "resolved timestamp {} to lsn {}",
+                humantime::format_rfc3339(timestamp),
+                lsn
+            );
+            Ok(lsn)
+        }
+        LsnForTimestamp::Past(_) => Err(ApiError::BadRequest(format!(
+            "timestamp {} is before the GC cutoff {} of the ancestor timeline {}",
+            humantime::format_rfc3339(timestamp),
+            *ancestor_timeline.tline.get_latest_gc_cutoff_lsn(),
+            ancestor_timeline_id
+        ))),
+        LsnForTimestamp::Future(_) if timestamp > SystemTime::now() => {
+            Err(ApiError::BadRequest(format!(
+                "timestamp {} is in the future",
+                humantime::format_rfc3339(timestamp)
+            )))
+        }
+        LsnForTimestamp::Future(last_record_lsn) => {
+            // No commits after the timestamp yet, so the latest state is the
+            // state at the timestamp.
+            info!(
+                "no commits after timestamp {}, branching at the last record lsn {}",
+                humantime::format_rfc3339(timestamp),
+                last_record_lsn
+            );
+            Ok(last_record_lsn)
+        }
+        LsnForTimestamp::NoData(_) => Err(ApiError::BadRequest(format!(
+            "no commit timestamps found on the ancestor timeline {}",
+            ancestor_timeline_id
+        ))),
+    }
+}
+
 async fn timeline_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -248,6 +345,216 @@ async fn wal_receiver_get_handler(request: Request<Body>) -> Result<Response<Bod
     json_response(StatusCode::OK, wal_receiver)
 }
 
+/// Query parameters of the request, by name. The last value of a repeated parameter is used.
+fn parse_query_params(request: &Request<Body>) -> HashMap<String, String> {
+    request
+        .uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_lsn_query_param(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<Lsn>, ApiError> {
+    params
+        .get(name)
+        .map(|lsn| {
+            Lsn::from_str(lsn)
+                .map_err(|e| ApiError::BadRequest(format!("invalid {} '{}': {}", name, lsn, e)))
+        })
+        .transpose()
+}
+
+// The page to trace is given either as a 'key' in hex, or as a 'rel' and 'blkno'.
+// 'lsn' is optional, the last record LSN of the timeline is used by default.
+fn parse_reconstruct_trace_query(request: &Request<Body>) -> Result<(Key, Option<Lsn>), ApiError> {
+    let params = parse_query_params(request);
+
+    let key = match (params.get("key"), params.get("rel"), params.get("blkno")) {
+        (Some(key), None, None) => Key::from_hex(key)
+            .map_err(|e| ApiError::BadRequest(format!("invalid key '{}': {}", key, e)))?,
+        (None, Some(rel), Some(blkno)) => {
+            let rel = RelTag::from_str(rel).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+            let blkno = blkno
+                .parse()
+                .map_err(|e| ApiError::BadRequest(format!("invalid blkno '{}': {}", blkno, e)))?;
+            rel_block_to_key(rel, blkno)
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "either 'key', or 'rel' and 'blkno' must be specified".to_string(),
+            ))
+        }
+    };
+    let lsn = parse_lsn_query_param(&params, "lsn")?;
+
+    Ok((key, lsn))
+}
+
+async fn timeline_reconstruct_trace_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let (key, lsn) = parse_reconstruct_trace_query(&request)?;
+
+    let trace = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("reconstruct_trace", tenant = %tenant_id, timeline = %timeline_id).entered();
+
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+        let lsn = lsn.unwrap_or_else(|| timeline.tline.get_last_record_lsn());
+        timeline.tline.trace_get(key, lsn)
+    })
+    .await
+    .map_err(ApiError::from_err)?
+    .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, trace)
+}
+
+fn parse_import_query(request: &Request<Body>) -> Result<(Lsn, Lsn), ApiError> {
+    let params = parse_query_params(request);
+    let base_lsn = parse_lsn_query_param(&params, "base_lsn")?
+        .ok_or_else(|| ApiError::BadRequest("'base_lsn' must be specified".to_string()))?;
+    let end_lsn = parse_lsn_query_param(&params, "end_lsn")?.unwrap_or(base_lsn);
+
+    Ok((base_lsn, end_lsn))
+}
+
+async fn spool_body_to_file(body: &mut Body, mut file: tokio::fs::File) -> anyhow::Result<()> {
+    while let Some(chunk) = body.data().await {
+        file.write_all(&chunk.context("failed to read the request body")?)
+            .await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+async fn timeline_import_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let (base_lsn, end_lsn) = parse_import_query(&request)?;
+    let conf = get_config(&request);
+
+    // Don't bother receiving the archive if the import cannot succeed
+    tenant_mgr::get_repository_for_tenant(tenant_id)
+        .map_err(|e| ApiError::NotFound(format!("{:#}", e)))?;
+    let import_guard = match timelines::start_import(conf, tenant_id, timeline_id) {
+        Some(import_guard) => import_guard,
+        None => return json_response(StatusCode::CONFLICT, ()),
+    };
+
+    // The import reads the archive twice, so store it in a file first.
+    // The file is removed when it's dropped.
+    let tar_file = tempfile::Builder::new()
+        .prefix(&format!("tmp-import-{}-", timeline_id))
+        .suffix(".tar")
+        .tempfile_in(conf.tenant_path(&tenant_id))
+        .context("failed to create a temporary file for the archive")?;
+    let spool_file = tar_file
+        .as_file()
+        .try_clone()
+        .context("failed to open the temporary archive file")?;
+    spool_body_to_file(request.body_mut(), tokio::fs::File::from_std(spool_file)).await?;
+
+    let info = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_import", tenant = %tenant_id, timeline = %timeline_id).entered();
+        timelines::import_timeline(
+            conf,
+            &import_guard,
+            tenant_id,
+            timeline_id,
+            tar_file.path(),
+            base_lsn,
+            end_lsn,
+        )
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    Ok(match info {
+        Some(info) => json_response(StatusCode::CREATED, info)?,
+        None => json_response(StatusCode::CONFLICT, ())?,
+    })
+}
+
+async fn timeline_export_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let lsn = parse_lsn_query_param(&parse_query_params(&request), "lsn")?;
+    let conf = get_config(&request);
+
+    let rand_string: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let work_dir = conf
+        .tenant_path(&tenant_id)
+        .join(format!("tmp-export-{}.{}", timeline_id, rand_string));
+    let result = async {
+        tokio::fs::create_dir(&work_dir)
+            .await
+            .with_context(|| format!("failed to create directory {}", work_dir.display()))?;
+        let tar_path = work_dir.join("export.tar");
+
+        let (export_dir, export_tar_path) = (work_dir.clone(), tar_path.clone());
+        let lsn = tokio::task::spawn_blocking(move || {
+            let _enter =
+                info_span!("timeline_export", tenant = %tenant_id, timeline = %timeline_id)
+                    .entered();
+            export_timeline::export_timeline(
+                tenant_id,
+                timeline_id,
+                lsn,
+                &export_dir,
+                &export_tar_path,
+            )
+        })
+        .await??;
+
+        // The archive stays readable through the open file after the
+        // directory is removed
+        let file = tokio::fs::File::open(&tar_path).await?;
+        Ok::<_, anyhow::Error>((lsn, file))
+    }
+    .await;
+    if let Err(e) = tokio::fs::remove_dir_all(&work_dir).await {
+        warn!("failed to remove {}: {}", work_dir.display(), e);
+    }
+    let (lsn, file) = result.map_err(ApiError::from_err)?;
+
+    let stream = futures::stream::try_unfold(file, |mut file| async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        buf.truncate(n);
+        Ok(Some((buf, file)))
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-tar")
+        .header("X-Export-Lsn", lsn.to_string())
+        .body(Body::wrap_stream(stream))
+        .map_err(ApiError::from_err)
+}
+
 async fn timeline_attach_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -368,6 +675,29 @@ async fn timeline_detach_handler(request: Request<Body>) -> Result<Response<Body
     json_response(StatusCode::OK, ())
 }
 
+async fn timeline_delete_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+
+    tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_delete_handler", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        let state = get_state(&request);
+        tenant_mgr::delete_timeline(state.conf, tenant_id, timeline_id)
+    })
+    .await
+    .map_err(ApiError::from_err)?
+    .map_err(|e| match e.downcast_ref::<TimelineHasChildren>() {
+        Some(_) => ApiError::BadRequest(format!("{e:#}")),
+        None => ApiError::InternalServerError(e),
+    })?;
+
+    json_response(StatusCode::OK, ())
+}
+
 async fn tenant_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     // check for management permission
     check_permission(&request, None)?;
@@ -402,6 +732,9 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
             Some(humantime::parse_duration(&pitr_interval).map_err(ApiError::from_err)?);
     }
 
+    tenant_conf.max_local_layers_size = request_data.max_local_layers_size;
+    tenant_conf.wal_redo_process_pool_size = request_data.wal_redo_process_pool_size;
+    tenant_conf.max_logical_size = request_data.max_logical_size;
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
@@ -431,6 +764,50 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     })
 }
 
+async fn tenant_detach_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let remote_index = get_state(&request).remote_index.clone();
+    tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_detach_handler", tenant = %tenant_id).entered();
+        let state = get_state(&request);
+        tenant_mgr::detach_tenant(state.conf, tenant_id)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    // Forget the remote timelines too, so nothing gets synced for the detached tenant.
+    let removed_timelines = remote_index.write().await.remove_tenant_entries(tenant_id);
+    debug!("Removed remote index entries of the detached tenant: {removed_timelines:?}");
+
+    json_response(StatusCode::OK, ())
+}
+
+async fn tenant_delete_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let remote_timelines: HashSet<ZTimelineId> = get_state(&request)
+        .remote_index
+        .read()
+        .await
+        .all_sync_ids()
+        .filter(|sync_id| sync_id.tenant_id == tenant_id)
+        .map(|sync_id| sync_id.timeline_id)
+        .collect();
+
+    tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_delete_handler", tenant = %tenant_id).entered();
+        let state = get_state(&request);
+        tenant_mgr::delete_tenant(state.conf, tenant_id, remote_timelines)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, ())
+}
+
 async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let request_data: TenantConfigRequest = json_request(&mut request).await?;
     let tenant_id = request_data.tenant_id;
@@ -450,6 +827,9 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
             Some(humantime::parse_duration(&pitr_interval).map_err(ApiError::from_err)?);
     }
 
+    tenant_conf.max_local_layers_size = request_data.max_local_layers_size;
+    tenant_conf.wal_redo_process_pool_size = request_data.wal_redo_process_pool_size;
+    tenant_conf.max_logical_size = request_data.max_logical_size;
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
@@ -503,16 +883,34 @@ pub fn make_router(
         .get("/v1/tenant", tenant_list_handler)
         .post("/v1/tenant", tenant_create_handler)
         .put("/v1/tenant/config", tenant_config_handler)
+        .delete("/v1/tenant/:tenant_id", tenant_delete_handler)
+        .post("/v1/tenant/:tenant_id/detach", tenant_detach_handler)
         .get("/v1/tenant/:tenant_id/timeline", timeline_list_handler)
         .post("/v1/tenant/:tenant_id/timeline", timeline_create_handler)
         .get(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_detail_handler,
         )
+        .delete(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id",
+            timeline_delete_handler,
+        )
         .get(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_receiver",
             wal_receiver_get_handler,
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/reconstruct_trace",
+            timeline_reconstruct_trace_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/import",
+            timeline_import_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/export",
+            timeline_export_handler,
+        )
         .post(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/attach",
             timeline_attach_handler,
@@ -2,10 +2,14 @@
 //! Import data and WAL from a PostgreSQL data directory and WAL segments into
 //! a zenith Timeline.
 //!
+//! The data directory can be a local directory, or a tar archive with the same
+//! layout, e.g. one produced by pg_basebackup.
+//!
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
@@ -37,6 +41,7 @@ pub fn import_timeline_from_postgres_datadir<R: Repository>(
 
     let mut modification = tline.begin_modification(lsn);
     modification.init_empty()?;
+    let mut rels = HashMap::new();
 
     // Scan 'global'
     let mut relfiles: Vec<PathBuf> = Vec::new();
@@ -64,6 +69,7 @@ pub fn import_timeline_from_postgres_datadir<R: Repository>(
     for relfile in relfiles {
         import_relfile(
             &mut modification,
+            &mut rels,
             &relfile,
             pg_constants::GLOBALTABLESPACE_OID,
             0,
@@ -75,12 +81,11 @@ pub fn import_timeline_from_postgres_datadir<R: Repository>(
     for direntry in fs::read_dir(path.join("base"))? {
         let direntry = direntry?;
 
-        //skip all temporary files
-        if direntry.file_name().to_string_lossy() == "pgsql_tmp" {
-            continue;
-        }
-
-        let dboid = direntry.file_name().to_string_lossy().parse::<u32>()?;
+        // skip all temporary files, and anything else that's not a database directory
+        let dboid = match direntry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(dboid) => dboid,
+            Err(_) => continue,
+        };
 
         let mut relfiles: Vec<PathBuf> = Vec::new();
         for direntry in fs::read_dir(direntry.path())? {
@@ -105,6 +110,7 @@ pub fn import_timeline_from_postgres_datadir<R: Repository>(
         for relfile in relfiles {
             import_relfile(
                 &mut modification,
+                &mut rels,
                 &relfile,
                 pg_constants::DEFAULTTABLESPACE_OID,
                 dboid,
@@ -131,6 +137,7 @@ pub fn import_timeline_from_postgres_datadir<R: Repository>(
     // TODO: Scan pg_tblspc
 
     // We're done importing all the data files.
+    put_rel_creations(&mut modification, rels)?;
     modification.commit()?;
 
     // We expect the Postgres server to be shut down cleanly.
@@ -157,12 +164,198 @@ pub fn import_timeline_from_postgres_datadir<R: Repository>(
     Ok(())
 }
 
+///
+/// Import a timeline from a tar archive with the layout of a PostgreSQL data
+/// directory, like the one produced by pg_basebackup.
+///
+/// The data files in the archive are imported at 'base_lsn', which must be the
+/// checkpoint REDO pointer of the backup. Then the WAL between 'base_lsn' and
+/// 'end_lsn' is replayed from the segments in the pg_wal directory of the
+/// archive, to make the data consistent. If 'end_lsn' is equal to 'base_lsn',
+/// no WAL is replayed, and the cluster must have been shut down cleanly.
+///
+/// The archive is read twice, first the data files and then the WAL, so it
+/// must be a file rather than a stream.
+///
+pub fn import_timeline_from_tar<R: Repository>(
+    tar_path: &Path,
+    tline: &mut DatadirTimeline<R>,
+    base_lsn: Lsn,
+    end_lsn: Lsn,
+) -> Result<()> {
+    ensure!(
+        base_lsn <= end_lsn,
+        "end LSN {} is before base LSN {}",
+        end_lsn,
+        base_lsn
+    );
+
+    let mut pg_control: Option<ControlFileData> = None;
+    // Location of the WAL segments in the archive: segment number -> (offset, size)
+    let mut wal_segments: BTreeMap<XLogSegNo, (u64, u64)> = BTreeMap::new();
+
+    let mut modification = tline.begin_modification(base_lsn);
+    modification.init_empty()?;
+    let mut rels = HashMap::new();
+
+    let mut archive = tar::Archive::new(File::open(tar_path)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let path = header.path()?.into_owned();
+        // Archives created with e.g. 'tar -C $PGDATA -cf backup.tar .' have
+        // paths starting with './'
+        let path = path.strip_prefix(".").unwrap_or(&path).to_path_buf();
+
+        if path.starts_with("pg_tblspc") && !entry_type.is_dir() {
+            bail!("tablespaces are not supported, found {}", path.display());
+        }
+        if !entry_type.is_file() {
+            trace!("skipping {:?} entry {}", entry_type, path.display());
+            continue;
+        }
+
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let len = entry.size();
+        let components: Vec<_> = path
+            .components()
+            .map(|c| match c {
+                Component::Normal(name) => name.to_string_lossy().into_owned(),
+                _ => String::new(),
+            })
+            .collect();
+        let components: Vec<&str> = components.iter().map(|c| c.as_str()).collect();
+
+        match components.as_slice() {
+            ["global", "pg_control"] => {
+                pg_control = Some(import_control_bytes(
+                    &mut modification,
+                    read_all_bytes(entry)?,
+                )?);
+            }
+            ["global", "pg_filenode.map"] => {
+                modification.put_relmap_file(
+                    pg_constants::GLOBALTABLESPACE_OID,
+                    0,
+                    read_all_bytes(entry)?,
+                )?;
+            }
+            ["global", _] => import_rel(
+                &mut modification,
+                &mut rels,
+                &path,
+                pg_constants::GLOBALTABLESPACE_OID,
+                0,
+                entry,
+                len,
+            )?,
+            // Not a database directory, e.g. temporary files in 'base/pgsql_tmp'
+            ["base", dir, ..] if dir.parse::<Oid>().is_err() => {
+                trace!("skipping file {}", path.display())
+            }
+            ["base", _, "PG_VERSION"] => {}
+            ["base", dboid, "pg_filenode.map"] => {
+                modification.put_relmap_file(
+                    pg_constants::DEFAULTTABLESPACE_OID,
+                    dboid.parse()?,
+                    read_all_bytes(entry)?,
+                )?;
+            }
+            ["base", _, "pg_internal.init"] => {}
+            ["base", dboid, _] => import_rel(
+                &mut modification,
+                &mut rels,
+                &path,
+                pg_constants::DEFAULTTABLESPACE_OID,
+                dboid.parse()?,
+                entry,
+                len,
+            )?,
+            ["pg_xact", _] => import_slru(&mut modification, SlruKind::Clog, &path, entry, len)?,
+            ["pg_multixact", "members", _] => import_slru(
+                &mut modification,
+                SlruKind::MultiXactMembers,
+                &path,
+                entry,
+                len,
+            )?,
+            ["pg_multixact", "offsets", _] => import_slru(
+                &mut modification,
+                SlruKind::MultiXactOffsets,
+                &path,
+                entry,
+                len,
+            )?,
+            ["pg_twophase", _] => {
+                let xid = u32::from_str_radix(&file_name, 16)?;
+                modification.put_twophase_file(xid, read_all_bytes(entry)?)?;
+            }
+            ["pg_wal", _] if IsXLogFileName(&file_name) => {
+                let (segno, _tli) = XLogFromFileName(&file_name, pg_constants::WAL_SEGMENT_SIZE);
+                ensure!(
+                    len == pg_constants::WAL_SEGMENT_SIZE as u64,
+                    "WAL segment {} has unexpected size {}",
+                    file_name,
+                    len
+                );
+                let location = (entry.raw_file_position(), len);
+                if wal_segments.insert(segno, location).is_some() {
+                    bail!("WAL segment {} found more than once", file_name);
+                }
+            }
+            // Configuration files, backup_label etc. are not stored in the repository
+            _ => trace!("skipping file {}", path.display()),
+        }
+    }
+
+    // We're done importing all the data files.
+    put_rel_creations(&mut modification, rels)?;
+    modification.commit()?;
+
+    let pg_control = pg_control.context("pg_control file not found")?;
+    if end_lsn == base_lsn {
+        ensure!(
+            pg_control.state == DBState_DB_SHUTDOWNED,
+            "Postgres cluster was not shut down cleanly, WAL replay is required"
+        );
+        return Ok(());
+    }
+
+    import_wal_from_tar(tar_path, &wal_segments, tline, base_lsn, end_lsn)
+}
+
 // subroutine of import_timeline_from_postgres_datadir(), to load one relation file.
 fn import_relfile<R: Repository>(
     modification: &mut DatadirModification<R>,
+    rels: &mut HashMap<RelTag, BlockNumber>,
+    path: &Path,
+    spcoid: Oid,
+    dboid: Oid,
+) -> anyhow::Result<()> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    import_rel(modification, rels, path, spcoid, dboid, file, len)
+}
+
+///
+/// Import the pages of one segment of a relation.
+///
+/// The relations are not created here, because the segments of a relation can
+/// come in any order. Instead, the size of the relation seen so far is tracked
+/// in 'rels', and the caller creates them all with put_rel_creations() at the end.
+///
+fn import_rel<R: Repository, Reader: Read>(
+    modification: &mut DatadirModification<R>,
+    rels: &mut HashMap<RelTag, BlockNumber>,
     path: &Path,
     spcoid: Oid,
     dboid: Oid,
+    mut reader: Reader,
+    len: u64,
 ) -> anyhow::Result<()> {
     // Does it look like a relation file?
     trace!("importing rel file {}", path.display());
@@ -173,16 +366,11 @@ fn import_relfile<R: Repository>(
             e
         })?;
 
-    let mut file = File::open(path)?;
     let mut buf: [u8; 8192] = [0u8; 8192];
 
-    let len = file.metadata().unwrap().len();
     ensure!(len % pg_constants::BLCKSZ as u64 == 0);
     let nblocks = len / pg_constants::BLCKSZ as u64;
-
-    if segno != 0 {
-        todo!();
-    }
+    ensure!(nblocks <= pg_constants::RELSEG_SIZE as u64);
 
     let rel = RelTag {
         spcnode: spcoid,
@@ -190,31 +378,31 @@ fn import_relfile<R: Repository>(
         relnode,
         forknum,
     };
-    modification.put_rel_creation(rel, nblocks as u32)?;
-
-    let mut blknum: u32 = segno * (1024 * 1024 * 1024 / pg_constants::BLCKSZ as u32);
-    loop {
-        let r = file.read_exact(&mut buf);
-        match r {
-            Ok(_) => {
-                modification.put_rel_page_image(rel, blknum, Bytes::copy_from_slice(&buf))?;
-            }
 
-            // TODO: UnexpectedEof is expected
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => {
-                    // reached EOF. That's expected.
-                    ensure!(blknum == nblocks as u32, "unexpected EOF");
-                    break;
-                }
-                _ => {
-                    bail!("error reading file {}: {:#}", path.display(), err);
-                }
-            },
-        };
+    let mut blknum: u32 = segno * pg_constants::RELSEG_SIZE;
+    let end_blknum = blknum + nblocks as u32;
+    while blknum < end_blknum {
+        reader
+            .read_exact(&mut buf)
+            .with_context(|| format!("error reading file {}", path.display()))?;
+        modification.put_rel_page_image(rel, blknum, Bytes::copy_from_slice(&buf))?;
         blknum += 1;
     }
 
+    let size = rels.entry(rel).or_insert(0);
+    *size = (*size).max(end_blknum);
+
+    Ok(())
+}
+
+/// Create the relations imported with import_rel(), with their final sizes.
+fn put_rel_creations<R: Repository>(
+    modification: &mut DatadirModification<R>,
+    rels: HashMap<RelTag, BlockNumber>,
+) -> Result<()> {
+    for (rel, nblocks) in rels {
+        modification.put_rel_creation(rel, nblocks)?;
+    }
     Ok(())
 }
 
@@ -225,14 +413,10 @@ fn import_relmap_file<R: Repository>(
     dbnode: Oid,
     path: &Path,
 ) -> Result<()> {
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    // read the whole file
-    file.read_to_end(&mut buffer)?;
-
     trace!("importing relmap file {}", path.display());
 
-    modification.put_relmap_file(spcnode, dbnode, Bytes::copy_from_slice(&buffer[..]))?;
+    let buffer = read_all_bytes(File::open(path)?)?;
+    modification.put_relmap_file(spcnode, dbnode, buffer)?;
     Ok(())
 }
 
@@ -242,14 +426,10 @@ fn import_twophase_file<R: Repository>(
     xid: TransactionId,
     path: &Path,
 ) -> Result<()> {
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    // read the whole file
-    file.read_to_end(&mut buffer)?;
-
     trace!("importing non-rel file {}", path.display());
 
-    modification.put_twophase_file(xid, Bytes::copy_from_slice(&buffer[..]))?;
+    let buffer = read_all_bytes(File::open(path)?)?;
+    modification.put_twophase_file(xid, buffer)?;
     Ok(())
 }
 
@@ -262,21 +442,24 @@ fn import_control_file<R: Repository>(
     modification: &mut DatadirModification<R>,
     path: &Path,
 ) -> Result<ControlFileData> {
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    // read the whole file
-    file.read_to_end(&mut buffer)?;
-
     trace!("importing control file {}", path.display());
 
-    // Import it as ControlFile
-    modification.put_control_file(Bytes::copy_from_slice(&buffer[..]))?;
+    let buffer = read_all_bytes(File::open(path)?)?;
+    import_control_bytes(modification, buffer)
+}
 
+fn import_control_bytes<R: Repository>(
+    modification: &mut DatadirModification<R>,
+    buffer: Bytes,
+) -> Result<ControlFileData> {
     // Extract the checkpoint record and import it separately.
     let pg_control = ControlFileData::decode(&buffer)?;
     let checkpoint_bytes = pg_control.checkPointCopy.encode()?;
     modification.put_checkpoint(checkpoint_bytes)?;
 
+    // Import it as ControlFile
+    modification.put_control_file(buffer)?;
+
     Ok(pg_control)
 }
 
@@ -287,14 +470,24 @@ fn import_slru_file<R: Repository>(
     modification: &mut DatadirModification<R>,
     slru: SlruKind,
     path: &Path,
+) -> Result<()> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    import_slru(modification, slru, path, file, len)
+}
+
+fn import_slru<R: Repository, Reader: Read>(
+    modification: &mut DatadirModification<R>,
+    slru: SlruKind,
+    path: &Path,
+    mut reader: Reader,
+    len: u64,
 ) -> Result<()> {
     trace!("importing slru file {}", path.display());
 
-    let mut file = File::open(path)?;
     let mut buf: [u8; 8192] = [0u8; 8192];
     let segno = u32::from_str_radix(&path.file_name().unwrap().to_string_lossy(), 16)?;
 
-    let len = file.metadata().unwrap().len();
     ensure!(len % pg_constants::BLCKSZ as u64 == 0); // we assume SLRU block size is the same as BLCKSZ
     let nblocks = len / pg_constants::BLCKSZ as u64;
 
@@ -302,37 +495,22 @@ fn import_slru_file<R: Repository>(
 
     modification.put_slru_segment_creation(slru, segno, nblocks as u32)?;
 
-    let mut rpageno = 0;
-    loop {
-        let r = file.read_exact(&mut buf);
-        match r {
-            Ok(_) => {
-                modification.put_slru_page_image(
-                    slru,
-                    segno,
-                    rpageno,
-                    Bytes::copy_from_slice(&buf),
-                )?;
-            }
-
-            // TODO: UnexpectedEof is expected
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => {
-                    // reached EOF. That's expected.
-                    ensure!(rpageno == nblocks as u32, "unexpected EOF");
-                    break;
-                }
-                _ => {
-                    bail!("error reading file {}: {:#}", path.display(), err);
-                }
-            },
-        };
-        rpageno += 1;
+    for rpageno in 0..nblocks as u32 {
+        reader
+            .read_exact(&mut buf)
+            .with_context(|| format!("error reading file {}", path.display()))?;
+        modification.put_slru_page_image(slru, segno, rpageno, Bytes::copy_from_slice(&buf))?;
     }
 
     Ok(())
 }
 
+fn read_all_bytes<Reader: Read>(mut reader: Reader) -> Result<Bytes> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    Ok(Bytes::from(buf))
+}
+
 /// Scan PostgreSQL WAL files in given directory and load all records between
 /// 'startpoint' and 'endpoint' into the repository.
 fn import_wal<R: Repository>(
@@ -403,3 +581,63 @@ fn import_wal<R: Repository>(
 
     Ok(())
 }
+
+/// Load all the WAL records between 'startpoint' and 'endpoint' into the
+/// repository, from the WAL segments found in a tar archive.
+fn import_wal_from_tar<R: Repository>(
+    tar_path: &Path,
+    wal_segments: &BTreeMap<XLogSegNo, (u64, u64)>,
+    tline: &mut DatadirTimeline<R>,
+    startpoint: Lsn,
+    endpoint: Lsn,
+) -> Result<()> {
+    let mut waldecoder = WalStreamDecoder::new(startpoint);
+
+    let mut segno = startpoint.segment_number(pg_constants::WAL_SEGMENT_SIZE);
+    let mut offset = startpoint.segment_offset(pg_constants::WAL_SEGMENT_SIZE);
+    let mut last_lsn = startpoint;
+
+    let mut walingest = WalIngest::new(tline, startpoint)?;
+    let mut file = File::open(tar_path)?;
+
+    while last_lsn < endpoint {
+        let (position, size) = wal_segments.get(&segno).with_context(|| {
+            format!(
+                "WAL segment {} needed to reach {} not found in the archive",
+                XLogFileName(PG_TLI, segno, pg_constants::WAL_SEGMENT_SIZE),
+                endpoint
+            )
+        })?;
+
+        let mut buf = vec![0u8; *size as usize - offset];
+        file.seek(SeekFrom::Start(*position + offset as u64))?;
+        file.read_exact(&mut buf)?;
+        waldecoder.feed_bytes(&buf);
+
+        let mut nrecords = 0;
+        while last_lsn < endpoint {
+            match waldecoder.poll_decode()? {
+                Some((lsn, recdata)) => {
+                    walingest.ingest_record(tline, recdata, lsn)?;
+                    last_lsn = lsn;
+                    nrecords += 1;
+                }
+                None => break,
+            }
+        }
+        debug!("imported {} records up to {}", nrecords, last_lsn);
+
+        segno += 1;
+        offset = 0;
+    }
+
+    ensure!(
+        last_lsn == endpoint,
+        "end LSN {} is not at a record boundary, the last imported record ends at {}",
+        endpoint,
+        last_lsn
+    );
+    info!("imported WAL from {} to {}", startpoint, last_lsn);
+
+    Ok(())
+}
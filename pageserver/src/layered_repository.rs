@@ -21,7 +21,7 @@ use tracing::*;
 use std::cmp::{max, min, Ordering};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
@@ -31,6 +31,7 @@ use std::sync::atomic::{self, AtomicBool};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, TryLockError};
 use std::time::{Duration, Instant, SystemTime};
 
+use self::block_io::is_layer_corruption_error;
 use self::metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME};
 use crate::config::PageServerConf;
 use crate::keyspace::KeySpace;
@@ -38,7 +39,8 @@ use crate::storage_sync::index::RemoteIndex;
 use crate::tenant_config::{TenantConf, TenantConfOpt};
 
 use crate::repository::{
-    GcResult, Repository, RepositoryTimeline, Timeline, TimelineSyncStatusUpdate, TimelineWriter,
+    GcResult, Repository, RepositoryTimeline, Timeline, TimelineHasChildren,
+    TimelineSyncStatusUpdate, TimelineWriter,
 };
 use crate::repository::{Key, Value};
 use crate::tenant_mgr;
@@ -58,7 +60,7 @@ use utils::{
     crashsafe_dir,
     lsn::{AtomicLsn, Lsn, RecordLsn},
     seqwait::SeqWait,
-    zid::{ZTenantId, ZTimelineId},
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
 mod blob_io;
@@ -66,12 +68,14 @@ pub mod block_io;
 mod delta_layer;
 mod disk_btree;
 pub(crate) mod ephemeral_file;
-mod filename;
+pub mod filename;
 mod image_layer;
 mod inmemory_layer;
 mod layer_map;
 pub mod metadata;
 mod par_fsync;
+pub mod reconstruct_trace;
+mod remote_layer;
 mod storage_layer;
 
 use crate::pgdatadir_mapping::LsnForTimestamp;
@@ -83,6 +87,8 @@ use inmemory_layer::InMemoryLayer;
 use layer_map::LayerMap;
 use layer_map::SearchResult;
 use postgres_ffi::xlog_utils::to_pg_timestamp;
+use reconstruct_trace::{ReconstructTrace, TracedAncestorHop};
+use remote_layer::{RemoteLayer, RemoteLayerFileName};
 use storage_layer::{Layer, ValueReconstructResult, ValueReconstructState};
 
 // re-export this function so that page_cache.rs can use it.
@@ -132,6 +138,24 @@ lazy_static! {
     .expect("failed to define a metric");
 }
 
+lazy_static! {
+    static ref CURRENT_LOGICAL_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_current_logical_size",
+        "Current logical size grouped by timeline",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
+lazy_static! {
+    static ref SYNTHETIC_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_synthetic_size",
+        "Synthetic size, including the history retained for PITR, grouped by timeline",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
 // Metrics for cloud upload. These metrics reflect data uploaded to cloud storage,
 // or in testing they estimate how much we would upload if we did.
 lazy_static! {
@@ -147,6 +171,28 @@ lazy_static! {
     .expect("failed to define a metric");
 }
 
+// Metrics for the layer files, evicted from the local disk after being uploaded to cloud storage.
+lazy_static! {
+    static ref NUM_EVICTED_LAYERS: IntCounter = register_int_counter!(
+        "pageserver_evicted_layers_total",
+        "Number of layer files evicted from the local disk",
+    )
+    .expect("failed to define a metric");
+    static ref EVICTED_BYTES: IntCounter = register_int_counter!(
+        "pageserver_evicted_bytes_total",
+        "Total size of layer files evicted from the local disk",
+    )
+    .expect("failed to define a metric");
+}
+
+lazy_static! {
+    static ref NUM_CORRUPT_LAYERS: IntCounter = register_int_counter!(
+        "pageserver_corrupt_layers_total",
+        "Number of layer files that failed a checksum check and were downloaded again",
+    )
+    .expect("failed to define a metric");
+}
+
 /// Parts of the `.zenith/tenants/<tenantid>/timelines/<timelineid>` directory prefix.
 pub const TIMELINES_SEGMENT_NAME: &str = "timelines";
 
@@ -227,7 +273,9 @@ impl Repository for LayeredRepository {
         // Create the timeline directory, and write initial metadata to file.
         crashsafe_dir::create_dir_all(self.conf.timeline_path(&timelineid, &self.tenant_id))?;
 
-        let metadata = TimelineMetadata::new(Lsn(0), None, None, Lsn(0), initdb_lsn, initdb_lsn);
+        // The timeline is empty, so its logical size is known from the start
+        let metadata =
+            TimelineMetadata::new(Lsn(0), None, None, Lsn(0), initdb_lsn, initdb_lsn, Some(0));
         Self::save_metadata(self.conf, timelineid, self.tenant_id, &metadata, true)?;
 
         let timeline = LayeredTimeline::new(
@@ -239,6 +287,7 @@ impl Repository for LayeredRepository {
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
             self.upload_layers,
+            self.remote_index.clone(),
         );
         timeline.layers.write().unwrap().next_open_layer_at = Some(initdb_lsn);
 
@@ -291,7 +340,8 @@ impl Repository for LayeredRepository {
 
         // Create the metadata file, noting the ancestor of the new timeline.
         // There is initially no data in it, but all the read-calls know to look
-        // into the ancestor.
+        // into the ancestor. The logical size at the branch point is not known,
+        // it is calculated when the new timeline is loaded.
         let metadata = TimelineMetadata::new(
             start_lsn,
             dst_prev,
@@ -299,6 +349,7 @@ impl Repository for LayeredRepository {
             start_lsn,
             *src_timeline.latest_gc_cutoff_lsn.read().unwrap(),
             src_timeline.initdb_lsn,
+            None,
         );
         crashsafe_dir::create_dir_all(self.conf.timeline_path(&dst, &self.tenant_id))?;
         Self::save_metadata(self.conf, dst, self.tenant_id, &metadata, true)?;
@@ -358,6 +409,105 @@ impl Repository for LayeredRepository {
         Ok(())
     }
 
+    fn eviction_iteration(&self) -> Result<()> {
+        let max_local_layers_size = self.get_max_local_layers_size();
+        if max_local_layers_size == 0 {
+            return Ok(());
+        }
+
+        let timelines = self.timelines.lock().unwrap();
+        let loaded_timelines = timelines
+            .values()
+            .filter_map(|timeline| match timeline {
+                LayeredTimelineEntry::Loaded(timeline) => Some(Arc::clone(timeline)),
+                LayeredTimelineEntry::Unloaded { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        drop(timelines);
+
+        // Only the layers that are present in the remote storage can be evicted.
+        let remote_index = futures::executor::block_on(self.remote_index.read());
+        let uploaded_layers = loaded_timelines
+            .iter()
+            .filter_map(|timeline| {
+                let sync_id = ZTenantTimelineId::new(self.tenant_id, timeline.timeline_id);
+                let remote_timeline = remote_index.timeline_entry(&sync_id)?;
+                Some((timeline.timeline_id, remote_timeline.stored_files().clone()))
+            })
+            .collect::<HashMap<_, _>>();
+        drop(remote_index);
+
+        let mut local_layers_size = 0;
+        let mut eviction_candidates = Vec::new();
+        for timeline in &loaded_timelines {
+            let layers = timeline.layers.read().unwrap();
+            for layer in layers.iter_historic_layers() {
+                let (last_access_time, local_path) =
+                    match (layer.last_access_time(), layer.local_path()) {
+                        (Some(last_access_time), Some(local_path)) => {
+                            (last_access_time, local_path)
+                        }
+                        _ => continue,
+                    };
+                let file_size = match fs::metadata(&local_path) {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) => {
+                        warn!(
+                            "Failed to get the size of layer file {}: {e}",
+                            local_path.display()
+                        );
+                        continue;
+                    }
+                };
+
+                local_layers_size += file_size;
+                if uploaded_layers
+                    .get(&timeline.timeline_id)
+                    .map_or(false, |uploaded| uploaded.contains(&local_path))
+                {
+                    eviction_candidates.push((
+                        last_access_time,
+                        file_size,
+                        Arc::clone(timeline),
+                        Arc::clone(layer),
+                    ));
+                }
+            }
+        }
+
+        if local_layers_size <= max_local_layers_size {
+            return Ok(());
+        }
+        info!(
+            "Local layers take {local_layers_size} bytes, over the limit of {max_local_layers_size} bytes, evicting layers"
+        );
+
+        // GC removes layer files too, don't let it run concurrently with the eviction.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+
+        // Evict the least recently accessed layers first
+        eviction_candidates.sort_by_key(|(last_access_time, ..)| *last_access_time);
+        let mut num_evicted = 0;
+        for (_, file_size, timeline, layer) in eviction_candidates {
+            if local_layers_size <= max_local_layers_size {
+                break;
+            }
+            if timeline.evict_layer(&layer)?.is_some() {
+                local_layers_size -= file_size;
+                num_evicted += 1;
+                NUM_EVICTED_LAYERS.inc();
+                EVICTED_BYTES.inc_by(file_size);
+            }
+        }
+
+        info!("Evicted {num_evicted} layers, local layers take {local_layers_size} bytes now");
+        if local_layers_size > max_local_layers_size {
+            warn!("Not enough uploaded layers to evict, local layers size is still over the limit");
+        }
+
+        Ok(())
+    }
+
     ///
     /// Flush all in-memory data to disk.
     ///
@@ -415,6 +565,36 @@ impl Repository for LayeredRepository {
         Ok(())
     }
 
+    fn delete_timeline(&self, timeline_id: ZTimelineId) -> anyhow::Result<()> {
+        // GC iterates over all timelines of the repository, hold the lock to avoid it
+        // touching the files of the timeline we are about to remove.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+
+        let mut timelines = self.timelines.lock().unwrap();
+        let num_children = timelines
+            .iter()
+            .filter(|(_, entry)| entry.ancestor_timeline_id() == Some(timeline_id))
+            .count();
+
+        if num_children > 0 {
+            return Err(TimelineHasChildren.into());
+        }
+
+        let removed_entry = timelines.remove(&timeline_id).with_context(|| {
+            format!("Cannot delete timeline {timeline_id} that is not available locally")
+        })?;
+        drop(timelines);
+
+        // Compaction and layer flushes might still be running with the timeline reference obtained earlier.
+        // Wait for them to finish, so no new layer files appear or get scheduled for upload after this point.
+        if let LayeredTimelineEntry::Loaded(timeline) = removed_entry {
+            let _compaction_cs = timeline.compaction_cs.lock().unwrap();
+            let _layer_flush_lock = timeline.layer_flush_lock.lock().unwrap();
+        }
+
+        Ok(())
+    }
+
     fn apply_timeline_remote_sync_status_update(
         &self,
         timeline_id: ZTimelineId,
@@ -557,12 +737,37 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.pitr_interval)
     }
 
+    pub fn get_max_local_layers_size(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .max_local_layers_size
+            .unwrap_or(self.conf.default_tenant_conf.max_local_layers_size)
+    }
+
+    pub fn get_wal_redo_process_pool_size(&self) -> usize {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .wal_redo_process_pool_size
+            .unwrap_or(self.conf.default_tenant_conf.wal_redo_process_pool_size)
+    }
+
+    pub fn get_max_logical_size(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .max_logical_size
+            .unwrap_or(self.conf.default_tenant_conf.max_logical_size)
+    }
+
     pub fn update_tenant_config(&self, new_tenant_conf: TenantConfOpt) -> Result<()> {
         let mut tenant_conf = self.tenant_conf.write().unwrap();
 
         tenant_conf.update(&new_tenant_conf);
 
         LayeredRepository::persist_tenant_config(self.conf, self.tenant_id, *tenant_conf)?;
+        drop(tenant_conf);
+
+        self.walredo_mgr
+            .set_process_pool_size(self.get_wal_redo_process_pool_size());
         Ok(())
     }
 
@@ -636,6 +841,12 @@ impl LayeredRepository {
             .map(LayeredTimelineEntry::Loaded);
         let _enter = info_span!("loading local timeline").entered();
 
+        // layers that are present in the remote storage, but not locally, are downloaded on demand
+        let remote_layers = futures::executor::block_on(self.remote_index.read())
+            .timeline_entry(&ZTenantTimelineId::new(self.tenant_id, timeline_id))
+            .map(|remote_timeline| remote_timeline.stored_files().clone())
+            .unwrap_or_default();
+
         let timeline = LayeredTimeline::new(
             self.conf,
             Arc::clone(&self.tenant_conf),
@@ -645,9 +856,10 @@ impl LayeredRepository {
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
             self.upload_layers,
+            self.remote_index.clone(),
         );
         timeline
-            .load_layer_map(disk_consistent_lsn)
+            .load_layer_map(disk_consistent_lsn, remote_layers)
             .context("failed to load layermap")?;
 
         Ok(Arc::new(timeline))
@@ -661,7 +873,7 @@ impl LayeredRepository {
         remote_index: RemoteIndex,
         upload_layers: bool,
     ) -> LayeredRepository {
-        LayeredRepository {
+        let repo = LayeredRepository {
             tenant_id,
             conf,
             tenant_conf: Arc::new(RwLock::new(tenant_conf)),
@@ -670,7 +882,10 @@ impl LayeredRepository {
             walredo_mgr,
             remote_index,
             upload_layers,
-        }
+        };
+        repo.walredo_mgr
+            .set_process_pool_size(repo.get_wal_redo_process_pool_size());
+        repo
     }
 
     /// Locate and load config
@@ -939,6 +1154,13 @@ pub struct LayeredTimeline {
     ancestor_timeline: Option<LayeredTimelineEntry>,
     ancestor_lsn: Lsn,
 
+    // Logical size of the timeline at 'last_record_lsn', maintained
+    // incrementally by the writers. None until it has been calculated, for a
+    // timeline whose metadata file doesn't have it. Protected by 'write_lock'
+    // for updates, so that the value always corresponds to 'last_record_lsn'
+    // while the lock is held.
+    current_logical_size: Mutex<Option<isize>>,
+
     // Metrics
     reconstruct_time_histo: Histogram,
     materialized_page_cache_hit_counter: IntCounter,
@@ -946,11 +1168,16 @@ pub struct LayeredTimeline {
     compact_time_histo: Histogram,
     create_images_time_histo: Histogram,
     last_record_gauge: IntGauge,
+    current_logical_size_gauge: IntGauge,
+    synthetic_size_gauge: IntGauge,
     wait_lsn_time_histo: Histogram,
 
     /// If `true`, will backup its files that appear after each checkpointing to the remote storage.
     upload_layers: AtomicBool,
 
+    /// Used to check if a corrupt layer can be downloaded again from the remote storage.
+    remote_index: RemoteIndex,
+
     /// Ensures layers aren't frozen by checkpointer between
     /// [`LayeredTimeline::get_layer_for_write`] and layer reads.
     /// Locked automatically by [`LayeredTimelineWriter`] and checkpointer.
@@ -974,6 +1201,10 @@ pub struct LayeredTimeline {
     // garbage collecting data that is still needed by the child timelines.
     gc_info: RwLock<GcInfo>,
 
+    // LSNs pinned by long-running reads, like timeline exports, see lease_gc_lsn().
+    // GC keeps the data needed at them, like for the branch points in gc_info.
+    gc_leases: Mutex<Vec<Lsn>>,
+
     // It may change across major versions so for simplicity
     // keep it after running initdb for a timeline.
     // It is needed in checks when we want to error on some operations
@@ -1010,6 +1241,30 @@ struct GcInfo {
     pitr: Duration,
 }
 
+///
+/// An LSN pinned in GC of a timeline, see LayeredTimeline::lease_gc_lsn().
+/// The LSN is released when the lease is dropped.
+///
+pub struct GcLease<'a> {
+    timeline: &'a LayeredTimeline,
+    lsn: Lsn,
+}
+
+impl GcLease<'_> {
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+}
+
+impl Drop for GcLease<'_> {
+    fn drop(&mut self) {
+        let mut gc_leases = self.timeline.gc_leases.lock().unwrap();
+        if let Some(pos) = gc_leases.iter().position(|lsn| *lsn == self.lsn) {
+            gc_leases.swap_remove(pos);
+        }
+    }
+}
+
 /// Public interface functions
 impl Timeline for LayeredTimeline {
     fn get_ancestor_lsn(&self) -> Lsn {
@@ -1073,12 +1328,70 @@ impl Timeline for LayeredTimeline {
             img: cached_page_img,
         };
 
-        self.get_reconstruct_data(key, lsn, &mut reconstruct_state)?;
+        self.get_reconstruct_data(key, lsn, &mut reconstruct_state, None)?;
 
         self.reconstruct_time_histo
             .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))
     }
 
+    /// Look up the values of all the keys in the given range
+    fn get_range(&self, key_range: Range<Key>, lsn: Lsn) -> Result<Vec<Bytes>> {
+        debug_assert!(lsn <= self.get_last_record_lsn());
+
+        let mut keys = Vec::new();
+        let mut key = key_range.start;
+        while key < key_range.end {
+            keys.push(key);
+            key = key.next();
+        }
+
+        // Check the page cache first, like in 'get'
+        let mut values = Vec::with_capacity(keys.len());
+        let mut reconstruct_states = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let cached_page_img = self.lookup_cached_page(key, lsn);
+            let value = match &cached_page_img {
+                Some((cached_lsn, cached_img)) => match cached_lsn.cmp(&lsn) {
+                    Ordering::Less => None,
+                    Ordering::Equal => Some(cached_img.clone()),
+                    Ordering::Greater => bail!(
+                        "page cache returned an image at {cached_lsn} for key {key} after the requested lsn {lsn}"
+                    ),
+                },
+                None => None,
+            };
+            values.push(value);
+            reconstruct_states.push(ValueReconstructState {
+                records: Vec::new(),
+                img: cached_page_img,
+            });
+        }
+
+        let pending = (0..keys.len()).filter(|i| values[*i].is_none()).collect();
+        if let Err(e) =
+            self.get_range_reconstruct_data(&keys, pending, lsn, &mut reconstruct_states)
+        {
+            // Fall back to looking up the keys one by one. That takes care of
+            // corrupt layers, and produces a more detailed error if it persists.
+            debug!(
+                "batched lookup of keys {}..{} at {} failed, retrying one by one: {:#}",
+                key_range.start, key_range.end, lsn, e
+            );
+            return keys.into_iter().map(|key| self.get(key, lsn)).collect();
+        }
+
+        keys.into_iter()
+            .zip(reconstruct_states)
+            .zip(values)
+            .map(|((key, reconstruct_state), value)| match value {
+                Some(value) => Ok(value),
+                None => self.reconstruct_time_histo.observe_closure_duration(|| {
+                    self.reconstruct_value(key, lsn, reconstruct_state)
+                }),
+            })
+            .collect()
+    }
+
     /// Public entry point for checkpoint(). All the logic is in the private
     /// checkpoint_internal function, this public facade just wraps it for
     /// metrics collection.
@@ -1129,6 +1442,36 @@ impl Timeline for LayeredTimeline {
         self.disk_consistent_lsn.load()
     }
 
+    fn get_current_logical_size(&self) -> Option<u64> {
+        let current_logical_size = (*self.current_logical_size.lock().unwrap())?;
+        match u64::try_from(current_logical_size) {
+            Ok(size) => Some(size),
+            Err(_) => {
+                error!(
+                    "current_logical_size is out of range: {}",
+                    current_logical_size
+                );
+                Some(0)
+            }
+        }
+    }
+
+    fn set_current_logical_size(&self, size: u64) {
+        *self.current_logical_size.lock().unwrap() = Some(size as isize);
+        self.update_size_gauges(*self.latest_gc_cutoff_lsn.read().unwrap());
+    }
+
+    fn get_synthetic_size(&self) -> Option<u64> {
+        self.calculate_synthetic_size(*self.latest_gc_cutoff_lsn.read().unwrap())
+    }
+
+    fn get_logical_size_limit(&self) -> Option<u64> {
+        match self.get_max_logical_size() {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
     fn writer<'a>(&'a self) -> Box<dyn TimelineWriter + 'a> {
         Box::new(LayeredTimelineWriter {
             tl: self,
@@ -1166,6 +1509,13 @@ impl LayeredTimeline {
             .unwrap_or(self.conf.default_tenant_conf.image_creation_threshold)
     }
 
+    fn get_max_logical_size(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .max_logical_size
+            .unwrap_or(self.conf.default_tenant_conf.max_logical_size)
+    }
+
     /// Open a Timeline handle.
     ///
     /// Loads the metadata for the timeline into memory, but not the layer map.
@@ -1179,6 +1529,7 @@ impl LayeredTimeline {
         tenant_id: ZTenantId,
         walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
         upload_layers: bool,
+        remote_index: RemoteIndex,
     ) -> LayeredTimeline {
         let reconstruct_time_histo = RECONSTRUCT_TIME
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
@@ -1210,11 +1561,17 @@ impl LayeredTimeline {
         let last_record_gauge = LAST_RECORD_LSN
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
+        let current_logical_size_gauge = CURRENT_LOGICAL_SIZE
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let synthetic_size_gauge = SYNTHETIC_SIZE
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
         let wait_lsn_time_histo = WAIT_LSN_TIME
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
 
-        LayeredTimeline {
+        let timeline = LayeredTimeline {
             conf,
             tenant_conf,
             timeline_id,
@@ -1235,15 +1592,20 @@ impl LayeredTimeline {
             ancestor_timeline: ancestor,
             ancestor_lsn: metadata.ancestor_lsn(),
 
+            current_logical_size: Mutex::new(metadata.logical_size().map(|size| size as isize)),
+
             reconstruct_time_histo,
             materialized_page_cache_hit_counter,
             flush_time_histo,
             compact_time_histo,
             create_images_time_histo,
             last_record_gauge,
+            current_logical_size_gauge,
+            synthetic_size_gauge,
             wait_lsn_time_histo,
 
             upload_layers: AtomicBool::new(upload_layers),
+            remote_index,
 
             write_lock: Mutex::new(()),
             layer_flush_lock: Mutex::new(()),
@@ -1254,17 +1616,25 @@ impl LayeredTimeline {
                 cutoff: Lsn(0),
                 pitr: Duration::ZERO,
             }),
+            gc_leases: Mutex::new(Vec::new()),
 
             latest_gc_cutoff_lsn: RwLock::new(metadata.latest_gc_cutoff_lsn()),
             initdb_lsn: metadata.initdb_lsn(),
-        }
+        };
+        timeline.update_size_gauges(metadata.latest_gc_cutoff_lsn());
+        timeline
     }
 
     ///
     /// Scan the timeline directory to populate the layer map.
-    /// Returns all timeline-related files that were found and loaded.
+    /// Layers from `remote_layers` that are missing locally are added as remote layers,
+    /// to be downloaded on demand.
     ///
-    fn load_layer_map(&self, disk_consistent_lsn: Lsn) -> anyhow::Result<()> {
+    fn load_layer_map(
+        &self,
+        disk_consistent_lsn: Lsn,
+        mut remote_layers: HashSet<PathBuf>,
+    ) -> anyhow::Result<()> {
         let mut layers = self.layers.write().unwrap();
         let mut num_layers = 0;
 
@@ -1272,8 +1642,9 @@ impl LayeredTimeline {
         // structs representing all files on disk
         let timeline_path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
 
-        for direntry in fs::read_dir(timeline_path)? {
+        for direntry in fs::read_dir(&timeline_path)? {
             let direntry = direntry?;
+            remote_layers.remove(&direntry.path());
             let fname = direntry.file_name();
             let fname = fname.to_string_lossy();
 
@@ -1329,16 +1700,207 @@ impl LayeredTimeline {
             }
         }
 
+        let mut num_remote_layers = 0;
+        for remote_layer_path in remote_layers {
+            let remote_layer_name = match remote_layer_path
+                .file_name()
+                .and_then(|fname| fname.to_str())
+                .and_then(RemoteLayerFileName::parse_str)
+            {
+                Some(remote_layer_name) => remote_layer_name,
+                None => {
+                    warn!(
+                        "unrecognized remote layer path for timeline {}: {}",
+                        self.timeline_id,
+                        remote_layer_path.display()
+                    );
+                    continue;
+                }
+            };
+
+            let layer = RemoteLayer::new(
+                self.conf,
+                self.timeline_id,
+                self.tenant_id,
+                remote_layer_name,
+            );
+            // same as for the local layers, any layer beyond disk_consistent_lsn is not complete
+            if layer.get_lsn_range().end > disk_consistent_lsn + 1 {
+                warn!(
+                    "found future remote layer {} on timeline {} disk_consistent_lsn is {}",
+                    layer.filename().display(),
+                    self.timeline_id,
+                    disk_consistent_lsn
+                );
+                continue;
+            }
+
+            trace!("found remote layer {}", layer.filename().display());
+            layers.insert_historic(Arc::new(layer));
+            num_remote_layers += 1;
+        }
+
         layers.next_open_layer_at = Some(Lsn(disk_consistent_lsn.0) + 1);
 
         info!(
-            "loaded layer map with {} layers at {}",
-            num_layers, disk_consistent_lsn
+            "loaded layer map with {} layers, {} of them remote, at {}",
+            num_layers + num_remote_layers,
+            num_remote_layers,
+            disk_consistent_lsn
         );
 
         Ok(())
     }
 
+    ///
+    /// Replace an on-disk layer in the layer map with a remote one and remove its local file.
+    /// The layer contents get downloaded back from the remote storage, when accessed next time.
+    ///
+    /// Returns the remote layer that replaced the evicted one, or None if the layer is not
+    /// in the layer map anymore, e.g. removed by GC.
+    ///
+    /// The caller must hold the repository's `gc_cs`. Compaction reads the layers it
+    /// compacts outside of the layer map lock, so `compaction_cs` is held here to avoid
+    /// removing the files under it.
+    ///
+    fn evict_layer(&self, layer: &Arc<dyn Layer>) -> anyhow::Result<Option<Arc<dyn Layer>>> {
+        let _compaction_cs = self.compaction_cs.lock().unwrap();
+        self.replace_with_remote_layer(layer)
+    }
+
+    ///
+    /// Replace the layer with a RemoteLayer in the layer map and remove its local file.
+    /// Returns None, if the layer is not in the layer map anymore.
+    ///
+    fn replace_with_remote_layer(
+        &self,
+        layer: &Arc<dyn Layer>,
+    ) -> anyhow::Result<Option<Arc<dyn Layer>>> {
+        let local_path = layer
+            .local_path()
+            .context("Cannot evict a layer that has no local file")?;
+        let remote_layer_name = RemoteLayerFileName::parse_str(&layer.filename().to_string_lossy())
+            .with_context(|| {
+                format!(
+                    "Cannot evict layer with unexpected file name {}",
+                    local_path.display()
+                )
+            })?;
+        let remote_layer = RemoteLayer::new(
+            self.conf,
+            self.timeline_id,
+            self.tenant_id,
+            remote_layer_name,
+        );
+
+        // Page reconstruction reads the layers while holding the layer map lock,
+        // keep it until the file is removed, so no reads happen on the evicted file.
+        let remote_layer: Arc<dyn Layer> = Arc::new(remote_layer);
+        let mut layers = self.layers.write().unwrap();
+        if !layers.replace_historic(layer, Arc::clone(&remote_layer)) {
+            return Ok(None);
+        }
+        fs::remove_file(&local_path).with_context(|| {
+            format!(
+                "Failed to remove evicted layer file {}",
+                local_path.display()
+            )
+        })?;
+        debug!("evicted layer {}", local_path.display());
+
+        Ok(Some(remote_layer))
+    }
+
+    ///
+    /// Replace a layer, that failed a checksum check, with a RemoteLayer
+    /// downloading the layer file from the remote storage again.
+    ///
+    /// Returns the layer to use instead of the corrupt one.
+    ///
+    fn replace_corrupt_layer(&self, layer: &Arc<dyn Layer>) -> anyhow::Result<Arc<dyn Layer>> {
+        let local_path = layer
+            .local_path()
+            .context("Corrupt layer has no local file")?;
+        let sync_id = ZTenantTimelineId::new(self.tenant_id, self.timeline_id);
+        let uploaded = futures::executor::block_on(self.remote_index.read())
+            .timeline_entry(&sync_id)
+            .map_or(false, |remote_timeline| {
+                remote_timeline.stored_files().contains(&local_path)
+            });
+        ensure!(
+            uploaded,
+            "Corrupt layer {} is not present in the remote storage",
+            local_path.display()
+        );
+
+        // The corrupt layer may be read by compaction that holds `compaction_cs` already,
+        // replace it without taking the lock: the file is unusable anyway.
+        if let Some(remote_layer) = self.replace_with_remote_layer(layer)? {
+            return Ok(remote_layer);
+        }
+        // Another reader has replaced the layer already
+        let layers = self.layers.read().unwrap();
+        let replacement = layers
+            .iter_historic_layers()
+            .find(|historic| historic.local_path().as_ref() == Some(&local_path))
+            .with_context(|| {
+                format!(
+                    "Corrupt layer {} is not in the layer map",
+                    local_path.display()
+                )
+            })?;
+        Ok(Arc::clone(replacement))
+    }
+
+    ///
+    /// Get the data needed to reconstruct a value from a historic layer.
+    ///
+    /// If the layer file turns out to be corrupt, it is downloaded from the remote
+    /// storage again, and the layer map gets updated to use the new file. The
+    /// lock on the layer map is released in that case, so it's passed by value.
+    ///
+    /// Returns the layer that the data was read from.
+    ///
+    fn get_historic_reconstruct_data(
+        &self,
+        layers: RwLockReadGuard<LayerMap>,
+        layer: Arc<dyn Layer>,
+        key: Key,
+        lsn_range: Range<Lsn>,
+        reconstruct_state: &mut ValueReconstructState,
+    ) -> anyhow::Result<(ValueReconstructResult, Arc<dyn Layer>)> {
+        let records_len = reconstruct_state.records.len();
+        let img = reconstruct_state.img.clone();
+
+        let err = match layer.get_value_reconstruct_data(key, lsn_range.clone(), reconstruct_state)
+        {
+            Ok(result) => return Ok((result, layer)),
+            Err(err) if is_layer_corruption_error(&err) => err,
+            Err(err) => return Err(err),
+        };
+        drop(layers);
+        error!(
+            "Layer {} is corrupt, downloading it again: {err:?}",
+            layer.filename().display()
+        );
+
+        let layer = match self.replace_corrupt_layer(&layer) {
+            Ok(layer) => layer,
+            Err(replace_err) => {
+                error!("Failed to replace corrupt layer: {replace_err:?}");
+                return Err(err);
+            }
+        };
+        NUM_CORRUPT_LAYERS.inc();
+
+        // Forget what was read from the corrupt layer, and read again
+        reconstruct_state.records.truncate(records_len);
+        reconstruct_state.img = img;
+        let _layers = self.layers.read().unwrap();
+        let result = layer.get_value_reconstruct_data(key, lsn_range, reconstruct_state)?;
+        Ok((result, layer))
+    }
+
     ///
     /// Get a handle to a Layer for reading.
     ///
@@ -1347,11 +1909,15 @@ impl LayeredTimeline {
     ///
     /// This function takes the current timeline's locked LayerMap as an argument,
     /// so callers can avoid potential race conditions.
+    ///
+    /// If 'trace' is given, the layers visited and the ancestor timelines
+    /// traversed are recorded in it.
     fn get_reconstruct_data(
         &self,
         key: Key,
         request_lsn: Lsn,
         reconstruct_state: &mut ValueReconstructState,
+        mut trace: Option<&mut ReconstructTrace>,
     ) -> anyhow::Result<()> {
         // Start from the current timeline.
         let mut timeline_owned;
@@ -1384,6 +1950,9 @@ impl LayeredTimeline {
                     // If we reached an earlier cached page image, we're done.
                     if cont_lsn == cached_lsn + 1 {
                         self.materialized_page_cache_hit_counter.inc_by(1);
+                        if let Some(trace) = trace.as_deref_mut() {
+                            trace.materialized_page_hit = true;
+                        }
                         return Ok(());
                     }
                     if prev_lsn <= cont_lsn {
@@ -1418,6 +1987,13 @@ impl LayeredTimeline {
                     cont_lsn
                 );
                 let ancestor = timeline.get_ancestor_timeline()?;
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.ancestor_hops.push(TracedAncestorHop {
+                        timeline_id: timeline.timeline_id,
+                        ancestor_timeline_id: ancestor.timeline_id,
+                        ancestor_lsn: timeline.ancestor_lsn,
+                    });
+                }
                 timeline_owned = ancestor;
                 timeline = &*timeline_owned;
                 prev_lsn = Lsn(u64::MAX);
@@ -1435,11 +2011,22 @@ impl LayeredTimeline {
                     // Get all the data needed to reconstruct the page version from this layer.
                     // But if we have an older cached page image, no need to go past that.
                     let lsn_floor = max(cached_lsn + 1, start_lsn);
+                    let traced_before = traced_state(reconstruct_state);
                     result = open_layer.get_value_reconstruct_data(
                         key,
                         lsn_floor..cont_lsn,
                         reconstruct_state,
                     )?;
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.push_layer(
+                            timeline.timeline_id,
+                            open_layer.as_ref(),
+                            lsn_floor..cont_lsn,
+                            result,
+                            traced_before,
+                            reconstruct_state,
+                        );
+                    }
                     cont_lsn = lsn_floor;
                     traversal_path.push((result, cont_lsn, open_layer.clone()));
                     continue;
@@ -1450,11 +2037,22 @@ impl LayeredTimeline {
                 if cont_lsn > start_lsn {
                     //info!("CHECKING for {} at {} on frozen layer {}", key, cont_lsn, frozen_layer.filename().display());
                     let lsn_floor = max(cached_lsn + 1, start_lsn);
+                    let traced_before = traced_state(reconstruct_state);
                     result = frozen_layer.get_value_reconstruct_data(
                         key,
                         lsn_floor..cont_lsn,
                         reconstruct_state,
                     )?;
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.push_layer(
+                            timeline.timeline_id,
+                            frozen_layer.as_ref(),
+                            lsn_floor..cont_lsn,
+                            result,
+                            traced_before,
+                            reconstruct_state,
+                        );
+                    }
                     cont_lsn = lsn_floor;
                     traversal_path.push((result, cont_lsn, frozen_layer.clone()));
                     continue 'outer;
@@ -1465,11 +2063,25 @@ impl LayeredTimeline {
                 //info!("CHECKING for {} at {} on historic layer {}", key, cont_lsn, layer.filename().display());
 
                 let lsn_floor = max(cached_lsn + 1, lsn_floor);
-                result = layer.get_value_reconstruct_data(
+                let traced_before = traced_state(reconstruct_state);
+                let (layer_result, layer) = timeline.get_historic_reconstruct_data(
+                    layers,
+                    layer,
                     key,
                     lsn_floor..cont_lsn,
                     reconstruct_state,
                 )?;
+                result = layer_result;
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push_layer(
+                        timeline.timeline_id,
+                        layer.as_ref(),
+                        lsn_floor..cont_lsn,
+                        result,
+                        traced_before,
+                        reconstruct_state,
+                    );
+                }
                 cont_lsn = lsn_floor;
                 traversal_path.push((result, cont_lsn, layer));
             } else if timeline.ancestor_timeline.is_some() {
@@ -1483,6 +2095,180 @@ impl LayeredTimeline {
         }
     }
 
+    ///
+    /// Get the data needed to reconstruct the values of many keys at once.
+    ///
+    /// This works like 'get_reconstruct_data', but the keys whose traversal
+    /// is at the same LSN are looked up together, searching the layer map once
+    /// per range of keys covered by the same layers, instead of once per key.
+    ///
+    /// 'keys' must be in key order, and only the keys at the 'pending' indexes
+    /// are looked up. Corrupt layers are not replaced here: on any error, the
+    /// caller is expected to fall back to looking up the keys one by one.
+    ///
+    fn get_range_reconstruct_data(
+        &self,
+        keys: &[Key],
+        mut pending: Vec<usize>,
+        request_lsn: Lsn,
+        reconstruct_states: &mut [ValueReconstructState],
+    ) -> anyhow::Result<()> {
+        // Start from the current timeline.
+        let mut timeline_owned;
+        let mut timeline = self;
+
+        let cached_lsns = reconstruct_states
+            .iter()
+            .map(|state| state.img.as_ref().map_or(Lsn(0), |(lsn, _)| *lsn))
+            .collect::<Vec<_>>();
+        let mut cont_lsns = vec![Lsn(request_lsn.0 + 1); keys.len()];
+
+        loop {
+            // Keys that need to be continued on the ancestor timeline
+            let mut ancestor_pending = Vec::new();
+
+            while !pending.is_empty() {
+                let layers = timeline.layers.read().unwrap();
+
+                // Group the keys by the LSN that their search continues from.
+                // The keys within each group stay in key order.
+                pending.sort_unstable();
+                let mut groups: BTreeMap<Lsn, Vec<usize>> = BTreeMap::new();
+                for i in pending.drain(..) {
+                    if Lsn(cont_lsns[i].0 - 1) <= timeline.ancestor_lsn {
+                        ancestor_pending.push(i);
+                    } else {
+                        groups.entry(cont_lsns[i]).or_default().push(i);
+                    }
+                }
+
+                for (cont_lsn, group) in groups {
+                    // Find the layer to continue from, for each key in the group.
+                    // The open and frozen in-memory layers cover all keys, so they
+                    // come first, in order from newest to oldest, like in
+                    // 'get_reconstruct_data'.
+                    let mut lookups: Vec<(usize, Option<(Arc<dyn Layer>, Lsn)>)> = Vec::new();
+                    if let Some(inmem_layer) = layers
+                        .open_layer
+                        .iter()
+                        .chain(layers.frozen_layers.iter().rev())
+                        .find(|l| cont_lsn > l.get_lsn_range().start)
+                    {
+                        let start_lsn = inmem_layer.get_lsn_range().start;
+                        for i in group {
+                            let layer: Arc<dyn Layer> = inmem_layer.clone();
+                            lookups.push((i, Some((layer, start_lsn))));
+                        }
+                    } else {
+                        let key_range = keys[group[0]]..keys[group[group.len() - 1]].next();
+                        let mut group = group.into_iter().peekable();
+                        for (sub_range, search_result) in
+                            layers.search_range(key_range, cont_lsn)?
+                        {
+                            while let Some(i) = group.next_if(|i| sub_range.contains(&keys[*i])) {
+                                let found = search_result
+                                    .as_ref()
+                                    .map(|r| (Arc::clone(&r.layer), r.lsn_floor));
+                                lookups.push((i, found));
+                            }
+                        }
+                    }
+
+                    for (i, found) in lookups {
+                        let result = match found {
+                            Some((layer, lsn_floor)) => {
+                                let lsn_floor = max(cached_lsns[i] + 1, lsn_floor);
+                                let result = layer.get_value_reconstruct_data(
+                                    keys[i],
+                                    lsn_floor..cont_lsn,
+                                    &mut reconstruct_states[i],
+                                )?;
+                                cont_lsns[i] = lsn_floor;
+                                result
+                            }
+                            None if timeline.ancestor_timeline.is_some() => {
+                                // Nothing on this timeline. Traverse to parent
+                                cont_lsns[i] = Lsn(timeline.ancestor_lsn.0 + 1);
+                                ValueReconstructResult::Continue
+                            }
+                            None => ValueReconstructResult::Missing,
+                        };
+
+                        match result {
+                            ValueReconstructResult::Complete => {}
+                            ValueReconstructResult::Continue
+                                if cont_lsns[i] == cached_lsns[i] + 1 =>
+                            {
+                                // Reached an earlier cached page image
+                                self.materialized_page_cache_hit_counter.inc_by(1);
+                            }
+                            ValueReconstructResult::Continue => {
+                                ensure!(
+                                    cont_lsns[i] < cont_lsn,
+                                    "could not find layer with more data for key {} at LSN {}, request LSN {}",
+                                    keys[i],
+                                    Lsn(cont_lsn.0 - 1),
+                                    request_lsn
+                                );
+                                pending.push(i);
+                            }
+                            ValueReconstructResult::Missing => bail!(
+                                "could not find data for key {} at LSN {}, for request at LSN {}",
+                                keys[i],
+                                cont_lsn,
+                                request_lsn
+                            ),
+                        }
+                    }
+                }
+            }
+
+            if ancestor_pending.is_empty() {
+                return Ok(());
+            }
+            let ancestor = timeline.get_ancestor_timeline()?;
+            timeline_owned = ancestor;
+            timeline = &*timeline_owned;
+            pending = ancestor_pending;
+        }
+    }
+
+    ///
+    /// Reconstruct the value of 'key' at 'lsn' the same way as 'get' does, and
+    /// return an explanation of how it was done. For debugging.
+    ///
+    pub fn trace_get(&self, key: Key, lsn: Lsn) -> Result<ReconstructTrace> {
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "LSN {} is beyond the last record LSN {}",
+            lsn,
+            last_record_lsn
+        );
+        self.check_lsn_is_in_scope(lsn, &self.get_latest_gc_cutoff_lsn())?;
+
+        let mut trace = ReconstructTrace::new(key, lsn);
+
+        let cached_page_img = self.lookup_cached_page(&key, lsn);
+        trace.cached_image_lsn = cached_page_img.as_ref().map(|(cached_lsn, _)| *cached_lsn);
+        let mut reconstruct_state = ValueReconstructState {
+            records: Vec::new(),
+            img: cached_page_img,
+        };
+        if trace.cached_image_lsn == Some(lsn) {
+            trace.materialized_page_hit = true;
+        } else {
+            self.get_reconstruct_data(key, lsn, &mut reconstruct_state, Some(&mut trace))?;
+        }
+        trace.set_redo_input(&reconstruct_state);
+
+        let start = Instant::now();
+        self.reconstruct_value(key, lsn, reconstruct_state)?;
+        trace.redo_time_us = start.elapsed().as_micros() as u64;
+
+        Ok(trace)
+    }
+
     fn lookup_cached_page(&self, key: &Key, lsn: Lsn) -> Option<(Lsn, Bytes)> {
         let cache = page_cache::get();
 
@@ -1576,6 +2362,41 @@ impl LayeredTimeline {
         Ok(())
     }
 
+    fn update_current_logical_size(&self, delta: isize) {
+        let mut current_logical_size = self.current_logical_size.lock().unwrap();
+        if let Some(size) = current_logical_size.as_mut() {
+            *size += delta;
+            self.current_logical_size_gauge.set(*size as i64);
+        }
+    }
+
+    ///
+    /// Calculate the synthetic size, see [`Timeline::get_synthetic_size`].
+    ///
+    /// The retained history starts at the GC cutoff, or at the branch point or
+    /// at initdb if the timeline hasn't been garbage collected past them. The
+    /// WAL between that point and the last record is approximated by the
+    /// distance between the LSNs.
+    ///
+    fn calculate_synthetic_size(&self, latest_gc_cutoff_lsn: Lsn) -> Option<u64> {
+        let logical_size = self.get_current_logical_size()?;
+        let history_start = max(
+            max(latest_gc_cutoff_lsn, self.ancestor_lsn),
+            self.initdb_lsn,
+        );
+        let retained_wal = self.get_last_record_lsn().0.saturating_sub(history_start.0);
+        Some(logical_size + retained_wal)
+    }
+
+    fn update_size_gauges(&self, latest_gc_cutoff_lsn: Lsn) {
+        if let Some(size) = self.get_current_logical_size() {
+            self.current_logical_size_gauge.set(size as i64);
+        }
+        if let Some(size) = self.calculate_synthetic_size(latest_gc_cutoff_lsn) {
+            self.synthetic_size_gauge.set(size as i64);
+        }
+    }
+
     fn finish_write(&self, new_lsn: Lsn) {
         assert!(new_lsn.is_aligned());
 
@@ -1596,7 +2417,9 @@ impl LayeredTimeline {
             let open_layer_rc = Arc::clone(open_layer);
             // Does this layer need freezing?
             let end_lsn = Lsn(self.get_last_record_lsn().0 + 1);
-            open_layer.freeze(end_lsn);
+            // The write lock is held, so the logical size corresponds to the
+            // last record, i.e. to the end of the layer.
+            open_layer.freeze(end_lsn, self.get_current_logical_size());
 
             // The layer is no longer open, update the layer map to reflect this.
             // We will replace it with on-disk historics below.
@@ -1759,13 +2582,15 @@ impl LayeredTimeline {
                 .as_ref()
                 .map(LayeredTimelineEntry::timeline_id);
 
+            let latest_gc_cutoff_lsn = *self.latest_gc_cutoff_lsn.read().unwrap();
             let metadata = TimelineMetadata::new(
                 disk_consistent_lsn,
                 ondisk_prev_record_lsn,
                 ancestor_timelineid,
                 self.ancestor_lsn,
-                *self.latest_gc_cutoff_lsn.read().unwrap(),
+                latest_gc_cutoff_lsn,
                 self.initdb_lsn,
+                frozen_layer.logical_size_at_end(),
             );
 
             fail_point!("checkpoint-before-saving-metadata", |x| bail!(
@@ -1795,6 +2620,8 @@ impl LayeredTimeline {
 
             // Also update the in-memory copy
             self.disk_consistent_lsn.store(disk_consistent_lsn);
+
+            self.update_size_gauges(latest_gc_cutoff_lsn);
         }
 
         Ok(())
@@ -1909,18 +2736,9 @@ impl LayeredTimeline {
     fn create_image_layer(&self, partition: &KeySpace, lsn: Lsn) -> anyhow::Result<PathBuf> {
         let img_range =
             partition.ranges.first().unwrap().start..partition.ranges.last().unwrap().end;
-        let mut image_layer_writer =
+        let image_layer_writer =
             ImageLayerWriter::new(self.conf, self.timeline_id, self.tenant_id, &img_range, lsn)?;
-
-        for range in &partition.ranges {
-            let mut key = range.start;
-            while key < range.end {
-                let img = self.get(key, lsn)?;
-                image_layer_writer.put_image(key, &img)?;
-                key = key.next();
-            }
-        }
-        let image_layer = image_layer_writer.finish()?;
+        let image_layer = self.write_image_layer(image_layer_writer, partition, lsn)?;
 
         // Sync the new layer to disk before adding it to the layer map, to make sure
         // we don't garbage collect something based on the new layer, before it has
@@ -1946,6 +2764,79 @@ impl LayeredTimeline {
         Ok(new_path)
     }
 
+    fn write_image_layer(
+        &self,
+        mut image_layer_writer: ImageLayerWriter,
+        partition: &KeySpace,
+        lsn: Lsn,
+    ) -> anyhow::Result<ImageLayer> {
+        for range in &partition.ranges {
+            let mut key = range.start;
+            while key < range.end {
+                let img = self.get(key, lsn)?;
+                image_layer_writer.put_image(key, &img)?;
+                key = key.next();
+            }
+        }
+        image_layer_writer.finish()
+    }
+
+    ///
+    /// Pin 'lsn' in GC until the returned lease is dropped: GC keeps the
+    /// history needed to reconstruct the pages at 'lsn', even after the GC
+    /// cutoff has moved past it. Fails if that history might be gone already.
+    ///
+    pub fn lease_gc_lsn(&self, lsn: Lsn) -> Result<GcLease<'_>> {
+        let latest_gc_cutoff_lsn = self.get_latest_gc_cutoff_lsn();
+        self.check_lsn_is_in_scope(lsn, &latest_gc_cutoff_lsn)?;
+        // GC reads the leases after moving the cutoff, so it can't miss one
+        // that is added while the old cutoff is held.
+        self.gc_leases.lock().unwrap().push(lsn);
+        Ok(GcLease {
+            timeline: self,
+            lsn,
+        })
+    }
+
+    ///
+    /// Materialize 'keyspace' at the LSN of 'gc_lease' as image layers in
+    /// 'dir', for exporting the timeline. The layers are not added to the
+    /// layer map. Returns the paths of the new layer files.
+    ///
+    pub fn export_image_layers(
+        &self,
+        keyspace: &KeySpace,
+        gc_lease: &GcLease,
+        dir: &Path,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let lsn = gc_lease.lsn();
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "LSN {} is beyond the last record LSN {}",
+            lsn,
+            last_record_lsn
+        );
+
+        let partitioning = keyspace.partition(self.get_compaction_target_size());
+        let mut paths = Vec::with_capacity(partitioning.parts.len());
+        for part in partitioning.parts.iter() {
+            let img_range = part.ranges.first().unwrap().start..part.ranges.last().unwrap().end;
+            let image_layer_writer = ImageLayerWriter::new_in_dir(
+                self.conf,
+                dir,
+                self.timeline_id,
+                self.tenant_id,
+                &img_range,
+                lsn,
+            )?;
+            let image_layer = self.write_image_layer(image_layer_writer, part, lsn)?;
+            paths.push(image_layer.path());
+        }
+
+        Ok(paths)
+    }
+
     ///
     /// Collect a bunch of Level 0 layer files, and compact and reshuffle them as
     /// as Level 1 files.
@@ -2190,6 +3081,9 @@ impl LayeredTimeline {
                     LsnForTimestamp::Past(lsn) => {
                         debug!("past({})", lsn);
                     }
+                    LsnForTimestamp::NoData(lsn) => {
+                        debug!("nodata({})", lsn);
+                    }
                 }
                 debug!("pitr_cutoff_lsn = {:?}", pitr_cutoff_lsn)
             }
@@ -2216,10 +3110,17 @@ impl LayeredTimeline {
         // We need to ensure that no one branches at a point before latest_gc_cutoff_lsn.
         // See branch_timeline() for details.
         *self.latest_gc_cutoff_lsn.write().unwrap() = new_gc_cutoff;
+        self.update_size_gauges(new_gc_cutoff);
+
+        // Read after moving the cutoff, see lease_gc_lsn()
+        let leased_lsns = self.gc_leases.lock().unwrap().clone();
 
         info!("GC starting");
 
-        debug!("retain_lsns: {:?}", retain_lsns);
+        debug!(
+            "retain_lsns: {:?}, leased_lsns: {:?}",
+            retain_lsns, leased_lsns
+        );
 
         let mut layers_to_remove = Vec::new();
 
@@ -2272,7 +3173,7 @@ impl LayeredTimeline {
             // might be referenced by child branches forever.
             // We can track this in child timeline GC and delete parent layers when
             // they are no longer needed. This might be complicated with long inheritance chains.
-            for retain_lsn in retain_lsns {
+            for retain_lsn in retain_lsns.iter().chain(leased_lsns.iter()) {
                 // start_lsn is inclusive
                 if &l.get_lsn_range().start <= retain_lsn {
                     debug!(
@@ -2423,6 +3324,12 @@ impl LayeredTimeline {
     }
 }
 
+/// Helper function for get_reconstruct_data(), to remember how much data had been
+/// collected before searching a layer, for the trace.
+fn traced_state(state: &ValueReconstructState) -> (usize, Option<Lsn>) {
+    (state.records.len(), state.img.as_ref().map(|(lsn, _)| *lsn))
+}
+
 /// Helper function for get_reconstruct_data() to add the path of layers traversed
 /// to an error, as anyhow context information.
 fn layer_traversal_error(
@@ -2471,6 +3378,10 @@ impl<'a> TimelineWriter<'_> for LayeredTimelineWriter<'a> {
         self.tl.put_tombstone(key_range, lsn)
     }
 
+    fn update_current_logical_size(&self, delta: isize) {
+        self.tl.update_current_logical_size(delta)
+    }
+
     ///
     /// Remember the (end of) last valid WAL record remembered in the timeline.
     ///
@@ -2498,6 +3409,21 @@ pub fn dump_layerfile_from_path(path: &Path, verbose: bool) -> Result<()> {
     Ok(())
 }
 
+/// Check a layer file for consistency, see [`ImageLayer::validate`] and [`DeltaLayer::validate`]
+pub fn validate_layerfile_from_path(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let file = File::open(path)?;
+    let mut header_buf = [0u8; 2];
+    file.read_exact_at(&mut header_buf, 0)?;
+
+    match u16::from_be_bytes(header_buf) {
+        crate::IMAGE_FILE_MAGIC => ImageLayer::new_for_path(path, file)?.validate(),
+        crate::DELTA_FILE_MAGIC => DeltaLayer::new_for_path(path, file)?.validate(),
+        magic => bail!("unrecognized magic identifier: {:?}", magic),
+    }
+}
+
 /// Add a suffix to a layer file's name: .{num}.old
 /// Uses the first available num (starts at 0)
 fn rename_to_backup(path: PathBuf) -> anyhow::Result<()> {
@@ -2550,6 +3476,7 @@ pub mod tests {
     use super::*;
     use crate::keyspace::KeySpaceAccum;
     use crate::repository::repo_harness::*;
+    use crate::storage_sync::index::RemoteTimeline;
     use rand::{thread_rng, Rng};
 
     #[test]
@@ -2590,6 +3517,49 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn corrupt_layer_file() -> Result<()> {
+        const TEST_NAME: &str = "corrupt_layer_file";
+        let harness = RepoHarness::create(TEST_NAME)?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        {
+            let repo = harness.load();
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+            let writer = tline.writer();
+            writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+            writer.finish_write(Lsn(0x10));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+
+        let mut num_layer_files = 0;
+        for entry in fs::read_dir(harness.timeline_path(&TIMELINE_ID))? {
+            let path = entry?.path();
+            if !path.to_string_lossy().contains("__") {
+                continue;
+            }
+            // Flip a bit in the first blob, right after the summary block
+            let mut layer_bytes = fs::read(&path)?;
+            layer_bytes[page_cache::PAGE_SZ + 8] ^= 1;
+            fs::write(&path, layer_bytes)?;
+            num_layer_files += 1;
+        }
+        assert!(num_layer_files > 0, "Checkpoint should create layers");
+
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        let err = tline
+            .get(TEST_KEY, Lsn(0x10))
+            .expect_err("should fail to read a corrupt layer");
+        assert!(
+            is_layer_corruption_error(&err),
+            "expected a layer corruption error, got {err:?}"
+        );
+
+        Ok(())
+    }
+
     // Target file size in the unit tests. In production, the target
     // file size is much larger, maybe 1 GB. But a small size makes it
     // much faster to exercise all the logic for creating the files,
@@ -2836,6 +3806,72 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_range() -> Result<()> {
+        let repo = RepoHarness::create("test_get_range")?.load();
+        let mut tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        const NUM_KEYS: usize = 1000;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let key_range = test_key..test_key.add(NUM_KEYS as u32);
+
+        // Track when each page was last modified. Used to assert that
+        // a read sees the latest page version.
+        let mut updated = [Lsn(0); NUM_KEYS];
+
+        let mut lsn = Lsn(0);
+        #[allow(clippy::needless_range_loop)]
+        for blknum in 0..NUM_KEYS {
+            lsn = Lsn(lsn.0 + 0x10);
+            test_key.field6 = blknum as u32;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+            )?;
+            writer.finish_write(lsn);
+            updated[blknum] = lsn;
+        }
+
+        // Update random pages on a chain of branches, with some of the data in
+        // in-memory layers and some in layer files, and read all of them back
+        // in one call.
+        let mut tline_id = TIMELINE_ID;
+        for i in 0..10 {
+            let new_tline_id = ZTimelineId::generate();
+            repo.branch_timeline(tline_id, new_tline_id, lsn)?;
+            tline = repo.get_timeline_load(new_tline_id)?;
+            tline_id = new_tline_id;
+
+            for _ in 0..NUM_KEYS / 10 {
+                lsn = Lsn(lsn.0 + 0x10);
+                let blknum = thread_rng().gen_range(0..NUM_KEYS);
+                test_key.field6 = blknum as u32;
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                )?;
+                writer.finish_write(lsn);
+                updated[blknum] = lsn;
+            }
+            if i % 2 == 0 {
+                tline.checkpoint(CheckpointConfig::Forced)?;
+            }
+
+            let values = tline.get_range(key_range.clone(), lsn)?;
+            assert_eq!(values.len(), NUM_KEYS);
+            for (blknum, (value, last_lsn)) in values.iter().zip(updated.iter()).enumerate() {
+                assert_eq!(value, &TEST_IMG(&format!("{} at {}", blknum, last_lsn)));
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_traverse_ancestors() -> Result<()> {
         let repo = RepoHarness::create("test_traverse_ancestors")?.load();
@@ -2892,4 +3928,132 @@ pub mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_layer_eviction() -> Result<()> {
+        let repo = RepoHarness::create("test_layer_eviction")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let writer = tline.writer();
+            writer.put(
+                TEST_KEY,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+
+        let layer_paths = tline
+            .layers
+            .read()
+            .unwrap()
+            .iter_historic_layers()
+            .filter_map(|layer| layer.local_path())
+            .collect::<HashSet<_>>();
+        assert!(!layer_paths.is_empty(), "Checkpoints should create layers");
+
+        repo.eviction_iteration()?;
+        assert!(
+            layer_paths.iter().all(|path| path.exists()),
+            "No layers should be evicted without the local layers size limit"
+        );
+
+        repo.update_tenant_config(TenantConfOpt {
+            max_local_layers_size: Some(1),
+            ..TenantConfOpt::default()
+        })?;
+        repo.eviction_iteration()?;
+        assert!(
+            layer_paths.iter().all(|path| path.exists()),
+            "Layers that are not uploaded should not be evicted"
+        );
+
+        let mut remote_timeline =
+            RemoteTimeline::new(load_metadata(repo.conf, TIMELINE_ID, repo.tenant_id)?);
+        remote_timeline.add_timeline_layers(layer_paths.iter().cloned());
+        futures::executor::block_on(repo.remote_index.write()).add_timeline_entry(
+            ZTenantTimelineId::new(repo.tenant_id, TIMELINE_ID),
+            remote_timeline,
+        );
+
+        repo.eviction_iteration()?;
+        assert!(
+            layer_paths.iter().all(|path| !path.exists()),
+            "Uploaded layers should be evicted when over the local layers size limit"
+        );
+
+        let layers = tline.layers.read().unwrap();
+        assert_eq!(
+            layers
+                .iter_historic_layers()
+                .filter_map(|layer| layer.local_path())
+                .collect::<HashSet<_>>(),
+            layer_paths,
+            "Evicted layers should stay in the layer map"
+        );
+        assert!(
+            layers
+                .iter_historic_layers()
+                .all(|layer| layer.last_access_time().is_none()),
+            "Evicted layers should not be counted as local ones"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_lease() -> Result<()> {
+        let repo = RepoHarness::create("test_gc_lease")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30), Lsn(0x40)] {
+            let writer = tline.writer();
+            writer.put(
+                TEST_KEY,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+
+        let gc_lease = tline.lease_gc_lsn(Lsn(0x20))?;
+        let leased_layer_paths = tline
+            .layers
+            .read()
+            .unwrap()
+            .iter_historic_layers()
+            .filter(|layer| layer.get_lsn_range().start <= Lsn(0x20))
+            .filter_map(|layer| layer.local_path())
+            .collect::<Vec<_>>();
+        assert!(!leased_layer_paths.is_empty());
+
+        // The GC cutoff moves past the leased LSN, but its history is kept
+        repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+        assert!(*tline.get_latest_gc_cutoff_lsn() > Lsn(0x20));
+        assert!(leased_layer_paths.iter().all(|path| path.exists()));
+        assert_eq!(
+            tline.get(TEST_KEY, Lsn(0x20))?,
+            TEST_IMG(&format!("foo at {}", Lsn(0x20)))
+        );
+        drop(gc_lease);
+        assert!(tline.gc_leases.lock().unwrap().is_empty());
+
+        // The history before the cutoff cannot be leased anymore
+        assert!(tline.lease_gc_lsn(Lsn(0x20)).is_err());
+
+        Ok(())
+    }
 }
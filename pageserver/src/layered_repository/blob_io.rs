@@ -9,13 +9,32 @@
 //! by peeking at the first byte.
 //!
 //! len <  128: 0XXXXXXX
-//! len >= 128: 1XXXXXXX XXXXXXXX XXXXXXXX XXXXXXXX
+//! len >= 128: 1CXXXXXX XXXXXXXX XXXXXXXX XXXXXXXX
 //!
-use crate::layered_repository::block_io::{BlockCursor, BlockReader};
+//! The second highest bit of the four-byte header (C) tells whether the
+//! data is compressed with zstd, in which case the length is the length of
+//! the compressed data. Short blobs are never compressed. Blobs written
+//! before compression was introduced never have that bit set, because the
+//! length of a blob is limited to 1 GB.
+//!
+//! In layer files written with storage format version 5 or newer, the data
+//! is followed by a 4-byte CRC32C checksum of it, in big-endian. The checksum
+//! is not included in the length.
+//!
+use crate::config::LayerCompression;
+use crate::layered_repository::block_io::{
+    BlockCursor, BlockReader, LayerCorruptionError, CHECKSUM_SZ,
+};
 use crate::page_cache::PAGE_SZ;
 use std::cmp::min;
 use std::io::{Error, ErrorKind};
 
+/// Max length of a blob, longer blobs would clash with the compression flag
+pub const MAX_BLOB_LEN: usize = 0x3fff_ffff;
+
+/// Flag in the first byte of a 4-byte length header, set for compressed blobs
+const COMPRESSED_FLAG: u8 = 0x40;
+
 /// For reading
 pub trait BlobCursor {
     /// Read a blob into a new buffer.
@@ -43,6 +62,7 @@ where
         offset: u64,
         dstbuf: &mut Vec<u8>,
     ) -> Result<(), std::io::Error> {
+        let has_checksum = self.has_blob_checksums();
         let mut blknum = (offset / PAGE_SZ as u64) as u32;
         let mut off = (offset % PAGE_SZ as u64) as usize;
 
@@ -50,6 +70,7 @@ where
 
         // peek at the first byte, to determine if it's a 1- or 4-byte length
         let first_len_byte = buf[off];
+        let compressed = first_len_byte >= 0x80 && first_len_byte & COMPRESSED_FLAG != 0;
         let len: usize = if first_len_byte < 0x80 {
             // 1-byte length header
             off += 1;
@@ -69,14 +90,22 @@ where
                 len_buf.copy_from_slice(&buf[off..off + 4]);
                 off += 4;
             }
-            len_buf[0] &= 0x7f;
+            len_buf[0] &= 0x3f;
             u32::from_be_bytes(len_buf) as usize
         };
 
-        dstbuf.clear();
+        // Compressed data is read into a temporary buffer and decompressed
+        // into the destination buffer afterwards
+        let mut compressed_buf = Vec::new();
+        let readbuf = if compressed {
+            &mut compressed_buf
+        } else {
+            &mut *dstbuf
+        };
+        readbuf.clear();
 
-        // Read the payload
-        let mut remain = len;
+        // Read the payload, and the checksum after it
+        let mut remain = if has_checksum { len + CHECKSUM_SZ } else { len };
         while remain > 0 {
             let mut page_remain = PAGE_SZ - off;
             if page_remain == 0 {
@@ -87,10 +116,26 @@ where
                 page_remain = PAGE_SZ;
             }
             let this_blk_len = min(remain, page_remain);
-            dstbuf.extend_from_slice(&buf[off..off + this_blk_len]);
+            readbuf.extend_from_slice(&buf[off..off + this_blk_len]);
             remain -= this_blk_len;
             off += this_blk_len;
         }
+
+        if has_checksum {
+            let expected = u32::from_be_bytes(readbuf[len..].try_into().unwrap());
+            readbuf.truncate(len);
+            let actual = crc32c::crc32c(readbuf.as_slice());
+            if actual != expected {
+                return Err(LayerCorruptionError::io_error(format!(
+                    "checksum mismatch in blob at offset {offset}, expected {expected:08X}, got {actual:08X}"
+                )));
+            }
+        }
+
+        if compressed {
+            dstbuf.clear();
+            zstd::stream::copy_decode(compressed_buf.as_slice(), &mut *dstbuf)?;
+        }
         Ok(())
     }
 }
@@ -114,16 +159,18 @@ where
 {
     inner: W,
     offset: u64,
+    compression: LayerCompression,
 }
 
 impl<W> WriteBlobWriter<W>
 where
     W: std::io::Write,
 {
-    pub fn new(inner: W, start_offset: u64) -> Self {
+    pub fn new(inner: W, start_offset: u64, compression: LayerCompression) -> Self {
         WriteBlobWriter {
             inner,
             offset: start_offset,
+            compression,
         }
     }
 
@@ -139,6 +186,14 @@ where
     pub fn into_inner(self) -> W {
         self.inner
     }
+
+    /// Write the data of a blob, followed by its checksum
+    fn write_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.inner.write_all(data)?;
+        self.inner.write_all(&crc32c::crc32c(data).to_be_bytes())?;
+        self.offset += (data.len() + CHECKSUM_SZ) as u64;
+        Ok(())
+    }
 }
 
 impl<W> BlobWriter for WriteBlobWriter<W>
@@ -153,21 +208,115 @@ where
             let len_buf = srcbuf.len() as u8;
             self.inner.write_all(&[len_buf])?;
             self.offset += 1;
-        } else {
-            // Write a 4-byte length header
-            if srcbuf.len() > 0x7fff_ffff {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("blob too large ({} bytes)", srcbuf.len()),
-                ));
-            }
-            let mut len_buf = ((srcbuf.len()) as u32).to_be_bytes();
-            len_buf[0] |= 0x80;
-            self.inner.write_all(&len_buf)?;
-            self.offset += 4;
+            self.write_data(srcbuf)?;
+            return Ok(offset);
+        }
+
+        if srcbuf.len() > MAX_BLOB_LEN {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("blob too large ({} bytes)", srcbuf.len()),
+            ));
         }
-        self.inner.write_all(srcbuf)?;
-        self.offset += srcbuf.len() as u64;
+
+        // Keep the compressed version only if it's actually smaller
+        let compressed = match self.compression {
+            LayerCompression::None => None,
+            LayerCompression::Zstd => Some(zstd::bulk::compress(
+                srcbuf,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+        }
+        .filter(|compressed| compressed.len() < srcbuf.len());
+
+        // Write a 4-byte length header
+        let (data, flags) = match &compressed {
+            Some(compressed) => (compressed.as_slice(), 0x80 | COMPRESSED_FLAG),
+            None => (srcbuf, 0x80),
+        };
+        let mut len_buf = (data.len() as u32).to_be_bytes();
+        len_buf[0] |= flags;
+        self.inner.write_all(&len_buf)?;
+        self.offset += 4;
+
+        self.write_data(data)?;
         Ok(offset)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layered_repository::block_io::is_layer_corruption_error;
+    use rand::{thread_rng, RngCore};
+
+    /// BlockReader over an in-memory buffer
+    struct BufBlockReader(Vec<u8>);
+
+    impl BlockReader for BufBlockReader {
+        type BlockLease = Box<[u8; PAGE_SZ]>;
+
+        fn read_blk(&self, blknum: u32) -> Result<Self::BlockLease, std::io::Error> {
+            let mut buf = Box::new([0u8; PAGE_SZ]);
+            let start = (blknum as usize * PAGE_SZ).min(self.0.len());
+            let end = (start + PAGE_SZ).min(self.0.len());
+            buf[..end - start].copy_from_slice(&self.0[start..end]);
+            Ok(buf)
+        }
+
+        fn has_blob_checksums(&self) -> bool {
+            true
+        }
+    }
+
+    fn write_blobs(compression: LayerCompression, blobs: &[Vec<u8>]) -> (Vec<u8>, Vec<u64>) {
+        let mut writer = WriteBlobWriter::new(Vec::new(), 0, compression);
+        let offsets = blobs
+            .iter()
+            .map(|blob| writer.write_blob(blob).unwrap())
+            .collect();
+        (writer.into_inner(), offsets)
+    }
+
+    #[test]
+    fn test_blob_compression() -> Result<(), Error> {
+        let mut random_data = vec![0u8; 20000];
+        thread_rng().fill_bytes(&mut random_data);
+        let blobs = vec![
+            b"foo".to_vec(),
+            b"page".repeat(2048),
+            random_data,
+            Vec::new(),
+            b"blob".repeat(100),
+        ];
+
+        let (uncompressed, _) = write_blobs(LayerCompression::None, &blobs);
+        let (compressed, offsets) = write_blobs(LayerCompression::Zstd, &blobs);
+        assert!(compressed.len() < uncompressed.len());
+
+        let mut cursor = BlockCursor::new(BufBlockReader(compressed));
+        for (offset, expected) in offsets.into_iter().zip(blobs.iter()) {
+            assert_eq!(&cursor.read_blob(offset)?, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_checksum() -> Result<(), Error> {
+        let blobs = vec![b"foo".to_vec(), b"bar".repeat(100)];
+        let (mut data, offsets) = write_blobs(LayerCompression::None, &blobs);
+
+        // Corrupt a byte in the data of the second blob
+        data[offsets[1] as usize + 10] ^= 1;
+
+        let mut cursor = BlockCursor::new(BufBlockReader(data));
+        assert_eq!(cursor.read_blob(offsets[0])?, blobs[0]);
+        let err = cursor
+            .read_blob(offsets[1])
+            .expect_err("should fail on a checksum mismatch");
+        assert!(is_layer_corruption_error(&anyhow::Error::new(err)));
+
+        Ok(())
+    }
+}
@@ -9,6 +9,49 @@ use lazy_static::lazy_static;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::fs::FileExt;
 use std::sync::atomic::AtomicU64;
+use thiserror::Error;
+
+/// Size of the CRC32C checksums stored at the end of checksummed blocks,
+/// and after the blobs in layer files
+pub const CHECKSUM_SZ: usize = 4;
+
+/// Data read from a layer file doesn't match its checksum.
+///
+/// The block and blob reading functions return it wrapped into an I/O error,
+/// use [`is_layer_corruption_error`] to find it in an error chain.
+#[derive(Debug, Error)]
+#[error("layer file is corrupt: {0}")]
+pub struct LayerCorruptionError(String);
+
+impl LayerCorruptionError {
+    pub fn io_error(msg: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, LayerCorruptionError(msg))
+    }
+}
+
+/// Check if the error was caused by a checksum mismatch in a layer file.
+pub fn is_layer_corruption_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<LayerCorruptionError>()
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|io_error| io_error.get_ref())
+                .map_or(false, |inner| inner.is::<LayerCorruptionError>())
+    })
+}
+
+/// Verify the CRC32C checksum, stored in the last bytes of a block.
+pub fn verify_block_checksum(buf: &[u8], blknum: u32) -> Result<(), std::io::Error> {
+    let (data, checksum) = buf.split_at(buf.len() - CHECKSUM_SZ);
+    let expected = u32::from_be_bytes(checksum.try_into().unwrap());
+    let actual = crc32c::crc32c(data);
+    if actual != expected {
+        return Err(LayerCorruptionError::io_error(format!(
+            "checksum mismatch in block {blknum}, expected {expected:08X}, got {actual:08X}"
+        )));
+    }
+    Ok(())
+}
 
 /// This is implemented by anything that can read 8 kB (PAGE_SZ)
 /// blocks, using the page cache
@@ -25,6 +68,12 @@ pub trait BlockReader {
     ///
     fn read_blk(&self, blknum: u32) -> Result<Self::BlockLease, std::io::Error>;
 
+    /// Returns true if each blob read through this reader is followed by
+    /// a checksum of its data.
+    fn has_blob_checksums(&self) -> bool {
+        false
+    }
+
     ///
     /// Create a new "cursor" for reading from this reader.
     ///
@@ -47,6 +96,10 @@ where
     fn read_blk(&self, blknum: u32) -> Result<Self::BlockLease, std::io::Error> {
         (*self).read_blk(blknum)
     }
+
+    fn has_blob_checksums(&self) -> bool {
+        (*self).has_blob_checksums()
+    }
 }
 
 ///
@@ -104,6 +157,10 @@ where
 
         Ok(self)
     }
+
+    pub fn has_blob_checksums(&self) -> bool {
+        self.reader.has_blob_checksums()
+    }
 }
 
 impl<R> Deref for BlockCursor<R>
@@ -130,6 +187,10 @@ pub struct FileBlockReader<F> {
 
     /// Unique ID of this file, used as key in the page cache.
     file_id: u64,
+
+    /// Blocks starting from this one end with a checksum, verified when the
+    /// block is read from the file. None for files without checksums.
+    checksummed_blocks_start: Option<u32>,
 }
 
 impl<F> FileBlockReader<F>
@@ -139,7 +200,19 @@ where
     pub fn new(file: F) -> Self {
         let file_id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        FileBlockReader { file_id, file }
+        FileBlockReader {
+            file_id,
+            file,
+            checksummed_blocks_start: None,
+        }
+    }
+
+    /// Enable verification of the checksums, for files that have them.
+    ///
+    /// Blocks from 'checksummed_blocks_start' onwards are checked to end with a
+    /// checksum, and blobs are expected to be followed by their checksum.
+    pub fn enable_checksums(&mut self, checksummed_blocks_start: u32) {
+        self.checksummed_blocks_start = Some(checksummed_blocks_start);
     }
 
     /// Read a page from the underlying file into given buffer.
@@ -164,6 +237,9 @@ where
                 ReadBufResult::NotFound(mut write_guard) => {
                     // Read the page from disk into the buffer
                     self.fill_buffer(write_guard.deref_mut(), blknum)?;
+                    if matches!(self.checksummed_blocks_start, Some(start) if blknum >= start) {
+                        verify_block_checksum(&*write_guard, blknum)?;
+                    }
                     write_guard.mark_valid();
 
                     // Swap for read lock
@@ -172,6 +248,10 @@ where
             };
         }
     }
+
+    fn has_blob_checksums(&self) -> bool {
+        self.checksummed_blocks_start.is_some()
+    }
 }
 
 ///
@@ -29,13 +29,16 @@ use crate::layered_repository::block_io::{BlockBuf, BlockCursor, BlockReader, Fi
 use crate::layered_repository::disk_btree::{DiskBtreeBuilder, DiskBtreeReader, VisitDirection};
 use crate::layered_repository::filename::{DeltaFileName, PathOrConf};
 use crate::layered_repository::storage_layer::{
-    Layer, ValueReconstructResult, ValueReconstructState,
+    Layer, LayerAccessTime, ValueReconstructResult, ValueReconstructState,
 };
 use crate::page_cache::{PageReadGuard, PAGE_SZ};
 use crate::repository::{Key, Value, KEY_SIZE};
 use crate::virtual_file::VirtualFile;
 use crate::walrecord;
-use crate::{DELTA_FILE_MAGIC, STORAGE_FORMAT_VERSION};
+use crate::{
+    CHECKSUMS_STORAGE_FORMAT_VERSION, DELTA_FILE_MAGIC, MIN_SUPPORTED_STORAGE_FORMAT_VERSION,
+    STORAGE_FORMAT_VERSION,
+};
 use anyhow::{bail, ensure, Context, Result};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
@@ -46,6 +49,7 @@ use std::ops::Range;
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
 use tracing::*;
 
 use utils::{
@@ -181,6 +185,8 @@ pub struct DeltaLayer {
     pub lsn_range: Range<Lsn>,
 
     inner: RwLock<DeltaLayerInner>,
+
+    last_access: LayerAccessTime,
 }
 
 pub struct DeltaLayerInner {
@@ -330,6 +336,10 @@ impl Layer for DeltaLayer {
         false
     }
 
+    fn last_access_time(&self) -> Option<Instant> {
+        Some(self.last_access.get())
+    }
+
     /// debugging function to print out the contents of the layer
     fn dump(&self, verbose: bool) -> Result<()> {
         println!(
@@ -448,6 +458,7 @@ impl DeltaLayer {
     /// not loaded already.
     ///
     fn load(&self) -> Result<RwLockReadGuard<DeltaLayerInner>> {
+        self.last_access.record_access();
         loop {
             // Quick exit if already loaded
             let inner = self.inner.read().unwrap();
@@ -488,10 +499,17 @@ impl DeltaLayer {
         let file = inner.file.as_mut().unwrap();
         let summary_blk = file.read_blk(0)?;
         let actual_summary = Summary::des_prefix(summary_blk.as_ref())?;
+        ensure!(
+            (MIN_SUPPORTED_STORAGE_FORMAT_VERSION..=STORAGE_FORMAT_VERSION)
+                .contains(&actual_summary.format_version),
+            "unsupported format version {} in layer file",
+            actual_summary.format_version
+        );
 
         match &self.path_or_conf {
             PathOrConf::Conf(_) => {
                 let mut expected_summary = Summary::from(self);
+                expected_summary.format_version = actual_summary.format_version;
                 expected_summary.index_start_blk = actual_summary.index_start_blk;
                 expected_summary.index_root_blk = actual_summary.index_root_blk;
                 if actual_summary != expected_summary {
@@ -512,6 +530,10 @@ impl DeltaLayer {
             }
         }
 
+        if actual_summary.format_version >= CHECKSUMS_STORAGE_FORMAT_VERSION {
+            file.enable_checksums(actual_summary.index_start_blk);
+        }
+
         inner.index_start_blk = actual_summary.index_start_blk;
         inner.index_root_blk = actual_summary.index_root_blk;
 
@@ -534,6 +556,7 @@ impl DeltaLayer {
             tenantid,
             key_range: filename.key_range.clone(),
             lsn_range: filename.lsn_range.clone(),
+            last_access: LayerAccessTime::default(),
             inner: RwLock::new(DeltaLayerInner {
                 loaded: false,
                 file: None,
@@ -561,6 +584,7 @@ impl DeltaLayer {
             tenantid: summary.tenantid,
             key_range: summary.key_range,
             lsn_range: summary.lsn_range,
+            last_access: LayerAccessTime::default(),
             inner: RwLock::new(DeltaLayerInner {
                 loaded: false,
                 file: None,
@@ -586,6 +610,78 @@ impl DeltaLayer {
             &self.layer_name(),
         )
     }
+
+    ///
+    /// Check the layer file for consistency.
+    ///
+    /// Verifies that the file name matches the summary, that the index keys are
+    /// in order and within the key and LSN ranges of the layer, and that all the
+    /// values can be read and deserialized. Used by the scrub tool.
+    ///
+    pub fn validate(&self) -> Result<()> {
+        let path = self.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        ensure!(
+            DeltaFileName::parse_str(&file_name) == Some(self.layer_name()),
+            "file name does not match the layer summary, expected {}",
+            self.layer_name()
+        );
+
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+
+        let mut entries = Vec::new();
+        tree_reader.visit(
+            &[0u8; DELTA_KEY_SIZE],
+            VisitDirection::Forwards,
+            |delta_key, val| {
+                entries.push((DeltaKey::from_slice(delta_key), BlobRef(val)));
+                true
+            },
+        )?;
+
+        let index_start_offset = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let mut cursor = file.block_cursor();
+        let mut prev_delta_key: Option<&DeltaKey> = None;
+        for (delta_key, blob_ref) in &entries {
+            let key = delta_key.key();
+            let lsn = delta_key.lsn();
+            ensure!(
+                prev_delta_key.map_or(true, |prev| prev.0 < delta_key.0),
+                "index key {key} at {lsn} is not greater than the previous key"
+            );
+            prev_delta_key = Some(delta_key);
+            ensure!(
+                self.key_range.contains(&key),
+                "key {key} is outside of the layer key range"
+            );
+            ensure!(
+                self.lsn_range.contains(&lsn),
+                "LSN {lsn} of key {key} is outside of the layer LSN range"
+            );
+            ensure!(
+                (PAGE_SZ as u64..index_start_offset).contains(&blob_ref.pos()),
+                "value for key {key} at {lsn} is outside of the values part, offset {}",
+                blob_ref.pos()
+            );
+            let buf = cursor
+                .read_blob(blob_ref.pos())
+                .with_context(|| format!("failed to read value for key {key} at {lsn}"))?;
+            let val = Value::des(&buf)
+                .with_context(|| format!("failed to deserialize value for key {key} at {lsn}"))?;
+            ensure!(
+                val.will_init() == blob_ref.will_init(),
+                "will_init flag of value for key {key} at {lsn} does not match the index"
+            );
+        }
+
+        Ok(())
+    }
 }
 
 /// A builder object for constructing a new delta layer.
@@ -636,7 +732,7 @@ impl DeltaLayerWriter {
         // make room for the header block
         file.seek(SeekFrom::Start(PAGE_SZ as u64))?;
         let buf_writer = BufWriter::new(file);
-        let blob_writer = WriteBlobWriter::new(buf_writer, PAGE_SZ as u64);
+        let blob_writer = WriteBlobWriter::new(buf_writer, PAGE_SZ as u64, conf.layer_compression);
 
         // Initialize the b-tree index builder
         let block_buf = BlockBuf::new();
@@ -716,6 +812,7 @@ impl DeltaLayerWriter {
             timelineid: self.timelineid,
             key_range: self.key_start..key_end,
             lsn_range: self.lsn_range.clone(),
+            last_access: LayerAccessTime::default(),
             inner: RwLock::new(DeltaLayerInner {
                 loaded: false,
                 file: None,
@@ -768,6 +865,10 @@ impl<'a> BlockReader for Adapter<'a> {
     fn read_blk(&self, blknum: u32) -> Result<Self::BlockLease, std::io::Error> {
         self.0.file.as_ref().unwrap().read_blk(blknum)
     }
+
+    fn has_blob_checksums(&self) -> bool {
+        self.0.file.as_ref().unwrap().has_blob_checksums()
+    }
 }
 
 impl<'a> Iterator for DeltaValueIter<'a> {
@@ -9,6 +9,8 @@
 //! - The tree is created in a bulk operation. Insert/deletion after creation
 //!   is not suppported
 //! - page-oriented
+//! - each page ends with a CRC32C checksum of its contents. The checksum is
+//!   verified by the BlockReader, if the file has checksums
 //!
 //! TODO:
 //! - maybe something like an Adaptive Radix Tree would be more efficient?
@@ -25,7 +27,7 @@ use std::{cmp::Ordering, io, result};
 use thiserror::Error;
 use tracing::error;
 
-use crate::layered_repository::block_io::{BlockReader, BlockWriter};
+use crate::layered_repository::block_io::{BlockReader, BlockWriter, CHECKSUM_SZ};
 
 // The maximum size of a value stored in the B-tree. 5 bytes is enough currently.
 pub const VALUE_SZ: usize = 5;
@@ -587,7 +589,8 @@ struct BuildNode<const L: usize> {
     size: usize, // physical size of this node, if it was written to disk like this
 }
 
-const NODE_SIZE: usize = PAGE_SZ;
+// Leave room for the checksum at the end of the page
+const NODE_SIZE: usize = PAGE_SZ - CHECKSUM_SZ;
 
 const NODE_HDR_SIZE: usize = 2 + 1 + 1 + 1;
 
@@ -700,8 +703,10 @@ impl<const L: usize> BuildNode<L> {
 
         assert!(buf.len() == self.size);
 
-        assert!(buf.len() <= PAGE_SZ);
-        buf.resize(PAGE_SZ, 0);
+        assert!(buf.len() <= NODE_SIZE);
+        buf.resize(NODE_SIZE, 0);
+        let checksum = crc32c::crc32c(&buf);
+        buf.put_u32(checksum);
         buf.freeze()
     }
 
@@ -724,6 +729,7 @@ impl<const L: usize> BuildNode<L> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::layered_repository::block_io::verify_block_checksum;
     use rand::Rng;
     use std::collections::BTreeMap;
     use std::sync::atomic::{AtomicUsize, Ordering};
@@ -743,6 +749,7 @@ mod tests {
         fn read_blk(&self, blknum: u32) -> io::Result<Self::BlockLease> {
             let mut buf = [0u8; PAGE_SZ];
             buf.copy_from_slice(&self.blocks[blknum as usize]);
+            verify_block_checksum(&buf, blknum)?;
             Ok(std::rc::Rc::new(buf))
         }
     }
@@ -1026,6 +1033,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn corrupted_node() -> Result<()> {
+        let mut disk = TestDisk::new();
+        let mut writer = DiskBtreeBuilder::<_, 6>::new(&mut disk);
+        writer.append(b"xaaaaa", 1)?;
+        writer.append(b"xaaaba", 2)?;
+        let (root_offset, _writer) = writer.finish()?;
+
+        let mut block = disk.blocks[root_offset as usize].to_vec();
+        block[NODE_HDR_SIZE] ^= 1;
+        disk.blocks[root_offset as usize] = Bytes::from(block);
+
+        let reader = DiskBtreeReader::new(0, root_offset, disk);
+        match reader.get(b"xaaaaa") {
+            Err(DiskBtreeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            other => panic!("expected a checksum error, got {other:?}"),
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
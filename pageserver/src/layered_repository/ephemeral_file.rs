@@ -2,7 +2,7 @@
 //! used to keep in-memory layers spilled on disk.
 
 use crate::config::PageServerConf;
-use crate::layered_repository::blob_io::BlobWriter;
+use crate::layered_repository::blob_io::{BlobWriter, MAX_BLOB_LEN};
 use crate::layered_repository::block_io::BlockReader;
 use crate::page_cache;
 use crate::page_cache::PAGE_SZ;
@@ -191,6 +191,13 @@ impl FileExt for EphemeralFile {
 
 impl BlobWriter for EphemeralFile {
     fn write_blob(&mut self, srcbuf: &[u8]) -> Result<u64, Error> {
+        if srcbuf.len() > MAX_BLOB_LEN {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("blob too large ({} bytes)", srcbuf.len()),
+            ));
+        }
+
         let pos = self.size;
 
         let mut blknum = (self.size / PAGE_SZ as u64) as u32;
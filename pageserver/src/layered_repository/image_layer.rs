@@ -25,12 +25,15 @@ use crate::layered_repository::block_io::{BlockBuf, BlockReader, FileBlockReader
 use crate::layered_repository::disk_btree::{DiskBtreeBuilder, DiskBtreeReader, VisitDirection};
 use crate::layered_repository::filename::{ImageFileName, PathOrConf};
 use crate::layered_repository::storage_layer::{
-    Layer, ValueReconstructResult, ValueReconstructState,
+    Layer, LayerAccessTime, ValueReconstructResult, ValueReconstructState,
 };
 use crate::page_cache::PAGE_SZ;
 use crate::repository::{Key, Value, KEY_SIZE};
 use crate::virtual_file::VirtualFile;
-use crate::{IMAGE_FILE_MAGIC, STORAGE_FORMAT_VERSION};
+use crate::{
+    CHECKSUMS_STORAGE_FORMAT_VERSION, IMAGE_FILE_MAGIC, MIN_SUPPORTED_STORAGE_FORMAT_VERSION,
+    STORAGE_FORMAT_VERSION,
+};
 use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
 use hex;
@@ -42,6 +45,7 @@ use std::io::{Seek, SeekFrom};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::{RwLock, RwLockReadGuard};
+use std::time::Instant;
 use tracing::*;
 
 use utils::{
@@ -107,6 +111,8 @@ pub struct ImageLayer {
     pub lsn: Lsn,
 
     inner: RwLock<ImageLayerInner>,
+
+    last_access: LayerAccessTime,
 }
 
 pub struct ImageLayerInner {
@@ -200,6 +206,10 @@ impl Layer for ImageLayer {
         false
     }
 
+    fn last_access_time(&self) -> Option<Instant> {
+        Some(self.last_access.get())
+    }
+
     /// debugging function to print out the contents of the layer
     fn dump(&self, verbose: bool) -> Result<()> {
         println!(
@@ -242,20 +252,14 @@ impl ImageLayer {
         }
     }
 
-    fn temp_path_for(
-        conf: &PageServerConf,
-        timelineid: ZTimelineId,
-        tenantid: ZTenantId,
-        fname: &ImageFileName,
-    ) -> PathBuf {
+    fn temp_path_for(dir: &Path, fname: &ImageFileName) -> PathBuf {
         let rand_string: String = rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(8)
             .map(char::from)
             .collect();
 
-        conf.timeline_path(&timelineid, &tenantid)
-            .join(format!("{}.{}.temp", fname, rand_string))
+        dir.join(format!("{}.{}.temp", fname, rand_string))
     }
 
     ///
@@ -263,6 +267,7 @@ impl ImageLayer {
     /// not loaded already.
     ///
     fn load(&self) -> Result<RwLockReadGuard<ImageLayerInner>> {
+        self.last_access.record_access();
         loop {
             // Quick exit if already loaded
             let inner = self.inner.read().unwrap();
@@ -304,10 +309,17 @@ impl ImageLayer {
         let file = inner.file.as_mut().unwrap();
         let summary_blk = file.read_blk(0)?;
         let actual_summary = Summary::des_prefix(summary_blk.as_ref())?;
+        ensure!(
+            (MIN_SUPPORTED_STORAGE_FORMAT_VERSION..=STORAGE_FORMAT_VERSION)
+                .contains(&actual_summary.format_version),
+            "unsupported format version {} in layer file",
+            actual_summary.format_version
+        );
 
         match &self.path_or_conf {
             PathOrConf::Conf(_) => {
                 let mut expected_summary = Summary::from(self);
+                expected_summary.format_version = actual_summary.format_version;
                 expected_summary.index_start_blk = actual_summary.index_start_blk;
                 expected_summary.index_root_blk = actual_summary.index_root_blk;
 
@@ -329,6 +341,10 @@ impl ImageLayer {
             }
         }
 
+        if actual_summary.format_version >= CHECKSUMS_STORAGE_FORMAT_VERSION {
+            file.enable_checksums(actual_summary.index_start_blk);
+        }
+
         inner.index_start_blk = actual_summary.index_start_blk;
         inner.index_root_blk = actual_summary.index_root_blk;
         inner.loaded = true;
@@ -348,6 +364,7 @@ impl ImageLayer {
             tenantid,
             key_range: filename.key_range.clone(),
             lsn: filename.lsn,
+            last_access: LayerAccessTime::default(),
             inner: RwLock::new(ImageLayerInner {
                 loaded: false,
                 file: None,
@@ -375,6 +392,7 @@ impl ImageLayer {
             tenantid: summary.tenantid,
             key_range: summary.key_range,
             lsn: summary.lsn,
+            last_access: LayerAccessTime::default(),
             inner: RwLock::new(ImageLayerInner {
                 file: None,
                 loaded: false,
@@ -400,6 +418,58 @@ impl ImageLayer {
             &self.layer_name(),
         )
     }
+
+    ///
+    /// Check the layer file for consistency.
+    ///
+    /// Verifies that the file name matches the summary, that the index keys are
+    /// in order and within the key range of the layer, and that all the images
+    /// can be read. Used by the scrub tool.
+    ///
+    pub fn validate(&self) -> Result<()> {
+        let path = self.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        ensure!(
+            ImageFileName::parse_str(&file_name) == Some(self.layer_name()),
+            "file name does not match the layer summary, expected {}",
+            self.layer_name()
+        );
+
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader =
+            DiskBtreeReader::<_, KEY_SIZE>::new(inner.index_start_blk, inner.index_root_blk, file);
+
+        let mut entries = Vec::new();
+        tree_reader.visit(&[0u8; KEY_SIZE], VisitDirection::Forwards, |key, offset| {
+            entries.push((Key::from_slice(key), offset));
+            true
+        })?;
+
+        let index_start_offset = inner.index_start_blk as u64 * PAGE_SZ as u64;
+        let mut cursor = file.block_cursor();
+        let mut prev_key = None;
+        for (key, offset) in entries {
+            ensure!(
+                prev_key < Some(key),
+                "index key {key} is not greater than the previous key"
+            );
+            prev_key = Some(key);
+            ensure!(
+                self.key_range.contains(&key),
+                "key {key} is outside of the layer key range"
+            );
+            ensure!(
+                (PAGE_SZ as u64..index_start_offset).contains(&offset),
+                "image for key {key} is outside of the values part, offset {offset}"
+            );
+            cursor
+                .read_blob(offset)
+                .with_context(|| format!("failed to read image for key {key}"))?;
+        }
+
+        Ok(())
+    }
 }
 
 /// A builder object for constructing a new image layer.
@@ -413,8 +483,11 @@ impl ImageLayer {
 ///
 /// 3. Call `finish`.
 ///
+/// The layer is created in the timeline directory, unless it was started
+/// with `new_in_dir`.
+///
 pub struct ImageLayerWriter {
-    conf: &'static PageServerConf,
+    path_or_conf: PathOrConf,
     path: PathBuf,
     timelineid: ZTimelineId,
     tenantid: ZTenantId,
@@ -433,12 +506,67 @@ impl ImageLayerWriter {
         key_range: &Range<Key>,
         lsn: Lsn,
     ) -> anyhow::Result<ImageLayerWriter> {
-        // Create the file initially with a temporary filename.
-        // We'll atomically rename it to the final name when we're done.
-        let path = ImageLayer::temp_path_for(
+        let dir = conf.timeline_path(&timelineid, &tenantid);
+        Self::create(
             conf,
+            PathOrConf::Conf(conf),
+            &dir,
             timelineid,
             tenantid,
+            key_range,
+            lsn,
+        )
+    }
+
+    ///
+    /// Like `new`, but create the layer file in the given directory instead
+    /// of the timeline directory. Such a layer is not part of any timeline,
+    /// and an existing file with the same name is not overwritten.
+    ///
+    pub fn new_in_dir(
+        conf: &'static PageServerConf,
+        dir: &Path,
+        timelineid: ZTimelineId,
+        tenantid: ZTenantId,
+        key_range: &Range<Key>,
+        lsn: Lsn,
+    ) -> anyhow::Result<ImageLayerWriter> {
+        let final_path = dir.join(
+            ImageFileName {
+                key_range: key_range.clone(),
+                lsn,
+            }
+            .to_string(),
+        );
+        ensure!(
+            !final_path.exists(),
+            "image layer {} already exists",
+            final_path.display()
+        );
+        Self::create(
+            conf,
+            PathOrConf::Path(final_path),
+            dir,
+            timelineid,
+            tenantid,
+            key_range,
+            lsn,
+        )
+    }
+
+    fn create(
+        conf: &'static PageServerConf,
+        path_or_conf: PathOrConf,
+        dir: &Path,
+        timelineid: ZTimelineId,
+        tenantid: ZTenantId,
+        key_range: &Range<Key>,
+        lsn: Lsn,
+    ) -> anyhow::Result<ImageLayerWriter> {
+        // Create the file initially with a temporary filename.
+        // We'll atomically rename it to the final name when we're done.
+        let path = ImageLayer::temp_path_for(
+            dir,
             &ImageFileName {
                 key_range: key_range.clone(),
                 lsn,
@@ -448,14 +576,14 @@ impl ImageLayerWriter {
         let mut file = VirtualFile::create(&path)?;
         // make room for the header block
         file.seek(SeekFrom::Start(PAGE_SZ as u64))?;
-        let blob_writer = WriteBlobWriter::new(file, PAGE_SZ as u64);
+        let blob_writer = WriteBlobWriter::new(file, PAGE_SZ as u64, conf.layer_compression);
 
         // Initialize the b-tree index builder
         let block_buf = BlockBuf::new();
         let tree_builder = DiskBtreeBuilder::new(block_buf);
 
         let writer = ImageLayerWriter {
-            conf,
+            path_or_conf,
             path,
             timelineid,
             tenantid,
@@ -515,11 +643,12 @@ impl ImageLayerWriter {
         // reuse the same VirtualFile for reading later. That's why we don't
         // set inner.file here. The first read will have to re-open it.
         let layer = ImageLayer {
-            path_or_conf: PathOrConf::Conf(self.conf),
+            path_or_conf: self.path_or_conf,
             timelineid: self.timelineid,
             tenantid: self.tenantid,
             key_range: self.key_range.clone(),
             lsn: self.lsn,
+            last_access: LayerAccessTime::default(),
             inner: RwLock::new(ImageLayerInner {
                 loaded: false,
                 file: None,
@@ -536,7 +665,7 @@ impl ImageLayerWriter {
         // Note: This overwrites any existing file. There shouldn't be any.
         // FIXME: throw an error instead?
         let final_path = ImageLayer::path_for(
-            &PathOrConf::Conf(self.conf),
+            &layer.path_or_conf,
             self.timelineid,
             self.tenantid,
             &ImageFileName {
@@ -29,6 +29,7 @@ use std::fmt::Write as _;
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::RwLock;
+use std::time::Instant;
 
 pub struct InMemoryLayer {
     conf: &'static PageServerConf,
@@ -51,6 +52,11 @@ pub struct InMemoryLayerInner {
     /// Writes are only allowed when this is None
     end_lsn: Option<Lsn>,
 
+    /// Logical size of the timeline at the end of a frozen layer, if it was
+    /// known when the layer was frozen. Stored in the metadata file when the
+    /// layer is flushed.
+    logical_size_at_end: Option<u64>,
+
     ///
     /// All versions of all pages in the layer are kept here.  Indexed
     /// by block number and LSN. The value is an offset into the
@@ -180,6 +186,10 @@ impl Layer for InMemoryLayer {
         true
     }
 
+    fn last_access_time(&self) -> Option<Instant> {
+        None
+    }
+
     /// debugging function to print out the contents of the layer
     fn dump(&self, verbose: bool) -> Result<()> {
         let inner = self.inner.read().unwrap();
@@ -257,6 +267,7 @@ impl InMemoryLayer {
             start_lsn,
             inner: RwLock::new(InMemoryLayerInner {
                 end_lsn: None,
+                logical_size_at_end: None,
                 index: HashMap::new(),
                 file,
             }),
@@ -292,13 +303,15 @@ impl InMemoryLayer {
     }
 
     /// Make the layer non-writeable. Only call once.
-    /// Records the end_lsn for non-dropped layers.
+    /// Records the end_lsn for non-dropped layers, and the logical size of
+    /// the timeline at that point.
     /// `end_lsn` is exclusive
-    pub fn freeze(&self, end_lsn: Lsn) {
+    pub fn freeze(&self, end_lsn: Lsn, logical_size_at_end: Option<u64>) {
         let mut inner = self.inner.write().unwrap();
 
         assert!(self.start_lsn < end_lsn);
         inner.end_lsn = Some(end_lsn);
+        inner.logical_size_at_end = logical_size_at_end;
 
         for vec_map in inner.index.values() {
             for (lsn, _pos) in vec_map.as_slice() {
@@ -307,6 +320,11 @@ impl InMemoryLayer {
         }
     }
 
+    /// Logical size of the timeline at the end of this frozen layer, if known.
+    pub fn logical_size_at_end(&self) -> Option<u64> {
+        self.inner.read().unwrap().logical_size_at_end
+    }
+
     /// Write this frozen in-memory layer to disk.
     ///
     /// Returns a new delta layer with all the same data as this in-memory layer
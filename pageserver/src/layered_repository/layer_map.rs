@@ -173,6 +173,41 @@ impl LayerMap {
         }
     }
 
+    ///
+    /// Like 'search', but for all the keys in 'key_range' at once.
+    ///
+    /// The key range is split at the boundaries of the historic layers that
+    /// overlap it. All the keys within each of the resulting sub-ranges are
+    /// covered by the same set of layers, so the search only needs to be
+    /// done once per sub-range, instead of once per key. Returns the
+    /// sub-ranges in key order, with the search result for each.
+    ///
+    pub fn search_range(
+        &self,
+        key_range: Range<Key>,
+        end_lsn: Lsn,
+    ) -> Result<Vec<(Range<Key>, Option<SearchResult>)>> {
+        let mut boundaries = vec![key_range.start, key_range.end];
+        for l in self.historic_layers.iter() {
+            let l_key_range = l.get_key_range();
+            if !range_overlaps(&l_key_range, &key_range) {
+                continue;
+            }
+            for boundary in [l_key_range.start, l_key_range.end] {
+                if key_range.start < boundary && boundary < key_range.end {
+                    boundaries.push(boundary);
+                }
+            }
+        }
+        boundaries.sort();
+        boundaries.dedup();
+
+        boundaries
+            .windows(2)
+            .map(|w| Ok((w[0]..w[1], self.search(w[0], end_lsn)?)))
+            .collect()
+    }
+
     ///
     /// Insert an on-disk layer
     ///
@@ -201,6 +236,27 @@ impl LayerMap {
         NUM_ONDISK_LAYERS.dec();
     }
 
+    ///
+    /// Replace an on-disk layer in the map with another one, covering the same
+    /// key and LSN ranges.
+    ///
+    /// Returns false if the layer to replace is not in the map.
+    ///
+    pub fn replace_historic(&mut self, layer: &Arc<dyn Layer>, new_layer: Arc<dyn Layer>) -> bool {
+        #[allow(clippy::vtable_address_comparisons)]
+        match self
+            .historic_layers
+            .iter_mut()
+            .find(|other| Arc::ptr_eq(other, layer))
+        {
+            Some(other) => {
+                *other = new_layer;
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Is there a newer image layer for given key- and LSN-range?
     ///
     /// This is used for garbage collection, to determine if an old layer can
@@ -17,7 +17,10 @@ use utils::{
 };
 
 use crate::config::PageServerConf;
-use crate::STORAGE_FORMAT_VERSION;
+use crate::{
+    LOGICAL_SIZE_STORAGE_FORMAT_VERSION, MIN_SUPPORTED_STORAGE_FORMAT_VERSION,
+    STORAGE_FORMAT_VERSION,
+};
 
 /// We assume that a write of up to METADATA_MAX_SIZE bytes is atomic.
 ///
@@ -63,6 +66,36 @@ struct TimelineMetadataBody {
     ancestor_lsn: Lsn,
     latest_gc_cutoff_lsn: Lsn,
     initdb_lsn: Lsn,
+    // Logical size of the timeline at 'disk_consistent_lsn', if we know it.
+    // It's maintained incrementally as the WAL is ingested, and stored here
+    // so that it doesn't need to be calculated from scratch after restart.
+    logical_size: Option<u64>,
+}
+
+/// Metadata body of the format versions before
+/// [`LOGICAL_SIZE_STORAGE_FORMAT_VERSION`], without the logical size.
+#[derive(Debug, Deserialize)]
+struct TimelineMetadataBodyV5 {
+    disk_consistent_lsn: Lsn,
+    prev_record_lsn: Option<Lsn>,
+    ancestor_timeline: Option<ZTimelineId>,
+    ancestor_lsn: Lsn,
+    latest_gc_cutoff_lsn: Lsn,
+    initdb_lsn: Lsn,
+}
+
+impl From<TimelineMetadataBodyV5> for TimelineMetadataBody {
+    fn from(body: TimelineMetadataBodyV5) -> Self {
+        TimelineMetadataBody {
+            disk_consistent_lsn: body.disk_consistent_lsn,
+            prev_record_lsn: body.prev_record_lsn,
+            ancestor_timeline: body.ancestor_timeline,
+            ancestor_lsn: body.ancestor_lsn,
+            latest_gc_cutoff_lsn: body.latest_gc_cutoff_lsn,
+            initdb_lsn: body.initdb_lsn,
+            logical_size: None,
+        }
+    }
 }
 
 /// Points to a place in pageserver's local directory,
@@ -84,6 +117,7 @@ impl TimelineMetadata {
         ancestor_lsn: Lsn,
         latest_gc_cutoff_lsn: Lsn,
         initdb_lsn: Lsn,
+        logical_size: Option<u64>,
     ) -> Self {
         Self {
             hdr: TimelineMetadataHeader {
@@ -98,6 +132,7 @@ impl TimelineMetadata {
                 ancestor_lsn,
                 latest_gc_cutoff_lsn,
                 initdb_lsn,
+                logical_size,
             },
         }
     }
@@ -109,8 +144,10 @@ impl TimelineMetadata {
         );
         let hdr = TimelineMetadataHeader::des(&metadata_bytes[0..METADATA_HDR_SIZE])?;
         ensure!(
-            hdr.format_version == STORAGE_FORMAT_VERSION,
-            "format version mismatch"
+            (MIN_SUPPORTED_STORAGE_FORMAT_VERSION..=STORAGE_FORMAT_VERSION)
+                .contains(&hdr.format_version),
+            "unsupported format version {}",
+            hdr.format_version
         );
         let metadata_size = hdr.size as usize;
         ensure!(
@@ -122,7 +159,12 @@ impl TimelineMetadata {
             hdr.checksum == calculated_checksum,
             "metadata checksum mismatch"
         );
-        let body = TimelineMetadataBody::des(&metadata_bytes[METADATA_HDR_SIZE..metadata_size])?;
+        let body_bytes = &metadata_bytes[METADATA_HDR_SIZE..metadata_size];
+        let body = if hdr.format_version >= LOGICAL_SIZE_STORAGE_FORMAT_VERSION {
+            TimelineMetadataBody::des(body_bytes)?
+        } else {
+            TimelineMetadataBodyV5::des(body_bytes)?.into()
+        };
         ensure!(
             body.disk_consistent_lsn.is_aligned(),
             "disk_consistent_lsn is not aligned"
@@ -171,6 +213,11 @@ impl TimelineMetadata {
     pub fn initdb_lsn(&self) -> Lsn {
         self.body.initdb_lsn
     }
+
+    /// Logical size of the timeline at [`Self::disk_consistent_lsn`], if known.
+    pub fn logical_size(&self) -> Option<u64> {
+        self.body.logical_size
+    }
 }
 
 #[cfg(test)]
@@ -188,6 +235,7 @@ mod tests {
             Lsn(0),
             Lsn(0),
             Lsn(0),
+            Some(0x4000),
         );
 
         let metadata_bytes = original_metadata
@@ -202,4 +250,36 @@ mod tests {
             "Metadata that was serialized to bytes and deserialized back should not change"
         );
     }
+
+    #[test]
+    fn metadata_without_logical_size_deserializes() {
+        let body = TimelineMetadataBody {
+            disk_consistent_lsn: Lsn(0x200),
+            prev_record_lsn: Some(Lsn(0x100)),
+            ancestor_timeline: Some(TIMELINE_ID),
+            ancestor_lsn: Lsn(0),
+            latest_gc_cutoff_lsn: Lsn(0),
+            initdb_lsn: Lsn(0),
+            logical_size: None,
+        };
+
+        // Serialize the body in the old format, without the logical size
+        let mut body_bytes = body.ser().unwrap();
+        let logical_size_len = body.logical_size.ser().unwrap().len();
+        body_bytes.truncate(body_bytes.len() - logical_size_len);
+        let metadata_size = METADATA_HDR_SIZE + body_bytes.len();
+        let hdr = TimelineMetadataHeader {
+            checksum: crc32c::crc32c(&body_bytes),
+            size: metadata_size as u16,
+            format_version: LOGICAL_SIZE_STORAGE_FORMAT_VERSION - 1,
+        };
+        let mut metadata_bytes = vec![0u8; METADATA_MAX_SIZE];
+        metadata_bytes[0..METADATA_HDR_SIZE].copy_from_slice(&hdr.ser().unwrap());
+        metadata_bytes[METADATA_HDR_SIZE..metadata_size].copy_from_slice(&body_bytes);
+
+        let deserialized_metadata = TimelineMetadata::from_bytes(&metadata_bytes)
+            .expect("Should deserialize metadata of the previous format version");
+        assert_eq!(deserialized_metadata.body, body);
+        assert_eq!(deserialized_metadata.logical_size(), None);
+    }
 }
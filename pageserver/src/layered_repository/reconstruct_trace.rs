@@ -0,0 +1,145 @@
+//!
+//! Explain how a single page version was reconstructed.
+//!
+//! This is used by the debugging HTTP endpoint, to see which layers the
+//! read path traversed, what WAL records it collected on the way, and how
+//! long it took to apply them. The trace is collected by the same code that
+//! serves regular GetPage@LSN requests, see
+//! `LayeredTimeline::get_reconstruct_data`.
+//!
+
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::ops::Range;
+
+use utils::{lsn::Lsn, zid::ZTimelineId};
+
+use super::storage_layer::{Layer, ValueReconstructResult, ValueReconstructState};
+use crate::repository::Key;
+use crate::walrecord;
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct ReconstructTrace {
+    #[serde_as(as = "DisplayFromStr")]
+    pub key: Key,
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    /// LSN of the materialized page found in the page cache, if any.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub cached_image_lsn: Option<Lsn>,
+    /// True if the layer traversal stopped at the cached page image.
+    pub materialized_page_hit: bool,
+    /// Layers visited, in the order they were visited.
+    pub layers: Vec<TracedLayer>,
+    /// Branch points crossed on the way to the ancestor timelines.
+    pub ancestor_hops: Vec<TracedAncestorHop>,
+    /// LSN of the base page image that the WAL records were applied on.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub image_lsn: Option<Lsn>,
+    /// WAL records applied on top of the base image, oldest first.
+    pub records: Vec<TracedRecord>,
+    pub redo_time_us: u64,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct TracedLayer {
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    pub layer: String,
+    pub in_memory: bool,
+    /// Range of LSNs that was searched in this layer.
+    #[serde_as(as = "DisplayFromStr")]
+    pub start_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub end_lsn: Lsn,
+    pub result: String,
+    pub records: usize,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub image_lsn: Option<Lsn>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct TracedAncestorHop {
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub ancestor_timeline_id: ZTimelineId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub ancestor_lsn: Lsn,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct TracedRecord {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    pub will_init: bool,
+    pub description: String,
+}
+
+impl ReconstructTrace {
+    pub fn new(key: Key, lsn: Lsn) -> Self {
+        ReconstructTrace {
+            key,
+            lsn,
+            cached_image_lsn: None,
+            materialized_page_hit: false,
+            layers: Vec::new(),
+            ancestor_hops: Vec::new(),
+            image_lsn: None,
+            records: Vec::new(),
+            redo_time_us: 0,
+        }
+    }
+
+    ///
+    /// Remember a visit to a layer. 'before' is the number of records and the
+    /// image LSN in the reconstruct state before the layer was searched.
+    ///
+    pub(super) fn push_layer(
+        &mut self,
+        timeline_id: ZTimelineId,
+        layer: &dyn Layer,
+        lsn_range: Range<Lsn>,
+        result: ValueReconstructResult,
+        before: (usize, Option<Lsn>),
+        state: &ValueReconstructState,
+    ) {
+        let (records_before, image_before) = before;
+        let image_lsn = state.img.as_ref().map(|(lsn, _)| *lsn);
+        self.layers.push(TracedLayer {
+            timeline_id,
+            layer: layer.filename().display().to_string(),
+            in_memory: layer.is_in_memory(),
+            start_lsn: lsn_range.start,
+            end_lsn: lsn_range.end,
+            result: format!("{:?}", result),
+            records: state.records.len() - records_before,
+            image_lsn: if image_lsn != image_before {
+                image_lsn
+            } else {
+                None
+            },
+        });
+    }
+
+    /// Remember the base image and the records that are about to be applied.
+    pub(super) fn set_redo_input(&mut self, state: &ValueReconstructState) {
+        self.image_lsn = state.img.as_ref().map(|(lsn, _)| *lsn);
+        // The records are collected from newest to oldest
+        self.records = state
+            .records
+            .iter()
+            .rev()
+            .map(|(lsn, rec)| TracedRecord {
+                lsn: *lsn,
+                will_init: rec.will_init(),
+                description: walrecord::describe_wal_record(rec)
+                    .unwrap_or_else(|e| format!("could not describe the record: {}", e)),
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,207 @@
+//! A RemoteLayer represents an image or a delta layer that is present in the
+//! remote storage, but not in the timeline directory on the local disk.
+//!
+//! Such layers get into the layer map when a timeline is attached or loaded
+//! with some of its remote layers missing locally. The layer file gets
+//! downloaded the first time its contents are accessed, after that, all
+//! calls are forwarded to the regular ImageLayer or DeltaLayer, opened on
+//! the downloaded file.
+use crate::config::PageServerConf;
+use crate::layered_repository::delta_layer::DeltaLayer;
+use crate::layered_repository::filename::{DeltaFileName, ImageFileName};
+use crate::layered_repository::image_layer::ImageLayer;
+use crate::layered_repository::storage_layer::{
+    Layer, ValueReconstructResult, ValueReconstructState,
+};
+use crate::repository::{Key, Value};
+use crate::storage_sync;
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::*;
+
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+/// File name of the remote layer, defining the kind of the layer it represents.
+pub enum RemoteLayerFileName {
+    Image(ImageFileName),
+    Delta(DeltaFileName),
+}
+
+impl RemoteLayerFileName {
+    /// Parse a layer file name, returns None if the name is not a valid image or delta layer file name.
+    pub fn parse_str(fname: &str) -> Option<Self> {
+        ImageFileName::parse_str(fname)
+            .map(Self::Image)
+            .or_else(|| DeltaFileName::parse_str(fname).map(Self::Delta))
+    }
+}
+
+impl std::fmt::Display for RemoteLayerFileName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Image(fname) => std::fmt::Display::fmt(fname, f),
+            Self::Delta(fname) => std::fmt::Display::fmt(fname, f),
+        }
+    }
+}
+
+pub struct RemoteLayer {
+    conf: &'static PageServerConf,
+    tenantid: ZTenantId,
+    timelineid: ZTimelineId,
+    file_name: RemoteLayerFileName,
+
+    /// The layer, opened on the downloaded file. Initialized on first access.
+    local_layer: OnceCell<Arc<dyn Layer>>,
+}
+
+impl Layer for RemoteLayer {
+    fn filename(&self) -> PathBuf {
+        PathBuf::from(self.file_name.to_string())
+    }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        Some(self.path())
+    }
+
+    fn get_tenant_id(&self) -> ZTenantId {
+        self.tenantid
+    }
+
+    fn get_timeline_id(&self) -> ZTimelineId {
+        self.timelineid
+    }
+
+    fn get_key_range(&self) -> Range<Key> {
+        match &self.file_name {
+            RemoteLayerFileName::Image(fname) => fname.key_range.clone(),
+            RemoteLayerFileName::Delta(fname) => fname.key_range.clone(),
+        }
+    }
+
+    fn get_lsn_range(&self) -> Range<Lsn> {
+        match &self.file_name {
+            RemoteLayerFileName::Image(fname) => fname.lsn..fname.lsn + 1,
+            RemoteLayerFileName::Delta(fname) => fname.lsn_range.clone(),
+        }
+    }
+
+    fn get_value_reconstruct_data(
+        &self,
+        key: Key,
+        lsn_range: Range<Lsn>,
+        reconstruct_state: &mut ValueReconstructState,
+    ) -> anyhow::Result<ValueReconstructResult> {
+        self.local_layer()?
+            .get_value_reconstruct_data(key, lsn_range, reconstruct_state)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>> + '_> {
+        match self.local_layer() {
+            Ok(layer) => layer.iter(),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn delete(&self) -> Result<()> {
+        // the layer might have never been downloaded, nothing to delete locally then
+        match fs::remove_file(self.path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_incremental(&self) -> bool {
+        matches!(self.file_name, RemoteLayerFileName::Delta(_))
+    }
+
+    fn is_in_memory(&self) -> bool {
+        false
+    }
+
+    fn last_access_time(&self) -> Option<Instant> {
+        // not downloaded layers have no local file to evict
+        self.local_layer
+            .get()
+            .and_then(|layer| layer.last_access_time())
+    }
+
+    /// debugging function to print out the contents of the layer
+    fn dump(&self, verbose: bool) -> Result<()> {
+        match self.local_layer.get() {
+            Some(layer) => layer.dump(verbose),
+            None => {
+                println!(
+                    "----- remote layer for ten {} tli {} {} ----",
+                    self.tenantid, self.timelineid, self.file_name
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+impl RemoteLayer {
+    pub fn new(
+        conf: &'static PageServerConf,
+        timelineid: ZTimelineId,
+        tenantid: ZTenantId,
+        file_name: RemoteLayerFileName,
+    ) -> RemoteLayer {
+        RemoteLayer {
+            conf,
+            tenantid,
+            timelineid,
+            file_name,
+            local_layer: OnceCell::new(),
+        }
+    }
+
+    /// Path to the layer file in pageserver workdir, the layer gets downloaded into.
+    pub fn path(&self) -> PathBuf {
+        self.conf
+            .timeline_path(&self.timelineid, &self.tenantid)
+            .join(self.file_name.to_string())
+    }
+
+    ///
+    /// Download the layer file, if it's not present locally yet, and open it.
+    ///
+    /// Concurrent callers wait for the first one to finish the download.
+    ///
+    fn local_layer(&self) -> Result<&Arc<dyn Layer>> {
+        self.local_layer.get_or_try_init(|| {
+            let path = self.path();
+            if !path.exists() {
+                info!("downloading remote layer {}", path.display());
+                storage_sync::download_layer_file(&path).with_context(|| {
+                    format!("Failed to download remote layer {}", path.display())
+                })?;
+            }
+
+            let layer: Arc<dyn Layer> = match &self.file_name {
+                RemoteLayerFileName::Image(fname) => Arc::new(ImageLayer::new(
+                    self.conf,
+                    self.timelineid,
+                    self.tenantid,
+                    fname,
+                )),
+                RemoteLayerFileName::Delta(fname) => Arc::new(DeltaLayer::new(
+                    self.conf,
+                    self.timelineid,
+                    self.tenantid,
+                    fname,
+                )),
+            };
+            Ok(layer)
+        })
+    }
+}
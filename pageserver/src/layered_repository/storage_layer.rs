@@ -8,6 +8,8 @@ use anyhow::Result;
 use bytes::Bytes;
 use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use utils::{
     lsn::Lsn,
@@ -32,6 +34,28 @@ where
     a.start == b.start && a.end == b.end
 }
 
+/// Time of the last access to the contents of an on-disk layer.
+///
+/// Used to find the least recently used layers, when the local layer files
+/// need to be evicted.
+pub struct LayerAccessTime(Mutex<Instant>);
+
+impl Default for LayerAccessTime {
+    fn default() -> Self {
+        Self(Mutex::new(Instant::now()))
+    }
+}
+
+impl LayerAccessTime {
+    pub fn record_access(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    pub fn get(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
 /// Struct used to communicate across calls to 'get_value_reconstruct_data'.
 ///
 /// Before first call, you can fill in 'page_img' if you have an older cached
@@ -136,6 +160,10 @@ pub trait Layer: Send + Sync {
     /// Returns true for layers that are represented in memory.
     fn is_in_memory(&self) -> bool;
 
+    /// Time of the last access to the layer file contents.
+    /// None for the layers that have no file on the local disk.
+    fn last_access_time(&self) -> Option<Instant>;
+
     /// Iterate through all keys and values stored in the layer
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>> + '_>;
 
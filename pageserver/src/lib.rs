@@ -1,5 +1,7 @@
 pub mod basebackup;
+pub mod broker;
 pub mod config;
+pub mod export_timeline;
 pub mod http;
 pub mod import_datadir;
 pub mod keyspace;
@@ -37,7 +39,23 @@ use pgdatadir_mapping::DatadirTimeline;
 /// This is embedded in the metadata file, and also in the header of all the
 /// layer files. If you make any backwards-incompatible changes to the storage
 /// format, bump this!
-pub const STORAGE_FORMAT_VERSION: u16 = 3;
+///
+/// Version 4 added optional compression of the blobs in layer files.
+/// Version 5 added checksums of the index blocks and blobs in layer files.
+/// Version 6 added the logical size of the timeline to the metadata file.
+pub const STORAGE_FORMAT_VERSION: u16 = 6;
+
+/// Oldest storage format version that can still be read.
+///
+/// Version 3 files differ from version 4 only by never having compressed
+/// blobs, so they are read as is.
+pub const MIN_SUPPORTED_STORAGE_FORMAT_VERSION: u16 = 3;
+
+/// Layer files of this storage format version and newer have checksums.
+pub const CHECKSUMS_STORAGE_FORMAT_VERSION: u16 = 5;
+
+/// Metadata files of this storage format version and newer have the logical size.
+pub const LOGICAL_SIZE_STORAGE_FORMAT_VERSION: u16 = 6;
 
 // Magic constants used to identify different kinds of files
 pub const IMAGE_FILE_MAGIC: u16 = 0x5A60;
@@ -54,6 +72,9 @@ lazy_static! {
 
 pub const LOG_FILE_NAME: &str = "pageserver.log";
 
+/// Locked by the running pageserver, to prevent other processes from modifying its files.
+pub const LOCK_FILE_NAME: &str = "pageserver.lock";
+
 /// Config for the Repository checkpointer
 #[derive(Debug, Clone, Copy)]
 pub enum CheckpointConfig {
@@ -76,6 +97,10 @@ pub fn shutdown_pageserver(exit_code: i32) {
     postgres_backend::set_pgbackend_shutdown_requested();
     thread_mgr::shutdown_threads(Some(ThreadKind::PageRequestHandler), None, None);
 
+    // Stop launching new WAL receivers, and publishing timeline state.
+    thread_mgr::shutdown_threads(Some(ThreadKind::WalReceiverManager), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::BrokerPublisher), None, None);
+
     // Shut down all the tenants. This flushes everything to disk and kills
     // the checkpoint and GC threads.
     tenant_mgr::shutdown_all_tenants();
@@ -7,6 +7,8 @@
 //     *status* -- show actual info about this pageserver,
 //     *pagestream* -- enter mode where smgr and pageserver talk with their
 //  custom protocol.
+//     *pagestream_v2* -- same as pagestream, but also accepts GetPages requests
+//  for a range of blocks.
 //     *callmemaybe <zenith timelineid> $url* -- ask pageserver to start walreceiver on $url
 //
 
@@ -45,12 +47,24 @@ use postgres_ffi::xlog_utils::to_pg_timestamp;
 
 use postgres_ffi::pg_constants;
 
+/// Max number of blocks in a single GetPages request
+const MAX_GET_PAGES_BLOCKS: u32 = 1024;
+
+/// Version of the pagestream protocol, chosen by the command that starts it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PagestreamProtocolVersion {
+    V1,
+    /// Adds the GetPages request
+    V2,
+}
+
 // Wrapped in libpq CopyData
 enum PagestreamFeMessage {
     Exists(PagestreamExistsRequest),
     Nblocks(PagestreamNblocksRequest),
     GetPage(PagestreamGetPageRequest),
     DbSize(PagestreamDbSizeRequest),
+    GetPages(PagestreamGetPagesRequest),
 }
 
 // Wrapped in libpq CopyData
@@ -60,6 +74,7 @@ enum PagestreamBeMessage {
     GetPage(PagestreamGetPageResponse),
     Error(PagestreamErrorResponse),
     DbSize(PagestreamDbSizeResponse),
+    GetPages(PagestreamGetPagesResponse),
 }
 
 #[derive(Debug)]
@@ -91,6 +106,16 @@ struct PagestreamDbSizeRequest {
     dbnode: u32,
 }
 
+/// Request for the blocks blkno..blkno+nblocks of a relation
+#[derive(Debug)]
+struct PagestreamGetPagesRequest {
+    latest: bool,
+    lsn: Lsn,
+    rel: RelTag,
+    blkno: u32,
+    nblocks: u32,
+}
+
 #[derive(Debug)]
 struct PagestreamExistsResponse {
     exists: bool,
@@ -116,11 +141,24 @@ struct PagestreamDbSizeResponse {
     db_size: i64,
 }
 
+/// One page of the response to a GetPages request. The response consists of
+/// one such message for each requested block, in block order.
+#[derive(Debug)]
+struct PagestreamGetPagesResponse {
+    blkno: u32,
+    page: Bytes,
+}
+
 impl PagestreamFeMessage {
-    fn parse(mut body: Bytes) -> anyhow::Result<PagestreamFeMessage> {
+    fn parse(
+        mut body: Bytes,
+        protocol_version: PagestreamProtocolVersion,
+    ) -> anyhow::Result<PagestreamFeMessage> {
         // TODO these gets can fail
 
-        // these correspond to the ZenithMessageTag enum in pagestore_client.h
+        // these correspond to the ZenithMessageTag enum in pagestore_client.h,
+        // except for GetPages: it's not in pagestore_client.h, and only accepted
+        // from the clients that started the "pagestream_v2" protocol
         //
         // TODO: consider using protobuf or serde bincode for less error prone
         // serialization.
@@ -162,6 +200,20 @@ impl PagestreamFeMessage {
                 lsn: Lsn::from(body.get_u64()),
                 dbnode: body.get_u32(),
             })),
+            4 if protocol_version == PagestreamProtocolVersion::V2 => {
+                Ok(PagestreamFeMessage::GetPages(PagestreamGetPagesRequest {
+                    latest: body.get_u8() != 0,
+                    lsn: Lsn::from(body.get_u64()),
+                    rel: RelTag {
+                        spcnode: body.get_u32(),
+                        dbnode: body.get_u32(),
+                        relnode: body.get_u32(),
+                        forknum: body.get_u8(),
+                    },
+                    blkno: body.get_u32(),
+                    nblocks: body.get_u32(),
+                }))
+            }
             _ => bail!("unknown smgr message tag: {},'{:?}'", msg_tag, body),
         }
     }
@@ -196,6 +248,12 @@ impl PagestreamBeMessage {
                 bytes.put_u8(104); /* tag from pagestore_client.h */
                 bytes.put_i64(resp.db_size);
             }
+
+            Self::GetPages(resp) => {
+                bytes.put_u8(105); /* GetPages response, "pagestream_v2" only */
+                bytes.put_u32(resp.blkno);
+                bytes.put(&resp.page[..]);
+            }
         }
 
         bytes.into()
@@ -369,6 +427,7 @@ impl PageServerHandler {
         pgb: &mut PostgresBackend,
         timelineid: ZTimelineId,
         tenantid: ZTenantId,
+        protocol_version: PagestreamProtocolVersion,
     ) -> anyhow::Result<()> {
         let _enter = info_span!("pagestream", timeline = %timelineid, tenant = %tenantid).entered();
 
@@ -393,7 +452,8 @@ impl PageServerHandler {
                             _ => continue,
                         };
 
-                        let zenith_fe_msg = PagestreamFeMessage::parse(copy_data_bytes)?;
+                        let zenith_fe_msg =
+                            PagestreamFeMessage::parse(copy_data_bytes, protocol_version)?;
                         let tenant_id = tenantid.to_string();
                         let timeline_id = timelineid.to_string();
 
@@ -418,6 +478,33 @@ impl PageServerHandler {
                                 .observe_closure_duration(|| {
                                     self.handle_db_size_request(timeline.as_ref(), &req)
                                 }),
+                            PagestreamFeMessage::GetPages(req) => {
+                                let pages = SMGR_QUERY_TIME
+                                    .with_label_values(&[
+                                        "get_pages_at_lsn",
+                                        &tenant_id,
+                                        &timeline_id,
+                                    ])
+                                    .observe_closure_duration(|| {
+                                        self.handle_get_pages_at_lsn_request(
+                                            timeline.as_ref(),
+                                            &req,
+                                        )
+                                    });
+                                // All the pages are read before the response is streamed,
+                                // so on error, the client gets just the error message.
+                                match pages {
+                                    Ok(pages) => {
+                                        for page in pages {
+                                            pgb.write_message(&BeMessage::CopyData(
+                                                &page.serialize(),
+                                            ))?;
+                                        }
+                                        continue;
+                                    }
+                                    Err(e) => Err(e),
+                                }
+                            }
                         };
 
                         let response = response.unwrap_or_else(|e| {
@@ -485,6 +572,7 @@ impl PageServerHandler {
             if lsn <= last_record_lsn {
                 lsn = last_record_lsn;
             } else {
+                timeline.check_lsn_not_stopped_by_size_limit(lsn)?;
                 timeline.tline.wait_lsn(lsn)?;
                 // Since we waited for 'lsn' to arrive, that is now the last
                 // record LSN. (Or close enough for our purposes; the
@@ -495,6 +583,7 @@ impl PageServerHandler {
             if lsn == Lsn(0) {
                 bail!("invalid LSN(0) in request");
             }
+            timeline.check_lsn_not_stopped_by_size_limit(lsn)?;
             timeline.tline.wait_lsn(lsn)?;
         }
         ensure!(
@@ -588,6 +677,35 @@ impl PageServerHandler {
         }))
     }
 
+    fn handle_get_pages_at_lsn_request<R: Repository>(
+        &self,
+        timeline: &DatadirTimeline<R>,
+        req: &PagestreamGetPagesRequest,
+    ) -> Result<Vec<PagestreamBeMessage>> {
+        let _enter = info_span!("get_pages", rel = %req.rel, blkno = &req.blkno, nblocks = &req.nblocks, req_lsn = %req.lsn)
+            .entered();
+        ensure!(
+            req.nblocks > 0 && req.nblocks <= MAX_GET_PAGES_BLOCKS,
+            "invalid number of blocks in GetPages request: {}",
+            req.nblocks
+        );
+        let end_blkno = req
+            .blkno
+            .checked_add(req.nblocks)
+            .context("block range of GetPages request overflows")?;
+
+        let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
+        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let pages = timeline.get_rel_page_range_at_lsn(req.rel, req.blkno..end_blkno, lsn)?;
+
+        Ok((req.blkno..end_blkno)
+            .zip(pages)
+            .map(|(blkno, page)| {
+                PagestreamBeMessage::GetPages(PagestreamGetPagesResponse { blkno, page })
+            })
+            .collect())
+    }
+
     fn handle_basebackup_request(
         &self,
         pgb: &mut PostgresBackend,
@@ -681,19 +799,25 @@ impl postgres_backend::Handler for PageServerHandler {
     ) -> anyhow::Result<()> {
         debug!("process query {:?}", query_string);
 
-        if query_string.starts_with("pagestream ") {
-            let (_, params_raw) = query_string.split_at("pagestream ".len());
+        if query_string.starts_with("pagestream ") || query_string.starts_with("pagestream_v2 ") {
+            let (command, params_raw) = query_string.split_once(' ').unwrap();
+            let protocol_version = if command == "pagestream_v2" {
+                PagestreamProtocolVersion::V2
+            } else {
+                PagestreamProtocolVersion::V1
+            };
             let params = params_raw.split(' ').collect::<Vec<_>>();
             ensure!(
                 params.len() == 2,
-                "invalid param number for pagestream command"
+                "invalid param number for {} command",
+                command
             );
             let tenantid = ZTenantId::from_str(params[0])?;
             let timelineid = ZTimelineId::from_str(params[1])?;
 
             self.check_permission(Some(tenantid))?;
 
-            self.handle_pagerequests(pgb, timelineid, tenantid)?;
+            self.handle_pagerequests(pgb, timelineid, tenantid, protocol_version)?;
         } else if query_string.starts_with("basebackup ") {
             let (_, params_raw) = query_string.split_at("basebackup ".len());
             let params = params_raw.split_whitespace().collect::<Vec<_>>();
@@ -734,11 +858,24 @@ impl postgres_backend::Handler for PageServerHandler {
             let _enter =
                 info_span!("callmemaybe", timeline = %timelineid, tenant = %tenantid).entered();
 
-            // Check that the timeline exists
-            tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
-                .context("Cannot load local timeline")?;
-
-            walreceiver::launch_wal_receiver(self.conf, tenantid, timelineid, &connstr)?;
+            if self.conf.broker_endpoints.is_empty() {
+                // Check that the timeline exists
+                let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
+                    .context("Cannot load local timeline")?;
+
+                // The WAL receiver would only stop at the same refused record again.
+                if let Some(stop_lsn) = timeline.get_size_limit_stop_lsn() {
+                    debug!(
+                        "not launching WAL receiver, WAL at {} was refused by the logical size limit",
+                        stop_lsn
+                    );
+                } else {
+                    walreceiver::launch_wal_receiver(self.conf, tenantid, timelineid, &connstr)?;
+                }
+            } else {
+                // WAL receivers are managed based on the safekeepers' state in the broker
+                debug!("ignoring callmemaybe request, the broker is used to find safekeepers");
+            }
 
             pgb.write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.to_ascii_lowercase().starts_with("set ") {
@@ -786,6 +923,9 @@ impl postgres_backend::Handler for PageServerHandler {
                 RowDescriptor::int8_col(b"gc_period"),
                 RowDescriptor::int8_col(b"image_creation_threshold"),
                 RowDescriptor::int8_col(b"pitr_interval"),
+                RowDescriptor::int8_col(b"max_local_layers_size"),
+                RowDescriptor::int8_col(b"wal_redo_process_pool_size"),
+                RowDescriptor::int8_col(b"max_logical_size"),
             ]))?
             .write_message_noflush(&BeMessage::DataRow(&[
                 Some(repo.get_checkpoint_distance().to_string().as_bytes()),
@@ -801,6 +941,9 @@ impl postgres_backend::Handler for PageServerHandler {
                 Some(repo.get_gc_period().as_secs().to_string().as_bytes()),
                 Some(repo.get_image_creation_threshold().to_string().as_bytes()),
                 Some(repo.get_pitr_interval().as_secs().to_string().as_bytes()),
+                Some(repo.get_max_local_layers_size().to_string().as_bytes()),
+                Some(repo.get_wal_redo_process_pool_size().to_string().as_bytes()),
+                Some(repo.get_max_logical_size().to_string().as_bytes()),
             ]))?
             .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("do_gc ") {
@@ -921,6 +1064,7 @@ impl postgres_backend::Handler for PageServerHandler {
                 LsnForTimestamp::Present(lsn) => format!("{}", lsn),
                 LsnForTimestamp::Future(_lsn) => "future".into(),
                 LsnForTimestamp::Past(_lsn) => "past".into(),
+                LsnForTimestamp::NoData(_lsn) => "nodata".into(),
             };
             pgb.write_message_noflush(&BeMessage::DataRow(&[Some(result.as_bytes())]))?;
             pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
@@ -960,3 +1104,70 @@ impl<'a> io::Write for CopyDataSink<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serialize a GetPages request like a "pagestream_v2" client does
+    fn serialize_get_pages(req: &PagestreamGetPagesRequest) -> Bytes {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(4);
+        bytes.put_u8(req.latest as u8);
+        bytes.put_u64(req.lsn.0);
+        bytes.put_u32(req.rel.spcnode);
+        bytes.put_u32(req.rel.dbnode);
+        bytes.put_u32(req.rel.relnode);
+        bytes.put_u8(req.rel.forknum);
+        bytes.put_u32(req.blkno);
+        bytes.put_u32(req.nblocks);
+        bytes.into()
+    }
+
+    #[test]
+    fn get_pages_request_roundtrip() {
+        let req = PagestreamGetPagesRequest {
+            latest: true,
+            lsn: Lsn(0x0123_4567_89AB_CDEF),
+            rel: RelTag {
+                spcnode: 1663,
+                dbnode: 13010,
+                relnode: 16384,
+                forknum: 0,
+            },
+            blkno: 42,
+            nblocks: 8,
+        };
+        let body = serialize_get_pages(&req);
+
+        match PagestreamFeMessage::parse(body.clone(), PagestreamProtocolVersion::V2).unwrap() {
+            PagestreamFeMessage::GetPages(parsed) => {
+                assert_eq!(parsed.latest, req.latest);
+                assert_eq!(parsed.lsn, req.lsn);
+                assert_eq!(parsed.rel, req.rel);
+                assert_eq!(parsed.blkno, req.blkno);
+                assert_eq!(parsed.nblocks, req.nblocks);
+                assert_eq!(serialize_get_pages(&parsed), body);
+            }
+            _ => panic!("expected a GetPages request"),
+        }
+
+        // The original protocol doesn't have the GetPages request
+        assert!(PagestreamFeMessage::parse(body, PagestreamProtocolVersion::V1).is_err());
+    }
+
+    #[test]
+    fn get_pages_response_serialize() {
+        let page = Bytes::from(vec![0xAB; 8192]);
+        let resp = PagestreamBeMessage::GetPages(PagestreamGetPagesResponse {
+            blkno: 42,
+            page: page.clone(),
+        });
+
+        let mut bytes = resp.serialize();
+        assert_eq!(bytes.len(), 1 + 4 + page.len());
+        assert_eq!(bytes.get_u8(), 105);
+        assert_eq!(bytes.get_u32(), 42);
+        assert_eq!(bytes, page);
+    }
+}
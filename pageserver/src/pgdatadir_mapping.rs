@@ -16,10 +16,10 @@ use bytes::{Buf, Bytes};
 use postgres_ffi::xlog_utils::TimestampTz;
 use postgres_ffi::{pg_constants, Oid, TransactionId};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::cmp::{max, min};
+use std::collections::{hash_map, HashMap, HashSet};
 use std::ops::Range;
-use std::sync::atomic::{AtomicIsize, Ordering};
-use std::sync::{Arc, Mutex, RwLockReadGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use tracing::{debug, error, trace, warn};
 use utils::{bin_ser::BeSer, lsn::Lsn};
 
@@ -42,8 +42,47 @@ where
     /// Configuration: how often should the partitioning be recalculated.
     repartition_threshold: u64,
 
-    /// Current logical size of the "datadir", at the last LSN.
-    current_logical_size: AtomicIsize,
+    /// Cache of the relation sizes, to answer the frequent size and existence
+    /// requests without going through the whole 'get' path. See CachedRelSize.
+    rel_size_cache: RwLock<RelSizeCache>,
+
+    /// Set when WAL that would grow the timeline was refused, because its
+    /// logical size had exceeded the tenant's 'max_logical_size' limit. Holds
+    /// the LSN of the refused record. WAL ingestion stops there until the
+    /// limit is raised.
+    size_limit_stop_lsn: Mutex<Option<Lsn>>,
+}
+
+/// WAL that would grow the timeline was refused, because its logical size has
+/// exceeded the tenant's 'max_logical_size' limit.
+#[derive(Debug, thiserror::Error)]
+#[error("timeline has exceeded its logical size limit of {limit} bytes")]
+pub struct LogicalSizeLimitExceeded {
+    pub limit: u64,
+}
+
+/// Upper bound on the number of relation size cache entries of a timeline.
+/// The cache is emptied when it grows beyond it.
+const MAX_REL_SIZE_CACHE_ENTRIES: usize = 100_000;
+
+#[derive(Default)]
+struct RelSizeCache {
+    entries: HashMap<RelTag, CachedRelSize>,
+    /// Highest LSN of an entry removed from the cache, because the relation was
+    /// dropped or the cache was emptied. Sizes read at an older LSN are not
+    /// cached, as they could be outdated at the LSN of the removal already.
+    removed_lsn: Lsn,
+}
+
+/// Entry of the relation size cache.
+///
+/// The entries are updated by every ingested change of the relation's size,
+/// and the reads only add the sizes at the last record LSN, so the cached size
+/// is valid at any LSN at or after 'lsn', up to the last record LSN.
+#[derive(Debug, Clone, Copy)]
+struct CachedRelSize {
+    lsn: Lsn,
+    nblocks: BlockNumber,
 }
 
 #[derive(Debug)]
@@ -51,6 +90,7 @@ pub enum LsnForTimestamp {
     Present(Lsn),
     Future(Lsn),
     Past(Lsn),
+    NoData(Lsn),
 }
 
 impl<R: Repository> DatadirTimeline<R> {
@@ -58,20 +98,23 @@ impl<R: Repository> DatadirTimeline<R> {
         DatadirTimeline {
             tline,
             partitioning: Mutex::new((KeyPartitioning::new(), Lsn(0))),
-            current_logical_size: AtomicIsize::new(0),
             repartition_threshold,
+            rel_size_cache: RwLock::new(RelSizeCache::default()),
+            size_limit_stop_lsn: Mutex::new(None),
         }
     }
 
-    /// (Re-)calculate the logical size of the database at the latest LSN.
+    /// Calculate the logical size of the database at the latest LSN, unless
+    /// it was already stored with the timeline.
     ///
     /// This can be a slow operation.
     pub fn init_logical_size(&self) -> Result<()> {
+        if self.tline.get_current_logical_size().is_some() {
+            return Ok(());
+        }
         let last_lsn = self.tline.get_last_record_lsn();
-        self.current_logical_size.store(
-            self.get_current_logical_size_non_incremental(last_lsn)? as isize,
-            Ordering::SeqCst,
-        );
+        let logical_size = self.get_current_logical_size_non_incremental(last_lsn)?;
+        self.tline.set_current_logical_size(logical_size as u64);
         Ok(())
     }
 
@@ -99,6 +142,7 @@ impl<R: Repository> DatadirTimeline<R> {
             pending_updates: HashMap::new(),
             pending_deletions: Vec::new(),
             pending_nblocks: 0,
+            pending_rel_sizes: HashMap::new(),
         }
     }
 
@@ -123,10 +167,43 @@ impl<R: Repository> DatadirTimeline<R> {
         self.tline.get(key, lsn)
     }
 
+    /// Look up versions of a range of pages of a relation.
+    pub fn get_rel_page_range_at_lsn(
+        &self,
+        tag: RelTag,
+        blknums: Range<BlockNumber>,
+        lsn: Lsn,
+    ) -> Result<Vec<Bytes>> {
+        ensure!(tag.relnode != 0, "invalid relnode");
+
+        // Pages beyond EOF are returned as all-zeros, like in get_rel_page_at_lsn
+        let nblocks = self.get_rel_size(tag, lsn)?;
+        let end = min(blknums.end, nblocks);
+        let mut pages = if blknums.start < end {
+            let key_range = rel_block_to_key(tag, blknums.start)..rel_block_to_key(tag, end);
+            self.tline.get_range(key_range, lsn)?
+        } else {
+            Vec::new()
+        };
+        if blknums.end > nblocks {
+            debug!(
+                "read beyond EOF at {} blks {}..{} at {}, size is {}: returning all-zeros pages",
+                tag, blknums.start, blknums.end, lsn, nblocks
+            );
+            let n_zero_pages = blknums.end - max(blknums.start, nblocks);
+            pages.extend(std::iter::repeat(ZERO_PAGE.clone()).take(n_zero_pages as usize));
+        }
+        Ok(pages)
+    }
+
     /// Get size of a relation file
     pub fn get_rel_size(&self, tag: RelTag, lsn: Lsn) -> Result<BlockNumber> {
         ensure!(tag.relnode != 0, "invalid relnode");
 
+        if let Some(cached) = self.get_cached_rel_size(tag, lsn) {
+            return Ok(cached.nblocks);
+        }
+
         if (tag.forknum == pg_constants::FSM_FORKNUM
             || tag.forknum == pg_constants::VISIBILITYMAP_FORKNUM)
             && !self.get_rel_exists(tag, lsn)?
@@ -140,13 +217,26 @@ impl<R: Repository> DatadirTimeline<R> {
 
         let key = rel_size_to_key(tag);
         let mut buf = self.tline.get(key, lsn)?;
-        Ok(buf.get_u32_le())
+        let nblocks = buf.get_u32_le();
+
+        // The cached size is used for all the later LSNs, so only cache the size
+        // at the last record LSN: the relation might have changed after an older
+        // LSN before the cache had an entry for it, e.g. before a restart. The
+        // changes after the last record LSN update the cache on ingest.
+        if lsn >= self.tline.get_last_record_lsn() {
+            self.update_cached_rel_sizes(lsn, [(tag, Some(nblocks))]);
+        }
+        Ok(nblocks)
     }
 
     /// Does relation exist?
     pub fn get_rel_exists(&self, tag: RelTag, lsn: Lsn) -> Result<bool> {
         ensure!(tag.relnode != 0, "invalid relnode");
 
+        if self.get_cached_rel_size(tag, lsn).is_some() {
+            return Ok(true);
+        }
+
         // fetch directory listing
         let key = rel_dir_to_key(tag.spcnode, tag.dbnode);
         let buf = self.tline.get(key, lsn)?;
@@ -157,6 +247,64 @@ impl<R: Repository> DatadirTimeline<R> {
         Ok(exists)
     }
 
+    /// Look up a relation in the relation size cache, if the entry is valid at 'lsn'
+    fn get_cached_rel_size(&self, tag: RelTag, lsn: Lsn) -> Option<CachedRelSize> {
+        let rel_size_cache = self.rel_size_cache.read().unwrap();
+        rel_size_cache
+            .entries
+            .get(&tag)
+            .filter(|cached| lsn >= cached.lsn)
+            .copied()
+    }
+
+    /// Update the relation size cache with the sizes of relations at 'lsn',
+    /// None meaning that the relation was dropped and its entry is removed.
+    /// Entries from a later LSN are kept.
+    fn update_cached_rel_sizes(
+        &self,
+        lsn: Lsn,
+        rel_sizes: impl IntoIterator<Item = (RelTag, Option<BlockNumber>)>,
+    ) {
+        let mut rel_size_cache = self.rel_size_cache.write().unwrap();
+        if lsn < rel_size_cache.removed_lsn {
+            return;
+        }
+        let RelSizeCache {
+            entries,
+            removed_lsn,
+        } = &mut *rel_size_cache;
+        for (tag, nblocks) in rel_sizes {
+            match (entries.entry(tag), nblocks) {
+                (hash_map::Entry::Occupied(entry), None) => {
+                    *removed_lsn = max(*removed_lsn, max(lsn, entry.get().lsn));
+                    entry.remove();
+                }
+                (hash_map::Entry::Vacant(_), None) => {
+                    *removed_lsn = max(*removed_lsn, lsn);
+                }
+                (hash_map::Entry::Occupied(mut entry), Some(nblocks)) => {
+                    if lsn >= entry.get().lsn {
+                        entry.insert(CachedRelSize { lsn, nblocks });
+                    }
+                }
+                (hash_map::Entry::Vacant(entry), Some(nblocks)) => {
+                    entry.insert(CachedRelSize { lsn, nblocks });
+                }
+            }
+        }
+
+        if entries.len() > MAX_REL_SIZE_CACHE_ENTRIES {
+            debug!(
+                "relation size cache has {} entries, emptying it",
+                entries.len()
+            );
+            if let Some(max_lsn) = entries.values().map(|cached| cached.lsn).max() {
+                *removed_lsn = max(*removed_lsn, max_lsn);
+            }
+            entries.clear();
+        }
+    }
+
     /// Get a list of all existing relations in given tablespace and database.
     pub fn list_rels(&self, spcnode: Oid, dbnode: Oid, lsn: Lsn) -> Result<HashSet<RelTag>> {
         // fetch directory listing
@@ -250,7 +398,7 @@ impl<R: Repository> DatadirTimeline<R> {
             (false, false) => {
                 // This can happen if no commit records have been processed yet, e.g.
                 // just after importing a cluster.
-                bail!("no commit timestamps found");
+                Ok(LsnForTimestamp::NoData(max_lsn))
             }
             (true, false) => {
                 // Didn't find any commit timestamps larger than the request
@@ -382,19 +530,73 @@ impl<R: Repository> DatadirTimeline<R> {
     ///
     /// NOTE: counted incrementally, includes ancestors,
     pub fn get_current_logical_size(&self) -> usize {
-        let current_logical_size = self.current_logical_size.load(Ordering::Acquire);
-        match usize::try_from(current_logical_size) {
-            Ok(sz) => sz,
-            Err(_) => {
-                error!(
-                    "current_logical_size is out of range: {}",
-                    current_logical_size
-                );
+        match self.tline.get_current_logical_size() {
+            Some(size) => size as usize,
+            None => {
+                error!("current_logical_size is not initialized");
                 0
             }
         }
     }
 
+    /// Has the logical size of the timeline exceeded the tenant's
+    /// 'max_logical_size' limit? Past it, WAL that grows the timeline is
+    /// refused, see `check_logical_size_limit`.
+    pub fn is_logical_size_limit_exceeded(&self) -> bool {
+        match self.tline.get_logical_size_limit() {
+            Some(limit) => self.get_current_logical_size() as u64 > limit,
+            None => false,
+        }
+    }
+
+    /// Refuse to grow the timeline with the WAL record at 'lsn', if its
+    /// logical size has exceeded the tenant's 'max_logical_size' limit.
+    ///
+    /// The refused record stops the WAL ingestion, the record and everything
+    /// after it are not ingested until the limit is raised.
+    fn check_logical_size_limit(&self, lsn: Lsn) -> Result<()> {
+        if let Some(limit) = self.tline.get_logical_size_limit() {
+            if self.get_current_logical_size() as u64 > limit {
+                let mut stop_lsn = self.size_limit_stop_lsn.lock().unwrap();
+                if stop_lsn.is_none() {
+                    warn!(
+                        "refusing WAL record at {}, logical size {} exceeds the limit of {} bytes",
+                        lsn,
+                        self.get_current_logical_size(),
+                        limit
+                    );
+                }
+                *stop_lsn = Some(lsn);
+                return Err(LogicalSizeLimitExceeded { limit }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// If the WAL ingestion is stopped at a refused record because of the
+    /// logical size limit, return the LSN of the record. Returns None, and
+    /// lets the ingestion resume, once the limit is raised.
+    pub fn get_size_limit_stop_lsn(&self) -> Option<Lsn> {
+        let mut stop_lsn = self.size_limit_stop_lsn.lock().unwrap();
+        if stop_lsn.is_some() && !self.is_logical_size_limit_exceeded() {
+            *stop_lsn = None;
+        }
+        *stop_lsn
+    }
+
+    /// Fail right away for requests at an LSN that cannot be reached, because
+    /// the WAL ingestion is stopped by the logical size limit. Waiting for the
+    /// LSN would only time out.
+    pub fn check_lsn_not_stopped_by_size_limit(&self, lsn: Lsn) -> Result<()> {
+        if self.get_size_limit_stop_lsn().is_some() && lsn > self.get_last_record_lsn() {
+            return Err(LogicalSizeLimitExceeded {
+                limit: self.tline.get_logical_size_limit().unwrap_or(0),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     /// Does the same as get_current_logical_size but counted on demand.
     /// Used to initialize the logical size tracking on startup.
     ///
@@ -422,7 +624,7 @@ impl<R: Repository> DatadirTimeline<R> {
     /// Get a KeySpace that covers all the Keys that are in use at the given LSN.
     /// Anything that's not listed maybe removed from the underlying storage (from
     /// that LSN forwards).
-    fn collect_keyspace(&self, lsn: Lsn) -> Result<KeySpace> {
+    pub fn collect_keyspace(&self, lsn: Lsn) -> Result<KeySpace> {
         // Iterate through key ranges, greedily packing them into partitions
         let mut result = KeySpaceAccum::new();
 
@@ -527,6 +729,9 @@ pub struct DatadirModification<'a, R: Repository> {
     pending_updates: HashMap<Key, Value>,
     pending_deletions: Vec<Range<Key>>,
     pending_nblocks: isize,
+    // New sizes of the relations changed by this modification, None for
+    // dropped relations. Applied to the relation size cache on commit.
+    pending_rel_sizes: HashMap<RelTag, Option<BlockNumber>>,
 }
 
 impl<'a, R: Repository> DatadirModification<'a, R> {
@@ -673,6 +878,18 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         if dir.dbdirs.remove(&(spcnode, dbnode)).is_some() {
             let buf = DbDirectory::ser(&dir)?;
             self.put(DBDIR_KEY, Value::Image(buf.into()));
+
+            // All the relations of the database are dropped with it
+            let rel_dir = RelDirectory::des(&self.get(rel_dir_to_key(spcnode, dbnode))?)?;
+            for (relnode, forknum) in rel_dir.rels {
+                let rel = RelTag {
+                    spcnode,
+                    dbnode,
+                    relnode,
+                    forknum,
+                };
+                self.pending_rel_sizes.insert(rel, None);
+            }
         } else {
             warn!(
                 "dropped dbdir for spcnode {} dbnode {} did not exist in db directory",
@@ -692,6 +909,9 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
     /// 'nblocks' is the initial size.
     pub fn put_rel_creation(&mut self, rel: RelTag, nblocks: BlockNumber) -> Result<()> {
         ensure!(rel.relnode != 0, "invalid relnode");
+        if nblocks > 0 {
+            self.tline.check_logical_size_limit(self.lsn)?;
+        }
         // It's possible that this is the first rel for this db in this
         // tablespace.  Create the reldir entry for it if so.
         let mut dbdir = DbDirectory::des(&self.get(DBDIR_KEY)?)?;
@@ -724,6 +944,7 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         self.put(size_key, Value::Image(Bytes::from(buf.to_vec())));
 
         self.pending_nblocks += nblocks as isize;
+        self.pending_rel_sizes.insert(rel, Some(nblocks));
 
         // Even if nblocks > 0, we don't insert any actual blocks here. That's up to the
         // caller.
@@ -745,6 +966,7 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
 
         // Update logical database size.
         self.pending_nblocks -= old_size as isize - nblocks as isize;
+        self.pending_rel_sizes.insert(rel, Some(nblocks));
         Ok(())
     }
 
@@ -755,11 +977,15 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         // Put size
         let size_key = rel_size_to_key(rel);
         let old_size = self.get(size_key)?.get_u32_le();
+        if nblocks > old_size {
+            self.tline.check_logical_size_limit(self.lsn)?;
+        }
 
         let buf = nblocks.to_le_bytes();
         self.put(size_key, Value::Image(Bytes::from(buf.to_vec())));
 
         self.pending_nblocks += nblocks as isize - old_size as isize;
+        self.pending_rel_sizes.insert(rel, Some(nblocks));
         Ok(())
     }
 
@@ -782,6 +1008,7 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         let size_key = rel_size_to_key(rel);
         let old_size = self.get(size_key)?.get_u32_le();
         self.pending_nblocks -= old_size as isize;
+        self.pending_rel_sizes.insert(rel, None);
 
         // Delete size entry, as well as all blocks
         self.delete(rel_key_range(rel));
@@ -895,15 +1122,17 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
             writer.delete(key_range.clone(), self.lsn)?;
         }
 
-        writer.finish_write(self.lsn);
+        // Update the cached sizes before the new LSN becomes visible to the
+        // readers, so that they don't see the old sizes at the new LSN.
+        self.tline
+            .update_cached_rel_sizes(self.lsn, self.pending_rel_sizes);
 
         if pending_nblocks != 0 {
-            self.tline.current_logical_size.fetch_add(
-                pending_nblocks * pg_constants::BLCKSZ as isize,
-                Ordering::SeqCst,
-            );
+            writer.update_current_logical_size(pending_nblocks * pg_constants::BLCKSZ as isize);
         }
 
+        writer.finish_write(self.lsn);
+
         Ok(())
     }
 
@@ -1104,7 +1333,7 @@ fn rel_dir_to_key(spcnode: Oid, dbnode: Oid) -> Key {
     }
 }
 
-fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
+pub fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
     Key {
         field1: 0x00,
         field2: rel.spcnode,
@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::fmt;
+use std::str::FromStr;
 
-use postgres_ffi::relfile_utils::forknumber_to_name;
+use postgres_ffi::relfile_utils::{forkname_to_number, forknumber_to_name};
 use postgres_ffi::Oid;
 
 ///
@@ -75,6 +76,30 @@ impl fmt::Display for RelTag {
     }
 }
 
+/// Parse RelTag from the format produced by Display.
+impl FromStr for RelTag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '/');
+        let (spcnode, dbnode, relnode) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(spcnode), Some(dbnode), Some(relnode)) => (spcnode, dbnode, relnode),
+            _ => anyhow::bail!("invalid relation '{}'", s),
+        };
+        let (relnode, forkname) = match relnode.split_once('_') {
+            Some((relnode, forkname)) => (relnode, Some(forkname)),
+            None => (relnode, None),
+        };
+        Ok(RelTag {
+            forknum: forkname_to_number(forkname)
+                .map_err(|_| anyhow::anyhow!("invalid fork name in relation '{}'", s))?,
+            spcnode: spcnode.parse()?,
+            dbnode: dbnode.parse()?,
+            relnode: relnode.parse()?,
+        })
+    }
+}
+
 ///
 /// Non-relation transaction status files (clog (a.k.a. pg_xact) and
 /// pg_multixact) in Postgres are handled by SLRU (Simple LRU) buffer,
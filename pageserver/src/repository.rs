@@ -259,9 +259,20 @@ pub trait Repository: Send + Sync {
     /// api's 'compact' command.
     fn compaction_iteration(&self) -> Result<()>;
 
+    /// Evict the least recently used layer files from the local disk,
+    /// if the tenant's layers take more space than allowed.
+    /// Only the layers that are uploaded to the remote storage are evicted,
+    /// those are downloaded back on demand.
+    /// This function is periodically called by compactor thread.
+    fn eviction_iteration(&self) -> Result<()>;
+
     /// detaches timeline-related in-memory data.
     fn detach_timeline(&self, timeline_id: ZTimelineId) -> Result<()>;
 
+    /// removes timeline-related in-memory data, waiting for in-flight GC, compaction and layer flushes
+    /// of that timeline to finish, so that its files can be safely removed afterwards.
+    fn delete_timeline(&self, timeline_id: ZTimelineId) -> Result<()>;
+
     // Allows to retrieve remote timeline index from the repo. Used in walreceiver to grab remote consistent lsn.
     fn get_remote_index(&self) -> &RemoteIndex;
 }
@@ -282,6 +293,11 @@ pub enum RepositoryTimeline<T> {
     },
 }
 
+/// Returned when a timeline deletion is refused because other timelines are branched off it.
+#[derive(Debug, thiserror::Error)]
+#[error("Cannot delete timeline which has child timelines")]
+pub struct TimelineHasChildren;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LocalTimelineState {
     // timeline is loaded into memory (with layer map and all the bits),
@@ -353,6 +369,14 @@ pub trait Timeline: Send + Sync {
     ///
     fn get(&self, key: Key, lsn: Lsn) -> Result<Bytes>;
 
+    /// Look up the versions of all the keys in the given range, in key order.
+    ///
+    /// The result is the same as calling 'get' for each key, but the
+    /// implementation can share the work of finding the layers between the keys.
+    /// The same rules for non-existent keys apply.
+    ///
+    fn get_range(&self, key_range: Range<Key>, lsn: Lsn) -> Result<Vec<Bytes>>;
+
     /// Get the ancestor's timeline id
     fn get_ancestor_timeline_id(&self) -> Option<ZTimelineId>;
 
@@ -374,6 +398,24 @@ pub trait Timeline: Send + Sync {
 
     fn get_disk_consistent_lsn(&self) -> Lsn;
 
+    /// Get the logical size of the timeline at the last record LSN, if it's
+    /// known. It is maintained incrementally by the writers, and persisted
+    /// with the timeline.
+    fn get_current_logical_size(&self) -> Option<u64>;
+
+    /// Set the logical size at the last record LSN, after calculating it
+    /// from scratch.
+    fn set_current_logical_size(&self, size: u64);
+
+    /// Get the synthetic size of the timeline: the logical size, plus the
+    /// amount of WAL retained to allow branching and PITR at any point after
+    /// the GC cutoff. None if the logical size is not known.
+    fn get_synthetic_size(&self) -> Option<u64>;
+
+    /// Get the limit of the logical size, from the tenant's 'max_logical_size'
+    /// setting. None if the size is not limited.
+    fn get_logical_size_limit(&self) -> Option<u64>;
+
     /// Mutate the timeline with a [`TimelineWriter`].
     ///
     /// FIXME: This ought to return &'a TimelineWriter, where TimelineWriter
@@ -410,6 +452,13 @@ pub trait TimelineWriter<'a> {
 
     fn delete(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()>;
 
+    /// Add 'delta' bytes to the logical size of the timeline.
+    ///
+    /// Call this before finish_write(), so that the logical size is updated
+    /// atomically with the last record LSN. Does nothing if the logical size
+    /// is not known yet.
+    fn update_current_logical_size(&self, delta: isize);
+
     /// Track the end of the latest digested WAL record.
     ///
     /// Call this after you have finished writing all the WAL up to 'lsn'.
@@ -469,6 +518,9 @@ pub mod repo_harness {
                 gc_period: Some(tenant_conf.gc_period),
                 image_creation_threshold: Some(tenant_conf.image_creation_threshold),
                 pitr_interval: Some(tenant_conf.pitr_interval),
+                max_local_layers_size: Some(tenant_conf.max_local_layers_size),
+                wal_redo_process_pool_size: Some(tenant_conf.wal_redo_process_pool_size),
+                max_logical_size: Some(tenant_conf.max_logical_size),
             }
         }
     }
@@ -129,6 +129,10 @@
 //! * downloads do not contain any actual files to download, so that "external", sync pageserver code is able to schedule the timeline download
 //! without accessing any extra information about its files.
 //!
+//! Download tasks do not fetch any layers: only the local metadata file gets updated, making the timeline available to pageserver right away.
+//! Timeline's layer map gets remote layer entries for every remote layer missing locally and those are downloaded with [`download_layer_file`]
+//! the first time the page reconstruction needs them, outside of the sync loop.
+//!
 //! Uploads and downloads sync layer files in arbitrary order, but only after all layer files are synched the local metadada (for download) and remote index part (for upload) are updated,
 //! to avoid having a corrupt state without the relevant layer files.
 //! Refer to [`upload`] and [`download`] for more details.
@@ -136,7 +140,7 @@
 //! Synchronization never removes any local files from pageserver workdir or remote files from the remote storage, yet there could be overwrites of the same files (index part and metadata file updates, future checksum mismatch fixes).
 //! NOTE: No real contents or checksum check happens right now and is a subject to improve later.
 //!
-//! After the whole timeline is downloaded, [`crate::tenant_mgr::apply_timeline_sync_status_updates`] function is used to update pageserver memory stage for the timeline processed.
+//! After the timeline metadata is downloaded, [`crate::tenant_mgr::apply_timeline_sync_status_updates`] function is used to update pageserver memory stage for the timeline processed.
 
 mod delete;
 mod download;
@@ -157,7 +161,7 @@ use anyhow::{anyhow, bail, Context};
 use futures::stream::{FuturesUnordered, StreamExt};
 use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
-use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use remote_storage::{GenericRemoteStorage, RemoteStorage, RemoteStorageConfig};
 use tokio::{
     fs,
     runtime::Runtime,
@@ -167,7 +171,7 @@ use tracing::*;
 
 use self::{
     delete::delete_timeline_layers,
-    download::{download_timeline_layers, DownloadedTimeline},
+    download::{prepare_timeline_download, DownloadedTimeline},
     index::{IndexPart, RemoteTimeline, RemoteTimelineIndex},
     upload::{upload_index_part, upload_timeline_layers, UploadedTimeline},
 };
@@ -220,6 +224,10 @@ lazy_static! {
 
 static SYNC_QUEUE: OnceCell<SyncQueue> = OnceCell::new();
 
+/// Remote storage and a runtime to download layer files on demand with, outside of the sync loop.
+/// Initialized along with the sync loop, if the remote storage is configured.
+static LAYER_DOWNLOADER: OnceCell<(Runtime, GenericRemoteStorage)> = OnceCell::new();
+
 /// A timeline status to share with pageserver's sync counterpart,
 /// after comparing local and remote timeline state.
 #[derive(Clone, Copy, Debug)]
@@ -228,9 +236,9 @@ pub enum LocalTimelineInitStatus {
     /// There could be some layers requiring uploading,
     /// but this does not block the timeline from any user interaction.
     LocallyComplete,
-    /// A timeline has some files remotely, that are not present locally and need downloading.
-    /// Downloading might update timeline's metadata locally and current pageserver logic deals with local layers only,
-    /// so the data needs to be downloaded first before the timeline can be used.
+    /// A timeline has some files remotely, that are not present locally.
+    /// The layers are downloaded on demand, but the remote metadata might be newer than the local one,
+    /// so it needs to be downloaded first before the timeline can be used.
     NeedsSync,
 }
 
@@ -256,6 +264,8 @@ pub fn start_local_timeline_sync(
 
     match config.remote_storage_config.as_ref() {
         Some(storage_config) => {
+            init_layer_downloader(config, storage_config)
+                .context("Failed to init the on-demand layer downloader")?;
             match GenericRemoteStorage::new(config.workdir.clone(), storage_config)
                 .context("Failed to init the generic remote storage")?
             {
@@ -304,6 +314,47 @@ pub fn start_local_timeline_sync(
     }
 }
 
+fn init_layer_downloader(
+    config: &'static PageServerConf,
+    storage_config: &RemoteStorageConfig,
+) -> anyhow::Result<()> {
+    let storage = GenericRemoteStorage::new(config.workdir.clone(), storage_config)
+        .context("Failed to init the generic remote storage")?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("layer download worker")
+        .enable_all()
+        .build()
+        .context("Failed to create layer download runtime")?;
+    LAYER_DOWNLOADER
+        .set((runtime, storage))
+        .map_err(|_| anyhow!("Could not initialize layer downloader"))
+}
+
+/// Downloads a layer file from the remote storage into its local path, blocking until the download is finished.
+/// Used by the layers that are present in the remote storage only, the first time their contents are accessed.
+pub fn download_layer_file(layer_path: &Path) -> anyhow::Result<()> {
+    let (runtime, storage) = LAYER_DOWNLOADER
+        .get()
+        .context("No remote storage configured, cannot download layers on demand")?;
+    let download_start = Instant::now();
+    let download_result = runtime.block_on(async {
+        match storage {
+            GenericRemoteStorage::Local(storage) => {
+                download::download_layer(storage, layer_path).await
+            }
+            GenericRemoteStorage::S3(storage) => {
+                download::download_layer(storage, layer_path).await
+            }
+        }
+    });
+    register_sync_status(
+        download_start,
+        "download_layer",
+        Some(download_result.is_ok()),
+    );
+    download_result
+}
+
 fn local_tenant_timeline_files(
     config: &'static PageServerConf,
 ) -> anyhow::Result<HashMap<ZTenantTimelineId, (TimelineMetadata, HashSet<PathBuf>)>> {
@@ -540,6 +591,14 @@ impl SyncQueue {
         (batches, q.len())
     }
 
+    /// Drops all queued tasks of the tenant, returns the number of tasks dropped.
+    fn remove_tenant_tasks(&self, tenant_id: ZTenantId) -> usize {
+        let mut q = self.queue.lock().unwrap();
+        let tasks_before = q.len();
+        q.retain(|(sync_id, _)| sync_id.tenant_id != tenant_id);
+        tasks_before - q.len()
+    }
+
     #[cfg(test)]
     fn len(&self) -> usize {
         self.queue.lock().unwrap().len()
@@ -601,7 +660,23 @@ impl SyncTaskBatch {
     }
 
     fn add(&mut self, task: SyncTask) {
+        // The timeline is gone locally when its deletion gets scheduled, nothing to upload or download for it anymore.
+        let timeline_deleted = match &task {
+            SyncTask::Delete(new_delete) => new_delete.data.timeline_deletion,
+            SyncTask::Download(_) | SyncTask::Upload(_) => false,
+        } || self
+            .delete
+            .as_ref()
+            .map_or(false, |batch_delete| batch_delete.data.timeline_deletion);
+        if timeline_deleted {
+            self.upload = None;
+            self.download = None;
+        }
+
         match task {
+            SyncTask::Download(_) | SyncTask::Upload(_) if timeline_deleted => {
+                debug!("Skipping upload or download task for the timeline being deleted")
+            }
             SyncTask::Download(new_download) => match &mut self.download {
                 Some(batch_download) => {
                     batch_download.retries = batch_download.retries.min(new_download.retries);
@@ -646,6 +721,7 @@ impl SyncTaskBatch {
                         .data
                         .deletion_registered
                         .min(new_delete.data.deletion_registered);
+                    batch_delete.data.timeline_deletion |= new_delete.data.timeline_deletion;
 
                     // Do not download and upload the layers getting removed in the same batch
                     if let Some(batch_download) = &mut self.download {
@@ -714,6 +790,9 @@ struct LayersDeletion {
     /// the corresponding files on S3 won't exist for pageserver albeit being physically present on that remote storage still.
     /// Then all that's left is to remove the files from the remote storage, without concerns about consistency.
     deletion_registered: bool,
+    /// If `true`, the whole timeline is getting deleted: all its remote layers and the [`IndexPart`] get removed
+    /// along with the timeline's [`RemoteIndex`] entry, in addition to the `layers_to_delete`.
+    timeline_deletion: bool,
 }
 
 /// Adds the new checkpoint files as an upload sync task to the queue.
@@ -772,11 +851,50 @@ pub fn schedule_layer_delete(
             layers_to_delete,
             deleted_layers: HashSet::new(),
             deletion_registered: false,
+            timeline_deletion: false,
         }),
     );
     debug!("Deletion task for tenant {tenant_id}, timeline {timeline_id} sent")
 }
 
+/// Requests the removal of the entire timeline from the remote storage: all its layers and its [`IndexPart`].
+/// Any pending upload and download tasks for the timeline, batched together with the deletion, are discarded.
+/// On task failure, it gets retried again from the start a number of times.
+///
+/// Ensure that the loop is started otherwise the task is never processed.
+pub fn schedule_timeline_delete(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
+    let sync_queue = match SYNC_QUEUE.get() {
+        Some(queue) => queue,
+        None => {
+            warn!("Could not send timeline deletion task for tenant {tenant_id}, timeline {timeline_id}");
+            return;
+        }
+    };
+    sync_queue.push(
+        ZTenantTimelineId {
+            tenant_id,
+            timeline_id,
+        },
+        SyncTask::delete(LayersDeletion {
+            layers_to_delete: HashSet::new(),
+            deleted_layers: HashSet::new(),
+            deletion_registered: false,
+            timeline_deletion: true,
+        }),
+    );
+    debug!("Timeline deletion task for tenant {tenant_id}, timeline {timeline_id} sent")
+}
+
+/// Drops the queued upload, download and deletion tasks of the tenant, so they don't touch the remote storage after
+/// the tenant is detached. The uploads already in progress are dropped later, since the tenant files are not present
+/// locally anymore, see [`process_sync_task_batch`].
+pub fn cancel_tenant_sync_tasks(tenant_id: ZTenantId) {
+    if let Some(sync_queue) = SYNC_QUEUE.get() {
+        let cancelled = sync_queue.remove_tenant_tasks(tenant_id);
+        debug!("Cancelled {cancelled} sync tasks for tenant {tenant_id}");
+    }
+}
+
 /// Requests the download of the entire timeline for a given tenant.
 /// No existing local files are currently overwritten, except the metadata file (if its disk_consistent_lsn is less than the downloaded one).
 /// The metadata file is always updated last, to avoid inconsistencies.
@@ -999,7 +1117,19 @@ where
     let sync_start = Instant::now();
     let current_remote_timeline = { index.read().await.timeline_entry(&sync_id).cloned() };
 
-    let upload_data = batch.upload.clone();
+    // An upload retried after the tenant got detached or deleted would recreate its remote data.
+    let upload_data = match batch.upload.clone() {
+        Some(_)
+            if !conf
+                .timeline_path(&sync_id.timeline_id, &sync_id.tenant_id)
+                .exists() =>
+        {
+            info!("Timeline {sync_id} is not present locally anymore, skipping its upload");
+            None
+        }
+        upload_data => upload_data,
+    };
+    let upload_scheduled = upload_data.is_some();
     let download_data = batch.download.clone();
     // Run both upload and download tasks concurrently (not in parallel):
     // download and upload tasks do not conflict and spoil the pageserver state even if they are executed in parallel.
@@ -1058,7 +1188,7 @@ where
                     ControlFlow::Continue(new_download_data) => {
                         return download_timeline_data(
                             conf,
-                            (storage.as_ref(), &index, sync_queue),
+                            (&index, sync_queue),
                             current_remote_timeline.as_ref(),
                             sync_id,
                             new_download_data,
@@ -1082,7 +1212,7 @@ where
     );
 
     if let Some(delete_data) = batch.delete {
-        if upload_result.is_some() {
+        if !upload_scheduled || upload_result.is_some() {
             match validate_task_retries(delete_data, max_sync_errors)
                 .instrument(info_span!("retries_validation"))
                 .await
@@ -1122,38 +1252,22 @@ where
     status_update
 }
 
-async fn download_timeline_data<P, S>(
+async fn download_timeline_data(
     conf: &'static PageServerConf,
-    (storage, index, sync_queue): (&S, &RemoteIndex, &SyncQueue),
+    (index, sync_queue): (&RemoteIndex, &SyncQueue),
     current_remote_timeline: Option<&RemoteTimeline>,
     sync_id: ZTenantTimelineId,
     new_download_data: SyncData<LayersDownload>,
     sync_start: Instant,
     task_name: &str,
-) -> Option<TimelineSyncStatusUpdate>
-where
-    P: Debug + Send + Sync + 'static,
-    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
-{
-    match download_timeline_layers(
-        conf,
-        storage,
-        sync_queue,
-        current_remote_timeline,
-        sync_id,
-        new_download_data,
-    )
-    .await
-    {
+) -> Option<TimelineSyncStatusUpdate> {
+    match prepare_timeline_download(current_remote_timeline, sync_id, new_download_data) {
         DownloadedTimeline::Abort => {
             register_sync_status(sync_start, task_name, None);
             if let Err(e) = index.write().await.set_awaits_download(&sync_id, false) {
                 error!("Timeline {sync_id} was expected to be in the remote index after a download attempt, but it's absent: {e:?}");
             }
         }
-        DownloadedTimeline::FailedAndRescheduled => {
-            register_sync_status(sync_start, task_name, Some(false));
-        }
         DownloadedTimeline::Successful(mut download_data) => {
             match update_local_metadata(conf, sync_id, current_remote_timeline).await {
                 Ok(()) => match index.write().await.set_awaits_download(&sync_id, false) {
@@ -1252,6 +1366,25 @@ async fn delete_timeline_data<P, S>(
 {
     let timeline_delete = &mut new_delete_data.data;
 
+    if timeline_delete.timeline_deletion && !timeline_delete.deletion_registered {
+        // Removing the index entry makes the timeline disappear for the pageserver, the files left are removed next.
+        // Index part is deleted along with the layers, the retried deletions do not need it.
+        if let Some(remote_timeline) = index.write().await.remove_timeline_entry(&sync_id) {
+            let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
+                .with_file_name(IndexPart::FILE_NAME)
+                .with_extension(IndexPart::FILE_EXTENSION);
+            timeline_delete
+                .layers_to_delete
+                .extend(remote_timeline.stored_files().iter().cloned());
+            timeline_delete.layers_to_delete.insert(index_part_path);
+        } else {
+            info!(
+                "No remote index entry for timeline {sync_id}, deleting the scheduled layers only"
+            );
+        }
+        timeline_delete.deletion_registered = true;
+    }
+
     if !timeline_delete.deletion_registered {
         if let Err(e) = update_remote_data(
             conf,
@@ -1643,7 +1776,15 @@ mod test_utils {
     }
 
     pub(super) fn dummy_metadata(disk_consistent_lsn: Lsn) -> TimelineMetadata {
-        TimelineMetadata::new(disk_consistent_lsn, None, None, Lsn(0), Lsn(0), Lsn(0))
+        TimelineMetadata::new(
+            disk_consistent_lsn,
+            None,
+            None,
+            Lsn(0),
+            Lsn(0),
+            Lsn(0),
+            None,
+        )
     }
 }
 
@@ -1690,6 +1831,7 @@ mod tests {
             layers_to_delete: HashSet::from([PathBuf::from("de")]),
             deleted_layers: HashSet::from([PathBuf::from("del")]),
             deletion_registered: false,
+            timeline_deletion: false,
         });
 
         sync_queue.push(TEST_SYNC_ID, download_task.clone());
@@ -1739,6 +1881,7 @@ mod tests {
             layers_to_delete: HashSet::from([PathBuf::from("de")]),
             deleted_layers: HashSet::from([PathBuf::from("del")]),
             deletion_registered: false,
+            timeline_deletion: false,
         };
 
         sync_queue.push(TEST_SYNC_ID, SyncTask::download(download.clone()));
@@ -1839,4 +1982,61 @@ mod tests {
             "Should have one task left out of the batch"
         );
     }
+
+    #[tokio::test]
+    async fn timeline_deletion_discards_other_tasks_batch() {
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+
+        let download = LayersDownload {
+            layers_to_skip: HashSet::from([PathBuf::from("sk")]),
+        };
+        let upload_1 = LayersUpload {
+            layers_to_upload: HashSet::from([PathBuf::from("up1")]),
+            uploaded_layers: HashSet::new(),
+            metadata: Some(dummy_metadata(Lsn(2))),
+        };
+        let upload_2 = LayersUpload {
+            layers_to_upload: HashSet::from([PathBuf::from("up2")]),
+            uploaded_layers: HashSet::new(),
+            metadata: Some(dummy_metadata(Lsn(3))),
+        };
+        let delete = LayersDeletion {
+            layers_to_delete: HashSet::from([PathBuf::from("de")]),
+            deleted_layers: HashSet::new(),
+            deletion_registered: false,
+            timeline_deletion: false,
+        };
+        let timeline_delete = LayersDeletion {
+            layers_to_delete: HashSet::new(),
+            deleted_layers: HashSet::new(),
+            deletion_registered: false,
+            timeline_deletion: true,
+        };
+
+        sync_queue.push(TEST_SYNC_ID, SyncTask::download(download));
+        sync_queue.push(TEST_SYNC_ID, SyncTask::upload(upload_1));
+        sync_queue.push(TEST_SYNC_ID, SyncTask::delete(delete));
+        sync_queue.push(TEST_SYNC_ID, SyncTask::delete(timeline_delete));
+        sync_queue.push(TEST_SYNC_ID, SyncTask::upload(upload_2));
+
+        let (mut batch, _) = sync_queue.next_task_batch();
+        assert_eq!(
+            Some(SyncTaskBatch {
+                upload: None,
+                download: None,
+                delete: Some(SyncData {
+                    retries: 0,
+                    data: LayersDeletion {
+                        layers_to_delete: HashSet::from([PathBuf::from("de")]),
+                        deleted_layers: HashSet::new(),
+                        deletion_registered: false,
+                        timeline_deletion: true,
+                    }
+                }),
+            }),
+            batch.remove(&TEST_SYNC_ID),
+            "Timeline deletion should discard all uploads and downloads of the timeline, before and after it"
+        );
+        assert!(batch.is_empty(), "Should check all batch tasks");
+    }
 }
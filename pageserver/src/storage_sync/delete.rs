@@ -136,6 +136,7 @@ mod tests {
                     deleted_layers: HashSet::new(),
                     layers_to_delete: HashSet::new(),
                     deletion_registered: false,
+                    timeline_deletion: false,
                 },
             },
         )
@@ -204,6 +205,7 @@ mod tests {
                         local_timeline_path.join("something_different"),
                     ]),
                     deletion_registered: true,
+                    timeline_deletion: false,
                 },
             },
         )
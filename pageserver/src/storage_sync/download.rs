@@ -1,9 +1,8 @@
 //! Timeline synchrnonization logic to fetch the layer files from remote storage into pageserver's local directory.
 
-use std::{collections::HashSet, fmt::Debug, path::Path};
+use std::{fmt::Debug, path::Path};
 
 use anyhow::Context;
-use futures::stream::{FuturesUnordered, StreamExt};
 use remote_storage::{path_with_suffix_extension, RemoteStorage};
 use tokio::{
     fs,
@@ -11,14 +10,12 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
-use crate::{
-    config::PageServerConf, layered_repository::metadata::metadata_path, storage_sync::SyncTask,
-};
+use crate::{config::PageServerConf, layered_repository::metadata::metadata_path};
 use utils::zid::ZTenantTimelineId;
 
 use super::{
     index::{IndexPart, RemoteTimeline},
-    LayersDownload, SyncData, SyncQueue,
+    LayersDownload, SyncData,
 };
 
 pub const TEMP_DOWNLOAD_EXTENSION: &str = "temp_download";
@@ -70,30 +67,19 @@ pub(super) enum DownloadedTimeline {
     /// Remote timeline data is either absent or corrupt, no download possible.
     Abort,
     /// Remote timeline data is found, its latest checkpoint's metadata contents (disk_consistent_lsn) is known.
-    /// Initial download failed due to some error, the download task is rescheduled for another retry.
-    FailedAndRescheduled,
-    /// Remote timeline data is found, its latest checkpoint's metadata contents (disk_consistent_lsn) is known.
     /// Initial download successful.
     Successful(SyncData<LayersDownload>),
 }
 
-/// Attempts to download all given timeline's layers.
-/// Timeline files that already exist locally are skipped during the download, but the local metadata file is
-/// updated in the end, if the remote one contains a newer disk_consistent_lsn.
-///
-/// On an error, bumps the retries count and updates the files to skip with successful downloads, rescheduling the task.
-pub(super) async fn download_timeline_layers<'a, P, S>(
-    conf: &'static PageServerConf,
-    storage: &'a S,
-    sync_queue: &'a SyncQueue,
-    remote_timeline: Option<&'a RemoteTimeline>,
+/// Prepares the timeline to be used locally.
+/// No layers are downloaded here: the timeline's layer map gets remote entries for the layers missing locally
+/// and those get fetched with [`download_layer`] the first time they are accessed.
+/// The local metadata file is updated by the caller in the end, if the remote one contains a newer disk_consistent_lsn.
+pub(super) fn prepare_timeline_download(
+    remote_timeline: Option<&RemoteTimeline>,
     sync_id: ZTenantTimelineId,
-    mut download_data: SyncData<LayersDownload>,
-) -> DownloadedTimeline
-where
-    P: Debug + Send + Sync + 'static,
-    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
-{
+    download_data: SyncData<LayersDownload>,
+) -> DownloadedTimeline {
     let remote_timeline = match remote_timeline {
         Some(remote_timeline) => {
             if !remote_timeline.awaits_download {
@@ -108,154 +94,143 @@ where
         }
     };
 
-    let download = &mut download_data.data;
-
     let layers_to_download = remote_timeline
         .stored_files()
-        .difference(&download.layers_to_skip)
-        .cloned()
-        .collect::<Vec<_>>();
+        .difference(&download_data.data.layers_to_skip)
+        .count();
+    info!("{layers_to_download} timeline layers are left in the remote storage, to be downloaded on demand");
 
-    debug!("Layers to download: {layers_to_download:?}");
-    info!("Downloading {} timeline layers", layers_to_download.len());
+    DownloadedTimeline::Successful(download_data)
+}
 
-    if layers_to_download.is_empty() {
-        info!("No layers to download after filtering, skipping");
-        return DownloadedTimeline::Successful(download_data);
+/// Downloads a single layer file from the remote storage into its local path.
+/// The file is written under a temporary name first and gets renamed into the final path after it's fsynced,
+/// so the layer path either does not exist or contains the complete layer.
+pub(super) async fn download_layer<P, S>(
+    storage: &S,
+    layer_destination_path: &Path,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    if layer_destination_path.exists() {
+        debug!(
+            "Layer already exists locally, skipping download: {}",
+            layer_destination_path.display()
+        );
+        return Ok(());
     }
 
-    let mut download_tasks = layers_to_download
-        .into_iter()
-        .map(|layer_desination_path| async move {
-            if layer_desination_path.exists() {
-                debug!(
-                    "Layer already exists locally, skipping download: {}",
-                    layer_desination_path.display()
+    let layer_storage_path = storage
+        .remote_object_id(layer_destination_path)
+        .with_context(|| {
+            format!(
+                "Failed to get the layer storage path for local path '{}'",
+                layer_destination_path.display()
+            )
+        })?;
+
+    // Perform a rename inspired by durable_rename from file_utils.c.
+    // The sequence:
+    //     write(tmp)
+    //     fsync(tmp)
+    //     rename(tmp, new)
+    //     fsync(new)
+    //     fsync(parent)
+    // For more context about durable_rename check this email from postgres mailing list:
+    // https://www.postgresql.org/message-id/56583BDD.9060302@2ndquadrant.com
+    // If pageserver crashes the temp file will be deleted on startup and re-downloaded.
+    let temp_file_path =
+        path_with_suffix_extension(layer_destination_path, TEMP_DOWNLOAD_EXTENSION);
+
+    let download_result =
+        download_to_temp_file(storage, &layer_storage_path, &temp_file_path).await;
+    if let Err(e) = download_result {
+        if let Err(remove_error) = fs::remove_file(&temp_file_path).await {
+            if remove_error.kind() != io::ErrorKind::NotFound {
+                warn!(
+                    "Failed to remove temporary download file '{}': {remove_error}",
+                    temp_file_path.display()
                 );
-            } else {
-                let layer_storage_path = storage
-                    .remote_object_id(&layer_desination_path)
-                    .with_context(|| {
-                        format!(
-                            "Failed to get the layer storage path for local path '{}'",
-                            layer_desination_path.display()
-                        )
-                    })?;
-
-                // Perform a rename inspired by durable_rename from file_utils.c.
-                // The sequence:
-                //     write(tmp)
-                //     fsync(tmp)
-                //     rename(tmp, new)
-                //     fsync(new)
-                //     fsync(parent)
-                // For more context about durable_rename check this email from postgres mailing list:
-                // https://www.postgresql.org/message-id/56583BDD.9060302@2ndquadrant.com
-                // If pageserver crashes the temp file will be deleted on startup and re-downloaded.
-                let temp_file_path =
-                    path_with_suffix_extension(&layer_desination_path, TEMP_DOWNLOAD_EXTENSION);
-
-                let mut destination_file =
-                    fs::File::create(&temp_file_path).await.with_context(|| {
-                        format!(
-                            "Failed to create a destination file for layer '{}'",
-                            temp_file_path.display()
-                        )
-                    })?;
-
-                storage
-                    .download(&layer_storage_path, &mut destination_file)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "Failed to download a layer from storage path '{layer_storage_path:?}'"
-                        )
-                    })?;
-
-                // Tokio doc here: https://docs.rs/tokio/1.17.0/tokio/fs/struct.File.html states that:
-                // A file will not be closed immediately when it goes out of scope if there are any IO operations
-                // that have not yet completed. To ensure that a file is closed immediately when it is dropped,
-                // you should call flush before dropping it.
-                //
-                // From the tokio code I see that it waits for pending operations to complete. There shouldt be any because
-                // we assume that `destination_file` file is fully written. I e there is no pending .write(...).await operations.
-                // But for additional safety lets check/wait for any pending operations.
-                destination_file.flush().await.with_context(|| {
-                    format!(
-                        "failed to flush source file at {}",
-                        temp_file_path.display()
-                    )
-                })?;
-
-                // not using sync_data because it can lose file size update
-                destination_file.sync_all().await.with_context(|| {
-                    format!(
-                        "failed to fsync source file at {}",
-                        temp_file_path.display()
-                    )
-                })?;
-                drop(destination_file);
-
-                fail::fail_point!("remote-storage-download-pre-rename", |_| {
-                    anyhow::bail!("remote-storage-download-pre-rename failpoint triggered")
-                });
-
-                fs::rename(&temp_file_path, &layer_desination_path).await?;
-
-                fsync_path(&layer_desination_path).await.with_context(|| {
-                    format!(
-                        "Cannot fsync layer destination path {}",
-                        layer_desination_path.display(),
-                    )
-                })?;
-            }
-            Ok::<_, anyhow::Error>(layer_desination_path)
-        })
-        .collect::<FuturesUnordered<_>>();
-
-    let mut errors_happened = false;
-    // keep files we've downloaded to remove them from layers_to_skip if directory fsync fails
-    let mut undo = HashSet::new();
-    while let Some(download_result) = download_tasks.next().await {
-        match download_result {
-            Ok(downloaded_path) => {
-                undo.insert(downloaded_path.clone());
-                download.layers_to_skip.insert(downloaded_path);
-            }
-            Err(e) => {
-                errors_happened = true;
-                error!("Failed to download a layer for timeline {sync_id}: {e:?}");
             }
         }
+        return Err(e);
     }
 
-    // fsync timeline directory which is a parent directory for downloaded files
-    let ZTenantTimelineId {
-        tenant_id,
-        timeline_id,
-    } = &sync_id;
-    let timeline_dir = conf.timeline_path(timeline_id, tenant_id);
-    if let Err(e) = fsync_path(&timeline_dir).await {
-        error!(
-            "Cannot fsync parent directory {} error {}",
-            timeline_dir.display(),
-            e
-        );
-        for item in undo {
-            download.layers_to_skip.remove(&item);
-        }
-        errors_happened = true;
-    }
+    fs::rename(&temp_file_path, layer_destination_path).await?;
 
-    if errors_happened {
-        debug!("Reenqueuing failed download task for timeline {sync_id}");
-        download_data.retries += 1;
-        sync_queue.push(sync_id, SyncTask::Download(download_data));
-        DownloadedTimeline::FailedAndRescheduled
-    } else {
-        info!("Successfully downloaded all layers");
-        DownloadedTimeline::Successful(download_data)
-    }
+    fsync_path(layer_destination_path).await.with_context(|| {
+        format!(
+            "Cannot fsync layer destination path {}",
+            layer_destination_path.display(),
+        )
+    })?;
+
+    // fsync timeline directory which is a parent directory for the downloaded file
+    let timeline_dir = layer_destination_path
+        .parent()
+        .context("Layer destination path should always have a parent dir")?;
+    fsync_path(timeline_dir)
+        .await
+        .with_context(|| format!("Cannot fsync parent directory {}", timeline_dir.display()))?;
+
+    Ok(())
+}
+
+/// Writes the remote object into the temporary file and fsyncs it.
+async fn download_to_temp_file<P, S>(
+    storage: &S,
+    layer_storage_path: &P,
+    temp_file_path: &Path,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let mut destination_file = fs::File::create(temp_file_path).await.with_context(|| {
+        format!(
+            "Failed to create a destination file for layer '{}'",
+            temp_file_path.display()
+        )
+    })?;
+
+    storage
+        .download(layer_storage_path, &mut destination_file)
+        .await
+        .with_context(|| {
+            format!("Failed to download a layer from storage path '{layer_storage_path:?}'")
+        })?;
+
+    // Tokio doc here: https://docs.rs/tokio/1.17.0/tokio/fs/struct.File.html states that:
+    // A file will not be closed immediately when it goes out of scope if there are any IO operations
+    // that have not yet completed. To ensure that a file is closed immediately when it is dropped,
+    // you should call flush before dropping it.
+    //
+    // From the tokio code I see that it waits for pending operations to complete. There shouldt be any because
+    // we assume that `destination_file` file is fully written. I e there is no pending .write(...).await operations.
+    // But for additional safety lets check/wait for any pending operations.
+    destination_file.flush().await.with_context(|| {
+        format!(
+            "failed to flush source file at {}",
+            temp_file_path.display()
+        )
+    })?;
+
+    // not using sync_data because it can lose file size update
+    destination_file.sync_all().await.with_context(|| {
+        format!(
+            "failed to fsync source file at {}",
+            temp_file_path.display()
+        )
+    })?;
+    drop(destination_file);
+
+    fail::fail_point!("remote-storage-download-pre-rename", |_| {
+        anyhow::bail!("remote-storage-download-pre-rename failpoint triggered")
+    });
+
+    Ok(())
 }
 
 async fn fsync_path(path: impl AsRef<Path>) -> Result<(), io::Error> {
@@ -264,10 +239,7 @@ async fn fsync_path(path: impl AsRef<Path>) -> Result<(), io::Error> {
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        collections::{BTreeSet, HashSet},
-        num::NonZeroUsize,
-    };
+    use std::collections::{BTreeSet, HashSet};
 
     use remote_storage::{LocalFs, RemoteStorage};
     use tempfile::tempdir;
@@ -286,7 +258,6 @@ mod tests {
     #[tokio::test]
     async fn download_timeline() -> anyhow::Result<()> {
         let harness = RepoHarness::create("download_timeline")?;
-        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
 
         let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
         let layer_files = ["a", "b", "layer_to_skip", "layer_to_keep_locally"];
@@ -325,10 +296,7 @@ mod tests {
                 .map(|layer| local_timeline_path.join(layer)),
         );
 
-        let download_data = match download_timeline_layers(
-            harness.conf,
-            &storage,
-            &sync_queue,
+        let download_data = match prepare_timeline_download(
             Some(&remote_timeline),
             sync_id,
             SyncData::new(
@@ -337,9 +305,7 @@ mod tests {
                     layers_to_skip: HashSet::from([local_timeline_path.join("layer_to_skip")]),
                 },
             ),
-        )
-        .await
-        {
+        ) {
             DownloadedTimeline::Successful(data) => data,
             wrong_result => {
                 panic!("Expected a successful download for timeline, but got: {wrong_result:?}")
@@ -350,19 +316,25 @@ mod tests {
             current_retries, download_data.retries,
             "On successful download, retries are not expected to change"
         );
+
+        let mut local_files = BTreeSet::new();
+        let mut read_dir = fs::read_dir(&local_timeline_path).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            local_files.insert(dir_entry.path());
+        }
         assert_eq!(
-            download_data
-                .data
-                .layers_to_skip
-                .into_iter()
-                .collect::<BTreeSet<_>>(),
-            layer_files
-                .iter()
-                .map(|layer| local_timeline_path.join(layer))
-                .collect(),
-            "On successful download, layers to skip should contain all downloaded files and present layers that were skipped"
+            local_files,
+            BTreeSet::from([local_timeline_path.join("layer_to_keep_locally")]),
+            "Timeline download should leave the layers to be downloaded on demand"
         );
 
+        for layer in layer_files
+            .iter()
+            .filter(|layer| layer != &&"layer_to_skip")
+        {
+            download_layer(&storage, &local_timeline_path.join(layer)).await?;
+        }
+
         let mut downloaded_files = BTreeSet::new();
         let mut read_dir = fs::read_dir(&local_timeline_path).await?;
         while let Some(dir_entry) = read_dir.next_entry().await? {
@@ -376,8 +348,16 @@ mod tests {
                 .filter(|layer| layer != &&"layer_to_skip")
                 .map(|layer| local_timeline_path.join(layer))
                 .collect(),
-            "On successful download, all layers that were not skipped, should be downloaded"
+            "All layers requested should be downloaded, without any temporary files left"
         );
+        for layer in &layer_files[..2] {
+            let layer_path = local_timeline_path.join(layer);
+            assert_eq!(
+                fs::read(&layer_path).await?,
+                fs::read(storage.remote_object_id(&layer_path)?).await?,
+                "Downloaded layer should have the same contents as the remote one"
+            );
+        }
 
         Ok(())
     }
@@ -385,14 +365,10 @@ mod tests {
     #[tokio::test]
     async fn download_timeline_negatives() -> anyhow::Result<()> {
         let harness = RepoHarness::create("download_timeline_negatives")?;
-        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
         let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
         let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
 
-        let empty_remote_timeline_download = download_timeline_layers(
-            harness.conf,
-            &storage,
-            &sync_queue,
+        let empty_remote_timeline_download = prepare_timeline_download(
             None,
             sync_id,
             SyncData::new(
@@ -401,8 +377,7 @@ mod tests {
                     layers_to_skip: HashSet::new(),
                 },
             ),
-        )
-        .await;
+        );
         assert!(
             matches!(empty_remote_timeline_download, DownloadedTimeline::Abort),
             "Should not allow downloading for empty remote timeline"
@@ -413,10 +388,7 @@ mod tests {
             !not_expecting_download_remote_timeline.awaits_download,
             "Should not expect download for the timeline"
         );
-        let already_downloading_remote_timeline_download = download_timeline_layers(
-            harness.conf,
-            &storage,
-            &sync_queue,
+        let already_downloading_remote_timeline_download = prepare_timeline_download(
             Some(&not_expecting_download_remote_timeline),
             sync_id,
             SyncData::new(
@@ -425,8 +397,7 @@ mod tests {
                     layers_to_skip: HashSet::new(),
                 },
             ),
-        )
-        .await;
+        );
         assert!(
             matches!(
                 already_downloading_remote_timeline_download,
@@ -435,6 +406,17 @@ mod tests {
             "Should not allow downloading for remote timeline that does not expect it"
         );
 
+        let missing_layer_path = harness.timeline_path(&TIMELINE_ID).join("missing_layer");
+        fs::create_dir_all(harness.timeline_path(&TIMELINE_ID)).await?;
+        assert!(
+            download_layer(&storage, &missing_layer_path).await.is_err(),
+            "Should fail to download a layer that is absent in the remote storage"
+        );
+        assert!(
+            !missing_layer_path.exists(),
+            "Failed download should not create the layer file"
+        );
+
         Ok(())
     }
 
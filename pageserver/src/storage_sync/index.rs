@@ -14,7 +14,10 @@ use serde_with::{serde_as, DisplayFromStr};
 use tokio::sync::RwLock;
 
 use crate::{config::PageServerConf, layered_repository::metadata::TimelineMetadata};
-use utils::{lsn::Lsn, zid::ZTenantTimelineId};
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
+};
 
 /// A part of the filesystem path, that needs a root to become a path again.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -103,6 +106,24 @@ impl RemoteTimelineIndex {
         self.timeline_entries.insert(id, entry);
     }
 
+    pub fn remove_timeline_entry(&mut self, id: &ZTenantTimelineId) -> Option<RemoteTimeline> {
+        self.timeline_entries.remove(id)
+    }
+
+    /// Removes the entries of all tenant timelines, returns their ids.
+    pub fn remove_tenant_entries(&mut self, tenant_id: ZTenantId) -> Vec<ZTimelineId> {
+        let mut removed = Vec::new();
+        self.timeline_entries.retain(|sync_id, _| {
+            if sync_id.tenant_id == tenant_id {
+                removed.push(sync_id.timeline_id);
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
     pub fn all_sync_ids(&self) -> impl Iterator<Item = ZTenantTimelineId> + '_ {
         self.timeline_entries.keys().copied()
     }
@@ -257,8 +278,15 @@ mod tests {
     fn index_part_conversion() {
         let harness = RepoHarness::create("index_part_conversion").unwrap();
         let timeline_path = harness.timeline_path(&TIMELINE_ID);
-        let metadata =
-            TimelineMetadata::new(Lsn(5).align(), Some(Lsn(4)), None, Lsn(3), Lsn(2), Lsn(1));
+        let metadata = TimelineMetadata::new(
+            Lsn(5).align(),
+            Some(Lsn(4)),
+            None,
+            Lsn(3),
+            Lsn(2),
+            Lsn(1),
+            None,
+        );
         let remote_timeline = RemoteTimeline {
             timeline_layers: HashSet::from([
                 timeline_path.join("layer_1"),
@@ -375,8 +403,15 @@ mod tests {
     fn index_part_conversion_negatives() {
         let harness = RepoHarness::create("index_part_conversion_negatives").unwrap();
         let timeline_path = harness.timeline_path(&TIMELINE_ID);
-        let metadata =
-            TimelineMetadata::new(Lsn(5).align(), Some(Lsn(4)), None, Lsn(3), Lsn(2), Lsn(1));
+        let metadata = TimelineMetadata::new(
+            Lsn(5).align(),
+            Some(Lsn(4)),
+            None,
+            Lsn(3),
+            Lsn(2),
+            Lsn(1),
+            None,
+        );
 
         let conversion_result = IndexPart::from_remote_timeline(
             &timeline_path,
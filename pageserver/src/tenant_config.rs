@@ -34,6 +34,11 @@ pub mod defaults {
     pub const DEFAULT_GC_PERIOD: &str = "100 s";
     pub const DEFAULT_IMAGE_CREATION_THRESHOLD: usize = 3;
     pub const DEFAULT_PITR_INTERVAL: &str = "30 days";
+    // Layer eviction is disabled by default.
+    pub const DEFAULT_MAX_LOCAL_LAYERS_SIZE: u64 = 0;
+    pub const DEFAULT_WAL_REDO_PROCESS_POOL_SIZE: usize = 1;
+    // The logical size limit is disabled by default.
+    pub const DEFAULT_MAX_LOGICAL_SIZE: u64 = 0;
 }
 
 /// Per-tenant configuration options
@@ -68,6 +73,20 @@ pub struct TenantConf {
     // Page versions older than this are garbage collected away.
     #[serde(with = "humantime_serde")]
     pub pitr_interval: Duration,
+    // Total size of the layer files kept on the local disk for the tenant.
+    // When exceeded, the least recently accessed layers that are already
+    // uploaded to the remote storage get evicted from the local disk,
+    // to be downloaded on demand later.
+    // The unit is bytes, 0 disables the eviction.
+    pub max_local_layers_size: u64,
+    // Max number of WAL redo processes to run for the tenant, to
+    // reconstruct pages concurrently.
+    pub wal_redo_process_pool_size: usize,
+    // Limit of the logical size of each timeline of the tenant. Once a timeline
+    // is past it, WAL that grows the timeline further is refused, and the WAL
+    // ingestion stops until the limit is raised. The unit is bytes, 0 disables
+    // the limit.
+    pub max_logical_size: u64,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -85,6 +104,9 @@ pub struct TenantConfOpt {
     pub image_creation_threshold: Option<usize>,
     #[serde(with = "humantime_serde")]
     pub pitr_interval: Option<Duration>,
+    pub max_local_layers_size: Option<u64>,
+    pub wal_redo_process_pool_size: Option<usize>,
+    pub max_logical_size: Option<u64>,
 }
 
 impl TenantConfOpt {
@@ -108,6 +130,15 @@ impl TenantConfOpt {
                 .image_creation_threshold
                 .unwrap_or(global_conf.image_creation_threshold),
             pitr_interval: self.pitr_interval.unwrap_or(global_conf.pitr_interval),
+            max_local_layers_size: self
+                .max_local_layers_size
+                .unwrap_or(global_conf.max_local_layers_size),
+            wal_redo_process_pool_size: self
+                .wal_redo_process_pool_size
+                .unwrap_or(global_conf.wal_redo_process_pool_size),
+            max_logical_size: self
+                .max_logical_size
+                .unwrap_or(global_conf.max_logical_size),
         }
     }
 
@@ -136,6 +167,15 @@ impl TenantConfOpt {
         if let Some(pitr_interval) = other.pitr_interval {
             self.pitr_interval = Some(pitr_interval);
         }
+        if let Some(max_local_layers_size) = other.max_local_layers_size {
+            self.max_local_layers_size = Some(max_local_layers_size);
+        }
+        if let Some(wal_redo_process_pool_size) = other.wal_redo_process_pool_size {
+            self.wal_redo_process_pool_size = Some(wal_redo_process_pool_size);
+        }
+        if let Some(max_logical_size) = other.max_logical_size {
+            self.max_logical_size = Some(max_logical_size);
+        }
     }
 }
 
@@ -155,6 +195,9 @@ impl TenantConf {
             image_creation_threshold: DEFAULT_IMAGE_CREATION_THRESHOLD,
             pitr_interval: humantime::parse_duration(DEFAULT_PITR_INTERVAL)
                 .expect("cannot parse default PITR interval"),
+            max_local_layers_size: DEFAULT_MAX_LOCAL_LAYERS_SIZE,
+            wal_redo_process_pool_size: DEFAULT_WAL_REDO_PROCESS_POOL_SIZE,
+            max_logical_size: DEFAULT_MAX_LOGICAL_SIZE,
         }
     }
 
@@ -175,6 +218,9 @@ impl TenantConf {
             gc_period: Duration::from_secs(10),
             image_creation_threshold: defaults::DEFAULT_IMAGE_CREATION_THRESHOLD,
             pitr_interval: Duration::from_secs(60 * 60),
+            max_local_layers_size: defaults::DEFAULT_MAX_LOCAL_LAYERS_SIZE,
+            wal_redo_process_pool_size: defaults::DEFAULT_WAL_REDO_PROCESS_POOL_SIZE,
+            max_logical_size: defaults::DEFAULT_MAX_LOGICAL_SIZE,
         }
     }
 }
@@ -4,7 +4,9 @@
 use crate::config::PageServerConf;
 use crate::layered_repository::{load_metadata, LayeredRepository};
 use crate::pgdatadir_mapping::DatadirTimeline;
-use crate::repository::{Repository, TimelineSyncStatusUpdate};
+use crate::repository::{
+    Repository, RepositoryTimeline, Timeline, TimelineHasChildren, TimelineSyncStatusUpdate,
+};
 use crate::storage_sync::index::RemoteIndex;
 use crate::storage_sync::{self, LocalTimelineInitStatus, SyncStartupData};
 use crate::tenant_config::TenantConfOpt;
@@ -18,13 +20,13 @@ use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::Arc;
 use tracing::*;
 use utils::lsn::Lsn;
 
-use utils::zid::{ZTenantId, ZTimelineId};
+use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
 
 mod tenants_state {
     use std::{
@@ -313,6 +315,26 @@ pub fn get_repository_for_tenant(tenant_id: ZTenantId) -> anyhow::Result<Arc<Rep
     Ok(Arc::clone(&tenant.repo))
 }
 
+/// Lists the local timelines loaded into memory, of the tenants that are
+/// neither stopping nor broken.
+pub fn list_loaded_local_timelines() -> Vec<(ZTenantTimelineId, Arc<DatadirTimelineImpl>)> {
+    tenants_state::read_tenants()
+        .iter()
+        .filter(|(_, tenant)| matches!(tenant.state, TenantState::Active | TenantState::Idle))
+        .flat_map(|(tenant_id, tenant)| {
+            tenant
+                .local_timelines
+                .iter()
+                .map(|(timeline_id, timeline)| {
+                    (
+                        ZTenantTimelineId::new(*tenant_id, *timeline_id),
+                        Arc::clone(timeline),
+                    )
+                })
+        })
+        .collect()
+}
+
 /// Retrieves local timeline for tenant.
 /// Loads it into memory if it is not already loaded.
 pub fn get_local_timeline_with_load(
@@ -366,6 +388,121 @@ pub fn detach_timeline(
     Ok(())
 }
 
+/// Removes the timeline both locally and from the remote storage.
+/// Fails if there are any child timelines, branched off the one deleted.
+pub fn delete_timeline(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> anyhow::Result<()> {
+    // Validate the request before stopping anything, so a refused deletion leaves the timeline working.
+    let repo = get_repository_for_tenant(tenant_id)?;
+    let mut has_timeline = false;
+    for (id, entry) in repo.list_timelines() {
+        if id == timeline_id {
+            has_timeline = true;
+        }
+        let ancestor_id = match entry {
+            RepositoryTimeline::Loaded(timeline) => timeline.get_ancestor_timeline_id(),
+            RepositoryTimeline::Unloaded { metadata } => metadata.ancestor_timeline(),
+        };
+        if ancestor_id == Some(timeline_id) {
+            return Err(TimelineHasChildren.into());
+        }
+    }
+    if !has_timeline {
+        bail!("Cannot delete timeline {timeline_id} that is not available locally");
+    }
+
+    // shutdown the timeline threads (this shuts down the walreceiver)
+    thread_mgr::shutdown_threads(None, Some(tenant_id), Some(timeline_id));
+
+    // Do not hold the tenants lock while waiting for the tenant's compaction and GC to leave the timeline
+    repo.delete_timeline(timeline_id)
+        .context("Failed to delete inmem tenant timeline")?;
+    if let Some(tenant) = tenants_state::write_tenants().get_mut(&tenant_id) {
+        tenant.local_timelines.remove(&timeline_id);
+    }
+
+    let local_timeline_directory = conf.timeline_path(&timeline_id, &tenant_id);
+    std::fs::remove_dir_all(&local_timeline_directory).with_context(|| {
+        format!(
+            "Failed to remove local timeline directory '{}'",
+            local_timeline_directory.display()
+        )
+    })?;
+
+    storage_sync::schedule_timeline_delete(tenant_id, timeline_id);
+
+    Ok(())
+}
+
+/// Stops all tenant threads, drops its in-memory state and removes the tenant directory from the local disk.
+/// The queued storage sync tasks of the tenant are cancelled, but the remote storage data is kept,
+/// so the tenant timelines can be attached back later.
+/// The caller is expected to drop the tenant entries from the remote index.
+pub fn detach_tenant(conf: &'static PageServerConf, tenant_id: ZTenantId) -> anyhow::Result<()> {
+    match tenants_state::write_tenants().get_mut(&tenant_id) {
+        Some(tenant) => tenant.state = TenantState::Stopping,
+        None => bail!("Tenant {tenant_id} not found in local tenant state"),
+    }
+
+    // shutdown all tenant threads: walreceivers, page request handlers, compaction and GC
+    thread_mgr::shutdown_threads(None, Some(tenant_id), None);
+
+    tenants_state::write_tenants().remove(&tenant_id);
+    storage_sync::cancel_tenant_sync_tasks(tenant_id);
+
+    remove_local_tenant_directory(conf, tenant_id)
+}
+
+/// Detaches the tenant, if it is loaded, and schedules the removal of all its timelines from the remote storage.
+/// `remote_timelines` are the timelines present in the remote storage, possibly not known to the local repository.
+pub fn delete_tenant(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    remote_timelines: HashSet<ZTimelineId>,
+) -> anyhow::Result<()> {
+    let mut timelines_to_delete = remote_timelines;
+    let tenant_loaded = tenants_state::read_tenants().contains_key(&tenant_id);
+    if tenant_loaded {
+        timelines_to_delete.extend(
+            get_repository_for_tenant(tenant_id)?
+                .list_timelines()
+                .into_iter()
+                .map(|(timeline_id, _)| timeline_id),
+        );
+        detach_tenant(conf, tenant_id)?;
+    } else {
+        if timelines_to_delete.is_empty() && !conf.tenant_path(&tenant_id).exists() {
+            bail!("Tenant {tenant_id} not found locally nor in the remote storage");
+        }
+        storage_sync::cancel_tenant_sync_tasks(tenant_id);
+        if conf.tenant_path(&tenant_id).exists() {
+            remove_local_tenant_directory(conf, tenant_id)?;
+        }
+    }
+
+    for timeline_id in timelines_to_delete {
+        storage_sync::schedule_timeline_delete(tenant_id, timeline_id);
+    }
+
+    Ok(())
+}
+
+fn remove_local_tenant_directory(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+) -> anyhow::Result<()> {
+    let local_tenant_directory = conf.tenant_path(&tenant_id);
+    std::fs::remove_dir_all(&local_tenant_directory).with_context(|| {
+        format!(
+            "Failed to remove local tenant directory '{}'",
+            local_tenant_directory.display()
+        )
+    })
+}
+
 fn load_local_timeline(
     repo: &RepositoryImpl,
     timeline_id: ZTimelineId,
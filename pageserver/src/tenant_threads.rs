@@ -1,5 +1,5 @@
 //! This module contains functions to serve per-tenant background processes,
-//! such as compaction and GC
+//! such as compaction, layer eviction and GC
 use crate::repository::Repository;
 use crate::tenant_mgr;
 use crate::tenant_mgr::TenantState;
@@ -34,6 +34,9 @@ fn compact_loop_ext(tenantid: ZTenantId) -> Result<()> {
         // Compact timelines
         let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
         repo.compaction_iteration()?;
+
+        // Evict layers, if the local disk budget is exceeded
+        repo.eviction_iteration()?;
     }
 
     trace!(
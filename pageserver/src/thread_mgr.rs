@@ -94,6 +94,14 @@ pub enum ThreadKind {
     // Thread that connects to a safekeeper to fetch WAL for one timeline.
     WalReceiver,
 
+    // Thread that watches safekeepers' timeline state in the broker, and
+    // launches WAL receivers or switches them to another safekeeper.
+    WalReceiverManager,
+
+    // Thread that publishes the timelines' disk and remote consistent LSNs
+    // to the broker.
+    BrokerPublisher,
+
     // Thread that handles compaction of all timelines for a tenant.
     Compactor,
 
@@ -7,17 +7,18 @@ use postgres_ffi::ControlFileData;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::{
+    collections::HashSet,
     fs,
     path::Path,
     process::{Command, Stdio},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 use tracing::*;
 
 use utils::{
     crashsafe_dir, logging,
     lsn::Lsn,
-    zid::{ZTenantId, ZTimelineId},
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
 use crate::{
@@ -48,8 +49,10 @@ pub struct LocalTimelineInfo {
     pub latest_gc_cutoff_lsn: Lsn,
     #[serde_as(as = "DisplayFromStr")]
     pub disk_consistent_lsn: Lsn,
-    pub current_logical_size: Option<usize>, // is None when timeline is Unloaded
+    pub current_logical_size: Option<usize>, // is None when not known for an Unloaded timeline
     pub current_logical_size_non_incremental: Option<usize>,
+    pub synthetic_size: Option<u64>, // is None when timeline is Unloaded
+    pub logical_size_limit_exceeded: bool,
     pub timeline_state: LocalTimelineState,
 }
 
@@ -78,6 +81,8 @@ impl LocalTimelineInfo {
             } else {
                 None
             },
+            synthetic_size: datadir_tline.tline.get_synthetic_size(),
+            logical_size_limit_exceeded: datadir_tline.is_logical_size_limit_exceeded(),
         };
         Ok(info)
     }
@@ -96,8 +101,10 @@ impl LocalTimelineInfo {
             prev_record_lsn: metadata.prev_record_lsn(),
             latest_gc_cutoff_lsn: metadata.latest_gc_cutoff_lsn(),
             timeline_state: LocalTimelineState::Unloaded,
-            current_logical_size: None,
+            current_logical_size: metadata.logical_size().map(|size| size as usize),
             current_logical_size_non_incremental: None,
+            synthetic_size: None,
+            logical_size_limit_exceeded: false,
         }
     }
 
@@ -416,3 +423,96 @@ pub(crate) fn create_timeline(
         remote: None,
     }))
 }
+
+lazy_static::lazy_static! {
+    static ref IMPORTS_IN_PROGRESS: Mutex<HashSet<ZTenantTimelineId>> = Mutex::new(HashSet::new());
+}
+
+/// Import of the timeline in progress, see [`start_import`].
+pub(crate) struct ImportGuard(ZTenantTimelineId);
+
+impl Drop for ImportGuard {
+    fn drop(&mut self) {
+        IMPORTS_IN_PROGRESS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Register the import of the timeline. Returns None if the timeline already
+/// exists or is being imported.
+pub(crate) fn start_import(
+    conf: &PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> Option<ImportGuard> {
+    let zttid = ZTenantTimelineId {
+        tenant_id,
+        timeline_id,
+    };
+    let mut imports = IMPORTS_IN_PROGRESS.lock().unwrap();
+    if conf.timeline_path(&timeline_id, &tenant_id).exists() || !imports.insert(zttid) {
+        return None;
+    }
+    Some(ImportGuard(zttid))
+}
+
+///
+/// Create a new timeline from a tar archive of a PostgreSQL data directory,
+/// see import_datadir::import_timeline_from_tar().
+/// The caller registers the import with start_import() first.
+///
+pub(crate) fn import_timeline(
+    conf: &'static PageServerConf,
+    _import: &ImportGuard,
+    tenant_id: ZTenantId,
+    new_timeline_id: ZTimelineId,
+    tar_path: &Path,
+    base_lsn: Lsn,
+    end_lsn: Lsn,
+) -> Result<Option<TimelineInfo>> {
+    let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+
+    if conf.timeline_path(&new_timeline_id, &tenant_id).exists() {
+        debug!("timeline {} already exists", new_timeline_id);
+        return Ok(None);
+    }
+
+    let timeline = repo.create_empty_timeline(new_timeline_id, base_lsn)?;
+    let import_result = (|| {
+        let mut page_tline: DatadirTimeline<RepositoryImpl> =
+            DatadirTimeline::new(timeline, u64::MAX);
+        import_datadir::import_timeline_from_tar(tar_path, &mut page_tline, base_lsn, end_lsn)?;
+
+        page_tline.tline.checkpoint(CheckpointConfig::Forced)?;
+
+        info!(
+            "imported timeline {} timeline.lsn {}",
+            new_timeline_id,
+            page_tline.tline.get_last_record_lsn()
+        );
+
+        // load the timeline into memory
+        let new_timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, new_timeline_id)?;
+        LocalTimelineInfo::from_loaded_timeline(&new_timeline, false)
+            .context("cannot fill timeline info")
+    })();
+
+    let new_timeline_info = match import_result {
+        Ok(info) => info,
+        Err(e) => {
+            // Don't leave the half-imported timeline behind, so that the import can be retried
+            if let Err(delete_err) = tenant_mgr::delete_timeline(conf, tenant_id, new_timeline_id) {
+                error!(
+                    "failed to remove timeline {} after failed import: {:#}",
+                    new_timeline_id, delete_err
+                );
+            }
+            return Err(e);
+        }
+    };
+    Ok(Some(TimelineInfo {
+        tenant_id,
+        timeline_id: new_timeline_id,
+        local: Some(new_timeline_info),
+        remote: None,
+    }))
+}
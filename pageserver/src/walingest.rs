@@ -1053,6 +1053,7 @@ mod tests {
     use crate::pgdatadir_mapping::create_test_timeline;
     use crate::repository::repo_harness::*;
     use postgres_ffi::pg_constants;
+    use std::sync::Arc;
 
     /// Arbitrary relation tag, for testing.
     const TESTREL_A: RelTag = RelTag {
@@ -1062,6 +1063,13 @@ mod tests {
         forknum: 0,
     };
 
+    const TESTREL_B: RelTag = RelTag {
+        spcnode: 0,
+        dbnode: 111,
+        relnode: 1001,
+        forknum: 0,
+    };
+
     fn assert_current_logical_size<R: Repository>(_timeline: &DatadirTimeline<R>, _lsn: Lsn) {
         // TODO
     }
@@ -1243,6 +1251,109 @@ mod tests {
         Ok(())
     }
 
+    // Test that reading old relation sizes doesn't affect the cached
+    // sizes at later LSNs
+    #[test]
+    fn test_relsize_cache() -> Result<()> {
+        let repo = RepoHarness::create("test_relsize_cache")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let mut walingest = init_walingest_test(&tline)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        walingest.put_rel_page_image(&mut m, TESTREL_A, 0, TEST_IMG("foo blk 0 at 2"))?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        walingest.put_rel_page_image(&mut m, TESTREL_A, 2, TEST_IMG("foo blk 2 at 3"))?;
+        m.commit()?;
+
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x20))?, 1);
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x30))?, 3);
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x20))?, 1);
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x30))?, 3);
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        walingest.put_rel_truncation(&mut m, TESTREL_A, 2)?;
+        m.commit()?;
+
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x30))?, 3);
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x40))?, 2);
+
+        let mut m = tline.begin_modification(Lsn(0x50));
+        walingest.put_rel_drop(&mut m, TESTREL_A)?;
+        m.commit()?;
+
+        assert_eq!(tline.get_rel_exists(TESTREL_A, Lsn(0x40))?, true);
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x40))?, 2);
+        assert_eq!(tline.get_rel_exists(TESTREL_A, Lsn(0x50))?, false);
+
+        // Read at an old LSN, extend the relation, and read at the latest LSN
+        let mut m = tline.begin_modification(Lsn(0x60));
+        walingest.put_rel_page_image(&mut m, TESTREL_B, 0, TEST_IMG("bar blk 0 at 6"))?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x70));
+        walingest.put_rel_page_image(&mut m, TESTREL_B, 1, TEST_IMG("bar blk 1 at 7"))?;
+        m.commit()?;
+        assert_eq!(tline.get_rel_size(TESTREL_B, Lsn(0x60))?, 1);
+        let mut m = tline.begin_modification(Lsn(0x80));
+        walingest.put_rel_page_image(&mut m, TESTREL_B, 3, TEST_IMG("bar blk 3 at 8"))?;
+        m.commit()?;
+        assert_eq!(tline.get_rel_size(TESTREL_B, Lsn(0x80))?, 4);
+
+        // Same with an empty cache, like after a restart: the size read at an
+        // old LSN must not be returned for the later LSNs
+        let tline =
+            DatadirTimeline::<crate::RepositoryImpl>::new(Arc::clone(&tline.tline), 256 * 1024);
+        assert_eq!(tline.get_rel_size(TESTREL_B, Lsn(0x60))?, 1);
+        assert_eq!(tline.get_rel_size(TESTREL_B, Lsn(0x70))?, 2);
+        assert_eq!(tline.get_rel_size(TESTREL_B, Lsn(0x80))?, 4);
+        assert_eq!(tline.get_rel_size(TESTREL_B, Lsn(0x70))?, 2);
+
+        Ok(())
+    }
+
+    // Once the logical size is past the tenant's limit, relation extension is
+    // refused and the requests past the refused record fail right away
+    #[test]
+    fn test_logical_size_limit() -> Result<()> {
+        let mut harness = RepoHarness::create("test_logical_size_limit")?;
+        harness.tenant_conf.max_logical_size = 2 * pg_constants::BLCKSZ as u64;
+        let tline = create_test_timeline(harness.load(), TIMELINE_ID)?;
+        let mut walingest = init_walingest_test(&tline)?;
+        tline.init_logical_size()?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        walingest.put_rel_page_image(&mut m, TESTREL_A, 2, TEST_IMG("foo blk 2 at 2"))?;
+        m.commit()?;
+        assert!(tline.is_logical_size_limit_exceeded());
+        assert_eq!(tline.get_size_limit_stop_lsn(), None);
+
+        // Overwriting the existing blocks doesn't grow the timeline
+        let mut m = tline.begin_modification(Lsn(0x30));
+        walingest.put_rel_page_image(&mut m, TESTREL_A, 0, TEST_IMG("foo blk 0 at 3"))?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        let err = walingest
+            .put_rel_page_image(&mut m, TESTREL_A, 3, TEST_IMG("foo blk 3 at 4"))
+            .unwrap_err();
+        assert!(err.downcast_ref::<LogicalSizeLimitExceeded>().is_some());
+        walingest.put_rel_creation(&mut m, TESTREL_B)?;
+        let err = walingest
+            .put_rel_page_image(&mut m, TESTREL_B, 0, TEST_IMG("bar blk 0 at 4"))
+            .unwrap_err();
+        assert!(err.downcast_ref::<LogicalSizeLimitExceeded>().is_some());
+        drop(m);
+
+        assert_eq!(tline.get_size_limit_stop_lsn(), Some(Lsn(0x40)));
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x30))?, 3);
+        tline.check_lsn_not_stopped_by_size_limit(Lsn(0x30))?;
+        assert!(tline
+            .check_lsn_not_stopped_by_size_limit(Lsn(0x40))
+            .is_err());
+
+        Ok(())
+    }
+
     // Test what happens if we truncated a relation
     // so that one of its segments was dropped
     // and then extended it again within the same layer.
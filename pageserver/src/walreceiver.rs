@@ -4,15 +4,25 @@
 //! timeline.
 //!
 //! We keep one WAL receiver active per timeline.
+//!
+//! If the pageserver is configured with broker endpoints, the WAL receiver
+//! manager thread watches the timeline state that safekeepers publish in the
+//! broker, and decides which safekeeper each WAL receiver streams from: the one
+//! with the most advanced commit LSN. The WAL receiver is switched to another
+//! safekeeper when the current one falls too far behind, or stops publishing
+//! its state. Without the broker, WAL receivers are launched by `callmemaybe`
+//! requests from the safekeepers.
 
 use crate::config::PageServerConf;
 use crate::repository::{Repository, Timeline};
 use crate::tenant_mgr;
+use crate::tenant_mgr::TenantState;
 use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
 use crate::walingest::WalIngest;
 use anyhow::{bail, Context, Error, Result};
 use bytes::BytesMut;
+use etcd_broker::{SkTimelineInfo, SkTimelineSubscriptionKind};
 use fail::fail_point;
 use lazy_static::lazy_static;
 use postgres_ffi::waldecoder::*;
@@ -23,20 +33,33 @@ use serde_with::{serde_as, DisplayFromStr};
 use std::cell::Cell;
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread_local;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::pin;
+use tokio::sync::Notify;
 use tokio_postgres::replication::ReplicationStream;
 use tokio_postgres::{Client, NoTls, SimpleQueryMessage, SimpleQueryRow};
 use tokio_stream::StreamExt;
 use tracing::*;
 use utils::{
+    connstring::connection_host_port,
     lsn::Lsn,
     pq_proto::ZenithFeedback,
-    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
+    zid::{NodeId, ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
+/// How far, in bytes of WAL, the safekeeper that a WAL receiver streams from can
+/// fall behind the most advanced one, before the WAL receiver is switched over.
+const MAX_LSN_WAL_LAG: u64 = 10 * 1024 * 1024;
+
+/// Safekeepers publish their timeline state to the broker every second. If there
+/// have been no updates from a safekeeper for this long, consider it gone.
+const SAFEKEEPER_INFO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before resubscribing, if the broker connection fails.
+const BROKER_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 ///
 /// A WAL receiver's data stored inside the global `WAL_RECEIVERS`.
 /// We keep one WAL receiver active per timeline.
@@ -50,6 +73,10 @@ pub struct WalReceiverEntry {
     last_received_msg_lsn: Option<Lsn>,
     /// the timestamp (in microseconds) of the last received message
     last_received_msg_ts: Option<u128>,
+    /// Signaled when `wal_producer_connstr` is changed, to make the WAL receiver
+    /// reconnect.
+    #[serde(skip)]
+    connstr_changed: Arc<Notify>,
 }
 
 lazy_static! {
@@ -102,6 +129,7 @@ pub fn launch_wal_receiver(
                 wal_producer_connstr: wal_producer_connstr.into(),
                 last_received_msg_lsn: None,
                 last_received_msg_ts: None,
+                connstr_changed: Arc::new(Notify::new()),
             };
             receivers.insert((tenantid, timelineid), receiver);
 
@@ -112,6 +140,24 @@ pub fn launch_wal_receiver(
     Ok(())
 }
 
+/// Make a running WAL receiver reconnect to a different WAL producer.
+/// Returns false if there is no WAL receiver running for the timeline.
+fn redirect_wal_receiver(
+    tenantid: ZTenantId,
+    timelineid: ZTimelineId,
+    wal_producer_connstr: &str,
+) -> bool {
+    let mut receivers = WAL_RECEIVERS.lock().unwrap();
+    match receivers.get_mut(&(tenantid, timelineid)) {
+        Some(receiver) => {
+            receiver.wal_producer_connstr = wal_producer_connstr.into();
+            receiver.connstr_changed.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
 /// Look up a WAL receiver's data in the global `WAL_RECEIVERS`
 pub fn get_wal_receiver_entry(
     tenant_id: ZTenantId,
@@ -128,32 +174,55 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
     let _enter = info_span!("WAL receiver", timeline = %timeline_id, tenant = %tenant_id).entered();
     info!("WAL receiver thread started");
 
-    // Look up the current WAL producer address
-    let wal_producer_connstr = {
-        match get_wal_receiver_entry(tenant_id, timeline_id) {
-            Some(e) => e.wal_producer_connstr,
-            None => {
-                info!(
-                    "Unable to create the WAL receiver thread: no WAL receiver entry found for tenant {} and timeline {}",
-                    tenant_id, timeline_id
-                );
-                return;
+    loop {
+        // Look up the current WAL producer address
+        let (wal_producer_connstr, connstr_changed) = {
+            match get_wal_receiver_entry(tenant_id, timeline_id) {
+                Some(e) => (e.wal_producer_connstr, e.connstr_changed),
+                None => {
+                    info!(
+                        "Unable to create the WAL receiver thread: no WAL receiver entry found for tenant {} and timeline {}",
+                        tenant_id, timeline_id
+                    );
+                    return;
+                }
             }
+        };
+
+        // Make a connection to the WAL safekeeper, or directly to the primary PostgreSQL server,
+        // and start streaming WAL from it.
+        let res = walreceiver_main(
+            conf,
+            tenant_id,
+            timeline_id,
+            &wal_producer_connstr,
+            &connstr_changed,
+        );
+
+        // TODO cleanup info messages
+        if let Err(e) = res {
+            info!("WAL streaming connection failed ({})", e);
+            break;
         }
-    };
 
-    // Make a connection to the WAL safekeeper, or directly to the primary PostgreSQL server,
-    // and start streaming WAL from it.
-    let res = walreceiver_main(conf, tenant_id, timeline_id, &wal_producer_connstr);
+        // If we were asked to stream from another WAL producer, reconnect.
+        if !thread_mgr::is_shutdown_requested() {
+            if let Some(e) = get_wal_receiver_entry(tenant_id, timeline_id) {
+                if e.wal_producer_connstr != wal_producer_connstr {
+                    info!(
+                        "switching WAL receiver from {:?} to {:?}",
+                        wal_producer_connstr, e.wal_producer_connstr
+                    );
+                    continue;
+                }
+            }
+        }
 
-    // TODO cleanup info messages
-    if let Err(e) = res {
-        info!("WAL streaming connection failed ({})", e);
-    } else {
         info!(
             "walreceiver disconnected tenant {}, timelineid {}",
             tenant_id, timeline_id
         );
+        break;
     }
 
     // Drop it from list of active WAL_RECEIVERS
@@ -162,16 +231,18 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
 }
 
 fn walreceiver_main(
-    _conf: &PageServerConf,
+    conf: &PageServerConf,
     tenant_id: ZTenantId,
     timeline_id: ZTimelineId,
     wal_producer_connstr: &str,
+    connstr_changed: &Notify,
 ) -> anyhow::Result<(), Error> {
-    // Connect to the database in replication mode.
+    // Connect to the database in replication mode. The application name tells
+    // the safekeepers which pageserver the timeline is attached to.
     info!("connecting to {:?}", wal_producer_connstr);
     let connect_cfg = format!(
-        "{} application_name=pageserver replication=true",
-        wal_producer_connstr
+        "{} application_name=pageserver_{} replication=true",
+        wal_producer_connstr, conf.id
     );
 
     let runtime = tokio::runtime::Builder::new_current_thread()
@@ -202,6 +273,7 @@ fn walreceiver_main(
     info!("{:?}", identify);
     let end_of_wal = Lsn::from(u64::from(identify.xlogpos));
     let mut caught_up = false;
+    let mut logical_size_limit_exceeded = false;
 
     let repo = tenant_mgr::get_repository_for_tenant(tenant_id)
         .with_context(|| format!("no repository found for tenant {}", tenant_id))?;
@@ -253,6 +325,10 @@ fn walreceiver_main(
                 info!("walreceiver interrupted");
                 None
             }
+            _ = connstr_changed.notified() => {
+                info!("WAL producer changed, disconnecting from {:?}", wal_producer_connstr);
+                None
+            }
             replication_message = physical_stream.next() => replication_message,
         }
     }) {
@@ -364,8 +440,25 @@ fn walreceiver_main(
 
             // Send zenith feedback message.
             // Regular standby_status_update fields are put into this message.
+            // Report the size limit along with the size, so that the compute
+            // can stop writing before its WAL gets refused here.
+            let max_timeline_size = timeline.tline.get_logical_size_limit().unwrap_or(0);
+            let size_limit_exceeded = timeline.is_logical_size_limit_exceeded();
+            if size_limit_exceeded != logical_size_limit_exceeded {
+                if size_limit_exceeded {
+                    warn!(
+                        "logical size {} exceeds the limit of {} bytes",
+                        timeline.get_current_logical_size(),
+                        max_timeline_size
+                    );
+                } else {
+                    info!("logical size is back within the limit");
+                }
+                logical_size_limit_exceeded = size_limit_exceeded;
+            }
             let zenith_status_update = ZenithFeedback {
                 current_timeline_size: timeline.get_current_logical_size() as u64,
+                max_timeline_size,
                 ps_writelsn: write_lsn,
                 ps_flushlsn: flush_lsn,
                 ps_applylsn: apply_lsn,
@@ -387,6 +480,244 @@ fn walreceiver_main(
     Ok(())
 }
 
+/// Safekeeper timeline state from the broker, as a WAL receiver connection candidate.
+struct SafekeeperCandidate {
+    info: SkTimelineInfo,
+    last_update: Instant,
+}
+
+/// What the WAL receiver manager knows about one timeline.
+#[derive(Default)]
+struct TimelineSafekeepers {
+    safekeepers: HashMap<NodeId, SafekeeperCandidate>,
+    /// The safekeeper that the WAL receiver was last pointed to
+    current: Option<NodeId>,
+}
+
+/// Launch the thread that picks safekeepers for the WAL receivers, based on
+/// the timeline state published to the broker.
+pub fn launch_wal_receiver_manager(conf: &'static PageServerConf) -> Result<()> {
+    thread_mgr::spawn(
+        ThreadKind::WalReceiverManager,
+        None,
+        None,
+        "WAL receiver manager thread",
+        false,
+        move || {
+            wal_receiver_manager_main(conf);
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+//
+// This is the entry point for the WAL receiver manager thread.
+//
+fn wal_receiver_manager_main(conf: &'static PageServerConf) {
+    let _enter = info_span!("WAL receiver manager").entered();
+    info!("started, broker endpoints {:?}", conf.broker_endpoints);
+
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("failed to create the WAL receiver manager runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let mut timelines = HashMap::new();
+    runtime.block_on(async {
+        while !thread_mgr::is_shutdown_requested() {
+            tokio::select! {
+                biased;
+                _ = thread_mgr::shutdown_watcher() => break,
+                res = watch_safekeepers(conf, &mut timelines) => {
+                    if let Err(e) = res {
+                        warn!("safekeeper timeline updates subscription failed: {:?}", e);
+                    }
+                }
+            }
+
+            tokio::select! {
+                biased;
+                _ = thread_mgr::shutdown_watcher() => break,
+                _ = tokio::time::sleep(BROKER_RETRY_INTERVAL) => {}
+            }
+        }
+    });
+
+    info!("WAL receiver manager thread stopped");
+}
+
+/// Subscribe to the safekeepers' timeline updates in the broker, and update
+/// the WAL receivers of the local timelines as they arrive.
+async fn watch_safekeepers(
+    conf: &'static PageServerConf,
+    timelines: &mut HashMap<ZTenantTimelineId, TimelineSafekeepers>,
+) -> Result<()> {
+    let mut client = etcd_broker::Client::connect(&conf.broker_endpoints, None)
+        .await
+        .context("failed to connect to the broker")?;
+    let mut subscription = etcd_broker::subscribe_to_safekeeper_timeline_updates(
+        &mut client,
+        SkTimelineSubscriptionKind::all(conf.broker_etcd_prefix.clone()),
+    )
+    .await
+    .context("failed to subscribe for safekeeper timeline updates")?;
+
+    while let Some(updates) = subscription.fetch_data().await {
+        let now = Instant::now();
+        for (zttid, safekeepers) in updates {
+            match tenant_mgr::get_tenant_state(zttid.tenant_id) {
+                Some(TenantState::Active | TenantState::Idle) => {}
+                // The tenant is not attached to this pageserver, or is going away
+                _ => {
+                    timelines.remove(&zttid);
+                    continue;
+                }
+            }
+
+            let timeline = timelines.entry(zttid).or_default();
+            for (safekeeper_id, info) in safekeepers {
+                timeline.safekeepers.insert(
+                    safekeeper_id,
+                    SafekeeperCandidate {
+                        info,
+                        last_update: now,
+                    },
+                );
+            }
+
+            if let Err(e) = update_wal_receiver(conf, zttid, timeline, now).await {
+                warn!("failed to update WAL receiver for {}: {:?}", zttid, e);
+            }
+        }
+    }
+
+    bail!("safekeeper timeline updates subscription was closed")
+}
+
+/// Launch the WAL receiver for a timeline, or switch it to another safekeeper,
+/// if the one it streams from is lagging or gone.
+async fn update_wal_receiver(
+    conf: &'static PageServerConf,
+    zttid: ZTenantTimelineId,
+    timeline: &mut TimelineSafekeepers,
+    now: Instant,
+) -> Result<()> {
+    timeline
+        .safekeepers
+        .retain(|_, sk| now.duration_since(sk.last_update) < SAFEKEEPER_INFO_TIMEOUT);
+
+    let best = timeline
+        .safekeepers
+        .iter()
+        .filter_map(|(id, sk)| {
+            let commit_lsn = sk.info.commit_lsn?;
+            let connstr = sk.info.safekeeper_connection_string.as_deref()?;
+            Some((*id, commit_lsn, connstr))
+        })
+        .max_by_key(|(_, commit_lsn, _)| *commit_lsn);
+    let (best_id, best_commit_lsn, best_connstr) = match best {
+        Some(best) => best,
+        None => return Ok(()),
+    };
+
+    let receiver_running = get_wal_receiver_entry(zttid.tenant_id, zttid.timeline_id).is_some();
+    if receiver_running {
+        if let Some(current_id) = timeline.current {
+            if current_id == best_id {
+                return Ok(());
+            }
+            match timeline
+                .safekeepers
+                .get(&current_id)
+                .and_then(|sk| sk.info.commit_lsn)
+            {
+                Some(current_commit_lsn) => {
+                    let lag = best_commit_lsn.0.saturating_sub(current_commit_lsn.0);
+                    if lag <= MAX_LSN_WAL_LAG {
+                        return Ok(());
+                    }
+                    info!(
+                        "safekeeper {} commit_lsn {} is {} bytes behind safekeeper {} commit_lsn {}, switching",
+                        current_id, current_commit_lsn, lag, best_id, best_commit_lsn
+                    );
+                }
+                None => info!(
+                    "no recent updates from safekeeper {}, switching to safekeeper {}",
+                    current_id, best_id
+                ),
+            }
+        }
+    }
+
+    let wal_producer_connstr = safekeeper_connstr(conf, zttid, best_connstr)?;
+    if !redirect_wal_receiver(zttid.tenant_id, zttid.timeline_id, &wal_producer_connstr) {
+        // Safekeepers publish the timelines of all pageservers, only stream
+        // the ones that we have.
+        // Loading the timeline reads its files from disk, keep it off the broker runtime.
+        let load_result = tokio::task::spawn_blocking(move || {
+            tenant_mgr::get_local_timeline_with_load(zttid.tenant_id, zttid.timeline_id)
+        })
+        .await
+        .context("timeline load task panicked")?;
+        let datadir_timeline = match load_result {
+            Ok(datadir_timeline) => datadir_timeline,
+            Err(e) => {
+                debug!("not launching WAL receiver for {}: {:#}", zttid, e);
+                return Ok(());
+            }
+        };
+        // The WAL receiver would only stop at the same refused record again.
+        if let Some(stop_lsn) = datadir_timeline.get_size_limit_stop_lsn() {
+            debug!(
+                "not launching WAL receiver for {}: WAL at {} was refused by the logical size limit",
+                zttid, stop_lsn
+            );
+            return Ok(());
+        }
+        info!(
+            "launching WAL receiver for {}, streaming from safekeeper {}",
+            zttid, best_id
+        );
+        launch_wal_receiver(
+            conf,
+            zttid.tenant_id,
+            zttid.timeline_id,
+            &wal_producer_connstr,
+        )?;
+    }
+    timeline.current = Some(best_id);
+
+    Ok(())
+}
+
+/// Connection string for streaming a timeline's WAL from a safekeeper, in the
+/// same format that the safekeepers use in `callmemaybe` requests.
+fn safekeeper_connstr(
+    conf: &PageServerConf,
+    zttid: ZTenantTimelineId,
+    safekeeper_addr: &str,
+) -> Result<String> {
+    // use Config parsing because SockAddr parsing doesn't allow to use host names instead of ip addresses
+    let safekeeper_conf: postgres::config::Config =
+        format!("postgresql://no_user@{}/no_db", safekeeper_addr)
+            .parse()
+            .with_context(|| format!("invalid safekeeper address {:?}", safekeeper_addr))?;
+    let (host, port) = connection_host_port(&safekeeper_conf);
+
+    // Safekeepers tell the pageservers streaming from them apart by pageserver_connstr
+    Ok(format!(
+        "host={} port={} options='-c ztimelineid={} ztenantid={} pageserver_connstr=postgresql://no_user:@{}'",
+        host, port, zttid.timeline_id, zttid.tenant_id, conf.listen_pg_addr,
+    ))
+}
+
 /// Data returned from the postgres `IDENTIFY_SYSTEM` command
 ///
 /// See the [postgres docs] for more details.
@@ -86,8 +86,9 @@ pub struct DecodedBkpBlock {
     pub bimg_info: u8,
 
     /* Buffer holding the rmgr-specific data associated with this block */
-    has_data: bool,
-    data_len: u16,
+    pub has_data: bool,
+    pub data_len: u16,
+    pub data_offset: u32,
 }
 
 impl DecodedBkpBlock {
@@ -255,6 +256,50 @@ impl XlHeapMultiInsert {
     }
 }
 
+/// Header of a tuple in the block data of heap insert and update records
+#[repr(C)]
+#[derive(Debug)]
+pub struct XlHeapHeader {
+    pub t_infomask2: u16,
+    pub t_infomask: u16,
+    pub t_hoff: u8,
+}
+
+pub const SIZE_OF_HEAP_HEADER: usize = 5;
+
+impl XlHeapHeader {
+    pub fn decode(buf: &mut Bytes) -> XlHeapHeader {
+        XlHeapHeader {
+            t_infomask2: buf.get_u16_le(),
+            t_infomask: buf.get_u16_le(),
+            t_hoff: buf.get_u8(),
+        }
+    }
+}
+
+/// Header of each tuple in the block data of a multi-insert record
+#[repr(C)]
+#[derive(Debug)]
+pub struct XlMultiInsertTuple {
+    pub datalen: u16,
+    pub t_infomask2: u16,
+    pub t_infomask: u16,
+    pub t_hoff: u8,
+}
+
+pub const SIZE_OF_MULTI_INSERT_TUPLE: usize = 7;
+
+impl XlMultiInsertTuple {
+    pub fn decode(buf: &mut Bytes) -> XlMultiInsertTuple {
+        XlMultiInsertTuple {
+            datalen: buf.get_u16_le(),
+            t_infomask2: buf.get_u16_le(),
+            t_infomask: buf.get_u16_le(),
+            t_hoff: buf.get_u8(),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct XlHeapDelete {
@@ -298,7 +343,7 @@ impl XlHeapUpdate {
             old_offnum: buf.get_u16_le(),
             old_infobits_set: buf.get_u8(),
             flags: buf.get_u8(),
-            t_cid: buf.get_u32(),
+            t_cid: buf.get_u32_le(),
             new_xmax: buf.get_u32_le(),
             new_offnum: buf.get_u16_le(),
         }
@@ -730,6 +775,7 @@ pub fn decode_wal_record(record: Bytes) -> Result<DecodedWALRecord, DeserializeE
             ptr += blk.bimg_len as usize;
         }
         if blk.has_data {
+            blk.data_offset = ptr as u32;
             ptr += blk.data_len as usize;
         }
     }
@@ -13,6 +13,10 @@
 //! See src/backend/tcop/zenith_wal_redo.c for the other side of
 //! this communication.
 //!
+//! Full-page images and the most common heap records are replayed in
+//! the page server itself, see the `heapam` module. That saves the
+//! round-trip to the postgres process for the bulk of the requests.
+//!
 //! The Postgres process is assumed to be secure against malicious WAL
 //! records. It achieves it by dropping privileges before replaying
 //! any WAL records, so that even if an attacker hijacks the Postgres
@@ -27,11 +31,13 @@ use std::fs;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::process::Stdio;
 use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
 use std::time::Duration;
 use std::time::Instant;
 use tracing::*;
@@ -41,14 +47,18 @@ use crate::config::PageServerConf;
 use crate::pgdatadir_mapping::{key_to_rel_block, key_to_slru_block};
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::Key;
-use crate::walrecord::ZenithWalRecord;
-use metrics::{register_histogram, register_int_counter, Histogram, IntCounter};
+use crate::walrecord::{DecodedWALRecord, ZenithWalRecord};
+use metrics::{
+    register_histogram, register_int_counter, register_int_gauge, Histogram, IntCounter, IntGauge,
+};
 use postgres_ffi::nonrelfile_utils::mx_offset_to_flags_bitshift;
 use postgres_ffi::nonrelfile_utils::mx_offset_to_flags_offset;
 use postgres_ffi::nonrelfile_utils::mx_offset_to_member_offset;
 use postgres_ffi::nonrelfile_utils::transaction_id_set_status;
 use postgres_ffi::pg_constants;
 
+mod heapam;
+
 ///
 /// `RelTag` + block number (`blknum`) gives us a unique id of the page in the cluster.
 ///
@@ -79,6 +89,10 @@ pub trait WalRedoManager: Send + Sync {
         base_img: Option<Bytes>,
         records: Vec<(Lsn, ZenithWalRecord)>,
     ) -> Result<Bytes, WalRedoError>;
+
+    /// Set the max number of WAL redo processes to use concurrently, for the
+    /// implementations that use processes. Called on tenant config changes.
+    fn set_process_pool_size(&self, _pool_size: usize) {}
 }
 
 ///
@@ -102,15 +116,20 @@ impl crate::walredo::WalRedoManager for DummyRedoManager {
 // Metrics collected on WAL redo operations
 //
 // We collect the time spent in actual WAL redo ('redo'), and time waiting
-// for access to the postgres process ('wait') since there is only one for
-// each tenant.
+// for an idle postgres process ('wait'), since each tenant has a limited
+// pool of them.
 lazy_static! {
     static ref WAL_REDO_TIME: Histogram =
         register_histogram!("pageserver_wal_redo_seconds", "Time spent on WAL redo")
             .expect("failed to define a metric");
     static ref WAL_REDO_WAIT_TIME: Histogram = register_histogram!(
         "pageserver_wal_redo_wait_seconds",
-        "Time spent waiting for access to the WAL redo process"
+        "Time spent waiting for an idle WAL redo process"
+    )
+    .expect("failed to define a metric");
+    static ref WAL_REDO_PROCESSES: IntGauge = register_int_gauge!(
+        "pageserver_wal_redo_processes",
+        "Number of running WAL redo processes"
     )
     .expect("failed to define a metric");
     static ref WAL_REDO_RECORD_COUNTER: IntCounter = register_int_counter!(
@@ -121,30 +140,75 @@ lazy_static! {
 }
 
 ///
-/// This is the real implementation that uses a Postgres process to
-/// perform WAL replay. Only one thread can use the processs at a time,
-/// that is controlled by the Mutex. In the future, we might want to
-/// launch a pool of processes to allow concurrent replay of multiple
-/// records.
+/// This is the real implementation that uses Postgres processes to
+/// perform WAL replay. Each process serves one request at a time, and
+/// the manager keeps a pool of them, launched on demand up to the pool
+/// size from the tenant config. Requests wait for an idle process when
+/// all of them are busy.
 ///
 pub struct PostgresRedoManager {
     tenantid: ZTenantId,
     conf: &'static PageServerConf,
 
-    process: Mutex<Option<PostgresRedoProcess>>,
+    pool_size: AtomicUsize,
+    pool: Mutex<RedoProcessPool>,
+    /// Signaled when a process becomes idle, or a pool slot is freed
+    pool_changed: Condvar,
+}
+
+#[derive(Default)]
+struct RedoProcessPool {
+    /// Processes waiting for requests
+    idle: Vec<PostgresRedoProcess>,
+    /// Which slots have a running or launching process. Each slot has its
+    /// own data directory.
+    used_slots: Vec<bool>,
+}
+
+impl RedoProcessPool {
+    fn num_processes(&self) -> usize {
+        self.used_slots.iter().filter(|used| **used).count()
+    }
+
+    fn allocate_slot(&mut self) -> usize {
+        match self.used_slots.iter().position(|used| !used) {
+            Some(slot) => {
+                self.used_slots[slot] = true;
+                slot
+            }
+            None => {
+                self.used_slots.push(true);
+                self.used_slots.len() - 1
+            }
+        }
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        self.used_slots[slot] = false;
+    }
+}
+
+/// Decode the Postgres WAL records that can be replayed by zenith redo
+/// functions. The decoded records are used both to decide how to replay
+/// them and to replay them, so that each record is decoded once.
+fn decode_for_zenith(records: &[(Lsn, ZenithWalRecord)]) -> Vec<Option<DecodedWALRecord>> {
+    records
+        .iter()
+        .map(|(_, rec)| match rec {
+            ZenithWalRecord::Postgres { will_init: _, rec } => heapam::decode_if_supported(rec),
+            _ => None,
+        })
+        .collect()
 }
 
 /// Can this request be served by zenith redo funcitons
 /// or we need to pass it to wal-redo postgres process?
-fn can_apply_in_zenith(rec: &ZenithWalRecord) -> bool {
-    // Currently, we don't have bespoken Rust code to replay any
-    // Postgres WAL records. But everything else is handled in zenith.
-    #[allow(clippy::match_like_matches_macro)]
+fn can_apply_in_zenith(rec: &ZenithWalRecord, decoded: Option<&DecodedWALRecord>) -> bool {
+    // Of the Postgres WAL records, we have bespoken Rust code to replay
+    // only some, those are decoded by decode_for_zenith. But everything
+    // else is handled in zenith.
     match rec {
-        ZenithWalRecord::Postgres {
-            will_init: _,
-            rec: _,
-        } => false,
+        ZenithWalRecord::Postgres { .. } => decoded.is_some(),
         _ => true,
     }
 }
@@ -185,15 +249,22 @@ impl WalRedoManager for PostgresRedoManager {
             return Err(WalRedoError::InvalidRequest);
         }
 
+        let decoded = decode_for_zenith(&records);
         let mut img: Option<Bytes> = base_img;
-        let mut batch_zenith = can_apply_in_zenith(&records[0].1);
+        let mut batch_zenith = can_apply_in_zenith(&records[0].1, decoded[0].as_ref());
         let mut batch_start = 0;
         for i in 1..records.len() {
-            let rec_zenith = can_apply_in_zenith(&records[i].1);
+            let rec_zenith = can_apply_in_zenith(&records[i].1, decoded[i].as_ref());
 
             if rec_zenith != batch_zenith {
                 let result = if batch_zenith {
-                    self.apply_batch_zenith(key, lsn, img, &records[batch_start..i])
+                    self.apply_batch_zenith(
+                        key,
+                        lsn,
+                        img,
+                        &records[batch_start..i],
+                        &decoded[batch_start..i],
+                    )
                 } else {
                     self.apply_batch_postgres(
                         key,
@@ -211,7 +282,13 @@ impl WalRedoManager for PostgresRedoManager {
         }
         // last batch
         if batch_zenith {
-            self.apply_batch_zenith(key, lsn, img, &records[batch_start..])
+            self.apply_batch_zenith(
+                key,
+                lsn,
+                img,
+                &records[batch_start..],
+                &decoded[batch_start..],
+            )
         } else {
             self.apply_batch_postgres(
                 key,
@@ -222,6 +299,32 @@ impl WalRedoManager for PostgresRedoManager {
             )
         }
     }
+
+    fn set_process_pool_size(&self, pool_size: usize) {
+        let pool_size = pool_size.max(1);
+        self.pool_size.store(pool_size, Ordering::Relaxed);
+
+        // Stop the extra idle processes now, the busy ones are stopped
+        // when they're returned to the pool
+        let mut pool = self.pool.lock().unwrap();
+        let mut extra_processes = Vec::new();
+        while pool.num_processes() > pool_size {
+            match pool.idle.pop() {
+                Some(process) => {
+                    pool.free_slot(process.slot);
+                    extra_processes.push(process);
+                }
+                None => break,
+            }
+        }
+        drop(pool);
+        self.pool_changed.notify_all();
+
+        for process in extra_processes {
+            WAL_REDO_PROCESSES.dec();
+            process.kill();
+        }
+    }
 }
 
 impl PostgresRedoManager {
@@ -233,7 +336,65 @@ impl PostgresRedoManager {
         PostgresRedoManager {
             tenantid,
             conf,
-            process: Mutex::new(None),
+            pool_size: AtomicUsize::new(1),
+            pool: Mutex::new(RedoProcessPool::default()),
+            pool_changed: Condvar::new(),
+        }
+    }
+
+    ///
+    /// Take an idle process from the pool, launching a new one if there
+    /// are none and the pool isn't full, or waiting for one otherwise.
+    ///
+    fn get_process(&self) -> Result<PooledRedoProcess, WalRedoError> {
+        let start_time = Instant::now();
+
+        let mut pool = self.pool.lock().unwrap();
+        let slot = loop {
+            if let Some(process) = pool.idle.pop() {
+                WAL_REDO_WAIT_TIME.observe(start_time.elapsed().as_secs_f64());
+                return Ok(PooledRedoProcess::new(self, process));
+            }
+            if pool.num_processes() < self.pool_size.load(Ordering::Relaxed) {
+                break pool.allocate_slot();
+            }
+            pool = self.pool_changed.wait(pool).unwrap();
+        };
+        drop(pool);
+        WAL_REDO_WAIT_TIME.observe(start_time.elapsed().as_secs_f64());
+
+        // Launch the process without holding the lock, it takes a while
+        match PostgresRedoProcess::launch(self.conf, &self.tenantid, slot) {
+            Ok(process) => {
+                WAL_REDO_PROCESSES.inc();
+                Ok(PooledRedoProcess::new(self, process))
+            }
+            Err(e) => {
+                self.pool.lock().unwrap().free_slot(slot);
+                self.pool_changed.notify_one();
+                Err(WalRedoError::IoError(e))
+            }
+        }
+    }
+
+    ///
+    /// Return a process taken with 'get_process' to the pool, called when its
+    /// PooledRedoProcess is dropped. Processes that failed, or don't fit in the
+    /// pool anymore, are killed.
+    ///
+    fn return_process(&self, process: PostgresRedoProcess, failed: bool) {
+        let mut pool = self.pool.lock().unwrap();
+        if failed || pool.num_processes() > self.pool_size.load(Ordering::Relaxed) {
+            pool.free_slot(process.slot);
+            drop(pool);
+            self.pool_changed.notify_one();
+
+            WAL_REDO_PROCESSES.dec();
+            process.kill();
+        } else {
+            pool.idle.push(process);
+            drop(pool);
+            self.pool_changed.notify_one();
         }
     }
 
@@ -250,28 +411,20 @@ impl PostgresRedoManager {
     ) -> Result<Bytes, WalRedoError> {
         let (rel, blknum) = key_to_rel_block(key).or(Err(WalRedoError::InvalidRecord))?;
 
+        let mut process = self.get_process()?;
         let start_time = Instant::now();
 
-        let mut process_guard = self.process.lock().unwrap();
-        let lock_time = Instant::now();
-
-        // launch the WAL redo process on first use
-        if process_guard.is_none() {
-            let p = PostgresRedoProcess::launch(self.conf, &self.tenantid)?;
-            *process_guard = Some(p);
-        }
-        let process = process_guard.as_mut().unwrap();
-
-        WAL_REDO_WAIT_TIME.observe(lock_time.duration_since(start_time).as_secs_f64());
-
         // Relational WAL records are applied using wal-redo-postgres
         let buf_tag = BufferTag { rel, blknum };
         let result = process
             .apply_wal_records(buf_tag, base_img, records, wal_redo_timeout)
             .map_err(WalRedoError::IoError);
+        if result.is_ok() {
+            process.reusable = true;
+        }
 
         let end_time = Instant::now();
-        let duration = end_time.duration_since(lock_time);
+        let duration = end_time.duration_since(start_time);
         WAL_REDO_TIME.observe(duration.as_secs_f64());
         debug!(
             "postgres applied {} WAL records in {} us to reconstruct page image at LSN {}",
@@ -280,22 +433,21 @@ impl PostgresRedoManager {
             lsn
         );
 
-        // If something went wrong, don't try to reuse the process. Kill it, and
-        // next request will launch a new one.
+        // If something went wrong, the process is not reused: it's killed when
+        // dropped, and a later request will launch a new one.
         if result.is_err() {
             error!(
                 "error applying {} WAL records to reconstruct page image at LSN {}",
                 records.len(),
                 lsn
             );
-            let process = process_guard.take().unwrap();
-            process.kill();
         }
         result
     }
 
     ///
-    /// Process a batch of WAL records using bespoken Zenith code.
+    /// Process a batch of WAL records using bespoken Zenith code. 'decoded'
+    /// has the Postgres records of the batch, decoded by decode_for_zenith.
     ///
     fn apply_batch_zenith(
         &self,
@@ -303,6 +455,7 @@ impl PostgresRedoManager {
         lsn: Lsn,
         base_img: Option<Bytes>,
         records: &[(Lsn, ZenithWalRecord)],
+        decoded: &[Option<DecodedWALRecord>],
     ) -> Result<Bytes, WalRedoError> {
         let start_time = Instant::now();
 
@@ -310,15 +463,15 @@ impl PostgresRedoManager {
         if let Some(fpi) = base_img {
             // If full-page image is provided, then use it...
             page.extend_from_slice(&fpi[..]);
-        } else {
-            // All the current WAL record types that we can handle require a base image.
+        } else if !records[0].1.will_init() {
+            // ... otherwise the first record must initialize the page.
             error!("invalid zenith WAL redo request with no base image");
             return Err(WalRedoError::InvalidRequest);
         }
 
         // Apply all the WAL records in the batch
-        for (record_lsn, record) in records.iter() {
-            self.apply_record_zenith(key, &mut page, *record_lsn, record)?;
+        for ((record_lsn, record), decoded) in records.iter().zip(decoded) {
+            self.apply_record_zenith(key, &mut page, *record_lsn, record, decoded.as_ref())?;
         }
         // Success!
         let end_time = Instant::now();
@@ -339,16 +492,15 @@ impl PostgresRedoManager {
         &self,
         key: Key,
         page: &mut BytesMut,
-        _record_lsn: Lsn,
+        record_lsn: Lsn,
         record: &ZenithWalRecord,
+        decoded: Option<&DecodedWALRecord>,
     ) -> Result<(), WalRedoError> {
         match record {
-            ZenithWalRecord::Postgres {
-                will_init: _,
-                rec: _,
-            } => {
-                error!("tried to pass postgres wal record to zenith WAL redo");
-                return Err(WalRedoError::InvalidRequest);
+            ZenithWalRecord::Postgres { .. } => {
+                let (rel, blknum) = key_to_rel_block(key).or(Err(WalRedoError::InvalidRecord))?;
+                let decoded = decoded.ok_or(WalRedoError::InvalidRecord)?;
+                heapam::apply(rel, blknum, page, record_lsn, decoded)?;
             }
             ZenithWalRecord::ClearVisibilityMapFlags {
                 new_heap_blkno,
@@ -554,10 +706,63 @@ impl PostgresRedoManager {
     }
 }
 
+impl Drop for PostgresRedoManager {
+    fn drop(&mut self) {
+        // The idle processes exit when their stdin gets closed
+        let pool = self.pool.get_mut().unwrap();
+        WAL_REDO_PROCESSES.sub(pool.idle.len() as i64);
+    }
+}
+
+///
+/// A process taken from the pool of a PostgresRedoManager, returned to the
+/// pool when dropped. Unless marked reusable after a successful request, the
+/// process is killed instead, so that an error or a panic in the middle of a
+/// request doesn't leave the process in an unknown state or leak its slot.
+///
+struct PooledRedoProcess<'a> {
+    manager: &'a PostgresRedoManager,
+    process: Option<PostgresRedoProcess>,
+    reusable: bool,
+}
+
+impl<'a> PooledRedoProcess<'a> {
+    fn new(manager: &'a PostgresRedoManager, process: PostgresRedoProcess) -> Self {
+        PooledRedoProcess {
+            manager,
+            process: Some(process),
+            reusable: false,
+        }
+    }
+}
+
+impl Deref for PooledRedoProcess<'_> {
+    type Target = PostgresRedoProcess;
+
+    fn deref(&self) -> &PostgresRedoProcess {
+        self.process.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledRedoProcess<'_> {
+    fn deref_mut(&mut self) -> &mut PostgresRedoProcess {
+        self.process.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledRedoProcess<'_> {
+    fn drop(&mut self) {
+        if let Some(process) = self.process.take() {
+            self.manager.return_process(process, !self.reusable);
+        }
+    }
+}
+
 ///
 /// Handle to the Postgres WAL redo process
 ///
 struct PostgresRedoProcess {
+    slot: usize,
     child: Child,
     stdin: ChildStdin,
     stdout: ChildStdout,
@@ -568,11 +773,20 @@ impl PostgresRedoProcess {
     //
     // Start postgres binary in special WAL redo mode.
     //
-    fn launch(conf: &PageServerConf, tenantid: &ZTenantId) -> Result<PostgresRedoProcess, Error> {
-        // FIXME: We need a dummy Postgres cluster to run the process in. Currently, we
-        // just create one with constant name. That fails if you try to launch more than
-        // one WAL redo manager concurrently.
-        let datadir = conf.tenant_path(tenantid).join("wal-redo-datadir");
+    fn launch(
+        conf: &PageServerConf,
+        tenantid: &ZTenantId,
+        slot: usize,
+    ) -> Result<PostgresRedoProcess, Error> {
+        // We need a dummy Postgres cluster to run the process in. Each slot of
+        // the process pool gets its own, the first one keeps the name used
+        // before there was a pool.
+        let datadir = if slot == 0 {
+            conf.tenant_path(tenantid).join("wal-redo-datadir")
+        } else {
+            conf.tenant_path(tenantid)
+                .join(format!("wal-redo-datadir.{slot}"))
+        };
 
         // Create empty data directory for wal-redo postgres, deleting old one first.
         if datadir.exists() {
@@ -642,6 +856,7 @@ impl PostgresRedoProcess {
         set_nonblock(stderr.as_raw_fd())?;
 
         Ok(PostgresRedoProcess {
+            slot,
             child,
             stdin,
             stdout,
@@ -829,3 +1044,401 @@ fn build_get_page_msg(tag: BufferTag, buf: &mut Vec<u8>) {
     tag.ser_into(buf)
         .expect("serialize BufferTag should always succeed");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgdatadir_mapping::rel_block_to_key;
+    use postgres_ffi::xlog_utils::XLOG_RECORD_CRC_OFFS;
+    use postgres_ffi::BlockNumber;
+
+    const TEST_XID: u32 = 1000;
+    const TEST_REL: RelTag = RelTag {
+        forknum: pg_constants::MAIN_FORKNUM,
+        spcnode: pg_constants::DEFAULTTABLESPACE_OID,
+        dbnode: 13000,
+        relnode: 16384,
+    };
+
+    /// Block reference of a WAL record built by `build_record`
+    #[derive(Default)]
+    struct TestBlock {
+        blkno: BlockNumber,
+        will_init: bool,
+        // page, hole offset and hole length of the full-page image
+        image: Option<(Bytes, u16, u16)>,
+        data: Vec<u8>,
+    }
+
+    /// Assemble a WAL record, like XLogRecordAssemble does
+    fn build_record(rmid: u8, info: u8, blocks: &[TestBlock], main_data: &[u8]) -> Bytes {
+        let mut headers: Vec<u8> = Vec::new();
+        let mut payload: Vec<u8> = Vec::new();
+        for (block_id, blk) in blocks.iter().enumerate() {
+            let mut fork_flags = TEST_REL.forknum;
+            if blk.image.is_some() {
+                fork_flags |= pg_constants::BKPBLOCK_HAS_IMAGE;
+            }
+            if !blk.data.is_empty() {
+                fork_flags |= pg_constants::BKPBLOCK_HAS_DATA;
+            }
+            if blk.will_init {
+                fork_flags |= pg_constants::BKPBLOCK_WILL_INIT;
+            }
+            if block_id > 0 {
+                fork_flags |= pg_constants::BKPBLOCK_SAME_REL;
+            }
+            headers.put_u8(block_id as u8);
+            headers.put_u8(fork_flags);
+            headers.put_u16_le(blk.data.len() as u16);
+            if let Some((page, hole_offset, hole_length)) = &blk.image {
+                let mut bimg_info = pg_constants::BKPIMAGE_APPLY;
+                if *hole_length > 0 {
+                    bimg_info |= pg_constants::BKPIMAGE_HAS_HOLE;
+                }
+                headers.put_u16_le(pg_constants::BLCKSZ - hole_length);
+                headers.put_u16_le(*hole_offset);
+                headers.put_u8(bimg_info);
+                payload.extend_from_slice(&page[..*hole_offset as usize]);
+                payload.extend_from_slice(&page[(hole_offset + hole_length) as usize..]);
+            }
+            if block_id == 0 {
+                headers.put_u32_le(TEST_REL.spcnode);
+                headers.put_u32_le(TEST_REL.dbnode);
+                headers.put_u32_le(TEST_REL.relnode);
+            }
+            headers.put_u32_le(blk.blkno);
+            payload.extend_from_slice(&blk.data);
+        }
+        if !main_data.is_empty() {
+            headers.put_u8(pg_constants::XLR_BLOCK_ID_DATA_SHORT);
+            headers.put_u8(main_data.len() as u8);
+            payload.extend_from_slice(main_data);
+        }
+
+        let mut rec: Vec<u8> = Vec::new();
+        rec.put_u32_le((XLOG_RECORD_CRC_OFFS + 4 + headers.len() + payload.len()) as u32);
+        rec.put_u32_le(TEST_XID);
+        rec.put_u64_le(0); // xl_prev
+        rec.put_u8(info);
+        rec.put_u8(rmid);
+        rec.put_u16_le(0);
+        rec.put_u32_le(0); // xl_crc, filled in below
+        rec.extend_from_slice(&headers);
+        rec.extend_from_slice(&payload);
+
+        let crc = crc32c::crc32c_append(0, &rec[XLOG_RECORD_CRC_OFFS + 4..]);
+        let crc = crc32c::crc32c_append(crc, &rec[..XLOG_RECORD_CRC_OFFS]);
+        LittleEndian::write_u32(&mut rec[XLOG_RECORD_CRC_OFFS..], crc);
+        Bytes::from(rec)
+    }
+
+    /// xl_heap_header and the tuple data. The test tuples have no nulls, so
+    /// there's just one byte of padding before the user data.
+    fn heap_tuple(user_data: &[u8]) -> Vec<u8> {
+        let mut tuple = Vec::new();
+        tuple.put_u16_le(2); // t_infomask2, number of attributes
+        tuple.put_u16_le(pg_constants::HEAP_XMAX_INVALID);
+        tuple.put_u8(24); // t_hoff
+        tuple.put_u8(0);
+        tuple.extend_from_slice(user_data);
+        tuple
+    }
+
+    fn heap_insert(
+        blkno: BlockNumber,
+        offnum: u16,
+        flags: u8,
+        init: bool,
+        user_data: &[u8],
+    ) -> Bytes {
+        let mut main_data = Vec::new();
+        main_data.put_u16_le(offnum);
+        main_data.put_u8(flags);
+        let info = if init {
+            pg_constants::XLOG_HEAP_INSERT | pg_constants::XLOG_HEAP_INIT_PAGE
+        } else {
+            pg_constants::XLOG_HEAP_INSERT
+        };
+        let block = TestBlock {
+            blkno,
+            will_init: init,
+            data: heap_tuple(user_data),
+            ..Default::default()
+        };
+        build_record(pg_constants::RM_HEAP_ID, info, &[block], &main_data)
+    }
+
+    fn heap_multi_insert(blkno: BlockNumber, offsets: &[u16], user_data: &[&[u8]]) -> Bytes {
+        let mut main_data = Vec::new();
+        main_data.put_u8(0); // flags
+        main_data.put_u8(0);
+        main_data.put_u16_le(offsets.len() as u16);
+        let mut data = Vec::new();
+        for (offnum, user_data) in offsets.iter().zip(user_data) {
+            main_data.put_u16_le(*offnum);
+            if data.len() % 2 == 1 {
+                data.put_u8(0);
+            }
+            data.put_u16_le(user_data.len() as u16 + 1);
+            data.put_u16_le(2);
+            data.put_u16_le(pg_constants::HEAP_XMAX_INVALID);
+            data.put_u8(24);
+            data.put_u8(0);
+            data.extend_from_slice(user_data);
+        }
+        let block = TestBlock {
+            blkno,
+            data,
+            ..Default::default()
+        };
+        build_record(
+            pg_constants::RM_HEAP2_ID,
+            pg_constants::XLOG_HEAP2_MULTI_INSERT,
+            &[block],
+            &main_data,
+        )
+    }
+
+    fn heap_delete(blkno: BlockNumber, offnum: u16, infobits_set: u8, flags: u8) -> Bytes {
+        let mut main_data = Vec::new();
+        main_data.put_u32_le(TEST_XID); // xmax
+        main_data.put_u16_le(offnum);
+        main_data.put_u16_le(0);
+        main_data.put_u32_le(3); // t_cid
+        main_data.put_u8(infobits_set);
+        main_data.put_u8(flags);
+        let block = TestBlock {
+            blkno,
+            ..Default::default()
+        };
+        build_record(
+            pg_constants::RM_HEAP_ID,
+            pg_constants::XLOG_HEAP_DELETE,
+            &[block],
+            &main_data,
+        )
+    }
+
+    fn heap_update(
+        info: u8,
+        new: TestBlock,
+        old_blkno: Option<BlockNumber>,
+        old_offnum: u16,
+        new_offnum: u16,
+        flags: u8,
+        prefix_suffix: (u16, u16),
+    ) -> Bytes {
+        let mut main_data = Vec::new();
+        main_data.put_u32_le(TEST_XID); // old_xmax
+        main_data.put_u16_le(old_offnum);
+        main_data.put_u8(pg_constants::XLHL_KEYS_UPDATED);
+        main_data.put_u8(flags);
+        main_data.put_u32_le(2); // t_cid
+        main_data.put_u32_le(0); // new_xmax
+        main_data.put_u16_le(new_offnum);
+
+        let (prefixlen, suffixlen) = prefix_suffix;
+        let mut data = Vec::new();
+        if flags & pg_constants::XLH_UPDATE_PREFIX_FROM_OLD != 0 {
+            data.put_u16_le(prefixlen);
+        }
+        if flags & pg_constants::XLH_UPDATE_SUFFIX_FROM_OLD != 0 {
+            data.put_u16_le(suffixlen);
+        }
+        data.extend_from_slice(&new.data);
+        let mut blocks = vec![TestBlock { data, ..new }];
+        if let Some(blkno) = old_blkno {
+            blocks.push(TestBlock {
+                blkno,
+                ..Default::default()
+            });
+        }
+        build_record(pg_constants::RM_HEAP_ID, info, &blocks, &main_data)
+    }
+
+    /// Block reference with a full-page image, with the hole between pd_lower and pd_upper
+    fn image_block(blkno: BlockNumber, page: &Bytes) -> TestBlock {
+        let lower = LittleEndian::read_u16(&page[12..14]);
+        let upper = LittleEndian::read_u16(&page[14..16]);
+        TestBlock {
+            blkno,
+            image: Some((page.clone(), lower, upper - lower)),
+            ..Default::default()
+        }
+    }
+
+    fn postgres_record(rec: Bytes) -> ZenithWalRecord {
+        let decoded = crate::walrecord::decode_wal_record(rec.clone()).unwrap();
+        ZenithWalRecord::Postgres {
+            will_init: decoded.blocks[0].will_init || decoded.blocks[0].apply_image,
+            rec,
+        }
+    }
+
+    /// Replay every prefix of 'records' both natively and with the WAL redo
+    /// process, and check that they produce the same page.
+    fn check_same_result(
+        mgr: &PostgresRedoManager,
+        blkno: BlockNumber,
+        records: &[(Lsn, ZenithWalRecord)],
+    ) {
+        let key = rel_block_to_key(TEST_REL, blkno);
+        for n in 1..=records.len() {
+            let lsn = records[n - 1].0;
+            let native = mgr
+                .apply_batch_zenith(
+                    key,
+                    lsn,
+                    None,
+                    &records[..n],
+                    &decode_for_zenith(&records[..n]),
+                )
+                .unwrap();
+            let postgres = mgr
+                .apply_batch_postgres(key, lsn, None, &records[..n], Duration::from_secs(60))
+                .unwrap();
+            assert_eq!(
+                native, postgres,
+                "blk {} differs after {} records",
+                blkno, n
+            );
+        }
+    }
+
+    // Needs the Postgres binaries in tmp_install or POSTGRES_DISTRIB_DIR,
+    // the test is skipped without them.
+    #[test]
+    fn heap_redo_matches_postgres() {
+        let repo_dir = PageServerConf::test_repo_dir("heap_redo_matches_postgres");
+        let _ = fs::remove_dir_all(&repo_dir);
+        let mut conf = PageServerConf::dummy_conf(repo_dir);
+        conf.pg_distrib_dir = std::env::var("POSTGRES_DISTRIB_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("../tmp_install"));
+        if !conf.pg_bin_dir().join("postgres").exists() {
+            eprintln!(
+                "skipping heap_redo_matches_postgres: no postgres in {}, set POSTGRES_DISTRIB_DIR",
+                conf.pg_distrib_dir.display()
+            );
+            return;
+        }
+        let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+        let tenantid = ZTenantId::generate();
+        fs::create_dir_all(conf.tenant_path(&tenantid)).unwrap();
+        let mgr = PostgresRedoManager::new(conf, tenantid);
+
+        let mut lsn = Lsn(0x0100_0000);
+        let mut next_lsn = || {
+            lsn += 0x100;
+            lsn
+        };
+
+        // Build up a page with inserts, updates and deletes
+        let mut records = vec![
+            heap_insert(0, 1, 0, true, b"0123456789abcdef"),
+            heap_insert(
+                0,
+                2,
+                pg_constants::XLH_INSERT_ALL_VISIBLE_CLEARED,
+                false,
+                b"second",
+            ),
+            heap_multi_insert(0, &[3, 4, 5], &[b"a", b"bb", b"ccc"]),
+            heap_update(
+                pg_constants::XLOG_HEAP_UPDATE,
+                TestBlock {
+                    data: heap_tuple(b"XYZ"),
+                    ..Default::default()
+                },
+                None,
+                1,
+                6,
+                pg_constants::XLH_UPDATE_PREFIX_FROM_OLD | pg_constants::XLH_UPDATE_SUFFIX_FROM_OLD,
+                (4, 4),
+            ),
+            heap_update(
+                pg_constants::XLOG_HEAP_HOT_UPDATE,
+                TestBlock {
+                    data: heap_tuple(b"second, updated"),
+                    ..Default::default()
+                },
+                None,
+                2,
+                7,
+                0,
+                (0, 0),
+            ),
+            heap_delete(
+                0,
+                3,
+                pg_constants::XLHL_XMAX_EXCL_LOCK | pg_constants::XLHL_KEYS_UPDATED,
+                pg_constants::XLH_DELETE_ALL_VISIBLE_CLEARED,
+            ),
+        ]
+        .into_iter()
+        .map(|rec| (next_lsn(), postgres_record(rec)))
+        .collect::<Vec<_>>();
+        assert!(decode_for_zenith(&records).iter().all(Option::is_some));
+
+        // Then restore a full-page image, first in a heap record and then
+        // in an FPI record, and continue from there
+        let key = rel_block_to_key(TEST_REL, 0);
+        let image = mgr
+            .apply_batch_zenith(
+                key,
+                records[2].0,
+                None,
+                &records[..3],
+                &decode_for_zenith(&records[..3]),
+            )
+            .unwrap();
+        let mut main_data = Vec::new();
+        main_data.put_u16_le(6); // offnum
+        main_data.put_u8(0); // flags
+        for rec in [
+            build_record(
+                pg_constants::RM_HEAP_ID,
+                pg_constants::XLOG_HEAP_INSERT,
+                &[image_block(0, &image)],
+                &main_data,
+            ),
+            heap_insert(0, 6, 0, false, b"after image"),
+            build_record(
+                pg_constants::RM_XLOG_ID,
+                pg_constants::XLOG_FPI_FOR_HINT,
+                &[image_block(0, &image)],
+                &[],
+            ),
+            heap_delete(0, 1, 0, 0),
+        ] {
+            records.push((next_lsn(), postgres_record(rec)));
+        }
+        check_same_result(&mgr, 0, &records);
+
+        // An update that moves the tuple to another page
+        let records = vec![
+            heap_insert(1, 1, 0, true, b"old version"),
+            heap_update(
+                pg_constants::XLOG_HEAP_UPDATE | pg_constants::XLOG_HEAP_INIT_PAGE,
+                TestBlock {
+                    blkno: 2,
+                    will_init: true,
+                    data: heap_tuple(b"new version"),
+                    ..Default::default()
+                },
+                Some(1),
+                1,
+                1,
+                pg_constants::XLH_UPDATE_OLD_ALL_VISIBLE_CLEARED
+                    | pg_constants::XLH_UPDATE_NEW_ALL_VISIBLE_CLEARED,
+                (0, 0),
+            ),
+        ]
+        .into_iter()
+        .map(|rec| (next_lsn(), postgres_record(rec)))
+        .collect::<Vec<_>>();
+        check_same_result(&mgr, 1, &records);
+        check_same_result(&mgr, 2, &records[1..]);
+    }
+}
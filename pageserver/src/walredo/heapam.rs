@@ -0,0 +1,768 @@
+//!
+//! Replay of the most common Postgres WAL records in the page server, without
+//! the WAL redo process: full-page images, and heap inserts, multi-inserts,
+//! deletes and updates.
+//!
+//! The functions here are ports of heap_xlog_insert(), heap_xlog_delete(),
+//! heap_xlog_update() and heap_xlog_multi_insert() from heapam.c, and of the
+//! bufpage.c routines they use. They must produce exactly the same page images
+//! as the Postgres redo routines. Anything they don't know how to handle is
+//! left to the WAL redo process, see `can_apply`.
+//!
+//! Page and tuple header fields are accessed at their offsets in the C structs,
+//! assuming a little-endian machine, like elsewhere in the page server.
+//!
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Bytes, BytesMut};
+use postgres_ffi::pg_constants;
+use postgres_ffi::{page_get_lsn, page_is_new, page_set_lsn, transaction_id_precedes};
+use postgres_ffi::{BlockNumber, OffsetNumber, TransactionId};
+use tracing::*;
+use utils::lsn::Lsn;
+
+use super::WalRedoError;
+use crate::reltag::RelTag;
+use crate::walrecord::{
+    decode_wal_record, DecodedBkpBlock, DecodedWALRecord, XlHeapDelete, XlHeapHeader, XlHeapInsert,
+    XlHeapMultiInsert, XlHeapUpdate, XlMultiInsertTuple, SIZE_OF_HEAP_HEADER,
+    SIZE_OF_MULTI_INSERT_TUPLE,
+};
+
+// Offsets of the PageHeaderData fields
+const PD_FLAGS: usize = 10;
+const PD_LOWER: usize = 12;
+const PD_UPPER: usize = 14;
+const PD_SPECIAL: usize = 16;
+const PD_PAGESIZE_VERSION: usize = 18;
+const PD_PRUNE_XID: usize = 20;
+
+// Offsets of the HeapTupleHeaderData fields
+const T_XMIN: usize = 0;
+const T_XMAX: usize = 4;
+const T_CID: usize = 8;
+const T_CTID: usize = 12;
+const T_INFOMASK2: usize = 18;
+const T_INFOMASK: usize = 20;
+const T_HOFF: usize = 22;
+
+// See FirstCommandId in c.h
+const FIRST_COMMAND_ID: u32 = 0;
+
+///
+/// Decode a Postgres WAL record, if it can be replayed by `apply`.
+///
+pub fn decode_if_supported(rec: &Bytes) -> Option<DecodedWALRecord> {
+    let decoded = decode_wal_record(rec.clone()).ok()?;
+    if can_apply(&decoded) {
+        Some(decoded)
+    } else {
+        None
+    }
+}
+
+///
+/// Can this decoded Postgres WAL record be replayed by `apply`?
+///
+pub fn can_apply(decoded: &DecodedWALRecord) -> bool {
+    let supported = match decoded.xl_rmid {
+        pg_constants::RM_XLOG_ID => {
+            let info = decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK;
+            info == pg_constants::XLOG_FPI || info == pg_constants::XLOG_FPI_FOR_HINT
+        }
+        pg_constants::RM_HEAP_ID => matches!(
+            decoded.xl_info & pg_constants::XLOG_HEAP_OPMASK,
+            pg_constants::XLOG_HEAP_INSERT
+                | pg_constants::XLOG_HEAP_DELETE
+                | pg_constants::XLOG_HEAP_UPDATE
+                | pg_constants::XLOG_HEAP_HOT_UPDATE
+        ),
+        pg_constants::RM_HEAP2_ID => {
+            decoded.xl_info & pg_constants::XLOG_HEAP_OPMASK
+                == pg_constants::XLOG_HEAP2_MULTI_INSERT
+        }
+        _ => false,
+    };
+
+    // Compressed page images are left to Postgres, like in walingest
+    supported
+        && decoded
+            .blocks
+            .iter()
+            .all(|blk| !blk.has_image || blk.bimg_info & pg_constants::BKPIMAGE_IS_COMPRESSED == 0)
+}
+
+///
+/// Apply a decoded Postgres WAL record, accepted by `can_apply`, to the given block.
+///
+/// 'page' may be empty if the record initializes the page.
+///
+pub fn apply(
+    rel: RelTag,
+    blknum: BlockNumber,
+    page: &mut BytesMut,
+    lsn: Lsn,
+    decoded: &DecodedWALRecord,
+) -> Result<(), WalRedoError> {
+    // Find the block reference for the page we're reconstructing. Only that
+    // page is modified, the WAL redo process does the same.
+    let block_id = decoded
+        .blocks
+        .iter()
+        .position(|blk| {
+            blk.rnode_spcnode == rel.spcnode
+                && blk.rnode_dbnode == rel.dbnode
+                && blk.rnode_relnode == rel.relnode
+                && blk.forknum == rel.forknum
+                && blk.blkno == blknum
+        })
+        .ok_or_else(|| invalid_record(lsn, "record does not modify the requested block"))?;
+    let blk = &decoded.blocks[block_id];
+
+    // Like XLogReadBufferForRedo, restore the full-page image if there's one
+    if blk.apply_image {
+        return restore_block_image(page, decoded, blk, lsn);
+    }
+
+    let info = decoded.xl_info & pg_constants::XLOG_HEAP_OPMASK;
+    match decoded.xl_rmid {
+        pg_constants::RM_XLOG_ID => {
+            // An FPI_FOR_HINT record doesn't necessarily include an image of every block
+            if decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK == pg_constants::XLOG_FPI {
+                return Err(invalid_record(
+                    lsn,
+                    "XLOG_FPI record did not contain a full-page image",
+                ));
+            }
+            Ok(())
+        }
+        pg_constants::RM_HEAP_ID => match info {
+            pg_constants::XLOG_HEAP_INSERT => heap_insert(page, decoded, lsn),
+            pg_constants::XLOG_HEAP_DELETE => heap_delete(page, decoded, lsn),
+            pg_constants::XLOG_HEAP_UPDATE => heap_update(page, decoded, block_id, lsn, false),
+            pg_constants::XLOG_HEAP_HOT_UPDATE => heap_update(page, decoded, block_id, lsn, true),
+            _ => Err(invalid_record(lsn, "unsupported heap record")),
+        },
+        pg_constants::RM_HEAP2_ID if info == pg_constants::XLOG_HEAP2_MULTI_INSERT => {
+            heap_multi_insert(page, decoded, lsn)
+        }
+        _ => Err(invalid_record(lsn, "unsupported record")),
+    }
+}
+
+fn invalid_record(lsn: Lsn, msg: &str) -> WalRedoError {
+    error!("cannot apply WAL record at {}: {}", lsn, msg);
+    WalRedoError::InvalidRecord
+}
+
+/// Check that the page has been initialized by the caller or an earlier record
+fn check_page(page: &BytesMut, lsn: Lsn) -> Result<(), WalRedoError> {
+    if page.len() != pg_constants::BLCKSZ as usize {
+        return Err(invalid_record(
+            lsn,
+            "no valid page image to apply the record to",
+        ));
+    }
+    Ok(())
+}
+
+/// Like XLogReadBufferForRedo, skip records that are already reflected on the page
+fn needs_redo(page: &[u8], lsn: Lsn) -> bool {
+    lsn > page_get_lsn(page)
+}
+
+fn main_data(decoded: &DecodedWALRecord) -> Bytes {
+    decoded.record.slice(decoded.main_data_offset..)
+}
+
+fn block_data(decoded: &DecodedWALRecord, blk: &DecodedBkpBlock) -> Bytes {
+    if !blk.has_data {
+        return Bytes::new();
+    }
+    let start = blk.data_offset as usize;
+    decoded.record.slice(start..start + blk.data_len as usize)
+}
+
+/// See RestoreBlockImage in xlogreader.c
+fn restore_block_image(
+    page: &mut BytesMut,
+    decoded: &DecodedWALRecord,
+    blk: &DecodedBkpBlock,
+    lsn: Lsn,
+) -> Result<(), WalRedoError> {
+    if blk.bimg_info & pg_constants::BKPIMAGE_IS_COMPRESSED != 0 {
+        return Err(invalid_record(
+            lsn,
+            "compressed page images are not supported",
+        ));
+    }
+    let img_offs = blk.bimg_offset as usize;
+    let image = &decoded.record[img_offs..img_offs + blk.bimg_len as usize];
+    let hole_offset = blk.hole_offset as usize;
+
+    page.clear();
+    page.extend_from_slice(&image[..hole_offset]);
+    page.resize(hole_offset + blk.hole_length as usize, 0u8);
+    page.extend_from_slice(&image[hole_offset..]);
+    if page.len() != pg_constants::BLCKSZ as usize {
+        return Err(invalid_record(lsn, "invalid page image size"));
+    }
+
+    // The page may be uninitialized. If so, we can't set the LSN because
+    // that would corrupt the page.
+    if !page_is_new(page) {
+        page_set_lsn(page, lsn);
+    }
+    Ok(())
+}
+
+//
+// Page manipulation, see bufpage.h and bufpage.c
+//
+
+fn get_u16(buf: &[u8], off: usize) -> u16 {
+    LittleEndian::read_u16(&buf[off..off + 2])
+}
+
+fn put_u16(buf: &mut [u8], off: usize, val: u16) {
+    LittleEndian::write_u16(&mut buf[off..off + 2], val)
+}
+
+fn get_u32(buf: &[u8], off: usize) -> u32 {
+    LittleEndian::read_u32(&buf[off..off + 4])
+}
+
+fn put_u32(buf: &mut [u8], off: usize, val: u32) {
+    LittleEndian::write_u32(&mut buf[off..off + 4], val)
+}
+
+/// See PageInit
+fn page_init(page: &mut BytesMut) {
+    page.clear();
+    page.resize(pg_constants::BLCKSZ as usize, 0u8);
+    put_u16(page, PD_LOWER, pg_constants::SIZE_OF_PAGE_HEADER);
+    put_u16(page, PD_UPPER, pg_constants::BLCKSZ);
+    put_u16(page, PD_SPECIAL, pg_constants::BLCKSZ);
+    put_u16(
+        page,
+        PD_PAGESIZE_VERSION,
+        pg_constants::BLCKSZ | pg_constants::PG_PAGE_LAYOUT_VERSION,
+    );
+}
+
+/// See PageGetMaxOffsetNumber
+fn page_get_max_offset_number(page: &[u8]) -> OffsetNumber {
+    let lower = get_u16(page, PD_LOWER);
+    if lower <= pg_constants::SIZE_OF_PAGE_HEADER {
+        0
+    } else {
+        (lower - pg_constants::SIZE_OF_PAGE_HEADER) / 4
+    }
+}
+
+fn item_id_offset(offnum: OffsetNumber) -> usize {
+    pg_constants::SIZE_OF_PAGE_HEADER as usize + (offnum as usize - 1) * 4
+}
+
+/// Unpacked ItemIdData: (lp_off, lp_flags, lp_len)
+fn page_get_item_id(page: &[u8], offnum: OffsetNumber) -> (usize, u8, usize) {
+    let lp = get_u32(page, item_id_offset(offnum));
+    (
+        (lp & 0x7fff) as usize,
+        ((lp >> 15) & 0x03) as u8,
+        (lp >> 17) as usize,
+    )
+}
+
+fn page_set_item_id(page: &mut [u8], offnum: OffsetNumber, off: usize, flags: u8, len: usize) {
+    let lp = (off as u32 & 0x7fff) | ((flags as u32 & 0x03) << 15) | ((len as u32) << 17);
+    put_u32(page, item_id_offset(offnum), lp);
+}
+
+/// Find the tuple at 'offnum', which must be a normal line pointer.
+/// Returns the offset and length of the tuple on the page.
+fn page_get_normal_item(
+    page: &[u8],
+    offnum: OffsetNumber,
+    lsn: Lsn,
+) -> Result<(usize, usize), WalRedoError> {
+    if offnum == 0 || page_get_max_offset_number(page) < offnum {
+        return Err(invalid_record(lsn, "invalid lp"));
+    }
+    let (off, flags, len) = page_get_item_id(page, offnum);
+    if flags != pg_constants::LP_NORMAL
+        || len < pg_constants::SIZEOF_HEAP_TUPLE_HEADER
+        || off + len > page.len()
+    {
+        return Err(invalid_record(lsn, "invalid lp"));
+    }
+    Ok((off, len))
+}
+
+/// See PageAddItemExtended, called with PAI_OVERWRITE | PAI_IS_HEAP, which is
+/// what the heap redo functions use.
+fn page_add_item(
+    page: &mut [u8],
+    item: &[u8],
+    offnum: OffsetNumber,
+    lsn: Lsn,
+) -> Result<(), WalRedoError> {
+    let lower = get_u16(page, PD_LOWER) as usize;
+    let upper = get_u16(page, PD_UPPER) as usize;
+    let special = get_u16(page, PD_SPECIAL) as usize;
+    if lower < pg_constants::SIZE_OF_PAGE_HEADER as usize
+        || lower > upper
+        || upper > special
+        || special > pg_constants::BLCKSZ as usize
+    {
+        return Err(invalid_record(lsn, "corrupted page pointers"));
+    }
+
+    let limit = page_get_max_offset_number(page) + 1;
+    if offnum == 0 {
+        return Err(invalid_record(lsn, "invalid item offset"));
+    }
+    if offnum < limit {
+        let (_, flags, len) = page_get_item_id(page, offnum);
+        if flags != pg_constants::LP_UNUSED || len != 0 {
+            return Err(invalid_record(lsn, "will not overwrite a used ItemId"));
+        }
+    }
+    if offnum > limit {
+        return Err(invalid_record(lsn, "specified item offset is too large"));
+    }
+    if offnum > pg_constants::MAX_HEAP_TUPLES_PER_PAGE {
+        return Err(invalid_record(
+            lsn,
+            "can't put more than MaxHeapTuplesPerPage items in a heap page",
+        ));
+    }
+
+    let new_lower = if offnum == limit { lower + 4 } else { lower };
+    let aligned_size = (item.len() + 7) & !7;
+    if upper < aligned_size || new_lower > upper - aligned_size {
+        return Err(invalid_record(lsn, "failed to add tuple"));
+    }
+    let new_upper = upper - aligned_size;
+
+    page_set_item_id(page, offnum, new_upper, pg_constants::LP_NORMAL, item.len());
+    page[new_upper..new_upper + item.len()].copy_from_slice(item);
+    put_u16(page, PD_LOWER, new_lower as u16);
+    put_u16(page, PD_UPPER, new_upper as u16);
+    Ok(())
+}
+
+/// See PageSetPrunable
+fn page_set_prunable(page: &mut [u8], xid: TransactionId) {
+    let prune_xid = get_u32(page, PD_PRUNE_XID);
+    if prune_xid == pg_constants::INVALID_TRANSACTION_ID || transaction_id_precedes(xid, prune_xid)
+    {
+        put_u32(page, PD_PRUNE_XID, xid);
+    }
+}
+
+fn page_clear_all_visible(page: &mut [u8]) {
+    let flags = get_u16(page, PD_FLAGS);
+    put_u16(page, PD_FLAGS, flags & !pg_constants::PD_ALL_VISIBLE);
+}
+
+fn page_set_all_visible(page: &mut [u8]) {
+    let flags = get_u16(page, PD_FLAGS);
+    put_u16(page, PD_FLAGS, flags | pg_constants::PD_ALL_VISIBLE);
+}
+
+//
+// Heap tuples, see htup_details.h
+//
+
+fn tuple_set_ctid(tuple: &mut [u8], blkno: BlockNumber, offnum: OffsetNumber) {
+    put_u16(tuple, T_CTID, (blkno >> 16) as u16);
+    put_u16(tuple, T_CTID + 2, blkno as u16);
+    put_u16(tuple, T_CTID + 4, offnum);
+}
+
+/// HeapTupleHeaderSetCmin and HeapTupleHeaderSetCmax, for a non-combo CID
+fn tuple_set_cid(tuple: &mut [u8], cid: u32) {
+    put_u32(tuple, T_CID, cid);
+    let infomask = get_u16(tuple, T_INFOMASK);
+    put_u16(tuple, T_INFOMASK, infomask & !pg_constants::HEAP_COMBOCID);
+}
+
+/// Build a tuple inserted by a WAL record: the header fields that are not
+/// WAL-logged are zero, except for the ones the heap redo functions set.
+fn new_heap_tuple(
+    t_infomask2: u16,
+    t_infomask: u16,
+    t_hoff: u8,
+    xmin: TransactionId,
+    blkno: BlockNumber,
+    offnum: OffsetNumber,
+    data_len: usize,
+) -> Vec<u8> {
+    let mut tuple = vec![0u8; pg_constants::SIZEOF_HEAP_TUPLE_HEADER];
+    tuple.reserve(data_len);
+    put_u16(&mut tuple, T_INFOMASK2, t_infomask2);
+    put_u16(&mut tuple, T_INFOMASK, t_infomask);
+    tuple[T_HOFF] = t_hoff;
+    put_u32(&mut tuple, T_XMIN, xmin);
+    tuple_set_cid(&mut tuple, FIRST_COMMAND_ID);
+    tuple_set_ctid(&mut tuple, blkno, offnum);
+    tuple
+}
+
+/// Clear the xmax bits of a tuple being deleted or updated, and set the ones
+/// WAL-logged in 'infobits_set'. See fix_infomask_from_infobits.
+fn tuple_reset_xmax_bits(tuple: &mut [u8], infobits_set: u8) {
+    let mut infomask = get_u16(tuple, T_INFOMASK);
+    let mut infomask2 = get_u16(tuple, T_INFOMASK2);
+
+    infomask &= !(pg_constants::HEAP_XMAX_BITS | pg_constants::HEAP_MOVED);
+    infomask2 &= !pg_constants::HEAP_KEYS_UPDATED;
+
+    if infobits_set & pg_constants::XLHL_XMAX_IS_MULTI != 0 {
+        infomask |= pg_constants::HEAP_XMAX_IS_MULTI;
+    }
+    if infobits_set & pg_constants::XLHL_XMAX_LOCK_ONLY != 0 {
+        infomask |= pg_constants::HEAP_XMAX_LOCK_ONLY;
+    }
+    if infobits_set & pg_constants::XLHL_XMAX_EXCL_LOCK != 0 {
+        infomask |= pg_constants::HEAP_XMAX_EXCL_LOCK;
+    }
+    // note HEAP_XMAX_SHR_LOCK isn't considered here
+    if infobits_set & pg_constants::XLHL_XMAX_KEYSHR_LOCK != 0 {
+        infomask |= pg_constants::HEAP_XMAX_KEYSHR_LOCK;
+    }
+    if infobits_set & pg_constants::XLHL_KEYS_UPDATED != 0 {
+        infomask2 |= pg_constants::HEAP_KEYS_UPDATED;
+    }
+
+    put_u16(tuple, T_INFOMASK, infomask);
+    put_u16(tuple, T_INFOMASK2, infomask2);
+}
+
+//
+// Heap redo functions, see heapam.c
+//
+
+/// See heap_xlog_insert
+fn heap_insert(
+    page: &mut BytesMut,
+    decoded: &DecodedWALRecord,
+    lsn: Lsn,
+) -> Result<(), WalRedoError> {
+    let xlrec = XlHeapInsert::decode(&mut main_data(decoded));
+    let blk = &decoded.blocks[0];
+
+    if decoded.xl_info & pg_constants::XLOG_HEAP_INIT_PAGE != 0 {
+        page_init(page);
+    } else {
+        check_page(page, lsn)?;
+        if !needs_redo(page, lsn) {
+            return Ok(());
+        }
+    }
+
+    if page_get_max_offset_number(page) + 1 < xlrec.offnum {
+        return Err(invalid_record(lsn, "invalid max offset number"));
+    }
+
+    let mut data = block_data(decoded, blk);
+    if data.len() <= SIZE_OF_HEAP_HEADER {
+        return Err(invalid_record(lsn, "invalid heap insert record"));
+    }
+    let xlhdr = XlHeapHeader::decode(&mut data);
+
+    let mut tuple = new_heap_tuple(
+        xlhdr.t_infomask2,
+        xlhdr.t_infomask,
+        xlhdr.t_hoff,
+        decoded.xl_xid,
+        blk.blkno,
+        xlrec.offnum,
+        data.len(),
+    );
+    tuple.extend_from_slice(&data);
+    page_add_item(page, &tuple, xlrec.offnum, lsn)?;
+
+    page_set_lsn(page, lsn);
+
+    if xlrec.flags & pg_constants::XLH_INSERT_ALL_VISIBLE_CLEARED != 0 {
+        page_clear_all_visible(page);
+    }
+    // XLH_INSERT_ALL_FROZEN_SET implies that all tuples are visible
+    if xlrec.flags & pg_constants::XLH_INSERT_ALL_FROZEN_SET != 0 {
+        page_set_all_visible(page);
+    }
+    Ok(())
+}
+
+/// See heap_xlog_multi_insert
+fn heap_multi_insert(
+    page: &mut BytesMut,
+    decoded: &DecodedWALRecord,
+    lsn: Lsn,
+) -> Result<(), WalRedoError> {
+    let mut main_data = main_data(decoded);
+    let xlrec = XlHeapMultiInsert::decode(&mut main_data);
+    let blk = &decoded.blocks[0];
+
+    let is_init = decoded.xl_info & pg_constants::XLOG_HEAP_INIT_PAGE != 0;
+    if is_init {
+        page_init(page);
+    } else {
+        check_page(page, lsn)?;
+        if !needs_redo(page, lsn) {
+            return Ok(());
+        }
+        if main_data.len() < 2 * xlrec.ntuples as usize {
+            return Err(invalid_record(lsn, "invalid heap multi-insert record"));
+        }
+    }
+
+    // Tuples are stored as block data, each tuple header SHORTALIGNed. The
+    // block data is MAXALIGNed in the WAL redo process, so aligning the
+    // offsets within the block data gives the same result.
+    let data = block_data(decoded, blk);
+    let mut pos = 0;
+    for i in 0..xlrec.ntuples {
+        // If we're reinitializing the page, the tuples are stored in
+        // order from FirstOffsetNumber. Otherwise there's an array of
+        // offsets in the WAL record, and the tuples come after that.
+        let offnum = if is_init {
+            i + 1
+        } else {
+            get_u16(&main_data, 2 * i as usize)
+        };
+        if page_get_max_offset_number(page) + 1 < offnum {
+            return Err(invalid_record(lsn, "invalid max offset number"));
+        }
+
+        pos = (pos + 1) & !1;
+        if pos + SIZE_OF_MULTI_INSERT_TUPLE > data.len() {
+            return Err(invalid_record(lsn, "total tuple length mismatch"));
+        }
+        let xlhdr = XlMultiInsertTuple::decode(&mut data.slice(pos..));
+        pos += SIZE_OF_MULTI_INSERT_TUPLE;
+        let datalen = xlhdr.datalen as usize;
+        if pos + datalen > data.len() {
+            return Err(invalid_record(lsn, "total tuple length mismatch"));
+        }
+
+        let mut tuple = new_heap_tuple(
+            xlhdr.t_infomask2,
+            xlhdr.t_infomask,
+            xlhdr.t_hoff,
+            decoded.xl_xid,
+            blk.blkno,
+            offnum,
+            datalen,
+        );
+        tuple.extend_from_slice(&data[pos..pos + datalen]);
+        pos += datalen;
+        page_add_item(page, &tuple, offnum, lsn)?;
+    }
+    if pos != data.len() {
+        return Err(invalid_record(lsn, "total tuple length mismatch"));
+    }
+
+    page_set_lsn(page, lsn);
+
+    if xlrec.flags & pg_constants::XLH_INSERT_ALL_VISIBLE_CLEARED != 0 {
+        page_clear_all_visible(page);
+    }
+    // XLH_INSERT_ALL_FROZEN_SET implies that all tuples are visible
+    if xlrec.flags & pg_constants::XLH_INSERT_ALL_FROZEN_SET != 0 {
+        page_set_all_visible(page);
+    }
+    Ok(())
+}
+
+/// See heap_xlog_delete
+fn heap_delete(
+    page: &mut BytesMut,
+    decoded: &DecodedWALRecord,
+    lsn: Lsn,
+) -> Result<(), WalRedoError> {
+    let xlrec = XlHeapDelete::decode(&mut main_data(decoded));
+    let blk = &decoded.blocks[0];
+
+    check_page(page, lsn)?;
+    if !needs_redo(page, lsn) {
+        return Ok(());
+    }
+
+    let (off, len) = page_get_normal_item(page, xlrec.offnum, lsn)?;
+    let tuple = &mut page[off..off + len];
+
+    tuple_reset_xmax_bits(tuple, xlrec.infobits_set);
+    let infomask2 = get_u16(tuple, T_INFOMASK2);
+    put_u16(
+        tuple,
+        T_INFOMASK2,
+        infomask2 & !pg_constants::HEAP_HOT_UPDATED,
+    );
+    if xlrec.flags & pg_constants::XLH_DELETE_IS_SUPER == 0 {
+        put_u32(tuple, T_XMAX, xlrec.xmax);
+    } else {
+        put_u32(tuple, T_XMIN, pg_constants::INVALID_TRANSACTION_ID);
+    }
+    tuple_set_cid(tuple, xlrec.t_cid);
+
+    // Make sure t_ctid is set correctly
+    if xlrec.flags & pg_constants::XLH_DELETE_IS_PARTITION_MOVE != 0 {
+        tuple_set_ctid(
+            tuple,
+            pg_constants::INVALID_BLOCK_NUMBER,
+            pg_constants::MOVED_PARTITIONS_OFFSET_NUMBER,
+        );
+    } else {
+        tuple_set_ctid(tuple, blk.blkno, xlrec.offnum);
+    }
+
+    // Mark the page as a candidate for pruning
+    page_set_prunable(page, decoded.xl_xid);
+
+    if xlrec.flags & pg_constants::XLH_DELETE_ALL_VISIBLE_CLEARED != 0 {
+        page_clear_all_visible(page);
+    }
+
+    page_set_lsn(page, lsn);
+    Ok(())
+}
+
+/// See heap_xlog_update. Block 0 is the page the new tuple goes to, and block 1
+/// the page of the old tuple if it's different. 'block_id' is the block being
+/// reconstructed.
+fn heap_update(
+    page: &mut BytesMut,
+    decoded: &DecodedWALRecord,
+    block_id: usize,
+    lsn: Lsn,
+    hot_update: bool,
+) -> Result<(), WalRedoError> {
+    let xlrec = XlHeapUpdate::decode(&mut main_data(decoded));
+    let newblk = decoded.blocks[0].blkno;
+    let same_block = decoded.blocks.len() < 2;
+
+    // Deal with old tuple version. Remember it, the new tuple can share a
+    // prefix and a suffix with it.
+    let mut old_tuple = None;
+    if block_id == 1 || same_block {
+        check_page(page, lsn)?;
+        if !needs_redo(page, lsn) {
+            return Ok(());
+        }
+
+        let (off, len) = page_get_normal_item(page, xlrec.old_offnum, lsn)?;
+        let tuple = &mut page[off..off + len];
+
+        tuple_reset_xmax_bits(tuple, xlrec.old_infobits_set);
+        let infomask2 = get_u16(tuple, T_INFOMASK2);
+        if hot_update {
+            put_u16(
+                tuple,
+                T_INFOMASK2,
+                infomask2 | pg_constants::HEAP_HOT_UPDATED,
+            );
+        } else {
+            put_u16(
+                tuple,
+                T_INFOMASK2,
+                infomask2 & !pg_constants::HEAP_HOT_UPDATED,
+            );
+        }
+        put_u32(tuple, T_XMAX, xlrec.old_xmax);
+        tuple_set_cid(tuple, xlrec.t_cid);
+        // Set forward chain link in t_ctid
+        tuple_set_ctid(tuple, newblk, xlrec.new_offnum);
+        old_tuple = Some(tuple.to_vec());
+
+        // Mark the page as a candidate for pruning
+        page_set_prunable(page, decoded.xl_xid);
+
+        if xlrec.flags & pg_constants::XLH_UPDATE_OLD_ALL_VISIBLE_CLEARED != 0 {
+            page_clear_all_visible(page);
+        }
+
+        page_set_lsn(page, lsn);
+
+        if !same_block {
+            return Ok(());
+        }
+    } else if decoded.xl_info & pg_constants::XLOG_HEAP_INIT_PAGE != 0 {
+        page_init(page);
+    } else {
+        check_page(page, lsn)?;
+        if !needs_redo(page, lsn) {
+            return Ok(());
+        }
+    }
+
+    // Deal with new tuple
+    if page_get_max_offset_number(page) + 1 < xlrec.new_offnum {
+        return Err(invalid_record(lsn, "invalid max offset number"));
+    }
+
+    let mut data = block_data(decoded, &decoded.blocks[0]);
+    let mut prefixlen = 0;
+    let mut suffixlen = 0;
+    if xlrec.flags & pg_constants::XLH_UPDATE_PREFIX_FROM_OLD != 0 && data.len() >= 2 {
+        prefixlen = get_u16(&data, 0) as usize;
+        data = data.slice(2..);
+    }
+    if xlrec.flags & pg_constants::XLH_UPDATE_SUFFIX_FROM_OLD != 0 && data.len() >= 2 {
+        suffixlen = get_u16(&data, 0) as usize;
+        data = data.slice(2..);
+    }
+    if data.len() < SIZE_OF_HEAP_HEADER {
+        return Err(invalid_record(lsn, "invalid heap update record"));
+    }
+    let xlhdr = XlHeapHeader::decode(&mut data);
+
+    let mut tuple = new_heap_tuple(
+        xlhdr.t_infomask2,
+        xlhdr.t_infomask,
+        xlhdr.t_hoff,
+        decoded.xl_xid,
+        newblk,
+        xlrec.new_offnum,
+        data.len() + prefixlen + suffixlen,
+    );
+    tuple_set_cid(&mut tuple, xlrec.t_cid);
+    put_u32(&mut tuple, T_XMAX, xlrec.new_xmax);
+
+    // Reconstruct the new tuple using the prefix and/or suffix from the
+    // old tuple, and the data stored in the WAL record.
+    if prefixlen > 0 || suffixlen > 0 {
+        let old_tuple = old_tuple.as_deref().ok_or_else(|| {
+            invalid_record(lsn, "prefix or suffix from an old tuple on another page")
+        })?;
+        let old_hoff = old_tuple[T_HOFF] as usize;
+        let bitmap_len = (xlhdr.t_hoff as usize)
+            .checked_sub(pg_constants::SIZEOF_HEAP_TUPLE_HEADER)
+            .filter(|len| *len <= data.len())
+            .ok_or_else(|| invalid_record(lsn, "invalid heap update record"))?;
+        if old_hoff + prefixlen > old_tuple.len() || suffixlen > old_tuple.len() {
+            return Err(invalid_record(lsn, "invalid heap update record"));
+        }
+
+        // copy bitmap [+ padding] [+ oid] from WAL record
+        tuple.extend_from_slice(&data[..bitmap_len]);
+        // copy prefix from old tuple
+        tuple.extend_from_slice(&old_tuple[old_hoff..old_hoff + prefixlen]);
+        // copy new tuple data from WAL record
+        tuple.extend_from_slice(&data[bitmap_len..]);
+        // copy suffix from old tuple
+        tuple.extend_from_slice(&old_tuple[old_tuple.len() - suffixlen..]);
+    } else {
+        tuple.extend_from_slice(&data);
+    }
+
+    page_add_item(page, &tuple, xlrec.new_offnum, lsn)?;
+
+    if xlrec.flags & pg_constants::XLH_UPDATE_NEW_ALL_VISIBLE_CLEARED != 0 {
+        page_clear_all_visible(page);
+    }
+
+    page_set_lsn(page, lsn);
+    Ok(())
+}
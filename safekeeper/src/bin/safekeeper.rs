@@ -21,6 +21,7 @@ use safekeeper::defaults::{
     DEFAULT_HTTP_LISTEN_ADDR, DEFAULT_PG_LISTEN_ADDR, DEFAULT_WAL_BACKUP_RUNTIME_THREADS,
 };
 use safekeeper::http;
+use safekeeper::recovery;
 use safekeeper::remove_wal;
 use safekeeper::timeline::GlobalTimelines;
 use safekeeper::wal_backup;
@@ -322,6 +323,12 @@ fn start_safekeeper(mut conf: SafeKeeperConf, given_id: Option<NodeId>, init: bo
                     broker::thread_main(conf_);
                 })?,
         );
+        // Peers' state needed for the recovery comes from the broker.
+        threads.push(
+            thread::Builder::new()
+                .name("recovery launcher thread".into())
+                .spawn(recovery::thread_main)?,
+        );
     } else {
         warn!("No broker endpoints providing, starting without node sync")
     }
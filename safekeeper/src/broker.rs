@@ -214,23 +214,46 @@ async fn pull_loop(conf: SafeKeeperConf) -> Result<()> {
     )
     .await
     .context("failed to subscribe for safekeeper info")?;
+    let mut ps_subscription = etcd_broker::subscribe_to_pageserver_timeline_updates(
+        &mut client,
+        SkTimelineSubscriptionKind::all(conf.broker_etcd_prefix.clone()),
+    )
+    .await
+    .context("failed to subscribe for pageserver info")?;
     loop {
-        match subscription.fetch_data().await {
-            Some(new_info) => {
-                for (zttid, sk_info) in new_info {
-                    // note: there are blocking operations below, but it's considered fine for now
-                    if let Ok(tli) = GlobalTimelines::get(&conf, zttid, false) {
-                        for (safekeeper_id, info) in sk_info {
-                            tli.record_safekeeper_info(&info, safekeeper_id).await?
+        tokio::select! {
+            new_info = subscription.fetch_data() => match new_info {
+                Some(new_info) => {
+                    for (zttid, sk_info) in new_info {
+                        // note: there are blocking operations below, but it's considered fine for now
+                        if let Ok(tli) = GlobalTimelines::get(&conf, zttid, false) {
+                            for (safekeeper_id, info) in sk_info {
+                                tli.record_safekeeper_info(&info, safekeeper_id).await?
+                            }
                         }
                     }
                 }
-            }
-            None => {
-                // XXX it means we lost connection with etcd, error is consumed inside sub object
-                debug!("timeline updates sender closed, aborting the pull loop");
-                return Ok(());
-            }
+                None => {
+                    // XXX it means we lost connection with etcd, error is consumed inside sub object
+                    debug!("timeline updates sender closed, aborting the pull loop");
+                    return Ok(());
+                }
+            },
+            new_info = ps_subscription.fetch_data() => match new_info {
+                Some(new_info) => {
+                    for (zttid, ps_info) in new_info {
+                        if let Ok(tli) = GlobalTimelines::get(&conf, zttid, false) {
+                            for (pageserver_id, info) in ps_info {
+                                tli.record_pageserver_info(&info, pageserver_id).await?
+                            }
+                        }
+                    }
+                }
+                None => {
+                    debug!("pageserver timeline updates sender closed, aborting the pull loop");
+                    return Ok(());
+                }
+            },
         }
     }
 }
@@ -21,7 +21,7 @@ use crate::SafeKeeperConf;
 use std::convert::TryInto;
 
 // contains persistent metadata for safekeeper
-const CONTROL_FILE_NAME: &str = "safekeeper.control";
+pub const CONTROL_FILE_NAME: &str = "safekeeper.control";
 // needed to atomically update the state using `rename`
 const CONTROL_FILE_NAME_PARTIAL: &str = "safekeeper.control.partial";
 pub const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
@@ -249,10 +249,15 @@ impl Storage for FileStorage {
 mod test {
     use super::FileStorage;
     use super::*;
+    use crate::control_file_upgrade::SafeKeeperStateV5;
+    use crate::membership::Configuration;
     use crate::{safekeeper::SafeKeeperState, SafeKeeperConf};
     use anyhow::Result;
     use std::fs;
-    use utils::{lsn::Lsn, zid::ZTenantTimelineId};
+    use utils::{
+        lsn::Lsn,
+        zid::{NodeId, ZTenantTimelineId},
+    };
 
     fn stub_conf() -> SafeKeeperConf {
         let workdir = tempfile::tempdir().unwrap().into_path();
@@ -321,4 +326,39 @@ mod test {
             Ok(_) => panic!("expected error"),
         }
     }
+
+    #[test]
+    fn test_upgrade_v5() {
+        let conf = stub_conf();
+        let zttid = ZTenantTimelineId::generate();
+        fs::create_dir_all(&conf.timeline_dir(&zttid)).expect("failed to create timeline dir");
+
+        let state = SafeKeeperState::new(&zttid, vec![NodeId(1), NodeId(2)]);
+        let state_v5 = SafeKeeperStateV5 {
+            tenant_id: state.tenant_id,
+            timeline_id: state.timeline_id,
+            acceptor_state: state.acceptor_state.clone(),
+            server: state.server.clone(),
+            proposer_uuid: state.proposer_uuid,
+            timeline_start_lsn: state.timeline_start_lsn,
+            local_start_lsn: state.local_start_lsn,
+            commit_lsn: Lsn(42),
+            backup_lsn: state.backup_lsn,
+            peer_horizon_lsn: state.peer_horizon_lsn,
+            remote_consistent_lsn: state.remote_consistent_lsn,
+            peers: state.peers.clone(),
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<LittleEndian>(SK_MAGIC).unwrap();
+        buf.write_u32::<LittleEndian>(5).unwrap();
+        state_v5.ser_into(&mut buf).unwrap();
+        let checksum = crc32c::crc32c(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        fs::write(conf.timeline_dir(&zttid).join(CONTROL_FILE_NAME), &buf)
+            .expect("failed to write control file");
+
+        let (_, state) = load_from_control_file(&conf, &zttid).expect("failed to read state");
+        assert_eq!(state.commit_lsn, Lsn(42));
+        assert_eq!(state.mconf, Configuration::new(vec![NodeId(1), NodeId(2)]));
+    }
 }
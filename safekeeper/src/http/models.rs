@@ -7,3 +7,10 @@ pub struct TimelineCreateRequest {
     pub timeline_id: ZTimelineId,
     pub peer_ids: Vec<NodeId>,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct TimelinePullRequest {
+    pub donor_id: NodeId,
+    /// HTTP address of the donor safekeeper, host:port.
+    pub donor_http_addr: String,
+}
@@ -1,5 +1,5 @@
 use etcd_broker::SkTimelineInfo;
-use hyper::{Body, Request, Response, StatusCode};
+use hyper::{header, Body, Request, Response, StatusCode};
 
 use serde::Serialize;
 use serde::Serializer;
@@ -7,9 +7,12 @@ use std::collections::HashMap;
 use std::fmt::Display;
 use std::sync::Arc;
 
+use crate::membership::Configuration;
+use crate::pull_timeline::{self, is_timeline_file};
 use crate::safekeeper::Term;
 use crate::safekeeper::TermHistory;
 use crate::timeline::{GlobalTimelines, TimelineDeleteForceResult};
+use crate::wal_storage;
 use crate::SafeKeeperConf;
 use utils::{
     http::{
@@ -23,7 +26,7 @@ use utils::{
     zid::{NodeId, ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
-use super::models::TimelineCreateRequest;
+use super::models::{TimelineCreateRequest, TimelinePullRequest};
 
 #[derive(Debug, Serialize)]
 struct SafekeeperStatus {
@@ -83,6 +86,7 @@ struct TimelineStatus {
     peer_horizon_lsn: Lsn,
     #[serde(serialize_with = "display_serialize")]
     remote_consistent_lsn: Lsn,
+    mconf: Configuration,
 }
 
 /// Report info about timeline.
@@ -114,6 +118,7 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
         backup_lsn: inmem.backup_lsn,
         peer_horizon_lsn: inmem.peer_horizon_lsn,
         remote_consistent_lsn: inmem.remote_consistent_lsn,
+        mconf: state.mconf,
     };
     json_response(StatusCode::OK, status)
 }
@@ -131,6 +136,131 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
     json_response(StatusCode::CREATED, ())
 }
 
+/// Report membership configuration of the timeline.
+async fn timeline_membership_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, tli.get_state().1.mconf)
+}
+
+/// Switch the timeline to the given membership configuration. Only valid
+/// steps of the joint consensus change are accepted, see `membership`.
+async fn timeline_membership_update_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    let mconf: Configuration = json_request(&mut request).await?;
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    let current = tli.get_state().1.mconf;
+    current
+        .check_transition(&mconf)
+        .map_err(|e| ApiError::BadRequest(format!("{:#}", e)))?;
+    tli.set_membership(mconf).map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, tli.get_state().1.mconf)
+}
+
+/// Report the persistent state and the files of the timeline, for pulling it
+/// to another safekeeper.
+async fn timeline_snapshot_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let snapshot = tli.snapshot(conf).map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, snapshot)
+}
+
+/// Send a file of the timeline listed in its snapshot.
+async fn timeline_file_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    let filename: String = parse_request_param(&request, "filename")?;
+    if !is_timeline_file(&filename) {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not a timeline file",
+            filename
+        )));
+    }
+
+    let path = get_conf(&request).timeline_dir(&zttid).join(&filename);
+    let content = tokio::fs::read(&path).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            ApiError::NotFound(format!("file {} of timeline {} not found", filename, zttid))
+        } else {
+            ApiError::from_err(e)
+        }
+    })?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from(content))
+        .map_err(ApiError::from_err)
+}
+
+/// Copy the timeline from another safekeeper. Together with deletion on the
+/// donor it moves the timeline between safekeepers.
+async fn timeline_pull_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    let request_data: TimelinePullRequest = json_request(&mut request).await?;
+
+    let conf = get_conf(&request);
+    let pull = pull_timeline::start_pull(conf, zttid).ok_or_else(|| {
+        ApiError::Conflict(format!(
+            "timeline {} already exists or is being pulled",
+            zttid
+        ))
+    })?;
+    pull_timeline::pull_timeline(
+        conf,
+        pull,
+        request_data.donor_id,
+        &request_data.donor_http_addr,
+    )
+    .await
+    .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::CREATED, ())
+}
+
+/// Check the completed WAL segments of the timeline against their checksums.
+async fn timeline_verify_wal_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    ensure_no_body(&mut request).await?;
+
+    let conf = get_conf(&request);
+    // make sure the timeline exists
+    GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let timeline_dir = conf.timeline_dir(&zttid);
+    let result =
+        tokio::task::spawn_blocking(move || wal_storage::verify_timeline_wal(&timeline_dir))
+            .await
+            .map_err(ApiError::from_err)?
+            .map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, result)
+}
+
 /// Deactivates the timeline and removes its data directory.
 ///
 /// It does not try to stop any processing of the timeline; there is no such code at the time of writing.
@@ -202,6 +332,30 @@ pub fn make_router(conf: SafeKeeperConf) -> RouterBuilder<hyper::Body, ApiError>
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_delete_force_handler,
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/membership",
+            timeline_membership_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/membership",
+            timeline_membership_update_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/snapshot",
+            timeline_snapshot_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/file/:filename",
+            timeline_file_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/pull",
+            timeline_pull_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/verify_wal",
+            timeline_verify_wal_handler,
+        )
         .delete("/v1/tenant/:tenant_id", tenant_delete_force_handler)
         // for tests
         .post(
@@ -94,6 +94,7 @@ pub fn handle_json_ctrl(
 /// Prepare safekeeper to process append requests without crashes,
 /// by sending ProposerGreeting with default server.wal_seg_size.
 fn prepare_safekeeper(spg: &mut SafekeeperPostgresHandler) -> Result<()> {
+    let mconf_generation = spg.timeline.get().get_state().1.mconf.generation;
     let greeting_request = ProposerAcceptorMessage::Greeting(ProposerGreeting {
         protocol_version: 2, // current protocol
         pg_version: 0,       // unknown
@@ -103,6 +104,7 @@ fn prepare_safekeeper(spg: &mut SafekeeperPostgresHandler) -> Result<()> {
         tenant_id: spg.ztenantid.unwrap(),
         tli: 0,
         wal_seg_size: pg_constants::WAL_SEGMENT_SIZE as u32, // 16MB, default for tests
+        mconf_generation: Some(mconf_generation),
     });
 
     let response = spg.timeline.get().process_msg(&greeting_request)?;
@@ -0,0 +1,236 @@
+//! Membership configuration of the safekeepers serving a timeline.
+//!
+//! The set of safekeepers is changed joint consensus style. From a stable
+//! configuration with `members`, the timeline first switches to a joint one,
+//! which additionally has `new_members`. Once the new members have caught up,
+//! it switches to the stable configuration consisting of `new_members` only.
+//! Every change bumps the generation.
+//!
+//! The proposer sends the generation it knows in its greeting. Once the
+//! membership of the timeline is configured, acceptors refuse proposers which
+//! send no generation or an older one than they know, and acceptors removed
+//! from the configuration stop voting and accepting WAL. Acceptors reply to
+//! the greeting with their configuration, and the proposer counts votes and
+//! acknowledged WAL with [`Configuration::is_quorum`] and
+//! [`Configuration::quorum_lsn`], requiring a quorum in both sets of a joint
+//! configuration.
+
+use anyhow::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utils::{lsn::Lsn, zid::NodeId};
+
+/// Number of the configuration, increases with each change.
+pub type Generation = u32;
+/// Generation of the timelines which membership was never configured. Such
+/// timelines accept any proposer.
+pub const INVALID_GENERATION: Generation = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    pub generation: Generation,
+    pub members: Vec<NodeId>,
+    /// Set in the joint configuration only.
+    pub new_members: Option<Vec<NodeId>>,
+}
+
+impl Configuration {
+    /// Unconfigured membership, `members` are only informational.
+    pub fn new(members: Vec<NodeId>) -> Self {
+        Configuration {
+            generation: INVALID_GENERATION,
+            members,
+            new_members: None,
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.new_members.is_some()
+    }
+
+    /// Whether the safekeeper is a member of either of the sets.
+    pub fn contains(&self, sk_id: NodeId) -> bool {
+        self.members.contains(&sk_id)
+            || self
+                .new_members
+                .as_ref()
+                .map_or(false, |new_members| new_members.contains(&sk_id))
+    }
+
+    /// Whether the safekeepers `ids` make a quorum: a majority of `members`,
+    /// and of `new_members` as well in the joint configuration.
+    pub fn is_quorum(&self, ids: &[NodeId]) -> bool {
+        let is_majority =
+            |set: &[NodeId]| set.iter().filter(|id| ids.contains(id)).count() > set.len() / 2;
+        is_majority(&self.members)
+            && self
+                .new_members
+                .as_ref()
+                .map_or(true, |new_members| is_majority(new_members))
+    }
+
+    /// Highest LSN reached by a quorum of the safekeepers, given their
+    /// positions, e.g. flush LSNs acknowledged to the proposer. None if the
+    /// known positions don't make a quorum.
+    pub fn quorum_lsn(&self, positions: &HashMap<NodeId, Lsn>) -> Option<Lsn> {
+        let majority_lsn = |set: &[NodeId]| {
+            let mut lsns = set
+                .iter()
+                .filter_map(|id| positions.get(id).copied())
+                .collect::<Vec<_>>();
+            let quorum = set.len() / 2 + 1;
+            if lsns.len() < quorum {
+                return None;
+            }
+            lsns.sort_unstable();
+            Some(lsns[lsns.len() - quorum])
+        };
+        let lsn = majority_lsn(&self.members)?;
+        match &self.new_members {
+            Some(new_members) => Some(lsn.min(majority_lsn(new_members)?)),
+            None => Some(lsn),
+        }
+    }
+
+    /// Check that switching from this configuration to `new` is a valid step:
+    /// either entering the joint configuration from a stable one, or leaving
+    /// it to its new members (or back to the old ones, aborting the change).
+    /// The very first configuration can be set directly.
+    pub fn check_transition(&self, new: &Configuration) -> Result<()> {
+        ensure!(
+            new.generation > self.generation,
+            "generation {} is not newer than current {}",
+            new.generation,
+            self.generation
+        );
+        ensure!(!new.members.is_empty(), "configuration must have members");
+        match (&self.new_members, &new.new_members) {
+            (None, None) => {
+                ensure!(
+                    self.generation == INVALID_GENERATION,
+                    "members can be changed only through a joint configuration"
+                );
+            }
+            (None, Some(new_members)) => {
+                ensure!(
+                    !new_members.is_empty(),
+                    "joint configuration must have new members"
+                );
+                ensure!(
+                    self.generation == INVALID_GENERATION
+                        || same_members(&new.members, &self.members),
+                    "joint configuration must keep current members {:?}",
+                    self.members
+                );
+            }
+            (Some(joint_new_members), None) => {
+                ensure!(
+                    same_members(&new.members, joint_new_members)
+                        || same_members(&new.members, &self.members),
+                    "joint configuration can be left only to its new members {:?} or old members {:?}",
+                    joint_new_members,
+                    self.members
+                );
+            }
+            (Some(_), Some(_)) => {
+                bail!(
+                    "already in joint configuration, generation {}",
+                    self.generation
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn same_members(a: &[NodeId], b: &[NodeId]) -> bool {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort_unstable();
+    a.dedup();
+    b.sort_unstable();
+    b.dedup();
+    a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(generation: Generation, members: &[u64], new_members: Option<&[u64]>) -> Configuration {
+        Configuration {
+            generation,
+            members: members.iter().map(|id| NodeId(*id)).collect(),
+            new_members: new_members.map(|m| m.iter().map(|id| NodeId(*id)).collect()),
+        }
+    }
+
+    #[test]
+    fn test_transitions() {
+        let initial = Configuration::new(vec![NodeId(1), NodeId(2), NodeId(3)]);
+        let stable = conf(1, &[1, 2, 3], None);
+        initial.check_transition(&stable).unwrap();
+
+        // replace 3 with 4
+        let joint = conf(2, &[3, 2, 1], Some(&[1, 2, 4]));
+        assert!(joint.contains(NodeId(3)) && joint.contains(NodeId(4)));
+        stable.check_transition(&joint).unwrap();
+        let new_stable = conf(3, &[1, 2, 4], None);
+        joint.check_transition(&new_stable).unwrap();
+        assert!(!new_stable.contains(NodeId(3)));
+
+        // stale generation
+        assert!(stable
+            .check_transition(&conf(1, &[1, 2, 3], Some(&[4])))
+            .is_err());
+        // skipping the joint configuration
+        assert!(stable.check_transition(&conf(2, &[1, 2, 4], None)).is_err());
+        // joint configuration from other members
+        assert!(stable
+            .check_transition(&conf(2, &[1, 2], Some(&[4])))
+            .is_err());
+        // aborting the change
+        joint.check_transition(&conf(3, &[1, 2, 3], None)).unwrap();
+        // leaving joint configuration to other members
+        assert!(joint.check_transition(&conf(3, &[1, 2], None)).is_err());
+        // nested joint configuration
+        assert!(joint
+            .check_transition(&conf(3, &[1, 2, 4], Some(&[5])))
+            .is_err());
+    }
+
+    #[test]
+    fn test_quorum() {
+        let ids = |ids: &[u64]| ids.iter().map(|id| NodeId(*id)).collect::<Vec<_>>();
+        let positions = |lsns: &[(u64, u64)]| {
+            lsns.iter()
+                .map(|(id, lsn)| (NodeId(*id), Lsn(*lsn)))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let stable = conf(1, &[1, 2, 3], None);
+        assert!(stable.is_quorum(&ids(&[1, 3])));
+        assert!(!stable.is_quorum(&ids(&[3, 4, 5])));
+        assert_eq!(
+            stable.quorum_lsn(&positions(&[(1, 10), (2, 30), (3, 20)])),
+            Some(Lsn(20))
+        );
+        assert_eq!(stable.quorum_lsn(&positions(&[(1, 10), (4, 30)])), None);
+
+        // replacing 3 with 4, both sets need a majority
+        let joint = conf(2, &[1, 2, 3], Some(&[1, 2, 4]));
+        assert!(joint.is_quorum(&ids(&[1, 2])));
+        assert!(!joint.is_quorum(&ids(&[1, 3])));
+        assert!(!joint.is_quorum(&ids(&[1, 4])));
+        assert!(joint.is_quorum(&ids(&[1, 3, 4])));
+        assert_eq!(
+            joint.quorum_lsn(&positions(&[(1, 10), (2, 20), (3, 40), (4, 30)])),
+            Some(Lsn(20))
+        );
+        assert_eq!(
+            joint.quorum_lsn(&positions(&[(1, 10), (3, 40), (4, 30)])),
+            Some(Lsn(10))
+        );
+        assert_eq!(joint.quorum_lsn(&positions(&[(1, 10), (3, 40)])), None);
+    }
+}
@@ -0,0 +1,212 @@
+//! Copying a timeline from another safekeeper, which together with timeline
+//! deletion allows to move timelines between safekeepers.
+//!
+//! The donor reports a snapshot of the timeline: its persistent state and the
+//! list of files in the timeline directory, taken at the same moment. The
+//! recipient downloads the files into a temporary directory, checks the WAL
+//! segments against their checksums and the control file against the
+//! reported state, moves the directory in place and loads the timeline,
+//! checking that its WAL reaches the donor's flush_lsn.
+//!
+//! The copying is not synchronized with the donor's activity, so the timeline
+//! should better be inactive (no compute) while it is pulled; otherwise the
+//! files are likely to change under our feet and the pull fails, but can be
+//! retried.
+
+use anyhow::{bail, ensure, Context, Result};
+use hyper::{body, Body, Client, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+
+use postgres_ffi::xlog_utils::{IsPartialXLogFileName, IsXLogFileName};
+use utils::{
+    bin_ser::LeSer,
+    lsn::Lsn,
+    zid::{NodeId, ZTenantTimelineId},
+};
+
+use crate::control_file::{self, CONTROL_FILE_NAME};
+use crate::safekeeper::SafeKeeperState;
+use crate::timeline::{GlobalTimelines, Timeline};
+use crate::wal_storage::{self, CHECKSUM_SUFFIX};
+use crate::SafeKeeperConf;
+
+/// Persistent state of the timeline along with its files.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimelineSnapshot {
+    /// State as persisted in the control file.
+    pub state: SafeKeeperState,
+    pub flush_lsn: Lsn,
+    /// Names of the control file, WAL segments and their checksums.
+    pub files: Vec<String>,
+}
+
+/// Whether the file of the timeline directory is needed to copy the timeline.
+pub fn is_timeline_file(fname: &str) -> bool {
+    let seg_name = fname.strip_suffix(CHECKSUM_SUFFIX).unwrap_or(fname);
+    fname == CONTROL_FILE_NAME || IsXLogFileName(seg_name) || IsPartialXLogFileName(seg_name)
+}
+
+/// Timelines being pulled, to keep concurrent pulls of the same timeline from
+/// sharing the temporary directory.
+static PULLS_IN_PROGRESS: Lazy<Mutex<HashSet<ZTenantTimelineId>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Pull of the timeline in progress, see [`start_pull`].
+pub struct PullGuard(ZTenantTimelineId);
+
+impl Drop for PullGuard {
+    fn drop(&mut self) {
+        PULLS_IN_PROGRESS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Register the pull of the timeline. Returns None if the timeline already
+/// exists or is being pulled.
+pub fn start_pull(conf: &SafeKeeperConf, zttid: ZTenantTimelineId) -> Option<PullGuard> {
+    let mut pulls = PULLS_IN_PROGRESS.lock().unwrap();
+    if GlobalTimelines::get_loaded(zttid).is_some()
+        || conf.timeline_dir(&zttid).exists()
+        || !pulls.insert(zttid)
+    {
+        return None;
+    }
+    Some(PullGuard(zttid))
+}
+
+/// Copy the timeline from the donor safekeeper with `donor_id` listening for
+/// HTTP at `donor_http_addr`, and register it.
+pub async fn pull_timeline(
+    conf: &SafeKeeperConf,
+    pull: PullGuard,
+    donor_id: NodeId,
+    donor_http_addr: &str,
+) -> Result<Arc<Timeline>> {
+    let zttid = pull.0;
+    let timeline_dir = conf.timeline_dir(&zttid);
+
+    let client = Client::new();
+    let donor_url = format!("http://{}/v1", donor_http_addr);
+
+    let status: serde_json::Value = get_json(&client, &format!("{}/status", donor_url)).await?;
+    ensure!(
+        status["id"] == donor_id.0,
+        "safekeeper at {} is not {}, status: {}",
+        donor_http_addr,
+        donor_id,
+        status
+    );
+
+    let timeline_url = format!(
+        "{}/tenant/{}/timeline/{}",
+        donor_url, zttid.tenant_id, zttid.timeline_id
+    );
+    let snapshot: TimelineSnapshot = get_json(&client, &format!("{}/snapshot", timeline_url))
+        .await
+        .context("failed to get timeline snapshot")?;
+    ensure!(
+        snapshot.state.tenant_id == zttid.tenant_id
+            && snapshot.state.timeline_id == zttid.timeline_id,
+        "donor returned state of another timeline {}/{}",
+        snapshot.state.tenant_id,
+        snapshot.state.timeline_id
+    );
+    info!(
+        "pulling timeline {} from safekeeper {}, flush_lsn {}, {} files",
+        zttid,
+        donor_id,
+        snapshot.flush_lsn,
+        snapshot.files.len()
+    );
+
+    // Download into a temporary directory first, so that a failed pull
+    // doesn't leave a half copied timeline behind.
+    let tmp_dir = conf
+        .tenant_dir(&zttid.tenant_id)
+        .join(format!("{}.pull", zttid.timeline_id));
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).await?;
+    }
+    fs::create_dir_all(&tmp_dir).await?;
+
+    for fname in &snapshot.files {
+        ensure!(
+            is_timeline_file(fname),
+            "unexpected file {} in timeline snapshot",
+            fname
+        );
+        let resp = get(&client, &format!("{}/file/{}", timeline_url, fname)).await?;
+        let content = body::to_bytes(resp.into_body()).await?;
+        let mut file = fs::File::create(tmp_dir.join(fname)).await?;
+        file.write_all(&content).await?;
+        file.sync_all().await?;
+    }
+
+    let wal_dir = tmp_dir.clone();
+    let verify_result =
+        tokio::task::spawn_blocking(move || wal_storage::verify_timeline_wal(&wal_dir)).await??;
+    ensure!(
+        verify_result.corrupted.is_empty(),
+        "pulled WAL segments {:?} don't match their checksums",
+        verify_result.corrupted
+    );
+
+    let state = control_file::FileStorage::load_control_file(tmp_dir.join(CONTROL_FILE_NAME))?;
+    if state.ser()? != snapshot.state.ser()? {
+        bail!(
+            "control file doesn't match donor state, timeline was probably modified during the pull"
+        );
+    }
+
+    fs::rename(&tmp_dir, &timeline_dir).await?;
+    fs::File::open(conf.tenant_dir(&zttid.tenant_id))
+        .await?
+        .sync_all()
+        .await?;
+
+    let tli = GlobalTimelines::get(conf, zttid, false)?;
+    let flush_lsn = tli.get_end_of_wal();
+    if flush_lsn < snapshot.flush_lsn {
+        GlobalTimelines::delete_force(conf, &zttid).await?;
+        bail!(
+            "pulled WAL ends at {}, but donor has it up to {}",
+            flush_lsn,
+            snapshot.flush_lsn
+        );
+    }
+
+    info!("pulled timeline {}, flush_lsn {}", zttid, flush_lsn);
+    Ok(tli)
+}
+
+async fn get(client: &Client<hyper::client::HttpConnector>, url: &str) -> Result<Response<Body>> {
+    let resp = client
+        .get(url.parse()?)
+        .await
+        .with_context(|| format!("failed to request {}", url))?;
+    if resp.status() != StatusCode::OK {
+        let status = resp.status();
+        let body = body::to_bytes(resp.into_body()).await?;
+        bail!(
+            "request {} failed with {}: {}",
+            url,
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    Ok(resp)
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    client: &Client<hyper::client::HttpConnector>,
+    url: &str,
+) -> Result<T> {
+    let resp = get(client, url).await?;
+    let body = body::to_bytes(resp.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
@@ -0,0 +1,161 @@
+//! Peer recovery: fetch WAL a lagging safekeeper misses from a more advanced
+//! peer.
+//!
+//! Normally safekeepers receive WAL only from the walproposer of an active
+//! compute, which also brings lagging safekeepers up to date after election.
+//! Without a compute, a safekeeper which was down or has fallen behind stays
+//! behind, reducing the redundancy of the WAL. So once in a while we look at
+//! the peers' state learned from the broker, and if some peer has more WAL in
+//! the same epoch, stream the missing part from its START_REPLICATION.
+//!
+//! Only safekeepers which already have the timeline state (got it from a
+//! walproposer at least once) recover this way.
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use postgres_protocol::message::backend::ReplicationMessage;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::pin;
+use tokio::runtime::Builder;
+use tokio::time::sleep;
+use tokio_postgres::replication::ReplicationStream;
+use tokio_postgres::NoTls;
+use tracing::*;
+
+use utils::{connstring::connection_host_port, lsn::Lsn, zid::NodeId};
+
+use crate::safekeeper::Term;
+use crate::timeline::{GlobalTimelines, Timeline};
+
+/// Application name walsender recognizes peer recovery connections by.
+pub const RECOVERY_APPNAME: &str = "safekeeper_recovery";
+
+const RECOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Peer to fetch WAL from, chosen by `Timeline::start_recovery`.
+#[derive(Debug, Clone)]
+pub struct Donor {
+    pub sk_id: NodeId,
+    /// Epoch of both the donor and us.
+    pub epoch: Term,
+    /// Donor's postgres protocol address.
+    pub pg_connstr: String,
+    /// LSN to stream from, the end of our WAL.
+    pub start_lsn: Lsn,
+    /// LSN to stream up to, the end of donor's WAL.
+    pub end_lsn: Lsn,
+}
+
+/// Resets the recovery flag of the timeline when the recovery is over,
+/// however it ends.
+struct RecoveryGuard(Arc<Timeline>);
+
+impl Drop for RecoveryGuard {
+    fn drop(&mut self) {
+        self.0.finish_recovery();
+    }
+}
+
+pub fn thread_main() {
+    let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to create recovery runtime");
+
+    runtime.block_on(main_loop());
+}
+
+/// Start a recovery task for each timeline that needs it. All the recoveries
+/// run on the runtime of this thread.
+async fn main_loop() {
+    loop {
+        for tli in GlobalTimelines::get_loaded_timelines() {
+            match tli.start_recovery() {
+                Ok(Some(donor)) => {
+                    let guard = RecoveryGuard(tli);
+                    tokio::spawn(recovery_main(guard, donor));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("failed to start recovery of timeline {}: {}", tli.zttid, e),
+            }
+        }
+        sleep(RECOVERY_CHECK_INTERVAL).await;
+    }
+}
+
+async fn recovery_main(guard: RecoveryGuard, donor: Donor) {
+    let tli = &guard.0;
+    let span = info_span!("recovery", timeline = %tli.zttid.timeline_id, donor = %donor.sk_id);
+    async {
+        info!(
+            "recovering WAL {}..{} from safekeeper {} at {}",
+            donor.start_lsn, donor.end_lsn, donor.sk_id, donor.pg_connstr
+        );
+        match recover(tli, &donor).await {
+            Ok(end_lsn) => info!("recovered WAL up to {}", end_lsn),
+            Err(e) => warn!("recovery failed: {:?}", e),
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Stream WAL from the donor and write it down, returns the end of the
+/// recovered WAL.
+async fn recover(tli: &Arc<Timeline>, donor: &Donor) -> Result<Lsn> {
+    // use Config parsing because SockAddr parsing doesn't allow to use host names instead of ip addresses
+    let donor_conf: postgres::config::Config =
+        format!("postgresql://no_user@{}/no_db", donor.pg_connstr)
+            .parse()
+            .with_context(|| format!("invalid safekeeper address {:?}", donor.pg_connstr))?;
+    let (host, port) = connection_host_port(&donor_conf);
+    let connstr = format!(
+        "host={} port={} options='-c ztimelineid={} ztenantid={}' application_name={} replication=true",
+        host, port, tli.zttid.timeline_id, tli.zttid.tenant_id, RECOVERY_APPNAME,
+    );
+
+    let (replication_client, connection) = tokio_postgres::connect(&connstr, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("connection error: {}", e);
+        }
+    });
+
+    let query = format!("START_REPLICATION PHYSICAL {}", donor.start_lsn);
+    let copy_stream = replication_client.copy_both_simple(&query).await?;
+    let physical_stream = ReplicationStream::new(copy_stream);
+    pin!(physical_stream);
+
+    let mut end_lsn = donor.start_lsn;
+    while end_lsn < donor.end_lsn {
+        let replication_message = match physical_stream.next().await {
+            Some(replication_message) => replication_message?,
+            None => bail!("donor closed the stream at {}", end_lsn),
+        };
+        match replication_message {
+            ReplicationMessage::XLogData(xlog_data) => {
+                let data = xlog_data.data().clone();
+                let begin_lsn = Lsn::from(xlog_data.wal_start());
+                trace!(
+                    "received XLogData between {} and {}",
+                    begin_lsn,
+                    begin_lsn + data.len() as u64
+                );
+
+                // Writing the WAL blocks on the disk, keep it off the runtime.
+                end_lsn = begin_lsn + data.len() as u64;
+                let tli = Arc::clone(tli);
+                let epoch = donor.epoch;
+                tokio::task::spawn_blocking(move || {
+                    tli.write_recovered_wal(epoch, begin_lsn, &data)
+                })
+                .await??;
+            }
+            // Donor doesn't wait for our feedback, so keepalives can be ignored.
+            _ => {}
+        }
+    }
+
+    Ok(end_lsn)
+}
@@ -8,11 +8,12 @@ use crate::{timeline::GlobalTimelines, SafeKeeperConf};
 
 pub fn thread_main(conf: SafeKeeperConf) {
     let wal_removal_interval = Duration::from_millis(5000);
+    let wal_backup_enabled = conf.remote_storage.is_some() && conf.wal_backup_enabled;
     loop {
         let active_tlis = GlobalTimelines::get_active_timelines();
         for zttid in &active_tlis {
             if let Ok(tli) = GlobalTimelines::get(&conf, *zttid, false) {
-                if let Err(e) = tli.remove_old_wal(conf.wal_backup_enabled) {
+                if let Err(e) = tli.remove_old_wal(wal_backup_enabled) {
                     warn!(
                         "failed to remove WAL for tenant {} timeline {}: {}",
                         tli.zttid.tenant_id, tli.zttid.timeline_id, e
@@ -4,7 +4,7 @@ use anyhow::{bail, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use etcd_broker::SkTimelineInfo;
+use etcd_broker::{PsTimelineInfo, SkTimelineInfo};
 use postgres_ffi::xlog_utils::TimeLineID;
 
 use postgres_ffi::xlog_utils::XLogSegNo;
@@ -18,6 +18,7 @@ use tracing::*;
 use lazy_static::lazy_static;
 
 use crate::control_file;
+use crate::membership::{Configuration, Generation, INVALID_GENERATION};
 use crate::send_wal::HotStandbyFeedback;
 
 use crate::wal_storage;
@@ -31,13 +32,13 @@ use utils::{
 };
 
 pub const SK_MAGIC: u32 = 0xcafeceefu32;
-pub const SK_FORMAT_VERSION: u32 = 5;
+pub const SK_FORMAT_VERSION: u32 = 6;
 const SK_PROTOCOL_VERSION: u32 = 2;
 const UNKNOWN_SERVER_VERSION: u32 = 0;
 
 /// Consensus logical timestamp.
 pub type Term = u64;
-const INVALID_TERM: Term = 0;
+pub const INVALID_TERM: Term = 0;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct TermSwitchEntry {
@@ -211,6 +212,8 @@ pub struct SafeKeeperState {
     // obviously can be stale. (Currently not saved at all, but let's provision
     // place to have less file version upgrades).
     pub peers: Peers,
+    /// Membership configuration of the timeline.
+    pub mconf: Configuration,
 }
 
 #[derive(Debug, Clone)]
@@ -246,6 +249,7 @@ impl SafeKeeperState {
             peer_horizon_lsn: Lsn(0),
             remote_consistent_lsn: Lsn(0),
             peers: Peers(peers.iter().map(|p| (*p, PeerInfo::new())).collect()),
+            mconf: Configuration::new(peers),
         }
     }
 
@@ -271,6 +275,11 @@ pub struct ProposerGreeting {
     pub tenant_id: ZTenantId,
     pub tli: TimeLineID,
     pub wal_seg_size: u32,
+    /// Generation of the membership configuration the proposer knows. Sent
+    /// after the fixed part of the message, required once the membership of
+    /// the timeline is configured.
+    #[serde(skip)]
+    pub mconf_generation: Option<Generation>,
 }
 
 /// Acceptor -> Proposer initial response: the highest term known to me
@@ -279,6 +288,9 @@ pub struct ProposerGreeting {
 pub struct AcceptorGreeting {
     term: u64,
     node_id: NodeId,
+    /// Our membership configuration, for the proposer to collect quorums in
+    /// both sets of a joint one, and to learn about a newer generation.
+    mconf: Configuration,
 }
 
 /// Vote request sent from proposer to safekeepers
@@ -386,7 +398,11 @@ impl ProposerAcceptorMessage {
         let tag = stream.read_u64::<LittleEndian>()? as u8 as char;
         match tag {
             'g' => {
-                let msg = ProposerGreeting::des_from(&mut stream)?;
+                let mut msg = ProposerGreeting::des_from(&mut stream)?;
+                let mut rest = stream.into_inner();
+                if rest.remaining() >= 4 {
+                    msg.mconf_generation = Some(rest.get_u32_le());
+                }
                 Ok(ProposerAcceptorMessage::Greeting(msg))
             }
             'v' => {
@@ -456,6 +472,18 @@ impl AcceptorProposerMessage {
                 buf.put_u64_le('g' as u64);
                 buf.put_u64_le(msg.term);
                 buf.put_u64_le(msg.node_id.0);
+                buf.put_u32_le(msg.mconf.generation);
+                buf.put_u32_le(msg.mconf.members.len() as u32);
+                for id in &msg.mconf.members {
+                    buf.put_u64_le(id.0);
+                }
+                // no new members means a stable configuration, the joint one
+                // always has some
+                let new_members = msg.mconf.new_members.as_deref().unwrap_or(&[]);
+                buf.put_u32_le(new_members.len() as u32);
+                for id in new_members {
+                    buf.put_u64_le(id.0);
+                }
             }
             AcceptorProposerMessage::VoteResponse(msg) => {
                 buf.put_u64_le('v' as u64);
@@ -643,6 +671,8 @@ where
                 self.state.timeline_id
             );
         }
+        self.check_membership()?;
+        self.check_proposer_generation(msg.mconf_generation)?;
 
         // set basic info about server, if not yet
         // TODO: verify that is doesn't change after
@@ -662,14 +692,67 @@ where
         Ok(Some(AcceptorProposerMessage::Greeting(AcceptorGreeting {
             term: self.state.acceptor_state.term,
             node_id: self.node_id,
+            mconf: self.state.mconf.clone(),
         })))
     }
 
+    /// Refuse all proposers if we are not a member of the timeline
+    /// configuration anymore.
+    fn check_membership(&self) -> Result<()> {
+        if !self.is_member() {
+            bail!(
+                "safekeeper {} is not a member of configuration generation {}",
+                self.node_id,
+                self.state.mconf.generation
+            );
+        }
+        Ok(())
+    }
+
+    /// Refuse proposers with stale membership configuration. Once the
+    /// membership is configured, the proposer must send its generation.
+    fn check_proposer_generation(&self, proposer_generation: Option<Generation>) -> Result<()> {
+        let generation = self.state.mconf.generation;
+        match proposer_generation {
+            Some(proposer_generation) if proposer_generation < generation => bail!(
+                "proposer configuration generation {} is older than {}",
+                proposer_generation,
+                generation
+            ),
+            None if generation != INVALID_GENERATION => bail!(
+                "proposer didn't send its configuration generation, {} is required",
+                generation
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether this safekeeper belongs to the timeline membership configuration;
+    /// timelines which membership was never configured admit everyone.
+    pub fn is_member(&self) -> bool {
+        self.state.mconf.generation == INVALID_GENERATION || self.state.mconf.contains(self.node_id)
+    }
+
+    /// Switch to the new membership configuration, if it is a valid step from
+    /// the current one.
+    pub fn set_membership(&mut self, mconf: Configuration) -> Result<()> {
+        self.state.mconf.check_transition(&mconf)?;
+        info!(
+            "switching membership configuration from {:?} to {:?}",
+            self.state.mconf, mconf
+        );
+        let mut state = self.state.clone();
+        state.mconf = mconf;
+        self.persist_control_file(state)
+    }
+
     /// Give vote for the given term, if we haven't done that previously.
     fn handle_vote_request(
         &mut self,
         msg: &VoteRequest,
     ) -> Result<Option<AcceptorProposerMessage>> {
+        // safekeeper removed from the configuration must not vote anymore
+        self.check_membership()?;
         // initialize with refusal
         let mut resp = VoteResponse {
             term: self.state.acceptor_state.term,
@@ -884,6 +967,40 @@ where
         )))
     }
 
+    /// Prepare to receive WAL from a peer safekeeper: cut off the incomplete
+    /// record at the end of our WAL, if any, as the peer streams WAL from a
+    /// record boundary. Returns the LSN to stream from.
+    pub fn start_recovery(&mut self) -> Result<Lsn> {
+        let flush_lsn = self.flush_lsn();
+        self.wal_store.truncate_wal(flush_lsn)?;
+        Ok(flush_lsn)
+    }
+
+    /// Append and flush WAL received from a peer safekeeper during recovery.
+    /// `epoch` is the term of the peer's last log entry; the WAL is written
+    /// only while our epoch is the same, which means that our WAL is a prefix
+    /// of the peer's one.
+    pub fn handle_recovered_wal(
+        &mut self,
+        epoch: Term,
+        begin_lsn: Lsn,
+        wal_data: &[u8],
+    ) -> Result<()> {
+        if self.get_epoch() != epoch {
+            bail!(
+                "epoch changed from {} to {} during recovery",
+                epoch,
+                self.get_epoch()
+            );
+        }
+
+        self.wal_store.write_wal(begin_lsn, wal_data)?;
+        self.wal_store.flush_wal()?;
+
+        // commit_lsn learnt from peers can advance now that we have more WAL.
+        self.update_commit_lsn()
+    }
+
     /// Update timeline state with peer safekeeper data.
     pub fn record_safekeeper_info(&mut self, sk_info: &SkTimelineInfo) -> Result<()> {
         let mut sync_control_file = false;
@@ -904,12 +1021,7 @@ where
             self.inmem.backup_lsn = new_backup_lsn;
         }
         if let Some(remote_consistent_lsn) = sk_info.remote_consistent_lsn {
-            let new_remote_consistent_lsn =
-                max(remote_consistent_lsn, self.inmem.remote_consistent_lsn);
-            sync_control_file |= self.state.remote_consistent_lsn
-                + (self.state.server.wal_seg_size as u64)
-                < new_remote_consistent_lsn;
-            self.inmem.remote_consistent_lsn = new_remote_consistent_lsn;
+            sync_control_file |= self.advance_remote_consistent_lsn(remote_consistent_lsn);
         }
         if let Some(peer_horizon_lsn) = sk_info.peer_horizon_lsn {
             let new_peer_horizon_lsn = max(peer_horizon_lsn, self.inmem.peer_horizon_lsn);
@@ -924,19 +1036,43 @@ where
         Ok(())
     }
 
+    /// Update timeline state with pageserver data.
+    pub fn record_pageserver_info(&mut self, ps_info: &PsTimelineInfo) -> Result<()> {
+        // When at least one pageserver has uploaded data up to remote_consistent_lsn,
+        // safekeeper is free to delete WAL preceding it.
+        if let Some(remote_consistent_lsn) = ps_info.remote_consistent_lsn {
+            if self.advance_remote_consistent_lsn(remote_consistent_lsn) {
+                self.persist_control_file(self.state.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Advance in-memory remote_consistent_lsn, returning whether it has gone
+    /// far enough ahead of the persistent one to sync the control file.
+    fn advance_remote_consistent_lsn(&mut self, remote_consistent_lsn: Lsn) -> bool {
+        let new_remote_consistent_lsn =
+            max(remote_consistent_lsn, self.inmem.remote_consistent_lsn);
+        self.inmem.remote_consistent_lsn = new_remote_consistent_lsn;
+        self.state.remote_consistent_lsn + (self.state.server.wal_seg_size as u64)
+            < new_remote_consistent_lsn
+    }
+
     /// Get oldest segno we still need to keep. We hold WAL till it is consumed
-    /// by all of 1) pageserver (remote_consistent_lsn) 2) peers 3) s3
-    /// offloading.
+    /// by all of 1) peers 2) s3 offloading, if enabled, or else the pageserver
+    /// (remote_consistent_lsn). Once offloaded, WAL the pageserver still needs
+    /// is streamed to it from s3.
     /// While it is safe to use inmem values for determining horizon,
     /// we use persistent to make possible normal states less surprising.
     pub fn get_horizon_segno(&self, wal_backup_enabled: bool) -> XLogSegNo {
-        let mut horizon_lsn = min(
-            self.state.remote_consistent_lsn,
-            self.state.peer_horizon_lsn,
-        );
-        if wal_backup_enabled {
-            horizon_lsn = min(horizon_lsn, self.state.backup_lsn);
-        }
+        let horizon_lsn = if wal_backup_enabled {
+            min(self.state.backup_lsn, self.state.peer_horizon_lsn)
+        } else {
+            min(
+                self.state.remote_consistent_lsn,
+                self.state.peer_horizon_lsn,
+            )
+        };
         horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
     }
 }
@@ -1086,4 +1222,98 @@ mod tests {
         sk.wal_store.truncate_wal(Lsn(3)).unwrap(); // imitate the complete record at 3 %)
         assert_eq!(sk.get_epoch(), 1);
     }
+
+    #[test]
+    fn test_recovered_wal() {
+        let storage = InMemoryState {
+            persisted_state: SafeKeeperState::empty(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+
+        let pem = ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(1),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::Elected(pem))
+            .unwrap();
+        assert_eq!(sk.get_epoch(), 1);
+
+        // peer knows that WAL up to 3 is committed, but we don't have it yet
+        sk.global_commit_lsn = Lsn(3);
+        assert_eq!(sk.start_recovery().unwrap(), Lsn(1));
+
+        // WAL from the peer in another epoch is refused
+        assert!(sk.handle_recovered_wal(2, Lsn(1), b"bb").is_err());
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(1));
+
+        // and in our epoch it is written, advancing commit_lsn
+        sk.handle_recovered_wal(1, Lsn(1), b"bb").unwrap();
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(3));
+        assert_eq!(sk.inmem.commit_lsn, Lsn(3));
+    }
+
+    #[test]
+    fn test_membership() {
+        let storage = InMemoryState {
+            persisted_state: SafeKeeperState::empty(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(1)).unwrap();
+        let members = |ids: &[u64]| ids.iter().map(|id| NodeId(*id)).collect::<Vec<_>>();
+
+        sk.set_membership(Configuration {
+            generation: 1,
+            members: members(&[1, 2, 3]),
+            new_members: None,
+        })
+        .unwrap();
+        assert_eq!(sk.state.mconf.generation, 1);
+        sk.check_membership().unwrap();
+        sk.check_proposer_generation(Some(1)).unwrap();
+        sk.check_proposer_generation(Some(2)).unwrap();
+        assert!(sk.check_proposer_generation(Some(0)).is_err());
+        // the generation is required once the membership is configured
+        assert!(sk.check_proposer_generation(None).is_err());
+
+        // can't skip the joint configuration
+        assert!(sk
+            .set_membership(Configuration {
+                generation: 2,
+                members: members(&[2, 3, 4]),
+                new_members: None,
+            })
+            .is_err());
+        assert_eq!(sk.state.mconf.generation, 1);
+
+        // replace 1 with 4
+        sk.set_membership(Configuration {
+            generation: 2,
+            members: members(&[1, 2, 3]),
+            new_members: Some(members(&[2, 3, 4])),
+        })
+        .unwrap();
+        assert!(sk.is_member());
+        sk.set_membership(Configuration {
+            generation: 3,
+            members: members(&[2, 3, 4]),
+            new_members: None,
+        })
+        .unwrap();
+        assert!(!sk.is_member());
+
+        // removed safekeeper doesn't vote
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 1 });
+        assert!(sk.process_msg(&vote_request).is_err());
+        assert_eq!(sk.state.acceptor_state.term, 0);
+    }
 }
@@ -2,7 +2,9 @@
 //! with the "START_REPLICATION" message.
 
 use crate::handler::SafekeeperPostgresHandler;
+use crate::recovery::RECOVERY_APPNAME;
 use crate::timeline::{ReplicaState, Timeline, TimelineTools};
+use crate::wal_backup;
 use crate::wal_storage::WalReader;
 use anyhow::{bail, Context, Result};
 
@@ -25,7 +27,7 @@ use utils::{
     postgres_backend::PostgresBackend,
     pq_proto::{BeMessage, FeMessage, WalSndKeepAlive, XLogDataBody, ZenithFeedback},
     sock_split::ReadStream,
-    zid::{ZTenantId, ZTimelineId},
+    zid::{NodeId, ZTenantId, ZTimelineId},
 };
 
 // See: https://www.postgresql.org/docs/13/protocol-replication.html
@@ -34,8 +36,19 @@ const STANDBY_STATUS_UPDATE_TAG_BYTE: u8 = b'r';
 // zenith extension of replication protocol
 const ZENITH_STATUS_UPDATE_TAG_BYTE: u8 = b'z';
 
+// Pageservers connect with application_name=pageserver_<node id>
+const PAGESERVER_APPNAME_PREFIX: &str = "pageserver_";
+
 type FullTransactionId = u64;
 
+fn pageserver_id_from_appname(appname: &str) -> Option<NodeId> {
+    appname
+        .strip_prefix(PAGESERVER_APPNAME_PREFIX)?
+        .parse()
+        .ok()
+        .map(NodeId)
+}
+
 /// Hot standby feedback received from replica
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct HotStandbyFeedback {
@@ -207,6 +220,10 @@ impl ReplicationConn {
         let bg_timeline = Arc::clone(spg.timeline.get());
         let bg_stream_in = self.stream_in.take().unwrap();
 
+        if let Some(ps_id) = spg.appname.as_deref().and_then(pageserver_id_from_appname) {
+            bg_timeline.set_pageserver_id(ps_id);
+        }
+
         let state = ReplicaState::new();
         // This replica_id is used below to check if it's time to stop replication.
         let replica_id = bg_timeline.add_replica(state);
@@ -249,20 +266,24 @@ impl ReplicationConn {
         // another compute rises which collects majority and starts fixing log
         // on this safekeeper itself. That's ok as (old) proposer will never be
         // able to commit such WAL.
-        let stop_pos: Option<Lsn> = if spg.appname == Some("wal_proposer_recovery".to_string()) {
-            Some(wal_end)
-        } else {
-            None
-        };
+        //
+        // Peer safekeepers recovering WAL from us are treated the same way.
+        let is_recovery = matches!(
+            spg.appname.as_deref(),
+            Some("wal_proposer_recovery") | Some(RECOVERY_APPNAME)
+        );
+        let stop_pos: Option<Lsn> = if is_recovery { Some(wal_end) } else { None };
         info!("Start replication from {:?} till {:?}", start_pos, stop_pos);
 
         // Don't spam pageserver with callmemaybe queries
         // when replication connection with pageserver is already established.
         let _guard = {
-            if spg.appname == Some("wal_proposer_recovery".to_string()) {
+            if is_recovery {
                 None
             } else {
-                let pageserver_connstr = pageserver_connstr.expect("there should be a pageserver connection string since this is not a wal_proposer_recovery");
+                let pageserver_connstr = pageserver_connstr.expect(
+                    "there should be a pageserver connection string since this is not a recovery",
+                );
                 let zttid = spg.timeline.get().zttid;
                 let tx_clone = spg.timeline.get().callmemaybe_tx.clone();
                 let subscription_key = SubscriptionStateKey::new(
@@ -291,10 +312,17 @@ impl ReplicationConn {
 
         let mut end_pos = Lsn(0);
 
+        // Segments already removed locally are streamed from the remote
+        // storage, if there is one.
+        let enable_remote_read = spg.conf.remote_storage.is_some();
+        if enable_remote_read {
+            wal_backup::init_remote_storage(&spg.conf);
+        }
         let mut wal_reader = WalReader::new(
             spg.conf.timeline_dir(&spg.timeline.get().zttid),
             wal_seg_size,
             start_pos,
+            enable_remote_read,
         );
 
         // buffer for wal sending, limited by MAX_SEND_SIZE
@@ -3,7 +3,7 @@
 
 use anyhow::{bail, Context, Result};
 
-use etcd_broker::SkTimelineInfo;
+use etcd_broker::{PsTimelineInfo, SkTimelineInfo};
 use lazy_static::lazy_static;
 use postgres_ffi::xlog_utils::XLogSegNo;
 
@@ -15,7 +15,7 @@ use std::collections::HashMap;
 use std::fs::{self};
 
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{Sender, UnboundedSender};
 use tracing::*;
 
@@ -27,9 +27,12 @@ use utils::{
 
 use crate::callmemaybe::{CallmeEvent, SubscriptionStateKey};
 use crate::control_file;
+use crate::membership::Configuration;
+use crate::pull_timeline::{is_timeline_file, TimelineSnapshot};
+use crate::recovery::Donor;
 use crate::safekeeper::{
     AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
-    SafekeeperMemState,
+    SafekeeperMemState, Term, INVALID_TERM,
 };
 use crate::send_wal::HotStandbyFeedback;
 
@@ -39,6 +42,10 @@ use crate::SafeKeeperConf;
 
 const POLL_STATE_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Peer info older than this is not used for choosing a recovery donor, the
+/// peer might be gone.
+const PEER_INFO_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Replica status update + hot standby feedback
 #[derive(Debug, Clone, Copy)]
 pub struct ReplicaState {
@@ -73,6 +80,15 @@ impl ReplicaState {
     }
 }
 
+/// State of a peer safekeeper, as learned from the broker.
+struct PeerState {
+    /// Term of the peer's last log entry.
+    term: Term,
+    flush_lsn: Lsn,
+    pg_connstr: Option<String>,
+    last_update: Instant,
+}
+
 /// Shared state associated with database instance
 struct SharedState {
     /// Safekeeper object
@@ -96,7 +112,14 @@ struct SharedState {
     active: bool,
     num_computes: u32,
     pageserver_connstr: Option<String>,
+    /// Pageserver that streamed the timeline's WAL last, the only one whose
+    /// updates from the broker are accepted.
+    pageserver_id: Option<NodeId>,
     last_removed_segno: XLogSegNo,
+    /// Peer safekeepers of the timeline.
+    peers: HashMap<NodeId, PeerState>,
+    /// True while WAL is being recovered from a peer.
+    recovery_active: bool,
 }
 
 impl SharedState {
@@ -120,7 +143,10 @@ impl SharedState {
             active: false,
             num_computes: 0,
             pageserver_connstr: None,
+            pageserver_id: None,
             last_removed_segno: 0,
+            peers: HashMap::new(),
+            recovery_active: false,
         })
     }
 
@@ -140,7 +166,10 @@ impl SharedState {
             active: false,
             num_computes: 0,
             pageserver_connstr: None,
+            pageserver_id: None,
             last_removed_segno: 0,
+            peers: HashMap::new(),
+            recovery_active: false,
         })
     }
     fn is_active(&self) -> bool {
@@ -524,6 +553,25 @@ impl Timeline {
         (shared_state.sk.inmem.clone(), shared_state.sk.state.clone())
     }
 
+    /// Persistent state with the list of timeline files taken at the same
+    /// moment, for copying the timeline to another safekeeper.
+    pub fn snapshot(&self, conf: &SafeKeeperConf) -> Result<TimelineSnapshot> {
+        let shared_state = self.mutex.lock().unwrap();
+        let mut files = Vec::new();
+        for entry in fs::read_dir(conf.timeline_dir(&self.zttid))? {
+            if let Some(fname) = entry?.file_name().to_str() {
+                if is_timeline_file(fname) {
+                    files.push(fname.to_owned());
+                }
+            }
+        }
+        Ok(TimelineSnapshot {
+            state: shared_state.sk.state.clone(),
+            flush_lsn: shared_state.sk.wal_store.flush_lsn(),
+            files,
+        })
+    }
+
     pub fn get_wal_backup_lsn(&self) -> Lsn {
         self.mutex.lock().unwrap().sk.inmem.backup_lsn
     }
@@ -557,7 +605,7 @@ impl Timeline {
     pub async fn record_safekeeper_info(
         &self,
         sk_info: &SkTimelineInfo,
-        _sk_id: NodeId,
+        sk_id: NodeId,
     ) -> Result<()> {
         let is_wal_backup_action_pending: bool;
         let commit_lsn: Lsn;
@@ -569,6 +617,17 @@ impl Timeline {
                 return Ok(());
             }
             shared_state.sk.record_safekeeper_info(sk_info)?;
+            if let (Some(term), Some(flush_lsn)) = (sk_info.last_log_term, sk_info.flush_lsn) {
+                shared_state.peers.insert(
+                    sk_id,
+                    PeerState {
+                        term,
+                        flush_lsn,
+                        pg_connstr: sk_info.safekeeper_connection_string.clone(),
+                        last_update: Instant::now(),
+                    },
+                );
+            }
             self.notify_wal_senders(&mut shared_state);
             is_wal_backup_action_pending = shared_state.update_status();
             commit_lsn = shared_state.sk.inmem.commit_lsn;
@@ -581,6 +640,115 @@ impl Timeline {
         Ok(())
     }
 
+    /// Check whether some peer has more WAL than we do in the same epoch, and
+    /// if so, prepare to fetch it and return the peer. Recovery is not done
+    /// while a compute is connected, its walproposer takes care of that.
+    pub fn start_recovery(&self) -> Result<Option<Donor>> {
+        let mut shared_state = self.mutex.lock().unwrap();
+        if shared_state.recovery_active
+            || shared_state.num_computes > 0
+            || shared_state.get_wal_seg_size() == 0
+            || !shared_state.sk.is_member()
+        {
+            return Ok(None);
+        }
+        let epoch = shared_state.sk.get_epoch();
+        if epoch == INVALID_TERM {
+            return Ok(None);
+        }
+        let flush_lsn = shared_state.sk.wal_store.flush_lsn();
+        let donor = shared_state
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.term == epoch
+                    && peer.flush_lsn > flush_lsn
+                    && peer.last_update.elapsed() < PEER_INFO_TIMEOUT
+            })
+            .filter_map(|(sk_id, peer)| {
+                let pg_connstr = peer.pg_connstr.clone()?;
+                Some((*sk_id, peer.flush_lsn, pg_connstr))
+            })
+            .max_by_key(|(_, flush_lsn, _)| *flush_lsn);
+        let (sk_id, end_lsn, pg_connstr) = match donor {
+            Some(donor) => donor,
+            None => return Ok(None),
+        };
+
+        let start_lsn = shared_state.sk.start_recovery()?;
+        shared_state.recovery_active = true;
+        Ok(Some(Donor {
+            sk_id,
+            epoch,
+            pg_connstr,
+            start_lsn,
+            end_lsn,
+        }))
+    }
+
+    /// Write WAL received from the recovery donor.
+    pub fn write_recovered_wal(&self, epoch: Term, begin_lsn: Lsn, wal_data: &[u8]) -> Result<()> {
+        let commit_lsn: Lsn;
+        {
+            let mut shared_state = self.mutex.lock().unwrap();
+            if shared_state.num_computes > 0 {
+                bail!("compute connected, stopping recovery");
+            }
+            shared_state
+                .sk
+                .handle_recovered_wal(epoch, begin_lsn, wal_data)?;
+            self.notify_wal_senders(&mut shared_state);
+            commit_lsn = shared_state.sk.inmem.commit_lsn;
+        }
+        self.commit_lsn_watch_tx.send(commit_lsn)?;
+        Ok(())
+    }
+
+    pub fn finish_recovery(&self) {
+        self.mutex.lock().unwrap().recovery_active = false;
+    }
+
+    /// Switch the timeline to the new membership configuration.
+    pub fn set_membership(&self, mconf: Configuration) -> Result<()> {
+        self.mutex.lock().unwrap().sk.set_membership(mconf)
+    }
+
+    /// Remember the pageserver streaming the timeline's WAL.
+    pub fn set_pageserver_id(&self, ps_id: NodeId) {
+        self.mutex.lock().unwrap().pageserver_id = Some(ps_id);
+    }
+
+    /// Update timeline state with pageserver data. Updates from the pageservers
+    /// other than the one streaming WAL of the timeline are ignored: a detached
+    /// pageserver might still publish its stale state.
+    pub async fn record_pageserver_info(
+        &self,
+        ps_info: &PsTimelineInfo,
+        ps_id: NodeId,
+    ) -> Result<()> {
+        let is_wal_backup_action_pending: bool;
+        {
+            let mut shared_state = self.mutex.lock().unwrap();
+            if shared_state.get_wal_seg_size() == 0 {
+                return Ok(());
+            }
+            if shared_state.pageserver_id != Some(ps_id) {
+                debug!(
+                    "ignoring info from pageserver {}, timeline {} is attached to {:?}",
+                    ps_id, self.zttid, shared_state.pageserver_id
+                );
+                return Ok(());
+            }
+            shared_state.sk.record_pageserver_info(ps_info)?;
+            is_wal_backup_action_pending = shared_state.update_status();
+        }
+        // Wake up wal backup launcher, if it is time to stop the offloading.
+        if is_wal_backup_action_pending {
+            self.wal_backup_launcher_tx.send(self.zttid).await?;
+        }
+        Ok(())
+    }
+
     pub fn add_replica(&self, state: ReplicaState) -> usize {
         let mut shared_state = self.mutex.lock().unwrap();
         shared_state.add_replica(state)
@@ -766,6 +934,12 @@ impl GlobalTimelines {
         state.timelines.get(&zttid).map(Arc::clone)
     }
 
+    /// Get all loaded timelines.
+    pub fn get_loaded_timelines() -> Vec<Arc<Timeline>> {
+        let state = TIMELINES_STATE.lock().unwrap();
+        state.timelines.values().cloned().collect()
+    }
+
     /// Get ZTenantTimelineIDs of all active timelines.
     pub fn get_active_timelines() -> Vec<ZTenantTimelineId> {
         let state = TIMELINES_STATE.lock().unwrap();
@@ -782,7 +956,9 @@ impl GlobalTimelines {
         zttid: &ZTenantTimelineId,
         was_active: bool,
     ) -> Result<TimelineDeleteForceResult> {
-        match std::fs::remove_dir_all(conf.timeline_dir(zttid)) {
+        let timeline_dir = conf.timeline_dir(zttid);
+        wal_storage::forget_verified_segments(&timeline_dir);
+        match std::fs::remove_dir_all(&timeline_dir) {
             Ok(_) => Ok(TimelineDeleteForceResult {
                 dir_existed: true,
                 was_active,
@@ -848,7 +1024,9 @@ impl GlobalTimelines {
             );
         }
         // There may be inactive timelines, so delete the whole tenant dir as well.
-        match std::fs::remove_dir_all(conf.tenant_dir(tenant_id)) {
+        let tenant_dir = conf.tenant_dir(tenant_id);
+        wal_storage::forget_verified_segments(&tenant_dir);
+        match std::fs::remove_dir_all(&tenant_dir) {
             Ok(_) => (),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
             e => e?,
@@ -3,6 +3,7 @@ use tokio::task::JoinHandle;
 
 use std::cmp::min;
 use std::collections::HashMap;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -10,7 +11,8 @@ use std::time::Duration;
 use postgres_ffi::xlog_utils::{XLogFileName, XLogSegNo, XLogSegNoOffsetToRecPtr, PG_TLI};
 use remote_storage::{GenericRemoteStorage, RemoteStorage};
 use tokio::fs::File;
-use tokio::runtime::Builder;
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tokio::runtime::{Builder, Runtime};
 
 use tokio::select;
 use tokio::sync::mpsc::{self, Receiver, Sender};
@@ -22,9 +24,9 @@ use utils::{lsn::Lsn, zid::ZTenantTimelineId};
 
 use crate::broker::{Election, ElectionLeader};
 use crate::timeline::{GlobalTimelines, Timeline};
-use crate::{broker, SafeKeeperConf};
+use crate::{broker, wal_storage, SafeKeeperConf};
 
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
 
 const BACKUP_ELECTION_NAME: &str = "WAL_BACKUP";
 
@@ -75,12 +77,7 @@ async fn wal_backup_launcher_main_loop(
         conf.remote_storage
     );
 
-    let conf_ = conf.clone();
-    REMOTE_STORAGE.get_or_init(|| {
-        conf_.remote_storage.as_ref().map(|c| {
-            GenericRemoteStorage::new(conf_.workdir, c).expect("failed to create remote storage")
-        })
-    });
+    init_remote_storage(&conf);
 
     let mut tasks: HashMap<ZTenantTimelineId, WalBackupTaskHandle> = HashMap::new();
 
@@ -333,6 +330,10 @@ pub async fn backup_lsn_range(
 async fn backup_single_segment(seg: &Segment, timeline_dir: &Path) -> Result<()> {
     let segment_file_name = seg.file_path(timeline_dir)?;
 
+    // Don't spread the corruption to the remote storage.
+    let path = segment_file_name.clone();
+    tokio::task::spawn_blocking(move || wal_storage::verify_segment(&path)).await??;
+
     backup_object(&segment_file_name, seg.size()).await?;
     debug!("Backup of {} done", segment_file_name.display());
 
@@ -384,6 +385,28 @@ fn get_segments(start: Lsn, end: Lsn, seg_size: usize) -> Vec<Segment> {
 
 static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
 
+/// Runtime used to download offloaded WAL for the walsender threads, which
+/// are not async.
+static REMOTE_READ_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("wal-read-runtime")
+        .enable_all()
+        .build()
+        .expect("failed to create remote WAL read runtime")
+});
+
+/// Set up the remote storage client, if remote storage is configured. Called
+/// by the launcher and by walsenders, whichever needs it first.
+pub fn init_remote_storage(conf: &SafeKeeperConf) {
+    REMOTE_STORAGE.get_or_init(|| {
+        conf.remote_storage.as_ref().map(|c| {
+            GenericRemoteStorage::new(conf.workdir.clone(), c)
+                .expect("failed to create remote storage")
+        })
+    });
+}
+
 async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
     let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
 
@@ -415,3 +438,73 @@ async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
 
     Ok(())
 }
+
+/// Size of the buffer between the download of an offloaded segment and its
+/// reader.
+const REMOTE_READ_BUFFER_SIZE: usize = 128 * 1024;
+
+/// Reader of an offloaded segment, see [`read_object`].
+pub struct RemoteSegmentReader {
+    reader: DuplexStream,
+    download: Option<JoinHandle<Result<()>>>,
+}
+
+impl io::Read for RemoteSegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = REMOTE_READ_RUNTIME.block_on(self.reader.read(buf))?;
+        if n == 0 {
+            // The download has stopped, report it if it failed.
+            if let Some(download) = self.download.take() {
+                REMOTE_READ_RUNTIME
+                    .block_on(download)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:#}", e)))?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Start downloading the offloaded segment `file_path` from the remote
+/// storage, from `offset` up to the end of the segment. `file_path` is the
+/// local path the segment was uploaded from. The data is streamed through a
+/// fixed size buffer as it is read, and the download stops if the reader is
+/// dropped.
+pub fn read_object(file_path: &Path, offset: u64) -> Result<RemoteSegmentReader> {
+    let storage: &'static GenericRemoteStorage = REMOTE_STORAGE
+        .get()
+        .and_then(|s| s.as_ref())
+        .context("remote storage is not configured")?;
+
+    info!(
+        "segment {} is not present locally, downloading it from offset {}",
+        file_path.display(),
+        offset
+    );
+
+    let (reader, mut writer) = tokio::io::duplex(REMOTE_READ_BUFFER_SIZE);
+    let file_path = file_path.to_owned();
+    let download = REMOTE_READ_RUNTIME.spawn(async move {
+        match storage {
+            GenericRemoteStorage::Local(local_storage) => {
+                let source = local_storage.remote_object_id(&file_path)?;
+                local_storage
+                    .download_byte_range(&source, offset, None, &mut writer)
+                    .await
+            }
+            GenericRemoteStorage::S3(s3_storage) => {
+                let s3key = s3_storage.remote_object_id(&file_path)?;
+                s3_storage
+                    .download_byte_range(&s3key, offset, None, &mut writer)
+                    .await
+            }
+        }
+        .with_context(|| format!("failed to download segment {}", file_path.display()))?;
+        Ok(())
+    });
+
+    Ok(RemoteSegmentReader {
+        reader,
+        download: Some(download),
+    })
+}
@@ -6,6 +6,12 @@
 //! - 000000010000000000000002.partial
 //!
 //! Note that last file has `.partial` suffix, that's different from postgres.
+//!
+//! Completed segments have CRC32C of their contents stored next to them, in
+//! files like `000000010000000000000001.checksum`. The checksum is computed as
+//! the WAL is written, and made durable before the segment loses its
+//! `.partial` suffix. Segments are checked the first time they are read and
+//! before they are offloaded, and can be checked on demand.
 
 use anyhow::{anyhow, bail, Context, Result};
 use std::io::{Read, Seek, SeekFrom};
@@ -15,9 +21,14 @@ use postgres_ffi::xlog_utils::{
     find_end_of_wal, IsPartialXLogFileName, IsXLogFileName, XLogFromFileName, XLogSegNo, PG_TLI,
 };
 use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
+use serde::Serialize;
+use std::ffi::OsString;
 use std::fs::{self, remove_file, File, OpenOptions};
-use std::io::Write;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 
 use tracing::*;
@@ -26,6 +37,7 @@ use utils::{lsn::Lsn, zid::ZTenantTimelineId};
 
 use crate::safekeeper::SafeKeeperState;
 
+use crate::wal_backup;
 use crate::SafeKeeperConf;
 use postgres_ffi::xlog_utils::{XLogFileName, XLOG_BLCKSZ};
 
@@ -149,6 +161,11 @@ pub struct PhysicalStorage {
     /// - points to write_lsn, so no seek is needed for writing
     /// - doesn't point to the end of the segment
     file: Option<File>,
+
+    /// CRC32C of the last segment from its start up to `write_lsn`, to store
+    /// the checksum of the segment once it's completed without reading it
+    /// back. None if it needs to be recomputed from the file.
+    partial_checksum: Option<u32>,
 }
 
 impl PhysicalStorage {
@@ -165,6 +182,7 @@ impl PhysicalStorage {
             flush_record_lsn: Lsn(0),
             decoder: WalStreamDecoder::new(Lsn(0)),
             file: None,
+            partial_checksum: None,
         }
     }
 
@@ -194,23 +212,50 @@ impl PhysicalStorage {
         Ok(())
     }
 
+    /// Call fsync on the timeline directory if config requires so.
+    fn fsync_timeline_dir(&self) -> Result<()> {
+        if !self.conf.no_sync {
+            File::open(&self.timeline_dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Durably store checksum of the just completed segment. Must be done
+    /// before renaming the segment from .partial, so that a completed
+    /// segment never lacks its checksum.
+    fn write_checksum(&self, wal_file_path: &Path, checksum: u32) -> Result<()> {
+        let checksum_path = checksum_file_path(wal_file_path);
+        let mut tmp_path = OsString::from(&checksum_path);
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(format!("{:08x}", checksum).as_bytes())?;
+        self.fsync_file(&mut file)?;
+        fs::rename(&tmp_path, &checksum_path)?;
+        self.fsync_timeline_dir()
+    }
+
     /// Open or create WAL segment file. Caller must call seek to the wanted position.
     /// Returns `file` and `is_partial`.
     fn open_or_create(&self, segno: XLogSegNo, wal_seg_size: usize) -> Result<(File, bool)> {
         let (wal_file_path, wal_file_partial_path) =
             wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
 
+        // The file is readable too, to compute the checksum of its beginning.
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+
         // Try to open already completed segment
-        if let Ok(file) = OpenOptions::new().write(true).open(&wal_file_path) {
+        if let Ok(file) = options.open(&wal_file_path) {
             Ok((file, false))
-        } else if let Ok(file) = OpenOptions::new().write(true).open(&wal_file_partial_path) {
+        } else if let Ok(file) = options.open(&wal_file_partial_path) {
             // Try to open existing partial file
             Ok((file, true))
         } else {
             // Create and fill new partial file
-            let mut file = OpenOptions::new()
+            let mut file = options
                 .create(true)
-                .write(true)
                 .open(&wal_file_partial_path)
                 .with_context(|| format!("Failed to open log file {:?}", &wal_file_path))?;
 
@@ -228,6 +273,8 @@ impl PhysicalStorage {
         buf: &[u8],
         wal_seg_size: usize,
     ) -> Result<()> {
+        let (wal_file_path, wal_file_partial_path) =
+            wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
         let mut file = if let Some(file) = self.file.take() {
             file
         } else {
@@ -237,19 +284,28 @@ impl PhysicalStorage {
             file
         };
 
+        // Resume the checksum of the segment, reading the already written
+        // part of the file only if we didn't write it ourselves, e.g. after restart.
+        let checksum = match self.partial_checksum.take() {
+            Some(checksum) => checksum,
+            None => compute_prefix_checksum(&file, xlogoff as u64)?,
+        };
         file.write_all(buf)?;
+        let checksum = crc32c::crc32c_append(checksum, buf);
 
         if xlogoff + buf.len() == wal_seg_size {
             // If we reached the end of a WAL segment, flush and close it.
             self.fdatasync_file(&mut file)?;
 
-            // Rename partial file to completed file
-            let (wal_file_path, wal_file_partial_path) =
-                wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
+            // Store the checksum, then rename partial file to completed file
+            self.write_checksum(&wal_file_path, checksum)?;
             fs::rename(&wal_file_partial_path, &wal_file_path)?;
+            // The next segment starts from scratch.
+            self.partial_checksum = Some(0);
         } else {
             // otherwise, file can be reused later
             self.file = Some(file);
+            self.partial_checksum = Some(checksum);
         }
 
         Ok(())
@@ -272,6 +328,7 @@ impl PhysicalStorage {
             }
 
             self.write_lsn = pos;
+            self.partial_checksum = None;
         }
 
         while !buf.is_empty() {
@@ -447,6 +504,7 @@ impl Storage for PhysicalStorage {
             // Make segment partial once again
             let (wal_file_path, wal_file_partial_path) =
                 wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
+            remove_checksum(&wal_file_path)?;
             fs::rename(&wal_file_path, &wal_file_partial_path)?;
         }
 
@@ -458,6 +516,7 @@ impl Storage for PhysicalStorage {
                 wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
             // TODO: better use fs::try_exists which is currenty avaialble only in nightly build
             if wal_file_path.exists() {
+                remove_checksum(&wal_file_path)?;
                 fs::remove_file(&wal_file_path)?;
             } else if wal_file_partial_path.exists() {
                 fs::remove_file(&wal_file_partial_path)?;
@@ -468,6 +527,7 @@ impl Storage for PhysicalStorage {
 
         // Update LSNs
         self.write_lsn = end_pos;
+        self.partial_checksum = None;
         self.write_record_lsn = end_pos;
         self.update_flush_lsn();
         Ok(())
@@ -491,14 +551,22 @@ fn remove_up_to(timeline_dir: &Path, wal_seg_size: usize, segno_up_to: XLogSegNo
         let fname = entry_path.file_name().unwrap();
 
         if let Some(fname_str) = fname.to_str() {
+            // Checksums go together with their segments
+            let (seg_name, is_checksum) = match fname_str.strip_suffix(CHECKSUM_SUFFIX) {
+                Some(seg_name) => (seg_name, true),
+                None => (fname_str, false),
+            };
             /* Ignore files that are not XLOG segments */
-            if !IsXLogFileName(fname_str) && !IsPartialXLogFileName(fname_str) {
+            if !IsXLogFileName(seg_name) && !IsPartialXLogFileName(seg_name) {
                 continue;
             }
-            let (segno, _) = XLogFromFileName(fname_str, wal_seg_size);
+            let (segno, _) = XLogFromFileName(seg_name, wal_seg_size);
             if segno <= segno_up_to {
-                remove_file(entry_path)?;
-                n_removed += 1;
+                remove_file(&entry_path)?;
+                if !is_checksum {
+                    VERIFIED_SEGMENTS.lock().unwrap().remove(&entry_path);
+                    n_removed += 1;
+                }
             }
         }
     }
@@ -516,72 +584,298 @@ pub struct WalReader {
     timeline_dir: PathBuf,
     wal_seg_size: usize,
     pos: Lsn,
-    file: Option<File>,
+    segment: Option<WalSegment>,
+    // Download segments which are not present locally from the remote storage
+    enable_remote_read: bool,
+}
+
+/// Segment the reader is currently reading from.
+enum WalSegment {
+    Local(File),
+    /// Offloaded segment, streamed from the remote storage from the reader
+    /// position till the end of the segment.
+    Remote(wal_backup::RemoteSegmentReader),
 }
 
 impl WalReader {
-    pub fn new(timeline_dir: PathBuf, wal_seg_size: usize, pos: Lsn) -> Self {
+    pub fn new(
+        timeline_dir: PathBuf,
+        wal_seg_size: usize,
+        pos: Lsn,
+        enable_remote_read: bool,
+    ) -> Self {
         Self {
             timeline_dir,
             wal_seg_size,
             pos,
-            file: None,
+            segment: None,
+            enable_remote_read,
         }
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        // Take the `File` from `wal_file`, or open a new file.
-        let mut file = match self.file.take() {
-            Some(file) => file,
-            None => {
-                // Open a new file.
-                let segno = self.pos.segment_number(self.wal_seg_size);
-                let wal_file_name = XLogFileName(PG_TLI, segno, self.wal_seg_size);
-                let wal_file_path = self.timeline_dir.join(wal_file_name);
-                Self::open_wal_file(&wal_file_path)?
-            }
-        };
-
         let xlogoff = self.pos.segment_offset(self.wal_seg_size) as usize;
 
+        // Take the segment from `segment`, or open a new one.
+        let mut segment = match self.segment.take() {
+            Some(segment) => segment,
+            None => self.open_segment(xlogoff)?,
+        };
+
         // How much to read and send in message? We cannot cross the WAL file
         // boundary, and we don't want send more than provided buffer.
         let send_size = min(buf.len(), self.wal_seg_size - xlogoff);
 
-        // Read some data from the file.
+        // Read some data from the segment.
         let buf = &mut buf[0..send_size];
-        file.seek(SeekFrom::Start(xlogoff as u64))
-            .and_then(|_| file.read_exact(buf))
-            .context("Failed to read data from WAL file")?;
+        match &mut segment {
+            WalSegment::Local(file) => file
+                .seek(SeekFrom::Start(xlogoff as u64))
+                .and_then(|_| file.read_exact(buf))
+                .context("Failed to read data from WAL file")?,
+            // Downloaded data starts at the position the segment was opened
+            // at, and the reader only moves forward, so no need to seek.
+            WalSegment::Remote(reader) => reader
+                .read_exact(buf)
+                .context("Failed to read data from downloaded WAL segment")?,
+        }
 
         self.pos += send_size as u64;
 
-        // Decide whether to reuse this file. If we don't set wal_file here
-        // a new file will be opened next time.
+        // Decide whether to reuse this segment. If we don't set it here
+        // a new one will be opened next time.
         if self.pos.segment_offset(self.wal_seg_size) != 0 {
-            self.file = Some(file);
+            self.segment = Some(segment);
         }
 
         Ok(send_size)
     }
 
-    /// Helper function for opening a wal file.
-    fn open_wal_file(wal_file_path: &Path) -> Result<File> {
+    /// Open the segment containing the current position, downloading it from
+    /// the remote storage if it was already removed locally.
+    fn open_segment(&self, xlogoff: usize) -> Result<WalSegment> {
+        let segno = self.pos.segment_number(self.wal_seg_size);
+        let wal_file_name = XLogFileName(PG_TLI, segno, self.wal_seg_size);
+        let wal_file_path = self.timeline_dir.join(wal_file_name);
+
+        let local_err = match Self::open_wal_file(&wal_file_path) {
+            Ok((file, is_partial)) => {
+                // Partial segment has no checksum yet.
+                if !is_partial {
+                    if let Err(e) = verify_segment_once(&wal_file_path) {
+                        error!("{:#}", e);
+                        return Err(e);
+                    }
+                }
+                return Ok(WalSegment::Local(file));
+            }
+            Err(e) => e,
+        };
+        if !self.enable_remote_read {
+            error!("{:#}", local_err);
+            return Err(local_err);
+        }
+
+        // Only full segments are offloaded, so look for the segment under its
+        // full name.
+        let reader = wal_backup::read_object(&wal_file_path, xlogoff as u64).map_err(|e| {
+            error!("{:#}", e);
+            e
+        })?;
+        Ok(WalSegment::Remote(reader))
+    }
+
+    /// Helper function for opening a wal file. Returns `file` and `is_partial`.
+    fn open_wal_file(wal_file_path: &Path) -> Result<(File, bool)> {
         // First try to open the .partial file.
         let mut partial_path = wal_file_path.to_owned();
         partial_path.set_extension("partial");
         if let Ok(opened_file) = File::open(&partial_path) {
-            return Ok(opened_file);
+            return Ok((opened_file, true));
         }
 
         // If that failed, try it without the .partial extension.
-        File::open(&wal_file_path)
-            .with_context(|| format!("Failed to open WAL file {:?}", wal_file_path))
-            .map_err(|e| {
-                error!("{}", e);
-                e
-            })
+        let file = File::open(&wal_file_path)
+            .with_context(|| format!("Failed to open WAL file {:?}", wal_file_path))?;
+        Ok((file, false))
+    }
+}
+
+/// Suffix of the files with checksums of the completed segments.
+pub const CHECKSUM_SUFFIX: &str = ".checksum";
+
+/// Path of the file with the checksum of the segment.
+fn checksum_file_path(wal_file_path: &Path) -> PathBuf {
+    let mut path = OsString::from(wal_file_path);
+    path.push(CHECKSUM_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// CRC32C of the whole file.
+fn compute_checksum(path: &Path) -> Result<u32> {
+    let file = File::open(path).with_context(|| format!("Failed to open WAL file {:?}", path))?;
+    compute_prefix_checksum(&file, u64::MAX)
+}
+
+/// CRC32C of the first `len` bytes of the file. Doesn't move the file position.
+fn compute_prefix_checksum(file: &File, len: u64) -> Result<u32> {
+    let mut buf = vec![0u8; XLOG_BLCKSZ * 16];
+    let mut checksum = 0;
+    let mut pos = 0;
+    while pos < len {
+        let to_read = min(buf.len() as u64, len - pos) as usize;
+        let n = file.read_at(&mut buf[..to_read], pos)?;
+        if n == 0 {
+            break;
+        }
+        checksum = crc32c::crc32c_append(checksum, &buf[..n]);
+        pos += n as u64;
+    }
+    Ok(checksum)
+}
+
+/// Checksum stored for the segment, if any.
+fn read_checksum(wal_file_path: &Path) -> Result<Option<u32>> {
+    let checksum_path = checksum_file_path(wal_file_path);
+    match fs::read_to_string(&checksum_path) {
+        Ok(s) => Ok(Some(u32::from_str_radix(s.trim(), 16).with_context(
+            || format!("Failed to parse checksum file {:?}", checksum_path),
+        )?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove the checksum of the segment, which is about to be truncated or removed.
+fn remove_checksum(wal_file_path: &Path) -> Result<()> {
+    VERIFIED_SEGMENTS.lock().unwrap().remove(wal_file_path);
+    match remove_file(checksum_file_path(wal_file_path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Result of checking the segment against its checksum.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SegmentCheck {
+    Valid,
+    /// Segments completed by older versions don't have checksums.
+    NoChecksum,
+    Corrupted {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+/// Check the completed segment against its checksum.
+pub fn check_segment(wal_file_path: &Path) -> Result<SegmentCheck> {
+    let expected = match read_checksum(wal_file_path)? {
+        Some(expected) => expected,
+        None => return Ok(SegmentCheck::NoChecksum),
+    };
+    let actual = compute_checksum(wal_file_path)?;
+    if actual == expected {
+        Ok(SegmentCheck::Valid)
+    } else {
+        Ok(SegmentCheck::Corrupted { expected, actual })
+    }
+}
+
+/// Bail out if the completed segment doesn't match its checksum.
+pub fn verify_segment(wal_file_path: &Path) -> Result<()> {
+    if let SegmentCheck::Corrupted { expected, actual } = check_segment(wal_file_path)? {
+        bail!(
+            "WAL segment {:?} is corrupted: checksum {:08x}, expected {:08x}",
+            wal_file_path,
+            actual,
+            expected
+        );
+    }
+    Ok(())
+}
+
+lazy_static! {
+    /// Completed segments found matching their checksums, with the checksum,
+    /// so that a segment is not read twice each time it's streamed. Segments
+    /// are forgotten when they are truncated or removed.
+    static ref VERIFIED_SEGMENTS: Mutex<HashMap<PathBuf, u32>> = Mutex::new(HashMap::new());
+}
+
+/// Same as verify_segment(), but skips the segments already verified.
+fn verify_segment_once(wal_file_path: &Path) -> Result<()> {
+    let expected = match read_checksum(wal_file_path)? {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    if VERIFIED_SEGMENTS.lock().unwrap().get(wal_file_path) == Some(&expected) {
+        return Ok(());
+    }
+    let actual = compute_checksum(wal_file_path)?;
+    if actual != expected {
+        bail!(
+            "WAL segment {:?} is corrupted: checksum {:08x}, expected {:08x}",
+            wal_file_path,
+            actual,
+            expected
+        );
+    }
+    VERIFIED_SEGMENTS
+        .lock()
+        .unwrap()
+        .insert(wal_file_path.to_owned(), expected);
+    Ok(())
+}
+
+/// Forget the verified segments in the directory, which is being removed.
+pub fn forget_verified_segments(dir: &Path) {
+    VERIFIED_SEGMENTS
+        .lock()
+        .unwrap()
+        .retain(|path, _| !path.starts_with(dir));
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct WalVerifyResult {
+    /// Number of segments matching their checksums.
+    pub verified: usize,
+    /// Number of segments without checksums.
+    pub without_checksum: usize,
+    /// Names of the segments not matching their checksums.
+    pub corrupted: Vec<String>,
+}
+
+/// Check all completed segments in the timeline directory.
+pub fn verify_timeline_wal(timeline_dir: &Path) -> Result<WalVerifyResult> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(timeline_dir)? {
+        if let Some(fname) = entry?.file_name().to_str() {
+            if IsXLogFileName(fname) {
+                segments.push(fname.to_owned());
+            }
+        }
+    }
+    segments.sort();
+
+    let mut result = WalVerifyResult::default();
+    for fname in segments {
+        let wal_file_path = timeline_dir.join(&fname);
+        match check_segment(&wal_file_path) {
+            Ok(SegmentCheck::Valid) => result.verified += 1,
+            Ok(SegmentCheck::NoChecksum) => result.without_checksum += 1,
+            Ok(SegmentCheck::Corrupted { expected, actual }) => {
+                VERIFIED_SEGMENTS.lock().unwrap().remove(&wal_file_path);
+                warn!(
+                    "WAL segment {:?} is corrupted: checksum {:08x}, expected {:08x}",
+                    wal_file_path, actual, expected
+                );
+                result.corrupted.push(fname);
+            }
+            // removed concurrently
+            Err(_) if !wal_file_path.exists() => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(result)
 }
 
 /// Zero block for filling created WAL segments.
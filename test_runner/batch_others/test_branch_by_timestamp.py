@@ -0,0 +1,70 @@
+from contextlib import closing
+from datetime import timedelta
+
+import pytest
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn
+
+
+#
+# Checks that a branch can be created at a point of time, and that the
+# timestamps in the future or before the GC cutoff are rejected.
+#
+def test_branch_by_timestamp(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    parent_timeline_id = env.zenith_cli.create_branch('test_branch_by_timestamp_parent')
+    pg = env.postgres.create_start('test_branch_by_timestamp_parent')
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE foo (x integer)")
+            tbl = []
+            for i in range(10):
+                cur.execute(f"INSERT INTO foo VALUES({i})")
+                cur.execute("SELECT clock_timestamp()")
+                # Get the timestamp at UTC
+                tbl.append((i, cur.fetchone()[0].replace(tzinfo=None)))
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            lsn = cur.fetchone()[0]
+
+    client = env.pageserver.http_client()
+    wait_for_last_record_lsn(client, env.initial_tenant, parent_timeline_id, lsn_from_hex(lsn))
+
+    i, timestamp = tbl[4]
+    child_timeline_id = env.zenith_cli.create_branch(
+        'test_branch_by_timestamp_child',
+        'test_branch_by_timestamp_parent',
+        ancestor_start_timestamp=f"{timestamp.isoformat()}Z")
+    log.info(f"branched at timestamp {timestamp} after inserting row {i}")
+
+    detail = client.timeline_detail(env.initial_tenant, child_timeline_id)
+    assert detail['local']['ancestor_timeline_id'] == parent_timeline_id.hex
+    assert lsn_from_hex(detail['local']['ancestor_lsn']) < lsn_from_hex(lsn)
+
+    pg_child = env.postgres.create_start('test_branch_by_timestamp_child')
+    assert pg_child.safe_psql("SELECT max(x) FROM foo") == [(i, )]
+
+    # A past timestamp after the last commit branches at the end of the timeline
+    detail = client.timeline_create(env.initial_tenant,
+                                    ancestor_timeline_id=parent_timeline_id,
+                                    ancestor_start_timestamp=f"{tbl[-1][1].isoformat()}Z")
+    assert lsn_from_hex(detail['local']['ancestor_lsn']) >= lsn_from_hex(lsn)
+
+    with pytest.raises(Exception, match="is in the future"):
+        client.timeline_create(env.initial_tenant,
+                               ancestor_timeline_id=parent_timeline_id,
+                               ancestor_start_timestamp=f"{(tbl[-1][1] + timedelta(hours=1)).isoformat()}Z")
+
+    with pytest.raises(Exception, match="is before the GC cutoff"):
+        client.timeline_create(env.initial_tenant,
+                               ancestor_timeline_id=parent_timeline_id,
+                               ancestor_start_timestamp=f"{(tbl[0][1] - timedelta(hours=10)).isoformat()}Z")
+
+    with pytest.raises(Exception, match="cannot be both specified"):
+        client.timeline_create(env.initial_tenant,
+                               ancestor_timeline_id=parent_timeline_id,
+                               ancestor_start_lsn=lsn,
+                               ancestor_start_timestamp=f"{timestamp.isoformat()}Z")
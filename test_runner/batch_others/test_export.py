@@ -0,0 +1,69 @@
+import json
+import os
+import shutil
+import tarfile
+from contextlib import closing
+from pathlib import Path
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn
+
+
+#
+# Checks that a branch exported at its last record LSN can be loaded back from
+# the archive, without its ancestor.
+#
+def test_export_timeline(zenith_env_builder: ZenithEnvBuilder, test_output_dir):
+    env = zenith_env_builder.init_start()
+    env.zenith_cli.create_branch('test_export_parent')
+    pg = env.postgres.create_start('test_export_parent')
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,10000), 'parent'")
+    pg.stop()
+
+    timeline_id = env.zenith_cli.create_branch('test_export_child', 'test_export_parent')
+    pg = env.postgres.create_start('test_export_child')
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("INSERT INTO t SELECT generate_series(10001,20000), 'child'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+    pg.stop()
+
+    client = env.pageserver.http_client()
+    wait_for_last_record_lsn(client, env.initial_tenant, timeline_id, current_lsn)
+
+    tar_path = os.path.join(test_output_dir, 'export.tar')
+    export_lsn = client.timeline_export(env.initial_tenant, timeline_id, tar_path)
+    log.info(f"exported timeline at {export_lsn}")
+    assert lsn_from_hex(export_lsn) >= current_lsn
+
+    with tarfile.open(tar_path) as tar:
+        names = set(tar.getnames())
+        index_part = json.load(tar.extractfile('index_part.json'))
+    assert 'metadata' in names
+    assert index_part['disk_consistent_lsn'] == export_lsn
+    assert set(index_part['timeline_layers']) == names - {'metadata', 'index_part.json'}
+    assert index_part['missing_layers'] == []
+
+    # Replace the timeline with the contents of the archive
+    env.pageserver.stop()
+    timeline_path = Path(env.repo_dir) / 'tenants' / env.initial_tenant.hex / 'timelines' / timeline_id.hex
+    shutil.rmtree(timeline_path)
+    timeline_path.mkdir()
+    with tarfile.open(tar_path) as tar:
+        tar.extractall(timeline_path)
+    (timeline_path / 'index_part.json').unlink()
+    env.pageserver.start()
+
+    detail = client.timeline_detail(env.initial_tenant, timeline_id)
+    assert detail['local']['ancestor_timeline_id'] is None
+    assert detail['local']['last_record_lsn'] == export_lsn
+
+    pg = env.postgres.create_start('test_export_child')
+    assert pg.safe_psql("SELECT value, count(*) FROM t GROUP BY value ORDER BY value") == [
+        ('child', 10000), ('parent', 10000)
+    ]
@@ -0,0 +1,116 @@
+import glob
+import json
+import os
+import threading
+import uuid
+from concurrent.futures import ThreadPoolExecutor
+
+from fixtures.log_helper import log
+from fixtures.zenith_fixtures import ZenithEnvBuilder, PgBin, PortDistributor, VanillaPostgres, wait_until
+
+
+#
+# Checks that a vanilla Postgres database can be moved to a new timeline, by
+# importing a pg_basebackup tar archive and the WAL generated during the backup.
+#
+def test_import_from_vanilla(zenith_env_builder: ZenithEnvBuilder,
+                             pg_bin: PgBin,
+                             port_distributor: PortDistributor,
+                             test_output_dir):
+    env = zenith_env_builder.init_start()
+
+    port = port_distributor.get_port()
+    backup_dir = os.path.join(test_output_dir, 'basebackup')
+    with VanillaPostgres(os.path.join(test_output_dir, 'pgdata-vanilla'), pg_bin,
+                         port) as vanilla_pg:
+        vanilla_pg.configure([f"port = {port}"])
+        vanilla_pg.start()
+        # the compute connects as this user
+        vanilla_pg.safe_psql("CREATE ROLE zenith_admin WITH SUPERUSER LOGIN")
+        vanilla_pg.safe_psql("CREATE TABLE t AS SELECT g AS key, 'payload' AS value "
+                             "FROM generate_series(1, 100000) g")
+
+        # -Xfetch puts the WAL needed to make the backup consistent into the archive
+        pg_bin.run_capture([
+            'pg_basebackup',
+            '-D',
+            backup_dir,
+            '-Ft',
+            '-Xfetch',
+            '-h',
+            'localhost',
+            '-p',
+            str(port),
+        ])
+
+    with open(os.path.join(backup_dir, 'backup_manifest')) as f:
+        wal_range = json.load(f)['WAL-Ranges'][0]
+    log.info(f"importing basebackup with WAL range {wal_range}")
+
+    timeline_id = env.zenith_cli.import_timeline('test_import_from_vanilla',
+                                                 os.path.join(backup_dir, 'base.tar'),
+                                                 base_lsn=wal_range['Start-LSN'],
+                                                 end_lsn=wal_range['End-LSN'])
+
+    client = env.pageserver.http_client()
+    detail = client.timeline_detail(env.initial_tenant, timeline_id)
+    assert detail['local']['last_record_lsn'] == wal_range['End-LSN']
+
+    pg = env.postgres.create_start('test_import_from_vanilla')
+    assert pg.safe_psql("SELECT count(*), min(key), max(key) FROM t") == [(100000, 1, 100000)]
+
+    # Importing into an existing timeline is refused
+    res = client.post(
+        f"http://localhost:{client.port}/v1/tenant/{env.initial_tenant.hex}/timeline/{timeline_id.hex}/import",
+        params={'base_lsn': wal_range['Start-LSN']},
+        data=b'')
+    assert res.status_code == 409
+
+    # A failed import leaves no timeline behind, so it can be retried with the same id
+    with open(os.path.join(backup_dir, 'base.tar'), 'rb') as f:
+        tar = f.read()
+    retry_timeline_id = uuid.uuid4()
+    import_url = f"http://localhost:{client.port}/v1/tenant/{env.initial_tenant.hex}/timeline/{retry_timeline_id.hex}/import"
+    import_params = {'base_lsn': wal_range['Start-LSN'], 'end_lsn': wal_range['End-LSN']}
+    res = client.post(import_url, params=import_params, data=tar[:len(tar) // 2])
+    assert res.status_code == 500
+    assert not os.path.exists(
+        os.path.join(env.repo_dir,
+                     'tenants',
+                     env.initial_tenant.hex,
+                     'timelines',
+                     retry_timeline_id.hex))
+
+    res = client.post(import_url, params=import_params, data=tar)
+    assert res.status_code == 201, res.text
+
+    # A concurrent import of the same timeline is refused while the first one receives the archive
+    concurrent_timeline_id = uuid.uuid4()
+    concurrent_url = f"http://localhost:{client.port}/v1/tenant/{env.initial_tenant.hex}/timeline/{concurrent_timeline_id.hex}/import"
+    release_rest = threading.Event()
+
+    def slow_body():
+        yield tar[:len(tar) // 2]
+        release_rest.wait()
+        yield tar[len(tar) // 2:]
+
+    def assert_import_started():
+        tmp_files = glob.glob(
+            os.path.join(env.repo_dir,
+                         'tenants',
+                         env.initial_tenant.hex,
+                         f'tmp-import-{concurrent_timeline_id.hex}-*.tar'))
+        assert tmp_files, "first import has not started receiving the archive"
+
+    with ThreadPoolExecutor(max_workers=1) as executor:
+        first = executor.submit(client.post, concurrent_url, params=import_params, data=slow_body())
+        try:
+            wait_until(number_of_iterations=10, interval=1, func=assert_import_started)
+            res = client.post(concurrent_url, params=import_params, data=tar)
+            assert res.status_code == 409
+        finally:
+            release_rest.set()
+        res = first.result()
+        assert res.status_code == 201, res.text
+    assert not glob.glob(
+        os.path.join(env.repo_dir, 'tenants', env.initial_tenant.hex, 'tmp-import-*'))
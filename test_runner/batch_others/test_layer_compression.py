@@ -0,0 +1,49 @@
+from contextlib import closing
+
+from fixtures.log_helper import log
+from fixtures.zenith_fixtures import ZenithEnvBuilder
+
+
+#
+# Checks that layers written with and without compression can be read
+# by the same pageserver.
+#
+def test_layer_compression(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    env.zenith_cli.create_branch('test_layer_compression')
+    pg = env.postgres.create_start('test_layer_compression')
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SHOW zenith.zenith_timeline")
+            timeline = cur.fetchone()[0]
+
+    def write_table(name: str):
+        with closing(pg.connect()) as conn:
+            with conn.cursor() as cur:
+                cur.execute(f"CREATE TABLE {name}(key int primary key, value text)")
+                cur.execute(f"INSERT INTO {name} SELECT generate_series(1,100000), 'payload'")
+        env.pageserver.safe_psql(f"checkpoint {env.initial_tenant.hex} {timeline}")
+
+    # uncompressed layers
+    write_table('t_plain')
+
+    log.info("restarting pageserver with zstd compression of layers")
+    env.pageserver.stop()
+    env.pageserver.start(overrides=["--pageserver-config-override=layer_compression='zstd'"])
+
+    # compressed layers
+    write_table('t_zstd')
+
+    # restart once more, to read everything back from the layer files
+    pg.stop()
+    env.pageserver.stop()
+    env.pageserver.start(overrides=["--pageserver-config-override=layer_compression='zstd'"])
+    pg.start()
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            for name in ['t_plain', 't_zstd']:
+                cur.execute(f"SELECT count(*) FROM {name}")
+                assert cur.fetchone() == (100000, )
@@ -0,0 +1,77 @@
+from contextlib import closing
+from pathlib import Path
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn, wait_for_upload
+
+PAGE_SZ = 8192
+
+
+#
+# Checks that layer files failing the checksum check are downloaded again
+# from the remote storage, instead of returning garbage.
+#
+def test_layer_corruption(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+
+    timeline_id = env.zenith_cli.create_branch('test_layer_corruption')
+    pg = env.postgres.create_start('test_layer_corruption')
+
+    client = env.pageserver.http_client()
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(client, env.initial_tenant, timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {env.initial_tenant.hex} {timeline_id.hex}")
+    wait_for_upload(client, env.initial_tenant, timeline_id, current_lsn)
+
+    # Restart to read the layers from disk, not from the page cache
+    pg.stop()
+    env.pageserver.stop()
+
+    timeline_subdir = Path('tenants') / env.initial_tenant.hex / 'timelines' / timeline_id.hex
+    timeline_path = Path(env.repo_dir) / timeline_subdir
+    remote_timeline_path = Path(env.repo_dir) / 'local_fs_remote_storage' / timeline_subdir
+    layer_files = [path for path in timeline_path.iterdir() if '__' in path.name]
+    assert layer_files, "Expected layer files after checkpoint"
+    for layer_file in layer_files:
+        assert (remote_timeline_path / layer_file.name).exists(), \
+            f"layer {layer_file.name} should be present in the remote storage"
+        log.info(f"corrupting layer file {layer_file}")
+        with open(layer_file, 'r+b') as f:
+            f.seek(PAGE_SZ + 8)
+            byte = f.read(1)
+            f.seek(PAGE_SZ + 8)
+            f.write(bytes([byte[0] ^ 1]))
+
+    env.pageserver.start()
+    pg.start()
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SELECT count(*), max(key) FROM t")
+            assert cur.fetchone() == (100000, 100000)
+
+    # The corrupt layers were replaced with their remote copies
+    metrics = client.get_metrics()
+    corrupt_layers = [
+        int(float(line.split()[-1])) for line in metrics.splitlines()
+        if line.startswith('pageserver_corrupt_layers_total')
+    ]
+    assert corrupt_layers and corrupt_layers[0] > 0, "corrupt layers should have been detected"
+
+    # Layers that weren't needed for the queries might still be corrupt locally,
+    # but the ones that were read must have been replaced with the remote copies.
+    redownloaded = [
+        layer_file for layer_file in layer_files if layer_file.exists()
+        and layer_file.read_bytes() == (remote_timeline_path / layer_file.name).read_bytes()
+    ]
+    assert len(redownloaded) == corrupt_layers[0], \
+        "corrupt layers should be downloaded again when read"
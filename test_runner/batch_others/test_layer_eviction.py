@@ -0,0 +1,49 @@
+from contextlib import closing
+from pathlib import Path
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+#
+# Checks that uploaded layers are evicted from the local disk when the tenant's
+# local layers size limit is exceeded, and that the evicted data is still readable.
+#
+def test_layer_eviction(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+
+    tenant_id, _ = env.zenith_cli.create_tenant(conf={'max_local_layers_size': '1'})
+    timeline_id = env.zenith_cli.create_timeline('test_layer_eviction', tenant_id=tenant_id)
+    pg = env.postgres.create_start('test_layer_eviction', tenant_id=tenant_id)
+
+    client = env.pageserver.http_client()
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id.hex}")
+    wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+
+    timeline_path = Path(env.repo_dir) / 'tenants' / tenant_id.hex / 'timelines' / timeline_id.hex
+
+    def assert_layers_evicted():
+        local_layers = [path for path in timeline_path.iterdir() if '__' in path.name]
+        log.info(f"local layers left: {local_layers}")
+        assert not local_layers
+
+    pg.stop()
+    wait_until(number_of_iterations=20, interval=1, func=assert_layers_evicted)
+
+    # evicted layers are downloaded back on demand
+    pg.start()
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SELECT count(*) FROM t")
+            assert cur.fetchone() == (100000, )
@@ -0,0 +1,53 @@
+from contextlib import closing
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnv, wait_for_last_record_lsn
+
+
+#
+# Checks that the reconstruct trace endpoint explains how a page version was
+# reconstructed, including the hop from a branch to its ancestor.
+#
+def test_reconstruct_trace(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    parent_timeline_id = env.zenith_cli.create_branch('test_reconstruct_trace_parent', 'empty')
+    pg = env.postgres.create_start('test_reconstruct_trace_parent')
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,10), 'payload'")
+            cur.execute("UPDATE t SET value = 'updated' WHERE key = 5")
+            cur.execute("""
+                SELECT (SELECT oid FROM pg_tablespace WHERE spcname = 'pg_default'),
+                       (SELECT oid FROM pg_database WHERE datname = current_database()),
+                       pg_relation_filenode('t'),
+                       pg_current_wal_flush_lsn()
+            """)
+            spcnode, dbnode, relnode, lsn = cur.fetchone()
+
+    rel = f"{spcnode}/{dbnode}/{relnode}"
+    client = env.pageserver.http_client()
+    wait_for_last_record_lsn(client, env.initial_tenant, parent_timeline_id, lsn_from_hex(lsn))
+
+    trace = client.reconstruct_trace(env.initial_tenant, parent_timeline_id, rel, 0, lsn)
+    log.info(f"trace on the parent: {trace}")
+    assert trace['lsn'] == lsn
+    assert len(trace['layers']) > 0
+    assert trace['ancestor_hops'] == []
+    # the page was initialized by the first insert, and then updated
+    records = trace['records']
+    assert len(records) >= 2 or trace['image_lsn'] is not None
+    assert all(lsn_from_hex(r['lsn']) <= lsn_from_hex(lsn) for r in records)
+
+    # The page was not modified on the branch, so it's read from the parent
+    child_timeline_id = env.zenith_cli.create_branch('test_reconstruct_trace_child',
+                                                     'test_reconstruct_trace_parent')
+    trace = client.reconstruct_trace(env.initial_tenant, child_timeline_id, rel, 0)
+    log.info(f"trace on the child: {trace}")
+    hops = trace['ancestor_hops']
+    assert len(hops) == 1
+    assert hops[0]['timeline_id'] == child_timeline_id.hex
+    assert hops[0]['ancestor_timeline_id'] == parent_timeline_id.hex
+    assert all(layer['timeline_id'] == parent_timeline_id.hex for layer in trace['layers'])
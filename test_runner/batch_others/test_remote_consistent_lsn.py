@@ -0,0 +1,62 @@
+import base64
+import json
+from contextlib import closing
+
+import requests
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+#
+# Checks that the pageserver publishes the LSN up to which the timeline data is
+# uploaded to the remote storage via the broker, and that all safekeepers learn it.
+#
+def test_remote_consistent_lsn_published(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 3
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+
+    timeline_id = env.zenith_cli.create_branch('test_remote_consistent_lsn_published')
+    pg = env.postgres.create_start('test_remote_consistent_lsn_published')
+
+    client = env.pageserver.http_client()
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(client, env.initial_tenant, timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {env.initial_tenant.hex} {timeline_id.hex}")
+    wait_for_upload(client, env.initial_tenant, timeline_id, current_lsn)
+
+    # The pageserver publishes its timeline state under its own key in etcd
+    pageserver_key = f'neon/{env.initial_tenant.hex}/{timeline_id.hex}/pageserver/1'
+
+    def pageserver_published_remote_consistent_lsn():
+        res = requests.post(f'{env.broker.client_url()}/v3/kv/range',
+                            json={'key': base64.b64encode(pageserver_key.encode()).decode()})
+        res.raise_for_status()
+        kvs = res.json().get('kvs', [])
+        assert kvs, f'no {pageserver_key} key in etcd'
+        ps_info = json.loads(base64.b64decode(kvs[0]['value']))
+        log.info(f"pageserver published {ps_info}")
+        assert lsn_from_hex(ps_info['remote_consistent_lsn']) >= current_lsn
+
+    wait_until(number_of_iterations=20,
+               interval=1,
+               func=pageserver_published_remote_consistent_lsn)
+
+    def all_safekeepers_know_remote_consistent_lsn():
+        for sk in env.safekeepers:
+            status = sk.http_client().timeline_status(env.initial_tenant.hex, timeline_id.hex)
+            log.info(f"safekeeper {sk.id} remote_consistent_lsn {status.remote_consistent_lsn}")
+            assert lsn_from_hex(status.remote_consistent_lsn) >= current_lsn
+
+    wait_until(number_of_iterations=20,
+               interval=1,
+               func=all_safekeepers_know_remote_consistent_lsn)
@@ -4,7 +4,6 @@
 import shutil, os
 from contextlib import closing
 from pathlib import Path
-import time
 from uuid import UUID
 from fixtures.zenith_fixtures import ZenithEnvBuilder, assert_local, wait_until, wait_for_last_record_lsn, wait_for_upload
 from fixtures.log_helper import log
@@ -25,8 +24,9 @@ import pytest
 # 2. Second pageserver
 #   * starts another pageserver, connected to the same remote storage
 #   * timeline_attach is called for the same timeline id
-#   * timeline status is polled until it's downloaded
-#   * queries the specific data, ensuring that it matches the one stored before
+#   * timeline status is polled until it's attached, with no layers downloaded yet
+#   * with a failpoint in the layer download, compute fails to start and no temporary download files are left
+#   * queries the specific data, ensuring that it matches the one stored before and the layers got downloaded on demand
 #
 # The tests are done for all types of remote storage pageserver supports.
 @pytest.mark.parametrize('storage_type', ['local_fs', 'mock_s3'])
@@ -85,30 +85,13 @@ def test_remote_storage_backup_and_restore(zenith_env_builder: ZenithEnvBuilder,
     ##### Second start, restore the data and ensure it's the same
     env.pageserver.start()
 
-    # Introduce failpoint in download
-    env.pageserver.safe_psql(f"failpoints remote-storage-download-pre-rename=return")
-
     client.timeline_attach(UUID(tenant_id), UUID(timeline_id))
 
-    # is there a better way to assert that fafilpoint triggered?
-    time.sleep(10)
-
-    # assert cannot attach timeline that is scheduled for download
-    with pytest.raises(Exception, match="Timeline download is already in progress"):
+    # assert cannot attach timeline that is already attached or scheduled for download
+    with pytest.raises(Exception, match="(Timeline download is already in progress|Timeline is already present locally)"):
         client.timeline_attach(UUID(tenant_id), UUID(timeline_id))
 
-    detail = client.timeline_detail(UUID(tenant_id), UUID(timeline_id))
-    log.info("Timeline detail with active failpoint: %s", detail)
-    assert detail['local'] is None
-    assert detail['remote']['awaits_download']
-
-    # trigger temporary download files removal
-    env.pageserver.stop()
-    env.pageserver.start()
-
-    client.timeline_attach(UUID(tenant_id), UUID(timeline_id))
-
-    log.info("waiting for timeline redownload")
+    log.info("waiting for timeline attach")
     wait_until(number_of_iterations=10,
                interval=1,
                func=lambda: assert_local(client, UUID(tenant_id), UUID(timeline_id)))
@@ -119,9 +102,32 @@ def test_remote_storage_backup_and_restore(zenith_env_builder: ZenithEnvBuilder,
     assert lsn_from_hex(detail['local']['last_record_lsn']) >= current_lsn, 'current db Lsn should shoud not be less than the one stored on remote storage'
     assert not detail['remote']['awaits_download']
 
-    pg = env.postgres.create_start('main')
+    # layers are downloaded on demand, attach only brings the metadata
+    timeline_dir = dir_to_clear / tenant_id / 'timelines' / timeline_id
+    assert not [path for path in timeline_dir.iterdir() if '__' in path.name], \
+        'no layers should be downloaded before the timeline is accessed'
+
+    # Introduce failpoint in download
+    env.pageserver.safe_psql("failpoints remote-storage-download-pre-rename=return")
+
+    pg = env.postgres.create('main')
+    with pytest.raises(Exception):
+        pg.start()
+    pg.stop()
+
+    assert not [path for path in timeline_dir.iterdir() if '__' in path.name], \
+        'no layers should be downloaded with the failpoint active'
+    assert not [path for path in timeline_dir.iterdir() if path.name.endswith('.temp_download')], \
+        'temporary download files should be removed after a failed download'
+
+    env.pageserver.safe_psql("failpoints remote-storage-download-pre-rename=off")
+
+    pg.start()
     with closing(pg.connect()) as conn:
         with conn.cursor() as cur:
             for checkpoint_number in checkpoint_numbers:
                 cur.execute(f'SELECT secret FROM t{checkpoint_number} WHERE id = {data_id};')
                 assert cur.fetchone() == (f'{data_secret}|{checkpoint_number}', )
+
+    assert [path for path in timeline_dir.iterdir() if '__' in path.name], \
+        'layers should be downloaded on demand when the timeline is accessed'
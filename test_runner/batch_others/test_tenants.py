@@ -3,10 +3,10 @@ from datetime import datetime
 import os
 import pytest
 
-from fixtures.zenith_fixtures import ZenithEnvBuilder
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn, wait_for_upload, wait_until
 from fixtures.log_helper import log
 from fixtures.metrics import parse_metrics
-from fixtures.utils import lsn_to_hex
+from fixtures.utils import lsn_from_hex, lsn_to_hex
 
 
 @pytest.mark.parametrize('with_safekeepers', [False, True])
@@ -118,3 +118,48 @@ def test_metrics_normal_work(zenith_env_builder: ZenithEnvBuilder):
         log.info(
             f"process_start_time_seconds (UTC): {datetime.fromtimestamp(metrics.query_one('process_start_time_seconds').value)}"
         )
+
+
+def test_tenant_detach_and_delete(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenant_1, _ = env.zenith_cli.create_tenant()
+    tenant_2, timeline_2 = env.zenith_cli.create_tenant()
+    tenants_path = zenith_env_builder.repo_dir / 'tenants'
+    assert (tenants_path / tenant_1.hex).exists()
+    assert (tenants_path / tenant_2.hex).exists()
+
+    # upload some data of the tenant to be deleted
+    pg = env.postgres.create_start('main', tenant_id=tenant_2)
+    pg.safe_psql("CREATE TABLE t AS SELECT generate_series(1, 1000) AS x")
+    current_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+    pg.stop()
+    wait_for_last_record_lsn(client, tenant_2, timeline_2, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {tenant_2.hex} {timeline_2.hex}")
+    wait_for_upload(client, tenant_2, timeline_2, current_lsn)
+
+    remote_tenant_path = zenith_env_builder.repo_dir / 'local_fs_remote_storage' / 'tenants' / tenant_2.hex
+
+    def remote_files():
+        return [p for p in remote_tenant_path.rglob('*') if p.is_file()]
+
+    assert len(remote_files()) > 0
+
+    env.zenith_cli.detach_tenant(tenant_1)
+    assert not (tenants_path / tenant_1.hex).exists()
+
+    env.zenith_cli.delete_tenant(tenant_2)
+    assert not (tenants_path / tenant_2.hex).exists()
+
+    def remote_data_purged():
+        files = remote_files()
+        assert files == [], f"remote files left: {files}"
+
+    wait_until(number_of_iterations=20, interval=1, func=remote_data_purged)
+
+    tenant_ids = [t['id'] for t in client.tenant_list()]
+    assert tenant_1.hex not in tenant_ids
+    assert tenant_2.hex not in tenant_ids
+    assert env.initial_tenant.hex in tenant_ids
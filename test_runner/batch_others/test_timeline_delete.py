@@ -0,0 +1,74 @@
+from contextlib import closing
+from pathlib import Path
+
+import pytest
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import LocalFsStorage, ZenithEnvBuilder, ZenithPageserverApiException, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+#
+# Creates a branch with some data backed up in the remote storage, then deletes it
+# and checks that both local and remote timeline files are gone.
+#
+def test_timeline_delete(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+
+    parent_timeline_id = env.zenith_cli.create_branch("test_timeline_delete_parent")
+    child_timeline_id = env.zenith_cli.create_branch("test_timeline_delete_child",
+                                                     "test_timeline_delete_parent")
+
+    ps_http = env.pageserver.http_client()
+
+    with pytest.raises(ZenithPageserverApiException,
+                       match="Cannot delete timeline which has child timelines") as excinfo:
+        ps_http.timeline_delete(env.initial_tenant, parent_timeline_id)
+    assert excinfo.value.__cause__.response.status_code == 400
+
+    # The refused deletion must leave the parent timeline working, including its WAL receiver
+    pg_parent = env.postgres.create_start("test_timeline_delete_parent")
+    with closing(pg_parent.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE parent_t(key int primary key)")
+            cur.execute("INSERT INTO parent_t SELECT generate_series(1,1000)")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            parent_lsn = lsn_from_hex(cur.fetchone()[0])
+    wait_for_last_record_lsn(ps_http, env.initial_tenant, parent_timeline_id, parent_lsn)
+    pg_parent.stop()
+
+    pg = env.postgres.create_start("test_timeline_delete_child")
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(ps_http, env.initial_tenant, child_timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {env.initial_tenant.hex} {child_timeline_id.hex}")
+    wait_for_upload(ps_http, env.initial_tenant, child_timeline_id, current_lsn)
+    pg.stop()
+
+    assert isinstance(env.remote_storage, LocalFsStorage)
+    timeline_subpath = Path('tenants') / env.initial_tenant.hex / 'timelines' / child_timeline_id.hex
+    local_timeline_path = Path(env.repo_dir) / timeline_subpath
+    remote_timeline_path = Path(env.remote_storage.local_path) / timeline_subpath
+    assert local_timeline_path.exists()
+    assert any(remote_timeline_path.iterdir()), "Expected timeline files in the remote storage"
+
+    ps_http.timeline_delete(env.initial_tenant, child_timeline_id)
+
+    assert not local_timeline_path.exists()
+    timeline_ids = [timeline['timeline_id'] for timeline in ps_http.timeline_list(env.initial_tenant)]
+    assert child_timeline_id.hex not in timeline_ids
+
+    def assert_remote_files_deleted():
+        remote_files = list(remote_timeline_path.iterdir()) if remote_timeline_path.exists() else []
+        log.info(f"remote timeline files left: {remote_files}")
+        assert not remote_files
+
+    wait_until(number_of_iterations=10, interval=1, func=assert_remote_files_deleted)
+
+    # The parent has no children anymore and can be deleted too
+    ps_http.timeline_delete(env.initial_tenant, parent_timeline_id)
@@ -1,8 +1,16 @@
+import pytest
 from contextlib import closing
 import psycopg2.extras
 import psycopg2.errors
-from fixtures.zenith_fixtures import ZenithEnv, ZenithEnvBuilder, Postgres, assert_local
+from fixtures.zenith_fixtures import (ZenithEnv,
+                                     ZenithEnvBuilder,
+                                     Postgres,
+                                     assert_local,
+                                     last_record_lsn,
+                                     wait_for_last_record_lsn,
+                                     wait_until)
 from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
 import time
 
 
@@ -43,6 +51,57 @@ def test_timeline_size(zenith_simple_env: ZenithEnv):
                 "current_logical_size_non_incremental"]
 
 
+#
+# Checks that the logical size is stored with the timeline and survives a
+# restart, and that the synthetic size accounts for the retained WAL.
+#
+def test_timeline_size_persists(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+    new_timeline_id = env.zenith_cli.create_branch('test_timeline_size_persists')
+    pg = env.postgres.create_start('test_timeline_size_persists')
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("""
+                CREATE TABLE foo AS
+                    SELECT 'long string to consume some space' || g AS t
+                    FROM generate_series(1, 10000) g
+            """)
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            lsn = cur.fetchone()[0]
+
+    client = env.pageserver.http_client()
+    wait_for_last_record_lsn(client, env.initial_tenant, new_timeline_id, lsn_from_hex(lsn))
+    pg.stop()
+    env.pageserver.safe_psql(f"checkpoint {env.initial_tenant.hex} {new_timeline_id.hex}")
+
+    before = assert_local(client, env.initial_tenant, new_timeline_id)['local']
+    assert before['current_logical_size'] == before['current_logical_size_non_incremental']
+    assert before['synthetic_size'] > before['current_logical_size']
+
+    env.pageserver.stop()
+    env.pageserver.start()
+
+    after = assert_local(client, env.initial_tenant, new_timeline_id)['local']
+    log.info(f"timeline sizes before restart: {before}, after restart: {after}")
+    assert after['current_logical_size'] == before['current_logical_size']
+    assert after['current_logical_size'] == after['current_logical_size_non_incremental']
+    assert after['synthetic_size'] >= after['current_logical_size']
+
+    metrics = client.get_metrics()
+
+    def metric_value(name: str) -> int:
+        values = [
+            int(float(line.split()[-1])) for line in metrics.splitlines()
+            if line.startswith(name + '{') and new_timeline_id.hex in line
+        ]
+        assert len(values) == 1, f"{name} not found"
+        return values[0]
+
+    assert metric_value('pageserver_current_logical_size') == after['current_logical_size']
+    assert metric_value('pageserver_synthetic_size') >= after['current_logical_size']
+
+
 # wait until received_lsn_lag is 0
 def wait_for_pageserver_catchup(pgmain: Postgres, polling_interval=1, timeout=60):
     started_at = time.time()
@@ -133,3 +192,71 @@ def test_timeline_size_quota(zenith_env_builder: ZenithEnvBuilder):
             cur.execute("SELECT * from pg_size_pretty(pg_cluster_size())")
             pg_cluster_size = cur.fetchone()
             log.info(f"pg_cluster_size = {pg_cluster_size}")
+
+
+#
+# Checks that the pageserver flags the timelines that exceed the tenant's
+# max_logical_size, and that the flag is cleared when the limit is raised.
+#
+def test_timeline_size_limit_on_pageserver(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    tenant_id, _ = env.zenith_cli.create_tenant(conf={'max_logical_size': str(30 * 1024 * 1024)})
+    timeline_id = env.zenith_cli.create_timeline('test_timeline_size_limit_on_pageserver',
+                                                 tenant_id=tenant_id)
+    pg = env.postgres.create_start('test_timeline_size_limit_on_pageserver', tenant_id=tenant_id)
+
+    client = env.pageserver.http_client()
+    assert not assert_local(client, tenant_id, timeline_id)['local']['logical_size_limit_exceeded']
+
+    def wait_for_ingestion(lsn):
+        # Wait until the pageserver has ingested the WAL up to 'lsn', or has
+        # stopped ingesting it before, at a record refused by the size limit.
+        def ingested_or_stopped():
+            last_lsn = last_record_lsn(client, tenant_id, timeline_id)
+            if last_lsn >= lsn:
+                return
+            time.sleep(1)
+            assert last_record_lsn(client, tenant_id, timeline_id) == last_lsn
+            assert assert_local(client, tenant_id, timeline_id)['local']['logical_size_limit_exceeded']
+
+        wait_until(30, 1, ingested_or_stopped)
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE EXTENSION zenith_test_utils")
+            cur.execute("CREATE TABLE foo (t text)")
+
+            # Keep writing until the pageserver refuses the WAL past the limit,
+            # and the compute can't get the pages written after it.
+            with pytest.raises(psycopg2.errors.IoError, match='exceeded its logical size limit'):
+                for i in range(100):
+                    cur.execute("""
+                        INSERT INTO foo
+                            SELECT 'long string to consume some space' || g
+                            FROM generate_series(1, 100000) g
+                    """)
+                    cur.execute("SELECT pg_current_wal_flush_lsn()")
+                    wait_for_ingestion(lsn_from_hex(cur.fetchone()[0]))
+                    cur.execute("SELECT clear_buffer_cache()")
+                    cur.execute("SELECT count(*) FROM foo")
+
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            lsn = lsn_from_hex(cur.fetchone()[0])
+
+    local_details = assert_local(client, tenant_id, timeline_id)['local']
+    log.info(f"timeline details with the size limit: {local_details}")
+    assert local_details['current_logical_size'] > 30 * 1024 * 1024
+    assert local_details['logical_size_limit_exceeded']
+    assert lsn_from_hex(local_details['last_record_lsn']) < lsn
+
+    # Once the limit is lifted, the refused WAL is ingested and the pages can be read again
+    env.zenith_cli.config_tenant(tenant_id=tenant_id, conf={'max_logical_size': '0'})
+    assert not assert_local(client, tenant_id, timeline_id)['local']['logical_size_limit_exceeded']
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, lsn)
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SELECT clear_buffer_cache()")
+            cur.execute("SELECT count(*) FROM foo")
+            assert cur.fetchone()[0] > 0
@@ -464,6 +464,130 @@ def test_wal_backup(zenith_env_builder: ZenithEnvBuilder, storage_type: str):
         victim.start()
 
 
+# Test that the safekeeper removes offloaded WAL the pageserver hasn't uploaded
+# yet, and that it is streamed to the pageserver from the remote storage.
+def test_wal_read_from_remote_storage(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 1
+    zenith_env_builder.enable_local_fs_remote_storage()
+    zenith_env_builder.remote_storage_users = RemoteStorageUsers.SAFEKEEPER
+    # Don't let the pageserver checkpoint, so that remote_consistent_lsn
+    # stays behind and the pageserver has to stream all the WAL since the
+    # branch creation again after restart.
+    zenith_env_builder.pageserver_config_override = "tenant_config={checkpoint_distance = 10000000000}"
+    env = zenith_env_builder.init_start()
+
+    env.zenith_cli.create_branch('test_wal_read_from_remote_storage')
+    pg = env.postgres.create_start('test_wal_read_from_remote_storage')
+
+    tenant_id = pg.safe_psql("show zenith.zenith_tenant")[0][0]
+    timeline_id = pg.safe_psql("show zenith.zenith_timeline")[0][0]
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute('create table t(key int, value text)')
+            # roughly fills two segments
+            cur.execute("insert into t select generate_series(1,500000), 'payload'")
+            cur.execute('select pg_current_wal_flush_lsn()')
+            flush_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    sk = env.safekeepers[0]
+    sk_http_cli = sk.http_client()
+    ps_http_cli = env.pageserver.http_client()
+    started_at = time.time()
+    while True:
+        tli_status = sk_http_cli.timeline_status(tenant_id, timeline_id)
+        ps_status = ps_http_cli.timeline_detail(uuid.UUID(tenant_id), uuid.UUID(timeline_id))
+        log.info(f"sk status is {tli_status}, pageserver status is {ps_status}")
+        backup_lsn = lsn_from_hex(tli_status.backup_lsn)
+        if backup_lsn >= 0x2000000 and lsn_from_hex(
+                ps_status['local']['last_record_lsn']) >= flush_lsn:
+            break
+        elapsed = time.time() - started_at
+        if elapsed > 30:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for WAL offloading")
+        time.sleep(0.5)
+
+    # The first segment is removed once offloaded, even though the pageserver
+    # hasn't uploaded it.
+    first_segment = os.path.join(sk.data_dir(), tenant_id, timeline_id, '000000010000000000000001')
+    started_at = time.time()
+    while os.path.exists(first_segment):
+        elapsed = time.time() - started_at
+        if elapsed > 30:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for first segment get removed")
+        time.sleep(0.5)
+    tli_status = sk_http_cli.timeline_status(tenant_id, timeline_id)
+    log.info(f"first segment removed, sk status is {tli_status}")
+    assert lsn_from_hex(tli_status.remote_consistent_lsn) < 0x2000000
+
+    pg.stop()
+    env.pageserver.stop(immediate=True)
+    sk.stop()
+
+    sk.start()
+    env.pageserver.start()
+
+    pg.start()
+    assert pg.safe_psql("select count(*) from t") == [(500000, )]
+
+
+# Test that a safekeeper which was down while WAL was written fetches it from
+# the peers once it is back, without a compute running.
+def test_peer_recovery(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 3
+    env = zenith_env_builder.init_start()
+
+    env.zenith_cli.create_branch('test_peer_recovery')
+    pg = env.postgres.create_start('test_peer_recovery')
+
+    tenant_id = pg.safe_psql("show zenith.zenith_tenant")[0][0]
+    timeline_id = pg.safe_psql("show zenith.zenith_timeline")[0][0]
+
+    pg.safe_psql('create table t(key int, value text)')
+    flush_lsn = lsn_from_hex(pg.safe_psql('select pg_current_wal_flush_lsn()')[0][0])
+
+    # Make sure the victim has some WAL of the current term, so that it is in
+    # the same epoch as the peers.
+    victim = env.safekeepers[0]
+    victim_http_cli = victim.http_client()
+    started_at = time.time()
+    while lsn_from_hex(victim_http_cli.timeline_status(tenant_id, timeline_id).flush_lsn) < flush_lsn:
+        elapsed = time.time() - started_at
+        if elapsed > 20:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for WAL on safekeeper")
+        time.sleep(0.5)
+
+    victim.stop()
+    pg.safe_psql("insert into t select generate_series(1,100000), 'payload'")
+    pg.stop()
+
+    peer_flush_lsns = [
+        sk.http_client().timeline_status(tenant_id, timeline_id).flush_lsn
+        for sk in env.safekeepers[1:]
+    ]
+    log.info(f"peers flush_lsn are {peer_flush_lsns}")
+    assert peer_flush_lsns[0] == peer_flush_lsns[1]
+
+    victim.start()
+    started_at = time.time()
+    while True:
+        victim_flush_lsn = victim_http_cli.timeline_status(tenant_id, timeline_id).flush_lsn
+        log.info(f"victim flush_lsn is {victim_flush_lsn}")
+        if victim_flush_lsn == peer_flush_lsns[0]:
+            break
+        elapsed = time.time() - started_at
+        if elapsed > 30:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for safekeeper recovery")
+        time.sleep(0.5)
+
+    # The recovered WAL is good enough to start a compute on. Stop one of the
+    # peers, so that the victim is needed for the quorum.
+    env.safekeepers[1].stop()
+    pg.start()
+    assert pg.safe_psql("select count(*) from t") == [(100000, )]
+    pg.safe_psql("insert into t values (0, 'after recovery')")
+
+
 class ProposerPostgres(PgProtocol):
     """Object for running postgres without ZenithEnv"""
     def __init__(self,
@@ -634,6 +758,66 @@ def test_timeline_status(zenith_env_builder: ZenithEnvBuilder):
     assert tli_status.timeline_start_lsn == timeline_start_lsn
 
 
+# Change the set of safekeepers through the joint configuration and check
+# that the removed safekeeper refuses the compute afterwards.
+def test_membership_change(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 3
+    env = zenith_env_builder.init_start()
+
+    env.zenith_cli.create_branch('test_membership_change')
+    pg = env.postgres.create_start('test_membership_change')
+
+    tenant_id = pg.safe_psql("show zenith.zenith_tenant")[0][0]
+    timeline_id = pg.safe_psql("show zenith.zenith_timeline")[0][0]
+    pg.safe_psql("create table t(i int)")
+
+    all_ids = [sk.id for sk in env.safekeepers]
+    new_ids = all_ids[:2]
+    removed = env.safekeepers[2]
+
+    def update_all(mconf):
+        for sk in env.safekeepers:
+            res = sk.http_client().timeline_membership_update(tenant_id, timeline_id, mconf)
+            assert res == mconf
+
+    update_all({'generation': 1, 'members': all_ids, 'new_members': None})
+    pg.safe_psql("insert into t values(1)")
+
+    http_cli = env.safekeepers[0].http_client()
+    # members can't be changed bypassing the joint configuration
+    with pytest.raises(Exception, match="400"):
+        http_cli.timeline_membership_update(tenant_id,
+                                            timeline_id, {
+                                                'generation': 2,
+                                                'members': new_ids,
+                                                'new_members': None
+                                            })
+
+    update_all({'generation': 2, 'members': all_ids, 'new_members': new_ids})
+    pg.safe_psql("insert into t values(2)")
+    update_all({'generation': 3, 'members': new_ids, 'new_members': None})
+
+    # stale generation is rejected
+    with pytest.raises(Exception, match="400"):
+        http_cli.timeline_membership_update(tenant_id,
+                                            timeline_id, {
+                                                'generation': 3,
+                                                'members': new_ids,
+                                                'new_members': all_ids
+                                            })
+    assert http_cli.timeline_membership(tenant_id, timeline_id)['generation'] == 3
+
+    # the restarted compute presents the current generation; the removed
+    # safekeeper doesn't accept it anymore, the rest still make the quorum
+    pg.stop().start()
+    assert pg.safe_psql("show zenith.safekeepers_generation") == [('3', )]
+    pg.safe_psql("insert into t values(3)")
+    assert pg.safe_psql("select sum(i) from t") == [(6, )]
+    removed_cli = removed.http_client()
+    flush_lsn = lsn_from_hex(pg.safe_psql('select pg_current_wal_flush_lsn()')[0][0])
+    assert lsn_from_hex(removed_cli.timeline_status(tenant_id, timeline_id).flush_lsn) < flush_lsn
+
+
 class SafekeeperEnv:
     def __init__(self,
                  repo_dir: Path,
@@ -846,6 +1030,91 @@ def test_replace_safekeeper(zenith_env_builder: ZenithEnvBuilder):
     show_statuses(env.safekeepers, tenant_id, timeline_id)
 
 
+# Move the timeline from one safekeeper to another one, which didn't have it,
+# by pulling it and deleting on the donor.
+def test_pull_timeline(zenith_env_builder: ZenithEnvBuilder):
+    def safekeepers_guc(env: ZenithEnv, sk_names: List[int]) -> str:
+        return ','.join([f'localhost:{sk.port.pg}' for sk in env.safekeepers if sk.id in sk_names])
+
+    zenith_env_builder.num_safekeepers = 4
+    env = zenith_env_builder.init_start()
+    env.zenith_cli.create_branch('test_pull_timeline')
+
+    pg = env.postgres.create('test_pull_timeline')
+    pg.adjust_for_safekeepers(safekeepers_guc(env, [1, 2, 3]))
+    pg.start()
+
+    tenant_id = pg.safe_psql("show zenith.zenith_tenant")[0][0]
+    timeline_id = pg.safe_psql("show zenith.zenith_timeline")[0][0]
+
+    pg.safe_psql("create table t(key int, value text)")
+    pg.safe_psql("insert into t select generate_series(1,100000), 'payload'")
+    pg.stop_and_destroy()
+
+    donor = env.safekeepers[0]
+    recipient = env.safekeepers[3]
+    donor_status = donor.http_client().timeline_status(tenant_id, timeline_id)
+
+    recipient_http_cli = recipient.http_client()
+    recipient_http_cli.timeline_pull(tenant_id, timeline_id, donor)
+    recipient_status = recipient_http_cli.timeline_status(tenant_id, timeline_id)
+    assert recipient_status.flush_lsn == donor_status.flush_lsn
+    assert recipient_status.acceptor_epoch == donor_status.acceptor_epoch
+
+    # timeline is already there
+    with pytest.raises(Exception, match="409"):
+        recipient_http_cli.timeline_pull(tenant_id, timeline_id, donor)
+
+    donor.http_client().timeline_delete_force(tenant_id, timeline_id)
+    donor.stop()
+
+    pg = env.postgres.create('test_pull_timeline')
+    pg.adjust_for_safekeepers(safekeepers_guc(env, [2, 3, 4]))
+    pg.start()
+    pg.safe_psql("insert into t values (0, 'something')")
+    assert pg.safe_psql("select count(*) from t") == [(100001, )]
+
+    # recipient is required for the quorum now
+    env.safekeepers[1].stop()
+    pg.safe_psql("insert into t values (0, 'something')")
+
+
+# Completed WAL segments get checksums, check that verification on demand
+# catches the corruption.
+def test_wal_checksums(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 1
+    env = zenith_env_builder.init_start()
+
+    env.zenith_cli.create_branch('test_wal_checksums')
+    pg = env.postgres.create_start('test_wal_checksums')
+
+    tenant_id = pg.safe_psql("show zenith.zenith_tenant")[0][0]
+    timeline_id = pg.safe_psql("show zenith.zenith_timeline")[0][0]
+
+    # generate a few segments
+    pg.safe_psql("create table t(key int, value text)")
+    pg.safe_psql("insert into t select generate_series(1,250000), 'payload'")
+
+    sk = env.safekeepers[0]
+    sk_http_cli = sk.http_client()
+    res = sk_http_cli.timeline_verify_wal(tenant_id, timeline_id)
+    assert res['verified'] > 0
+    assert res['without_checksum'] == 0
+    assert res['corrupted'] == []
+
+    timeline_dir = Path(sk.data_dir()) / tenant_id / timeline_id
+    segment = sorted(p for p in timeline_dir.iterdir() if len(p.name) == 24)[-1]
+    assert (timeline_dir / f"{segment.name}.checksum").exists()
+    with open(segment, 'r+b') as f:
+        f.seek(1000)
+        byte = f.read(1)
+        f.seek(1000)
+        f.write(bytes([byte[0] ^ 0xff]))
+
+    res = sk_http_cli.timeline_verify_wal(tenant_id, timeline_id)
+    assert res['corrupted'] == [segment.name]
+
+
 # We have `wal_keep_size=0`, so postgres should trim WAL once it's broadcasted
 # to all safekeepers. This test checks that compute WAL can fit into small number
 # of WAL segments.
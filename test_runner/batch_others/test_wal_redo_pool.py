@@ -0,0 +1,61 @@
+import threading
+from contextlib import closing
+from pathlib import Path
+
+import psycopg2.extras
+
+from fixtures.log_helper import log
+from fixtures.zenith_fixtures import ZenithEnvBuilder
+
+
+#
+# Checks that pages are reconstructed correctly by concurrent requests, when
+# the tenant has a pool of WAL redo processes, and that the pool size can be
+# changed on the fly.
+#
+def test_wal_redo_pool(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    tenant_id, _ = env.zenith_cli.create_tenant(conf={'wal_redo_process_pool_size': '4'})
+    env.zenith_cli.create_timeline('test_wal_redo_pool', tenant_id=tenant_id)
+    pg = env.postgres.create_start('test_wal_redo_pool', tenant_id=tenant_id)
+
+    num_tables = 4
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            for i in range(num_tables):
+                cur.execute(f"CREATE TABLE t{i}(key int primary key, value int)")
+                cur.execute(f"INSERT INTO t{i} SELECT generate_series(1,10000), 0")
+                cur.execute(f"UPDATE t{i} SET value = value + 1")
+
+    # restart the compute, so that the pages have to be reconstructed
+    # by the pageserver
+    pg.stop()
+    pg.start()
+
+    results = {}
+
+    def read_table(i: int):
+        with closing(pg.connect()) as conn:
+            with conn.cursor() as cur:
+                cur.execute(f"SELECT count(*), sum(value) FROM t{i}")
+                results[i] = cur.fetchone()
+
+    threads = [threading.Thread(target=read_table, args=(i, )) for i in range(num_tables)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+
+    assert results == {i: (10000, 10000) for i in range(num_tables)}
+
+    tenant_path = Path(env.repo_dir) / 'tenants' / tenant_id.hex
+    redo_datadirs = list(tenant_path.glob('wal-redo-datadir*'))
+    log.info(f"WAL redo data directories: {redo_datadirs}")
+    assert 1 <= len(redo_datadirs) <= 4
+
+    env.zenith_cli.config_tenant(tenant_id=tenant_id, conf={'wal_redo_process_pool_size': '1'})
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor(cursor_factory=psycopg2.extras.RealDictCursor) as pscur:
+            pscur.execute(f"show {tenant_id.hex}")
+            assert pscur.fetchone()['wal_redo_process_pool_size'] == 1
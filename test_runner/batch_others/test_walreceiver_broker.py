@@ -0,0 +1,57 @@
+import re
+from contextlib import closing
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn, wait_until
+
+
+#
+# Checks that the pageserver finds the safekeepers to stream WAL from in the
+# broker, and switches to another safekeeper when the current one goes away.
+#
+def test_walreceiver_switch_safekeeper(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 3
+    env = zenith_env_builder.init_start()
+
+    timeline_id = env.zenith_cli.create_branch('test_walreceiver_switch_safekeeper')
+    pg = env.postgres.create_start('test_walreceiver_switch_safekeeper')
+
+    client = env.pageserver.http_client()
+
+    def safekeeper_port() -> int:
+        connstr = client.wal_receiver_get(env.initial_tenant, timeline_id)['wal_producer_connstr']
+        match = re.search(r'port=(\d+)', connstr)
+        assert match is not None, connstr
+        return int(match.group(1))
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,10000), 'payload'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(client, env.initial_tenant, timeline_id, current_lsn)
+
+    old_port = safekeeper_port()
+    old_safekeeper = next(sk for sk in env.safekeepers if sk.port.pg == old_port)
+    log.info(f"pageserver streams from safekeeper {old_safekeeper.id}, stopping it")
+    old_safekeeper.stop()
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("INSERT INTO t SELECT generate_series(10001,20000), 'payload'")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    def switched():
+        assert safekeeper_port() != old_port
+
+    wait_until(number_of_iterations=30, interval=1, func=switched)
+    wait_for_last_record_lsn(client, env.initial_tenant, timeline_id, current_lsn)
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SELECT count(*) FROM t")
+            assert cur.fetchone() == (20000, )
@@ -789,12 +789,19 @@ class ZenithPageserverHttpClient(requests.Session):
         )
         self.verbose_error(res)
 
+    def timeline_delete(self, tenant_id: uuid.UUID, timeline_id: uuid.UUID):
+        res = self.delete(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}",
+        )
+        self.verbose_error(res)
+
     def timeline_create(
         self,
         tenant_id: uuid.UUID,
         new_timeline_id: Optional[uuid.UUID] = None,
         ancestor_timeline_id: Optional[uuid.UUID] = None,
         ancestor_start_lsn: Optional[str] = None,
+        ancestor_start_timestamp: Optional[str] = None,
     ) -> Dict[Any, Any]:
         res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline",
                         json={
@@ -802,6 +809,8 @@ class ZenithPageserverHttpClient(requests.Session):
                             new_timeline_id.hex if new_timeline_id else None,
                             'ancestor_start_lsn':
                             ancestor_start_lsn,
+                            'ancestor_start_timestamp':
+                            ancestor_start_timestamp,
                             'ancestor_timeline_id':
                             ancestor_timeline_id.hex if ancestor_timeline_id else None,
                         })
@@ -834,6 +843,14 @@ class ZenithPageserverHttpClient(requests.Session):
         assert isinstance(new_tenant_id, str)
         return uuid.UUID(new_tenant_id)
 
+    def tenant_detach(self, tenant_id: uuid.UUID):
+        res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/detach")
+        self.verbose_error(res)
+
+    def tenant_delete(self, tenant_id: uuid.UUID):
+        res = self.delete(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}")
+        self.verbose_error(res)
+
     def timeline_list(self, tenant_id: uuid.UUID) -> List[Dict[Any, Any]]:
         res = self.get(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline")
         self.verbose_error(res)
@@ -859,6 +876,44 @@ class ZenithPageserverHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
+    def reconstruct_trace(self,
+                          tenant_id: uuid.UUID,
+                          timeline_id: uuid.UUID,
+                          rel: str,
+                          blkno: int,
+                          lsn: Optional[str] = None) -> Dict[Any, Any]:
+        params: Dict[str, Any] = {'rel': rel, 'blkno': blkno}
+        if lsn is not None:
+            params['lsn'] = lsn
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/reconstruct_trace",
+            params=params,
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_export(self,
+                        tenant_id: uuid.UUID,
+                        timeline_id: uuid.UUID,
+                        tar_path: str,
+                        lsn: Optional[str] = None) -> str:
+        """
+        Download the timeline archive into 'tar_path', return the LSN it was exported at.
+        """
+        params = {'lsn': lsn} if lsn is not None else {}
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/export",
+            params=params,
+            stream=True,
+        )
+        self.verbose_error(res)
+        with open(tar_path, 'wb') as f:
+            for chunk in res.iter_content(chunk_size=64 * 1024):
+                f.write(chunk)
+        return res.headers['X-Export-Lsn']
+
     def get_metrics(self) -> str:
         res = self.get(f"http://localhost:{self.port}/metrics")
         self.verbose_error(res)
@@ -875,6 +930,8 @@ CREATE_TIMELINE_ID_EXTRACTOR = re.compile(r"^Created timeline '(?P<timeline_id>[
                                           re.MULTILINE)
 CREATE_TIMELINE_ID_EXTRACTOR = re.compile(r"^Created timeline '(?P<timeline_id>[^']+)'",
                                           re.MULTILINE)
+IMPORT_TIMELINE_ID_EXTRACTOR = re.compile(r"^Imported timeline '(?P<timeline_id>[^']+)'",
+                                          re.MULTILINE)
 TIMELINE_DATA_EXTRACTOR = re.compile(r"\s(?P<branch_name>[^\s]+)\s\[(?P<timeline_id>[^\]]+)\]",
                                      re.MULTILINE)
 
@@ -922,6 +979,14 @@ class ZenithCli:
                 sum(list(map(lambda kv: (['-c', kv[0] + ':' + kv[1]]), conf.items())), []))
         res.check_returncode()
 
+    def detach_tenant(self, tenant_id: uuid.UUID):
+        res = self.raw_cli(['tenant', 'detach', '--tenant-id', tenant_id.hex])
+        res.check_returncode()
+
+    def delete_tenant(self, tenant_id: uuid.UUID):
+        res = self.raw_cli(['tenant', 'delete', '--tenant-id', tenant_id.hex])
+        res.check_returncode()
+
     def list_tenants(self) -> 'subprocess.CompletedProcess[str]':
         res = self.raw_cli(['tenant', 'list'])
         res.check_returncode()
@@ -950,6 +1015,35 @@ class ZenithCli:
 
         return uuid.UUID(created_timeline_id)
 
+    def import_timeline(self,
+                        new_branch_name: str,
+                        tar_path: str,
+                        base_lsn: str,
+                        end_lsn: Optional[str] = None,
+                        tenant_id: Optional[uuid.UUID] = None) -> uuid.UUID:
+        cmd = [
+            'timeline',
+            'import',
+            '--branch-name',
+            new_branch_name,
+            '--tenant-id',
+            (tenant_id or self.env.initial_tenant).hex,
+            '--base-lsn',
+            base_lsn,
+            '--file',
+            tar_path,
+        ]
+        if end_lsn is not None:
+            cmd.extend(['--end-lsn', end_lsn])
+
+        res = self.raw_cli(cmd)
+        res.check_returncode()
+
+        matches = IMPORT_TIMELINE_ID_EXTRACTOR.search(res.stdout)
+        if matches is None:
+            raise Exception('could not find timeline id after `zenith timeline import` invocation')
+        return uuid.UUID(matches.group('timeline_id'))
+
     def create_root_branch(self, branch_name: str, tenant_id: Optional[uuid.UUID] = None):
         cmd = [
             'timeline',
@@ -978,7 +1072,8 @@ class ZenithCli:
                       new_branch_name: str = DEFAULT_BRANCH_NAME,
                       ancestor_branch_name: Optional[str] = None,
                       tenant_id: Optional[uuid.UUID] = None,
-                      ancestor_start_lsn: Optional[str] = None) -> uuid.UUID:
+                      ancestor_start_lsn: Optional[str] = None,
+                      ancestor_start_timestamp: Optional[str] = None) -> uuid.UUID:
         cmd = [
             'timeline',
             'branch',
@@ -991,6 +1086,8 @@ class ZenithCli:
             cmd.extend(['--ancestor-branch-name', ancestor_branch_name])
         if ancestor_start_lsn is not None:
             cmd.extend(['--ancestor-start-lsn', ancestor_start_lsn])
+        if ancestor_start_timestamp is not None:
+            cmd.extend(['--ancestor-start-timestamp', ancestor_start_timestamp])
 
         res = self.raw_cli(cmd)
         res.check_returncode()
@@ -1847,6 +1944,42 @@ class SafekeeperHttpClient(requests.Session):
             json=body)
         res.raise_for_status()
 
+    def timeline_membership(self, tenant_id: str, timeline_id: str) -> Dict[Any, Any]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/membership"
+        )
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_membership_update(self, tenant_id: str, timeline_id: str,
+                                   body: Dict[str, Any]) -> Dict[Any, Any]:
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/membership",
+            json=body)
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_pull(self, tenant_id: str, timeline_id: str, donor: 'Safekeeper'):
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/pull",
+            json={
+                'donor_id': donor.id, 'donor_http_addr': f"localhost:{donor.port.http}"
+            })
+        res.raise_for_status()
+
+    def timeline_verify_wal(self, tenant_id: str, timeline_id: str) -> Dict[Any, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/verify_wal"
+        )
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def timeline_delete_force(self, tenant_id: str, timeline_id: str) -> Dict[Any, Any]:
         res = self.delete(
             f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}")